@@ -0,0 +1,472 @@
+#![no_std]
+#![no_main]
+#![feature(impl_trait_in_assoc_type)]
+
+use core::{convert::Infallible, fmt::Write, mem::MaybeUninit};
+
+use embassy_executor::Spawner;
+use embassy_time::{Delay, Duration, Instant, Timer, WithTimeout};
+use esp_backtrace as _;
+use esp_hal::{
+    clock::CpuClock,
+    gpio::{Input, InputConfig, Level, Output, OutputConfig},
+    ledc::channel::Error as ServoError,
+};
+use esp_println::logger::init_logger;
+use haviliar_iot::{
+    controller::{espnow::EspNowTransport, gate::{GateController, GateEvent}, lora::LoraController, transport::Dispatcher},
+    factory::{display_factory::DisplayFactory, lora_factory::LoraFactory},
+    hal::{
+        config_store::{keys, ConfigStore},
+        display::Display,
+        peripheral_manager::PeripheralManagerStatic,
+        actuator::{ActuatorKind, GateActuator, GateTarget, Motion},
+        presence::{ContactSensor, Debounced, PresenceKind, PresenceSensor, UltrasonicSensor},
+        relay::RelayActuator,
+        servo_motor::ServoMotor,
+        wifi::Wifi,
+    },
+    protocol::{
+        gate::Heartbeat,
+        lora::{LoraEnvelope, NodeAddress},
+        message_type::MessageType,
+        node_config::{self, FIRST_GATEWAY_ADDRESS},
+        routing::{Decision, LinkQuality, RouteHeader, Router},
+    },
+};
+use log::*;
+
+esp_bootloader_esp_idf::esp_app_desc!();
+
+const HEAP_SIZE: usize = 72 * 1024;
+static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
+
+/// Passo do controle da cancela enquanto ela se move.
+const MOVE_TICK: Duration = Duration::from_millis(20);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+/// Canal usado pelo ESP-NOW quando `espnow.channel` nao esta na flash.
+const DEFAULT_ESP_NOW_CHANNEL: u8 = 1;
+
+type NodeDispatcher = Dispatcher<(EspNowTransport, LoraController)>;
+
+/// Acionador escolhido pela configuracao (`gate.actuator`).
+enum NodeActuator {
+    Servo(ServoMotor),
+    Relay(RelayActuator<Output<'static>>),
+}
+
+impl GateActuator for NodeActuator {
+    // Os pinos do rele nunca falham
+    type Error = ServoError;
+
+    fn drive(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        match self {
+            NodeActuator::Servo(servo) => servo.drive(target, now_ms),
+            NodeActuator::Relay(relay) => relay.drive(target, now_ms).map_err(|e| match e {}),
+        }
+    }
+
+    fn reverse(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        match self {
+            NodeActuator::Servo(servo) => servo.reverse(target, now_ms),
+            NodeActuator::Relay(relay) => relay.reverse(target, now_ms).map_err(|e| match e {}),
+        }
+    }
+
+    fn update(&mut self, now_ms: u64) -> Result<Motion, Self::Error> {
+        match self {
+            NodeActuator::Servo(servo) => servo.update(now_ms),
+            NodeActuator::Relay(relay) => relay.update(now_ms).map_err(|e| match e {}),
+        }
+    }
+
+    fn stop(&mut self) -> Result<(), Self::Error> {
+        match self {
+            NodeActuator::Servo(servo) => servo.stop(),
+            NodeActuator::Relay(relay) => relay.stop().map_err(|e| match e {}),
+        }
+    }
+}
+
+/// Sensor de presenca escolhido pela configuracao (`presence.sensor`).
+enum NodePresence {
+    Contact(ContactSensor<Input<'static>>),
+    Ultrasonic(UltrasonicSensor<Output<'static>, Input<'static>, Delay>),
+}
+
+impl NodePresence {
+    /// O ultrassonico mede aqui, sem travar o executor; o contato e lido na hora.
+    async fn measure(&mut self) {
+        if let NodePresence::Ultrasonic(ultrasonic) = self {
+            if let Err(e) = ultrasonic.measure().await {
+                debug!("Leitura do sensor ultrassonico falhou: {:?}", e);
+            }
+        }
+    }
+}
+
+impl PresenceSensor for NodePresence {
+    // Os pinos nunca falham
+    type Error = Infallible;
+
+    fn is_present(&mut self, now_ms: u64) -> Result<bool, Self::Error> {
+        match self {
+            NodePresence::Contact(contact) => contact.is_present(now_ms),
+            NodePresence::Ultrasonic(ultrasonic) => ultrasonic.is_present(now_ms),
+        }
+    }
+}
+
+/// Estado da cancela e dos frames originados por este no.
+struct GateNode {
+    router: Router,
+    dispatcher: NodeDispatcher,
+    gate: GateController<NodeActuator, NodePresence>,
+    display: Option<Display<'static>>,
+    /// Gateway que mandou o ultimo comando; recebe estados e heartbeats.
+    gateway: NodeAddress,
+    seq: u16,
+    last_link: Option<LinkQuality>,
+}
+
+impl GateNode {
+    fn address(&self) -> NodeAddress {
+        self.router.local()
+    }
+
+    /// Preenche origem, destino e roteamento e envia pelo melhor transporte.
+    async fn send(&mut self, mut envelope: LoraEnvelope, dst: NodeAddress) {
+        let header = self.router.originate(Some(dst), envelope.seq, envelope.msg_type as u8, Instant::now().as_millis());
+        envelope.src = Some(self.address());
+        envelope.dst = Some(dst);
+        header.apply(&mut envelope);
+
+        if let Err(e) = self.dispatcher.send(&envelope).await {
+            error!("Falha ao enviar {:?} seq {} para {}: {:?}", envelope.msg_type, envelope.seq, dst, e);
+        }
+    }
+
+    fn next_seq(&mut self) -> u16 {
+        self.seq = self.seq.wrapping_add(1);
+        self.seq
+    }
+
+    async fn handle(&mut self, envelope: LoraEnvelope, link: LinkQuality) {
+        self.last_link = Some(link);
+        let decision = self.router.on_receive(&RouteHeader::of(&envelope), link, Instant::now().as_millis());
+
+        match decision {
+            Decision::Deliver { relay } => {
+                if envelope.dst == Some(self.address()) {
+                    self.deliver(&envelope).await;
+                }
+                if let Some(header) = relay {
+                    let mut relayed = envelope;
+                    header.apply(&mut relayed);
+                    self.dispatcher.send(&relayed).await.ok();
+                }
+            }
+            Decision::Forward(header) => {
+                let mut relayed = envelope;
+                header.apply(&mut relayed);
+                debug!("Repetindo seq {} de {:?} para {:?}", relayed.seq, relayed.src, relayed.dst);
+                self.dispatcher.send(&relayed).await.ok();
+            }
+            Decision::Drop(reason) => {
+                debug!("Frame seq {} de {:?} descartado: {:?}", envelope.seq, envelope.src, reason);
+            }
+        }
+    }
+
+    async fn deliver(&mut self, envelope: &LoraEnvelope) {
+        match envelope.msg_type {
+            MessageType::Open => {
+                let Some(gateway) = envelope.src else {
+                    warn!("Open seq {} sem origem, ignorado", envelope.seq);
+                    return;
+                };
+                self.gateway = gateway;
+
+                // Reaberturas (ou reenvios do gateway) so estendem o prazo
+                let reply = match self.gate.open(Instant::now().as_millis()) {
+                    Ok(()) => MessageType::Ack,
+                    Err(e) => {
+                        error!("Cancela nao abriu: {:?}", e);
+                        MessageType::Nack
+                    }
+                };
+                let state = self.gate.state();
+                info!("Open seq {} de {}: cancela {:?}", envelope.seq, gateway, state);
+
+                let reply = LoraEnvelope::new(reply, envelope.seq, envelope.timestamp_ms, 0, [state as u8].to_vec());
+                self.send(reply, gateway).await;
+            }
+            MessageType::Reply => {
+                debug!("Reply seq {} do gateway", envelope.seq);
+            }
+            other => {
+                debug!("Mensagem {:?} ignorada pelo no", other);
+            }
+        }
+    }
+
+    /// Avanca a cancela e avisa o gateway de cada mudanca de estado.
+    async fn update_gate(&mut self) {
+        if let Some(presence) = self.gate.presence() {
+            presence.sensor().measure().await;
+        }
+        self.gate.update(Instant::now().as_millis());
+
+        while let Some(event) = self.gate.poll_event() {
+            match event {
+                GateEvent::Changed { previous, state, .. } => {
+                    info!("Cancela: {:?} -> {:?}", previous, state);
+                    self.show_status();
+                    let seq = self.next_seq();
+                    let report = LoraEnvelope::new(MessageType::GateState, seq, timestamp_ms(), 0, [state as u8].to_vec());
+                    self.send(report, self.gateway).await;
+                }
+                GateEvent::Fault { fault, .. } => error!("Falha na cancela: {:?}", fault),
+                GateEvent::Obstructed { state, .. } => warn!("Veiculo sob a cancela ({:?}): fechamento adiado", state),
+            }
+        }
+    }
+
+    /// Proximo instante em que a cancela precisa de `update_gate`.
+    fn gate_deadline(&self) -> Option<Instant> {
+        if self.gate.is_moving() {
+            return Some(Instant::now() + MOVE_TICK);
+        }
+        self.gate.next_deadline().map(Instant::from_millis)
+    }
+
+    async fn send_heartbeat(&mut self) {
+        let heartbeat = Heartbeat {
+            state: self.gate.state(),
+            uptime_secs: Instant::now().as_secs().min(u32::MAX as u64) as u32,
+            battery_percent: None,
+        };
+        let seq = self.next_seq();
+        let envelope = LoraEnvelope::new(MessageType::Heartbeat, seq, timestamp_ms(), 0, heartbeat.encode().to_vec());
+        self.send(envelope, self.gateway).await;
+        self.show_status();
+    }
+
+    fn show_status(&mut self) {
+        let Some(display) = self.display.as_mut() else {
+            return;
+        };
+        let mut title = heapless::String::<24>::new();
+        let mut link = heapless::String::<24>::new();
+        let _ = write!(title, "No {}", self.router.local());
+        match self.last_link {
+            Some(quality) => {
+                let _ = write!(link, "RSSI {} SNR {}", quality.rssi, quality.snr);
+            }
+            None => {
+                let _ = write!(link, "Sem sinal do gateway");
+            }
+        }
+
+        let result = display
+            .clear()
+            .and_then(|()| display.text_new_line(&title, 1))
+            .and_then(|()| display.text_new_line(self.gate.state().as_str(), 2))
+            .and_then(|()| display.text_new_line(&link, 3))
+            .and_then(|()| display.flush());
+        if let Err(e) = result {
+            error!("Falha ao atualizar o display: {:?}", e);
+        }
+    }
+}
+
+fn timestamp_ms() -> u32 {
+    Instant::now().as_millis().min(u32::MAX as u64) as u32
+}
+
+#[esp_hal_embassy::main]
+async fn main(_spawner: Spawner) {
+    unsafe {
+        esp_alloc::HEAP.add_region(esp_alloc::HeapRegion::new(
+            HEAP.as_mut_ptr() as *mut u8,
+            HEAP_SIZE,
+            esp_alloc::MemoryCapability::Internal.into(),
+        ));
+    }
+
+    init_logger(log::LevelFilter::Info);
+
+    let peripherals = esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
+    let peripheral_manager = PeripheralManagerStatic::init(peripherals);
+
+    let time_per = peripheral_manager.time_per();
+    esp_hal_embassy::init(time_per.timer0);
+
+    // Identidade do no fica na flash; valores de build so preenchem o que faltar
+    let mut config_store = ConfigStore::load().expect("Falha ao ler configuracao da flash");
+    let mut seeded = false;
+    for (key, value) in [
+        (keys::NODE_ID, option_env!("NODE_ID")),
+        (keys::ESPNOW_PMK, option_env!("ESPNOW_PMK")),
+        (keys::ESPNOW_LMK, option_env!("ESPNOW_LMK")),
+        (keys::ESPNOW_CHANNEL, option_env!("ESPNOW_CHANNEL")),
+        (keys::GATE_HOLD_MS, option_env!("GATE_HOLD_MS")),
+        (keys::GATE_MOVEMENT_TIMEOUT_MS, option_env!("GATE_MOVEMENT_TIMEOUT_MS")),
+        (keys::GATE_CLEAR_CLOSE_MS, option_env!("GATE_CLEAR_CLOSE_MS")),
+        (keys::GATE_ACTUATOR, option_env!("GATE_ACTUATOR")),
+        (keys::RELAY_PULSE_MS, option_env!("RELAY_PULSE_MS")),
+        (keys::RELAY_ACTIVE_LEVEL, option_env!("RELAY_ACTIVE_LEVEL")),
+        (keys::RELAY_LOCKOUT_MS, option_env!("RELAY_LOCKOUT_MS")),
+        (keys::RELAY_TRAVEL_MS, option_env!("RELAY_TRAVEL_MS")),
+        (keys::SERVO_MIN_PULSE_US, option_env!("SERVO_MIN_PULSE_US")),
+        (keys::SERVO_MAX_PULSE_US, option_env!("SERVO_MAX_PULSE_US")),
+        (keys::SERVO_OPEN_ANGLE, option_env!("SERVO_OPEN_ANGLE")),
+        (keys::SERVO_CLOSE_ANGLE, option_env!("SERVO_CLOSE_ANGLE")),
+        (keys::SERVO_PROFILE, option_env!("SERVO_PROFILE")),
+        (keys::SERVO_SPEED_DEG_S, option_env!("SERVO_SPEED_DEG_S")),
+        (keys::SERVO_ACCEL_DEG_S2, option_env!("SERVO_ACCEL_DEG_S2")),
+        (keys::SERVO_MOVE_MS, option_env!("SERVO_MOVE_MS")),
+        (keys::SERVO_RELEASE_MS, option_env!("SERVO_RELEASE_MS")),
+        (keys::PRESENCE_SENSOR, option_env!("PRESENCE_SENSOR")),
+        (keys::PRESENCE_ACTIVE_LEVEL, option_env!("PRESENCE_ACTIVE_LEVEL")),
+        (keys::PRESENCE_RANGE_CM, option_env!("PRESENCE_RANGE_CM")),
+        (keys::PRESENCE_ASSERT_MS, option_env!("PRESENCE_ASSERT_MS")),
+        (keys::PRESENCE_CLEAR_MS, option_env!("PRESENCE_CLEAR_MS")),
+    ] {
+        match config_store.seed(key, value) {
+            Ok(changed) => seeded |= changed,
+            Err(e) => error!("Falha ao guardar {}: {:?}", key, e),
+        }
+    }
+
+    // O radio WiFi so carrega ESP-NOW; o no nao entra em nenhuma rede
+    let wifi_peripherals = peripheral_manager.take_wifi_peripherals().unwrap();
+    let mut wifi = Wifi::new_radio(wifi_peripherals);
+
+    // Sem endereco gravado, sorteia um e grava para manter o mesmo apos reiniciar
+    let address = match config_store.node_address() {
+        Some(address) => address,
+        None => {
+            let mut rng = wifi.rng();
+            let address = core::iter::repeat_with(|| rng.random() as NodeAddress)
+                .find(|&address| node_config::is_node_address(address))
+                .unwrap();
+            let mut text = heapless::String::<8>::new();
+            let _ = write!(text, "{}", address);
+            if let Err(e) = config_store.set(keys::NODE_ID, &text) {
+                error!("Falha ao guardar o endereco do no: {:?}", e);
+            }
+            seeded = true;
+            warn!("Endereco do no nao configurado, usando {}", address);
+            address
+        }
+    };
+    if seeded {
+        if let Err(e) = config_store.commit() {
+            error!("Falha ao gravar configuracao: {:?}", e);
+        }
+    }
+    info!("No {} iniciando", address);
+
+    if let Err(e) = wifi.start_radio().await {
+        panic!("Falha ao iniciar o radio WiFi: {}", e);
+    }
+    let esp_now = wifi.take_esp_now().expect("ESP-NOW ja retirado");
+    let channel = config_store.esp_now_channel().unwrap_or(DEFAULT_ESP_NOW_CHANNEL);
+    if let Err(e) = esp_now.set_channel(channel) {
+        error!("Falha ao usar o canal {} no ESP-NOW: {:?}", channel, e);
+    }
+    let esp_now = EspNowTransport::new(esp_now, address, config_store.esp_now_security()).expect("Falha ao iniciar ESP-NOW");
+    // O controlador precisa viver enquanto o ESP-NOW estiver em uso
+    let (_wifi_controller, _runner, _stack) = wifi.take_components();
+
+    let display = match DisplayFactory::create_from_peripherals(peripheral_manager.take_display_peripherals().unwrap()) {
+        Ok(display) => Some(display),
+        Err(e) => {
+            error!("Display indisponivel: {}", e);
+            None
+        }
+    };
+
+    let lora_peripherals = peripheral_manager.take_lora_peripherals().unwrap();
+    let lora = match LoraFactory::create_from_manager(lora_peripherals).await {
+        Ok(lora) => lora,
+        Err(e) => {
+            error!("Falha ao inicializar LoRa: {:?}", e);
+            panic!("LoRa initialization failed");
+        }
+    };
+
+    let actuator = match config_store.actuator_kind() {
+        ActuatorKind::Servo => {
+            let settings = config_store.servo_settings();
+            info!("Cancela por servo: {:?}", settings);
+            NodeActuator::Servo(ServoMotor::with_settings(peripheral_manager.take_servo_peripherals().unwrap(), settings))
+        }
+        ActuatorKind::Relay => {
+            let settings = config_store.relay_settings();
+            info!("Cancela por rele: {:?}", settings);
+            NodeActuator::Relay(RelayActuator::from_peripherals(peripheral_manager.take_relay_peripherals().unwrap(), settings))
+        }
+    };
+    let presence_settings = config_store.presence_settings();
+    let presence = match config_store.presence_kind() {
+        PresenceKind::None => None,
+        kind => {
+            info!("Sensor de presenca {:?}: {:?}", kind, presence_settings);
+            let pins = peripheral_manager.take_presence_peripherals().unwrap();
+            // GPIO34 nao tem pull-up interno: o contato precisa de um externo
+            let input = Input::new(pins.input, InputConfig::default());
+            let sensor = match kind {
+                PresenceKind::Ultrasonic => {
+                    let trigger = Output::new(pins.trigger, Level::Low, OutputConfig::default());
+                    NodePresence::Ultrasonic(UltrasonicSensor::new(trigger, input, Delay, presence_settings.range_cm))
+                }
+                _ => NodePresence::Contact(ContactSensor::new(input, presence_settings.active_high)),
+            };
+            Some(Debounced::new(sensor, presence_settings))
+        }
+    };
+    let mut gate = GateController::with_presence(actuator, presence, config_store.gate_timing());
+    // Posicao desconhecida na partida: fecha
+    if let Err(e) = gate.reset(Instant::now().as_millis()) {
+        error!("Falha ao fechar a cancela na partida: {:?}", e);
+    }
+
+    let mut node = GateNode {
+        router: Router::new(address),
+        dispatcher: Dispatcher::new((esp_now, LoraController::new(lora))),
+        gate,
+        display,
+        gateway: FIRST_GATEWAY_ADDRESS,
+        seq: 0,
+        last_link: None,
+    };
+    node.show_status();
+
+    // Gateways ao alcance do ESP-NOW respondem com o proprio anuncio
+    if let Err(e) = node.dispatcher.transports().0.announce().await {
+        warn!("Falha ao anunciar o no via ESP-NOW: {:?}", e);
+    }
+
+    let mut next_heartbeat = Instant::now();
+    loop {
+        let deadline = node.gate_deadline().map_or(next_heartbeat, |gate_deadline| gate_deadline.min(next_heartbeat));
+        match node.dispatcher.receive().with_deadline(deadline).await {
+            Ok(Ok(received)) => node.handle(received.envelope, received.link).await,
+            Ok(Err(e)) => {
+                warn!("Erro ao receber envelope: {:?}", e);
+                Timer::after_millis(25).await;
+            }
+            Err(_) => {
+                // Passo da cancela, fechamento ou heartbeat
+            }
+        }
+
+        node.update_gate().await;
+        if Instant::now() >= next_heartbeat {
+            node.send_heartbeat().await;
+            next_heartbeat = Instant::now() + HEARTBEAT_INTERVAL;
+        }
+    }
+}
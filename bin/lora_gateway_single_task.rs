@@ -5,7 +5,7 @@
 use core::{fmt::Write, mem::MaybeUninit};
 
 use embassy_executor::Spawner;
-use embassy_net::{Runner, tcp::TcpSocket};
+use embassy_net::{Runner, Stack, tcp::TcpSocket};
 use embassy_sync::{
     blocking_mutex::raw::CriticalSectionRawMutex,
     channel::{Channel, Receiver, Sender},
@@ -16,12 +16,12 @@ use esp_backtrace as _;
 use esp_hal::clock::CpuClock;
 use esp_println::logger::init_logger;
 use haviliar_iot::{
-    controller::{lora::LoraController, mqtt::MqttController}, factory::lora_factory::LoraFactory, hal::{
-        lora::PAYLOAD_LENGTH, peripheral_manager::PeripheralManagerStatic, servo_motor::{self, ServoMotor}, wifi::Wifi
-    }, protocol::{lora::LoraEnvelope, message_type::MessageType}
+    controller::{broker::{LocalBroker, MAX_BROKER_CLIENTS}, command_tracker::CommandTracker, http_api::HttpApi, provisioning::run_captive_portal, discovery::run_discovery, gate_registry::GateRegistry, home_assistant::{HomeAssistant, HA_OPEN_PAYLOAD}, espnow::EspNowTransport, lora::LoraController, node_registry::NodeRegistry, transport::Dispatcher, resolver::{BrokerAddress, BrokerResolver}, tls::{TlsBuffers, TlsLink, TlsSettings}, mqtt::{ConnectionStateWatch, MqttBuffers, MqttChannel, MqttConfig, MqttController, MqttMessage, MqttRequestChannel, MqttRoute, MqttSocket, MAX_TOPIC_LENGTH}}, factory::lora_factory::LoraFactory, hal::{
+        lora::PAYLOAD_LENGTH, peripheral_manager::PeripheralManagerStatic, wifi::{ActiveNetworkWatch, NetworkAddresses, SlaacClient, Wifi, WifiEvent, WifiEventChannel, WifiRoamer, WifiSupervisor}, config_store::{keys, ConfigStore}
+    }, protocol::{command::{is_valid_reply_topic, CommandOutcome, CommandRequest, CommandResult}, provisioning::{parse_gate_list, GateSpec}, discovery::{GateDirection, GateInfo}, gate::{self, GateState, GateStateEvent, GateTopicKind, Heartbeat}, lora::{LoraEnvelope, NodeAddress}, message_type::MessageType, routing::{Decision, RouteHeader, Router}, topic::{topic_level, topic_matches}}
 };
 use log::*;
-use esp_wifi::wifi::{ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState};
+use esp_wifi::wifi::WifiDevice;
 use minicbor::decode::info;
 use static_cell::StaticCell;
 
@@ -30,67 +30,110 @@ esp_bootloader_esp_idf::esp_app_desc!();
 const HEAP_SIZE: usize = 64 * 1024;
 static mut HEAP: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
 
-// Poll curto para permitir alternar entre RX LoRa e fila de requests vindos do MQTT.
+// Poll curto para permitir alternar entre RX (LoRa e ESP-NOW) e fila de requests vindos do MQTT.
 const LORA_RX_POLL_MS: u64 = 5000;
+/// Rede aberta criada quando nao ha WiFi configurado.
+const SETUP_AP_SSID: &str = "Haviliar-Setup";
 
 struct GatewayConfig {
-    broker_ip: embassy_net::Ipv4Address,
+    /// Resolvido por DNS a cada conexao, com fallback para `_mqtt._tcp.local` via mDNS;
+    /// tambem e o nome verificado no certificado TLS. Padrao quando `mqtt.host` nao
+    /// esta na flash, assim como usuario, senha e token abaixo.
+    broker_host: &'static str,
     broker_port: u16,
     main_topic: &'static str,
     client_id: &'static str,
+    command_topic: &'static str,
+    mqtt_username: Option<&'static str>,
+    mqtt_password: Option<&'static str>,
+    mqtt_keep_alive_secs: u16,
     status_subtopic: &'static str,
     forward_ack_timeout_ms: u64,
+    operation_center_name: &'static str,
+    network_name: &'static str,
+    gates: &'static [(&'static str, &'static str, GateDirection, NodeAddress)],
+    http_api_token: Option<&'static str>,
+    /// Endereco do gateway na malha LoRa; nos repetidores roteiam ate ele.
+    lora_address: NodeAddress,
 }
 
 const GATEWAY_CONFIG: GatewayConfig = GatewayConfig {
-    broker_ip: embassy_net::Ipv4Address::new(10, 43, 53, 199),
-    broker_port: 1883,
+    broker_host: "haviliar-broker",
+    broker_port: if cfg!(feature = "mqtt-tls") { 8883 } else { 1883 },
     main_topic: "esp32-haviliar",
     client_id: "esp32-lora-gateway-dev",
+    command_topic: "esp32/open",
+    mqtt_username: option_env!("MQTT_USERNAME"),
+    mqtt_password: option_env!("MQTT_PASSWORD"),
+    mqtt_keep_alive_secs: 30,
     status_subtopic: "lora/open",
     forward_ack_timeout_ms: 5_000,
+    operation_center_name: "Estacionamento Centro",
+    network_name: "ESTACENTER-CENTRO-001",
+    gates: &[
+        ("G1", "Entrada Principal", GateDirection::Entry, 1),
+        ("G2", "Saída Principal", GateDirection::Exit, 2),
+    ],
+    http_api_token: option_env!("HTTP_API_TOKEN"),
+    lora_address: 0xFF00,
 };
 
 
+/// ESP-NOW primeiro (mais rapido, alcance do WiFi); LoRa para o resto.
+type GatewayDispatcher = Dispatcher<(EspNowTransport, LoraController)>;
+
 type ForwardToLoraChannel = Channel<CriticalSectionRawMutex, LoraEnvelope, 8>;
-type LoraToMqttChannel = Channel<CriticalSectionRawMutex, LoraEnvelope, 8>;
+type LoraToMqttChannel = Channel<CriticalSectionRawMutex, LoraEvent, 8>;
+
+enum LoraEvent {
+    /// Andamento de um comando encaminhado via LoRa, identificado pelo seq.
+    Command { seq: u16, outcome: CommandOutcome },
+    /// Posicao da cancela reportada pelo no.
+    GateState { node: NodeAddress, state: GateState },
+}
 
 static FORWARD_TO_LORA_CHANNEL: StaticCell<ForwardToLoraChannel> = StaticCell::new();
 static LORA_TO_MQTT_CHANNEL: StaticCell<LoraToMqttChannel> = StaticCell::new();
 static RX_BUFFER_CELL: StaticCell<[u8; 4096]> = StaticCell::new();
 static TX_BUFFER_CELL: StaticCell<[u8; 4096]> = StaticCell::new();
-static SOCKET_CELL: StaticCell<TcpSocket<'static>> = StaticCell::new();
-static MQTT_CLIENT_CELL: StaticCell<Mutex<CriticalSectionRawMutex, MqttController<'static>>> =
-    StaticCell::new();
+static SOCKET_CELL: StaticCell<MqttSocket<'static>> = StaticCell::new();
+const MQTT_BUFFER_SIZE: usize = 1024;
+static MQTT_RECV_BUFFER_CELL: StaticCell<[u8; MQTT_BUFFER_SIZE]> = StaticCell::new();
+static MQTT_WRITE_BUFFER_CELL: StaticCell<[u8; MQTT_BUFFER_SIZE]> = StaticCell::new();
+#[cfg(feature = "mqtt-tls")]
+static TLS_READ_BUFFER_CELL: StaticCell<[u8; 4096 + 256]> = StaticCell::new();
+#[cfg(feature = "mqtt-tls")]
+static TLS_WRITE_BUFFER_CELL: StaticCell<[u8; 4096 + 256]> = StaticCell::new();
+#[cfg(feature = "mqtt-tls")]
+static TLS_LINK_CELL: StaticCell<TlsLink<'static>> = StaticCell::new();
+static MQTT_STATE: ConnectionStateWatch = ConnectionStateWatch::new();
+static WIFI_NETWORK: ActiveNetworkWatch = ActiveNetworkWatch::new();
+static WIFI_EVENTS: WifiEventChannel = WifiEventChannel::new();
+static GATE_REGISTRY: GateRegistry = GateRegistry::new();
+static NODE_REGISTRY: NodeRegistry = NodeRegistry::new();
+static COMMAND_TRACKER: CommandTracker = CommandTracker::new(GATEWAY_CONFIG.main_topic);
+static HOME_ASSISTANT: HomeAssistant<'static> = HomeAssistant {
+    main_topic: GATEWAY_CONFIG.main_topic,
+    gateway_id: GATEWAY_CONFIG.client_id,
+    gates: &GATE_REGISTRY,
+    nodes: &NODE_REGISTRY,
+};
+static MQTT_INBOUND_CHANNEL: MqttChannel = MqttChannel::new();
+static MQTT_OUTBOUND_CHANNEL: MqttChannel = MqttChannel::new();
+static MQTT_REQUEST_CHANNEL: MqttRequestChannel = MqttRequestChannel::new();
+#[cfg(feature = "mqtt-broker")]
+static LOCAL_BROKER: LocalBroker = LocalBroker::new();
+#[cfg(feature = "http-api")]
+static HTTP_API_CELL: StaticCell<HttpApi<'static>> = StaticCell::new();
 
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>, ssid: &'static str, password: &'static str) {
-    loop {
-        if let WifiState::StaConnected = esp_wifi::wifi::wifi_state() {
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_secs(5)).await;
-            continue;
-        }
-
-        if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: ssid.try_into().unwrap(),
-                password: password.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
-            controller.start_async().await.unwrap();
-            info!("WiFi iniciado");
-        }
+async fn wifi_task(mut supervisor: WifiSupervisor) -> ! {
+    supervisor.run().await
+}
 
-        match controller.connect_async().await {
-            Ok(_) => info!("WiFi conectado"),
-            Err(e) => {
-                error!("Falha na conexao WiFi: {:?}", e);
-                Timer::after(Duration::from_secs(5)).await;
-            }
-        }
-    }
+#[embassy_executor::task]
+async fn slaac_task(mut slaac: SlaacClient) -> ! {
+    slaac.run().await
 }
 
 #[embassy_executor::task]
@@ -98,39 +141,72 @@ async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
     runner.run().await;
 }
 
+/// Preenche origem, destino e campos de roteamento de um frame do gateway.
+fn route_envelope(router: &Router, envelope: &mut LoraEnvelope, dst: Option<NodeAddress>) {
+    let header = router.originate(dst, envelope.seq, envelope.msg_type as u8, Instant::now().as_millis());
+    envelope.src = Some(router.local());
+    envelope.dst = dst;
+    header.apply(envelope);
+}
+
 #[embassy_executor::task]
 async fn task_lora_gateway(
-    mut lora: LoraController,
+    mut dispatcher: GatewayDispatcher,
     forward_channel: &'static ForwardToLoraChannel,
     result_channel: &'static LoraToMqttChannel,
-    mut servo_motor: ServoMotor
 ) {
     let forward_rx = forward_channel.receiver();
     let result_tx = result_channel.sender();
     let mut pending_forward: Option<LoraEnvelope> = None;
+    let mut pending_since = Instant::now();
+    let ack_timeout = Duration::from_millis(GATEWAY_CONFIG.forward_ack_timeout_ms);
+    // Rotas aprendidas de cada frame recebido, para alcancar nos fora do alcance direto
+    let mut router = Router::new(GATEWAY_CONFIG.lora_address);
+
+    // Nos ao alcance do ESP-NOW respondem com o proprio anuncio
+    if let Err(e) = dispatcher.transports().0.announce().await {
+        warn!("Falha ao anunciar o gateway via ESP-NOW: {:?}", e);
+    }
 
     loop {
-        let mut recv_buffer = [0u8; PAYLOAD_LENGTH];
-        let rx_result = lora
-            .receive_message(&mut recv_buffer)
+        let rx_result = dispatcher
+            .receive()
             .with_timeout(Duration::from_millis(LORA_RX_POLL_MS))
             .await;
 
         match rx_result {
-            Ok(Ok((envelope, _status))) => {
+            Ok(Ok(received)) => {
+                let envelope = received.envelope;
+                // Nos antigos nao mandam o endereco de origem e ficam todos como no 0
+                if !NODE_REGISTRY.record_link(envelope.src.unwrap_or(0), received.link) {
+                    warn!("Tabela de nos cheia, no {:?} ignorado", envelope.src);
+                }
+
+                // O gateway e o destino final: so processa o que for para ele ou broadcast
+                let decision = router.on_receive(&RouteHeader::of(&envelope), received.link, Instant::now().as_millis());
+                let delivered = matches!(decision, Decision::Deliver { .. });
 
                 match envelope.msg_type {
+                    _ if !delivered => {
+                        debug!("Frame seq {} do no {:?} via {:?} nao entregue: {:?}", envelope.seq, envelope.src, received.transport, decision);
+                    }
                     MessageType::Ack => {
                         // aq significa que recebemos um ACK de um forward que enviamos anteriormente, entao precisamos verificar se o seq do ACK corresponde ao pending_forward, e se sim, enviar o resultado para MQTT e limpar o pending_forward.
                         if let Some(pending) = &pending_forward {
                             if pending.seq == envelope.seq {
                                 // ACK corresponde ao pending_forward, podemos considerar o forward como bem sucedido e enviar o resultado para MQTT.
-                                let result = LoraEnvelope::new(MessageType::Reply, pending.seq, envelope.timestamp_ms, 0, b"LoRa forward ACK received".as_slice().to_vec());
+                                let mut result = LoraEnvelope::new(MessageType::Reply, pending.seq, envelope.timestamp_ms, 0, b"LoRa forward ACK received".as_slice().to_vec());
+                                route_envelope(&router, &mut result, envelope.src);
 
-                                lora.send_message_envelope(&result).await.ok(); // confirmar o reply
+                                dispatcher.send(&result).await.ok(); // confirmar o reply
 
-                                result_tx.send(result).await;
+                                result_tx.send(LoraEvent::Command { seq: pending.seq, outcome: CommandOutcome::Acknowledged }).await;
                                 pending_forward = None;
+
+                                // O no gate_node manda no ACK o estado em que a cancela ficou
+                                if let [state] = envelope.payload[..] {
+                                    result_tx.send(LoraEvent::GateState { node: envelope.src.unwrap_or(0), state: GateState::from_byte(state) }).await;
+                                }
                             } else {
                                 // ACK recebido, mas seq nao corresponde ao pending_forward. Pode ser um ACK atrasado ou fora de ordem. Ignorar.
                                 warn!("ACK recebido com seq {} mas pending_forward tem seq {}", envelope.seq, pending.seq);
@@ -140,26 +216,62 @@ async fn task_lora_gateway(
                             warn!("ACK recebido com seq {} mas nao temos nenhum forward pendente", envelope.seq);
                         }
                     }
+                    MessageType::Nack => {
+                        // O no recebeu o Open mas a cancela nao abriu: o comando falhou, nao adianta reenviar
+                        match &pending_forward {
+                            Some(pending) if pending.seq == envelope.seq => {
+                                warn!("No {:?} recusou o Open seq {}", envelope.src, envelope.seq);
+                                result_tx.send(LoraEvent::Command { seq: pending.seq, outcome: CommandOutcome::Failed }).await;
+                                pending_forward = None;
+
+                                if let [state] = envelope.payload[..] {
+                                    result_tx.send(LoraEvent::GateState { node: envelope.src.unwrap_or(0), state: GateState::from_byte(state) }).await;
+                                }
+                            }
+                            _ => warn!("NACK recebido com seq {} sem forward pendente correspondente", envelope.seq),
+                        }
+                    }
                     MessageType::Open => {
                         // aq significa que recebemos uma nova mensagem vinda de um dispositivo final, entao precisamos enviar um ACK de volta para o dispositivo final preencher a variavel de "pending ACK", e entao podemos processar a mensagem normalmente e enviar o resultado para MQTT.
-                        servo_motor.open().ok(); // abrir o servo motor para simular o processamento da mensagem recebida
-
-                        let ack = LoraEnvelope::new(MessageType::Ack, envelope.seq, envelope.timestamp_ms, 0, b"ACK".as_slice().to_vec());
-                        lora.send_message_envelope(&ack).await.ok(); // enviar ACK para dispositivo final
+                        // O gateway nao aciona nenhuma cancela localmente: so confirma e roteia.
+                        let mut ack = LoraEnvelope::new(MessageType::Ack, envelope.seq, envelope.timestamp_ms, 0, b"ACK".as_slice().to_vec());
+                        route_envelope(&router, &mut ack, envelope.src);
+                        dispatcher.send(&ack).await.ok(); // enviar ACK para dispositivo final
 
                         pending_forward = Some(ack); // marcar a mensagem recebida como pending_forward para esperar o ACK do dispositivo final
+                        pending_since = Instant::now();
 
                     }
                     MessageType::Reply => {
                         pending_forward = None;
                     }
+                    MessageType::GateState => {
+                        let state = GateState::from_byte(envelope.payload.first().copied().unwrap_or(0));
+                        result_tx.send(LoraEvent::GateState { node: envelope.src.unwrap_or(0), state }).await;
+                    }
+                    MessageType::Heartbeat => {
+                        let node = envelope.src.unwrap_or(0);
+                        match Heartbeat::decode(&envelope.payload) {
+                            Some(heartbeat) => {
+                                debug!("Heartbeat do no {}: {:?}", node, heartbeat);
+                                if let Some(percent) = heartbeat.battery_percent {
+                                    NODE_REGISTRY.record_battery(node, percent);
+                                }
+                                // So publica o estado quando ele mudou sem que o no avisasse (ex.: frame perdido)
+                                if GATE_REGISTRY.gate_for_node(node).is_some_and(|gate| gate.state != heartbeat.state) {
+                                    result_tx.send(LoraEvent::GateState { node, state: heartbeat.state }).await;
+                                }
+                            }
+                            None => warn!("Heartbeat invalido do no {}", node),
+                        }
+                    }
                     _ => {
                     }
                 }
                     
             }
             Ok(Err(e)) => {
-                error!("Erro de radio ao receber LoRa: {:?}", e);
+                error!("Erro ao receber envelope: {:?}", e);
                 Timer::after_millis(25).await;
             }
             Err(_) => {
@@ -168,26 +280,34 @@ async fn task_lora_gateway(
         }
 
         // por enquanto vou so receber novos requests enquanto nao tiver um forward pendente, pq o protocolo atual é "enviar um forward e esperar o ACK antes de enviar outro".
+        // Sem ACK dentro do prazo: desiste e avisa quem pediu
+        if let Some(pending) = pending_forward.take_if(|_| pending_since.elapsed() > ack_timeout) {
+            warn!("Timeout esperando ACK do seq {}", pending.seq);
+            result_tx.send(LoraEvent::Command { seq: pending.seq, outcome: CommandOutcome::TimedOut }).await;
+        }
+
         match pending_forward {
             Some(ref pending) => {
-                let payload_copy = pending.payload.clone();
-                
-                info!("Reenviando mensagem pendente para LoRa: seq={}, bytes={}", pending.seq, payload_copy.len());
+                info!("Reenviando mensagem pendente para LoRa: seq={}, bytes={}", pending.seq, pending.payload.len());
 
-                lora.send_message(pending.msg_type, pending.seq, pending.timestamp_ms, pending.elapsed_ms, payload_copy.as_slice()).await.ok();
+                // Envelope inteiro para manter o destino
+                dispatcher.send(pending).await.ok();
             } 
             None =>  {
-                if let Ok(request) = forward_rx.try_receive() {
-
-                    match lora.send_message_envelope(&request).await {
-                        Ok(()) => {
-                            info!("LoRa forward enviado: seq={}, bytes={}", request.seq, request.payload.len());
+                if let Ok(mut request) = forward_rx.try_receive() {
+                    let dst = request.dst;
+                    route_envelope(&router, &mut request, dst);
+
+                    match dispatcher.send(&request).await {
+                        Ok(transport) => {
+                            info!("Forward enviado via {:?}: seq={}, bytes={}", transport, request.seq, request.payload.len());
+                            result_tx.send(LoraEvent::Command { seq: request.seq, outcome: CommandOutcome::Delivered }).await;
                             pending_forward = Some(request);
+                            pending_since = Instant::now();
                         }
                         Err(e) => {
-                            error!("Falha ao enviar mensagem LoRa: {:?}", e);
-                            let result = LoraEnvelope::new(MessageType::Reply, request.seq, request.timestamp_ms, 0, b"LoRa send failed".as_slice().to_vec());
-                            result_tx.send(result).await;
+                            error!("Falha ao enviar forward: {:?}", e);
+                            result_tx.send(LoraEvent::Command { seq: request.seq, outcome: CommandOutcome::Failed }).await;
                         }
                     }
                 }
@@ -196,79 +316,242 @@ async fn task_lora_gateway(
     }
 }
 
+#[embassy_executor::task]
+async fn task_mqtt(
+    mut mqtt_controller: MqttController<'static>,
+    outbound: &'static MqttChannel,
+    requests: &'static MqttRequestChannel,
+) {
+    mqtt_controller.run(outbound, requests).await
+}
+
+#[embassy_executor::task]
+async fn task_discovery(outbound: &'static MqttChannel) {
+    run_discovery(&GATE_REGISTRY, &MQTT_STATE, outbound).await
+}
+
+#[embassy_executor::task]
+async fn task_home_assistant(outbound: &'static MqttChannel) {
+    HOME_ASSISTANT.run(&MQTT_STATE, outbound).await
+}
+
+#[cfg(feature = "mqtt-broker")]
+#[embassy_executor::task(pool_size = MAX_BROKER_CLIENTS)]
+async fn task_local_broker(stack: Stack<'static>, slot: usize) {
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 1536];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    LOCAL_BROKER.serve(slot, &mut socket).await
+}
+
+#[cfg(feature = "http-api")]
+#[embassy_executor::task(pool_size = 2)]
+async fn task_http_api(stack: Stack<'static>, api: &'static HttpApi<'static>) {
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 2048];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    api.serve(&mut socket).await
+}
+
+/// Enfileira para o broker remoto e, com o broker local ativo, espelha nele.
+async fn publish(outbound: &MqttChannel, message: MqttMessage) {
+    #[cfg(feature = "mqtt-broker")]
+    LOCAL_BROKER.publish_outbound(GATEWAY_CONFIG.main_topic, &message);
+    outbound.send(message).await;
+}
+
 #[embassy_executor::task]
 async fn task_mqtt_ingress(
-    mqtt_controller_mutex: &'static Mutex<CriticalSectionRawMutex, MqttController<'static>>,
+    inbound: &'static MqttChannel,
     sender: Sender<'static, CriticalSectionRawMutex, LoraEnvelope, 8>,
 ) {
-    let mut request_id: u16 = 0;
     let mut seq: u16 = 1;
+    let ha_filter = HOME_ASSISTANT.command_filter();
+    let gate_filter = gate::gate_command_filter(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.network_name);
+    let gate_level = gate::gate_level(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.network_name);
 
     loop {
-        let mut mqtt_controller = mqtt_controller_mutex.lock().await;
-        
-        match mqtt_controller.receive_message().await {
-            Ok((_topic, payload)) => {
-                let mut payload_copy = heapless::Vec::<u8, PAYLOAD_LENGTH>::new();
-                if payload_copy.extend_from_slice(payload).is_err() {
-                    error!("Payload MQTT maior que o limite LoRa ({} bytes)", PAYLOAD_LENGTH);
-                    drop(mqtt_controller);
-                    Timer::after_millis(100).await;
+        let message = inbound.receive().await;
+        let mut event_topic = heapless::String::<MAX_TOPIC_LENGTH>::new();
+
+        // JSON com requestId/replyTo; qualquer outro payload segue como antes, sem correlacao
+        let parsed = CommandRequest::parse(&message.payload);
+
+        // Topico da cancela ({main}/{rede}/{cancela}/cmd) ou botao do Home Assistant:
+        // o id da cancela vem no topico; no topico legado pode vir no campo "gate" do JSON
+        let gate_id = if gate_filter.as_deref().is_some_and(|filter| topic_matches(filter, &message.topic)) {
+            topic_level(&message.topic, gate_level)
+        } else if topic_matches(&ha_filter, &message.topic) {
+            if message.payload.as_slice() != HA_OPEN_PAYLOAD.as_bytes() {
+                warn!("Comando do Home Assistant desconhecido em '{}'", message.topic);
+                continue;
+            }
+            topic_level(&message.topic, HOME_ASSISTANT.command_gate_level())
+        } else {
+            parsed.as_ref().and_then(|request| request.gate)
+        };
+        let mut request = parsed.unwrap_or_default();
+
+        let (payload, destination): (&[u8], Option<NodeAddress>) = match gate_id {
+            Some(gate_id) => {
+                let Some(node) = GATE_REGISTRY.gate(gate_id).and_then(|gate| gate.node) else {
+                    warn!("Cancela '{}' desconhecida ou sem no LoRa associado", gate_id);
                     continue;
-                }
+                };
 
-                let now = Instant::now();
-                let timestamp_ms = now.as_millis().min(u32::MAX as u64) as u32;
-                
-                let envelope = LoraEnvelope::new(MessageType::Open, seq, timestamp_ms, 0, payload_copy.clone().to_vec());
-                sender.send(envelope).await;
-                
-                info!(
-                    "MQTT->LoRa enfileirado: request_id={}, seq={}, bytes={}",
-                    request_id,
-                    seq,
-                    payload_copy.len()
-                );
-                
-                request_id = request_id.wrapping_add(1);
-                seq = seq.wrapping_add(1);
-                
-                drop(mqtt_controller);
-            }
-            Err(e) => {
-                error!("Erro ao receber mensagem MQTT: {:?}", e);
-                drop(mqtt_controller);
+                // Sem replyTo o resultado vai para o /event da propria cancela
+                if request.reply_to.is_none() {
+                    if let Some(subtopic) = gate::gate_subtopic(GATEWAY_CONFIG.network_name, gate_id, GateTopicKind::Event) {
+                        if write!(event_topic, "{}/{}", GATEWAY_CONFIG.main_topic, subtopic).is_ok() {
+                            request.reply_to = Some(event_topic.as_str());
+                        }
+                    }
+                }
+                (gate_id.as_bytes(), Some(node))
             }
+            None => (&message.payload[..], None),
+        };
+
+        let mut payload_copy = heapless::Vec::<u8, PAYLOAD_LENGTH>::new();
+        if payload_copy.extend_from_slice(payload).is_err() {
+            error!("Payload MQTT maior que o limite LoRa ({} bytes)", PAYLOAD_LENGTH);
+            continue;
         }
 
-        Timer::after_millis(100).await;
+        let now = Instant::now();
+        let timestamp_ms = now.as_millis().min(u32::MAX as u64) as u32;
+
+        // Registrado antes de enfileirar para que nenhum evento do seq se perca
+        COMMAND_TRACKER.track(seq, &request);
+
+        let mut envelope = LoraEnvelope::new(MessageType::Open, seq, timestamp_ms, 0, payload_copy.clone().to_vec());
+        if let Some(node) = destination {
+            envelope = envelope.with_destination(node);
+        }
+        sender.send(envelope).await;
+
+        info!(
+            "MQTT->LoRa enfileirado: request_id={:?}, seq={}, no={:?}, bytes={}",
+            request.request_id,
+            seq,
+            destination,
+            payload_copy.len()
+        );
+
+        seq = seq.wrapping_add(1);
     }
 }
 
 #[embassy_executor::task]
 async fn task_mqtt_egress(
-    mqtt_controller_mutex: &'static Mutex<CriticalSectionRawMutex, MqttController<'static>>,
-    receiver: Receiver<'static, CriticalSectionRawMutex, LoraEnvelope, 8>,
+    outbound: &'static MqttChannel,
+    receiver: Receiver<'static, CriticalSectionRawMutex, LoraEvent, 8>,
 ) {
-    let mut payload = heapless::String::<128>::new();
-
+    // a publicacao so entra na fila; o task_mqtt envia sem esperar o timeout de recepcao
     loop {
-        let result = receiver.receive().await;
-        payload.clear();
-
-        // aq significa que foi recebido um ack do foward que foi recebido anteriormente via MQTT, ou seja, 
-        // o dispositivo final recebeu a solicitação e agora vamos notificar o mqtt disso
-
-        let mut mqtt_controller = mqtt_controller_mutex.lock().await;
-        match mqtt_controller
-            .publish_message(GATEWAY_CONFIG.status_subtopic, payload.as_bytes())
-            .await
-        {
-            Ok(()) => {}
-            Err(e) => error!("Falha ao publicar status no MQTT: {:?}", e),
+        match receiver.receive().await {
+            LoraEvent::Command { seq, outcome } => publish_command_result(outbound, seq, outcome).await,
+            LoraEvent::GateState { node, state } => publish_gate_state(outbound, node, state).await,
+        }
+    }
+}
+
+async fn publish_command_result(outbound: &MqttChannel, seq: u16, outcome: CommandOutcome) {
+    let mut payload = [0u8; 128];
+
+    // Eventos de seq que nao vieram do MQTT (ex.: ACK de um Open vindo do no) sao ignorados
+    let Some(report) = COMMAND_TRACKER.report(seq, outcome) else {
+        return;
+    };
+
+    let result = CommandResult {
+        request_id: report.command.request_id.as_deref(),
+        seq: Some(report.command.seq),
+        outcome: report.outcome,
+        latency_ms: report.latency_ms,
+    };
+    let Ok(len) = result.encode(&mut payload) else {
+        error!("Falha ao serializar resultado do seq={}", seq);
+        return;
+    };
+
+    // Com replyTo o resultado vai direto para quem pediu; senao para o topico de status
+    let message = match &report.command.reply_to {
+        Some(reply_to) => MqttMessage::absolute(reply_to, &payload[..len], false),
+        None => MqttMessage::new(GATEWAY_CONFIG.status_subtopic, &payload[..len]),
+    };
+
+    match message {
+        Some(message) => publish(outbound, message).await,
+        None => error!("Falha ao montar status MQTT para seq={}", seq),
+    }
+}
+
+/// Resposta a um comando descartado com a fila de entrada cheia: o broker ja
+/// recebeu o PUBACK, entao quem pediu recebe `failed` em vez de esperar.
+fn reject_command(message: &MqttMessage) -> Option<MqttMessage> {
+    let request = CommandRequest::parse(&message.payload).unwrap_or_default();
+    let result = CommandResult {
+        request_id: request.request_id,
+        seq: None,
+        outcome: CommandOutcome::Failed,
+        latency_ms: 0,
+    };
+    let mut payload = [0u8; 128];
+    let len = result.encode(&mut payload).ok()?;
+
+    // Mesmo destino de um resultado normal: replyTo, o /event da cancela ou o status
+    let ha_filter = HOME_ASSISTANT.command_filter();
+    let gate_filter = gate::gate_command_filter(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.network_name);
+    let gate_id = if gate_filter.as_deref().is_some_and(|filter| topic_matches(filter, &message.topic)) {
+        topic_level(&message.topic, gate::gate_level(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.network_name))
+    } else if topic_matches(&ha_filter, &message.topic) {
+        topic_level(&message.topic, HOME_ASSISTANT.command_gate_level())
+    } else {
+        None
+    };
+
+    let mut topic = heapless::String::<MAX_TOPIC_LENGTH>::new();
+    match (request.reply_to, gate_id) {
+        (Some(reply_to), _) if is_valid_reply_topic(GATEWAY_CONFIG.main_topic, reply_to) && reply_to != message.topic => {
+            topic.push_str(reply_to).ok()?
+        }
+        (_, Some(gate_id)) => {
+            let subtopic = gate::gate_subtopic(GATEWAY_CONFIG.network_name, gate_id, GateTopicKind::Event)?;
+            write!(topic, "{}/{}", GATEWAY_CONFIG.main_topic, subtopic).ok()?
         }
+        _ => write!(topic, "{}/{}", GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.status_subtopic).ok()?,
+    }
 
-        
+    MqttMessage::absolute(&topic, &payload[..len], false)
+}
+
+/// Estado retido em `/state`, para quem assinar depois, e a transicao em `/event`.
+async fn publish_gate_state(outbound: &MqttChannel, node: NodeAddress, state: GateState) {
+    let Some(gate) = GATE_REGISTRY.gate_for_node(node) else {
+        warn!("Estado {:?} recebido do no {} sem cancela associada", state, node);
+        return;
+    };
+    let previous = GATE_REGISTRY.set_gate_state(&gate.external_id, state).unwrap_or(GateState::Unknown);
+    info!("Cancela {}: {:?} -> {:?}", gate.external_id, previous, state);
+
+    let network = GATEWAY_CONFIG.network_name;
+    let state_message = gate::gate_subtopic(network, &gate.external_id, GateTopicKind::State)
+        .and_then(|topic| MqttMessage::retained(&topic, state.as_str().as_bytes()));
+
+    let mut payload = [0u8; 96];
+    let event_message = GateStateEvent { node, state, previous }
+        .encode(&mut payload)
+        .ok()
+        .zip(gate::gate_subtopic(network, &gate.external_id, GateTopicKind::Event))
+        .and_then(|(len, topic)| MqttMessage::new(&topic, &payload[..len]));
+
+    match (state_message, event_message) {
+        (Some(state_message), Some(event_message)) => {
+            publish(outbound, state_message).await;
+            publish(outbound, event_message).await;
+        }
+        _ => error!("Falha ao montar estado MQTT da cancela {}", gate.external_id),
     }
 }
 
@@ -290,23 +573,85 @@ async fn main(spawner: Spawner) {
     let time_per = peripheral_manager.time_per();
     esp_hal_embassy::init(time_per.timer0);
 
+    // Configuracao do site fica na flash; valores de build so preenchem o que faltar
+    let mut config_store = ConfigStore::load().expect("Falha ao ler configuracao da flash");
+    let mut seeded = false;
+    for (key, value) in [
+        (keys::WIFI_SSID, option_env!("SSID")),
+        (keys::WIFI_PASSWORD, option_env!("PASSWORD")),
+        (keys::WIFI_FALLBACKS[0].0, option_env!("SSID_2")),
+        (keys::WIFI_FALLBACKS[0].1, option_env!("PASSWORD_2")),
+        (keys::WIFI_FALLBACKS[1].0, option_env!("SSID_3")),
+        (keys::WIFI_FALLBACKS[1].1, option_env!("PASSWORD_3")),
+        (keys::MQTT_HOST, Some(GATEWAY_CONFIG.broker_host)),
+        (keys::MQTT_USERNAME, GATEWAY_CONFIG.mqtt_username),
+        (keys::MQTT_PASSWORD, GATEWAY_CONFIG.mqtt_password),
+        (keys::HTTP_TOKEN, GATEWAY_CONFIG.http_api_token),
+    ] {
+        seeded |= config_store.seed(key, value).unwrap_or(false);
+    }
+    if seeded {
+        if let Err(e) = config_store.commit() {
+            error!("Falha ao gravar configuracao: {:?}", e);
+        }
+    }
+
     let wifi_peripherals = peripheral_manager.take_wifi_peripherals().unwrap();
-    let wifi = Wifi::new(wifi_peripherals);
-    let ssid = wifi.ssid;
-    let password = wifi.password;
+    let networks = config_store.wifi_networks();
+    if networks.is_empty() {
+        // Primeira instalacao: sem WiFi gravado, abre o portal de configuracao
+        warn!("Credenciais WiFi nao configuradas, iniciando portal em {}", SETUP_AP_SSID);
+        let mut wifi = Wifi::new_access_point(wifi_peripherals, SETUP_AP_SSID);
+        if let Err(e) = wifi.start_access_point().await {
+            panic!("Falha ao iniciar ponto de acesso: {}", e);
+        }
+        // O controlador precisa viver enquanto o ponto de acesso estiver ativo
+        let (_wifi_controller, runner, stack) = wifi.take_components();
+        let _ = spawner.spawn(net_task(runner));
+        run_captive_portal(stack, &mut config_store).await;
+
+        info!("Configuracao salva, reiniciando em modo estacao");
+        Timer::after_secs(2).await;
+        esp_hal::system::software_reset();
+    }
+    let broker_host = config_store.get_static(keys::MQTT_HOST).unwrap_or(GATEWAY_CONFIG.broker_host);
+    let broker_port = config_store.mqtt_port().unwrap_or(GATEWAY_CONFIG.broker_port);
+    let mqtt_username = config_store.get_static(keys::MQTT_USERNAME);
+    let mqtt_password = config_store.get_static(keys::MQTT_PASSWORD);
+    #[cfg(feature = "http-api")]
+    let http_api_token = config_store.get_static(keys::HTTP_TOKEN);
+    let site_gates = config_store.get_static(keys::SITE_GATES);
+    let esp_now_security = config_store.esp_now_security();
+
+    let network_settings = config_store.network_settings();
+    let ipv6 = network_settings.ipv6;
+    let mut wifi = Wifi::new(wifi_peripherals, networks.clone(), network_settings);
+    let station_mac = wifi.station_mac();
+    let esp_now = wifi.take_esp_now().expect("ESP-NOW ja retirado");
+    #[cfg(feature = "mqtt-tls")]
+    let rng = wifi.rng();
+    let seed = wifi.rng().random();
     let (wifi_controller, runner, stack) = wifi.take_components();
 
-    let _ = spawner.spawn(connection(wifi_controller, ssid, password));
+    // Conexao, troca de rede e DHCP ficam com o supervisor; os eventos dele avisam quando ha IP
+    let roamer = WifiRoamer::new(networks, &WIFI_NETWORK);
+    let supervisor = WifiSupervisor::new(wifi_controller, stack, roamer, &WIFI_EVENTS, seed);
+    let mut wifi_events = WIFI_EVENTS.subscriber().unwrap();
+    let _ = spawner.spawn(wifi_task(supervisor));
     let _ = spawner.spawn(net_task(runner));
+    // Endereco IPv6 global por SLAAC, a partir dos anuncios do roteador
+    if ipv6 {
+        let _ = spawner.spawn(slaac_task(SlaacClient::new(stack, station_mac)));
+    }
 
     info!("Aguardando IP DHCP...");
-    loop {
-        if let Some(config) = stack.config_v4() {
-            info!("IP obtido: {}", config.address);
-            break;
+    while stack.config_v4().is_none() {
+        if let WifiEvent::GotIp(address) = wifi_events.next_message_pure().await {
+            info!("IP obtido: {}", address);
         }
-        Timer::after_millis(500).await;
     }
+    drop(wifi_events);
+    info!("Enderecos em uso: {:?}", NetworkAddresses::of(&stack));
 
     let rx_buffer = RX_BUFFER_CELL.init([0; 4096]);
     let tx_buffer = TX_BUFFER_CELL.init([0; 4096]);
@@ -315,34 +660,136 @@ async fn main(spawner: Spawner) {
     socket.set_timeout(Some(Duration::from_secs(60)));
     socket.set_keep_alive(Some(Duration::from_secs(30)));
 
-    let socket = SOCKET_CELL.init(socket);
-    loop {
-        match socket
-            .connect((GATEWAY_CONFIG.broker_ip, GATEWAY_CONFIG.broker_port))
-            .await
-        {
-            Ok(()) => {
-                info!("TCP conectado ao broker MQTT");
-                break;
+    let socket = SOCKET_CELL.init(Mutex::new(socket));
+
+    // O controller reabre o socket e refaz a sessao sozinho quando o broker cai
+    let mqtt_settings = MqttConfig {
+        username: mqtt_username,
+        password: mqtt_password,
+        keep_alive_secs: GATEWAY_CONFIG.mqtt_keep_alive_secs,
+        ..MqttConfig::new(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.client_id)
+    };
+    let mqtt_buffers = MqttBuffers {
+        recv: MQTT_RECV_BUFFER_CELL.init([0; MQTT_BUFFER_SIZE]),
+        write: MQTT_WRITE_BUFFER_CELL.init([0; MQTT_BUFFER_SIZE]),
+    };
+    let broker = BrokerResolver::new(stack, BrokerAddress::hostname(broker_host, broker_port));
+    #[cfg(not(feature = "mqtt-tls"))]
+    let mut mqtt_controller = MqttController::new(
+        socket,
+        broker,
+        mqtt_settings,
+        mqtt_buffers,
+        &MQTT_STATE,
+    );
+
+    // Certificados gerados conforme mqtt_poc/readme.md (DER, P-256)
+    #[cfg(feature = "mqtt-tls")]
+    let mut mqtt_controller = {
+        let tls_settings = TlsSettings {
+            server_name: broker_host,
+            ca_cert: include_bytes!("../mqtt_poc/certs/ca.der"),
+            #[cfg(feature = "mqtt-tls-client-cert")]
+            client_cert: Some(include_bytes!("../mqtt_poc/certs/client.der")),
+            #[cfg(feature = "mqtt-tls-client-cert")]
+            client_key: Some(include_bytes!("../mqtt_poc/certs/client.key.der")),
+            #[cfg(not(feature = "mqtt-tls-client-cert"))]
+            client_cert: None,
+            #[cfg(not(feature = "mqtt-tls-client-cert"))]
+            client_key: None,
+        };
+        let tls_buffers = TlsBuffers {
+            read: TLS_READ_BUFFER_CELL.init([0; 4096 + 256]),
+            write: TLS_WRITE_BUFFER_CELL.init([0; 4096 + 256]),
+        };
+        let tls = TLS_LINK_CELL.init(TlsLink::new(socket, tls_settings, tls_buffers, rng));
+
+        MqttController::new_tls(
+            socket,
+            tls,
+            broker,
+            mqtt_settings,
+            mqtt_buffers,
+            &MQTT_STATE,
+        )
+    };
+    if let Err(e) = mqtt_controller.subscribe(GATEWAY_CONFIG.command_topic, MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command)) {
+        error!("Topico de comando invalido: {:?}", e);
+    }
+    match gate::gate_command_filter(GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.network_name) {
+        Some(filter) => {
+            if let Err(e) = mqtt_controller.subscribe(&filter, MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command)) {
+                error!("Topico de comando das cancelas invalido: {:?}", e);
             }
-            Err(e) => {
-                error!("Falha no TCP connect com broker MQTT: {:?}", e);
-                Timer::after_secs(1).await;
+            #[cfg(feature = "mqtt-broker")]
+            let _ = LOCAL_BROKER.route(&filter, MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command));
+        }
+        None => error!("Topico de comando das cancelas muito longo"),
+    }
+    if let Err(e) = mqtt_controller.subscribe(&HOME_ASSISTANT.command_filter(), MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command)) {
+        error!("Topico de comando do Home Assistant invalido: {:?}", e);
+    }
+
+    // Clientes da rede local caem no mesmo pipeline de comandos do broker remoto
+    #[cfg(feature = "mqtt-broker")]
+    {
+        LOCAL_BROKER.set_credentials(mqtt_username, mqtt_password);
+        let _ = LOCAL_BROKER.route(GATEWAY_CONFIG.command_topic, MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command));
+        let _ = LOCAL_BROKER.route(&HOME_ASSISTANT.command_filter(), MqttRoute::Command(&MQTT_INBOUND_CHANNEL, reject_command));
+        for slot in 0..MAX_BROKER_CLIENTS {
+            let _ = spawner.spawn(task_local_broker(stack, slot));
+        }
+        info!("Broker MQTT local ouvindo na porta 1883");
+    }
+
+    // API REST local: abre cancelas pelo mesmo caminho dos comandos MQTT
+    #[cfg(feature = "http-api")]
+    match http_api_token {
+        Some(token) => {
+            let api = HTTP_API_CELL.init(HttpApi {
+                token,
+                main_topic: GATEWAY_CONFIG.main_topic,
+                network_name: GATEWAY_CONFIG.network_name,
+                gates: &GATE_REGISTRY,
+                nodes: &NODE_REGISTRY,
+                mqtt_state: &MQTT_STATE,
+                wifi: &WIFI_NETWORK,
+                inbound: &MQTT_INBOUND_CHANNEL,
+            });
+            for _ in 0..2 {
+                let _ = spawner.spawn(task_http_api(stack, api));
             }
+            info!("API HTTP ouvindo na porta 80");
         }
+        None => error!("{} nao definido, API HTTP desativada", keys::HTTP_TOKEN),
     }
 
-    let mqtt_controller =
-        match MqttController::new(socket, GATEWAY_CONFIG.main_topic, GATEWAY_CONFIG.client_id)
-            .await
-        {
-        Ok(controller) => controller,
-        Err(e) => {
-            error!("Falha ao inicializar MQTT: {:?}", e);
-            return;
+    // Registro inicial das cancelas; pode ser alterado em runtime e o discovery e republicado
+    if let Err(e) = GATE_REGISTRY.set_site(GATEWAY_CONFIG.operation_center_name, GATEWAY_CONFIG.network_name) {
+        error!("Nome do site invalido: {:?}", e);
+    }
+    // Cancelas gravadas pelo portal tem prioridade sobre as de build
+    let stored_gates = site_gates.into_iter().flat_map(parse_gate_list).filter_map(Result::ok);
+    let built_in_gates = GATEWAY_CONFIG
+        .gates
+        .iter()
+        .filter(|_| site_gates.is_none())
+        .map(|&(external_id, name, direction, node)| GateSpec {
+            external_id,
+            name,
+            direction,
+            node,
+        });
+    for spec in stored_gates.chain(built_in_gates) {
+        match GateInfo::new(spec.external_id, spec.name, spec.direction).map(|gate| gate.with_node(spec.node)) {
+            Some(gate) => {
+                if let Err(e) = GATE_REGISTRY.upsert_gate(gate) {
+                    error!("Falha ao registrar cancela {}: {:?}", spec.external_id, e);
+                }
+            }
+            None => error!("Cancela {} com id ou nome muito longo", spec.external_id),
         }
-    };
-    let mqtt_controller_mutex = MQTT_CLIENT_CELL.init(Mutex::new(mqtt_controller));
+    }
 
     let lora_peripherals = peripheral_manager.take_lora_peripherals().unwrap();
     let lora = match LoraFactory::create_from_manager(lora_peripherals).await {
@@ -353,16 +800,18 @@ async fn main(spawner: Spawner) {
         }
     };
     let lora_controller = LoraController::new(lora);
-
-    let servo_peripherals = peripheral_manager.take_servo_peripherals().unwrap();
-    let servo_motor = ServoMotor::new(servo_peripherals);
+    let esp_now = EspNowTransport::new(esp_now, GATEWAY_CONFIG.lora_address, esp_now_security).expect("Falha ao iniciar ESP-NOW");
+    let dispatcher = Dispatcher::new((esp_now, lora_controller));
 
     let forward_channel = FORWARD_TO_LORA_CHANNEL.init(Channel::new());
     let result_channel = LORA_TO_MQTT_CHANNEL.init(Channel::new());
 
-    let _ = spawner.spawn(task_lora_gateway(lora_controller, forward_channel, result_channel, servo_motor));
-    let _ = spawner.spawn(task_mqtt_ingress(mqtt_controller_mutex, forward_channel.sender()));
-    let _ = spawner.spawn(task_mqtt_egress(mqtt_controller_mutex, result_channel.receiver()));
+    let _ = spawner.spawn(task_lora_gateway(dispatcher, forward_channel, result_channel));
+    let _ = spawner.spawn(task_mqtt(mqtt_controller, &MQTT_OUTBOUND_CHANNEL, &MQTT_REQUEST_CHANNEL));
+    let _ = spawner.spawn(task_discovery(&MQTT_OUTBOUND_CHANNEL));
+    let _ = spawner.spawn(task_home_assistant(&MQTT_OUTBOUND_CHANNEL));
+    let _ = spawner.spawn(task_mqtt_ingress(&MQTT_INBOUND_CHANNEL, forward_channel.sender()));
+    let _ = spawner.spawn(task_mqtt_egress(&MQTT_OUTBOUND_CHANNEL, result_channel.receiver()));
 
     loop {
         Timer::after_secs(60).await;
@@ -4,18 +4,18 @@
 
 use embassy_executor::Spawner;
 use embassy_sync::{blocking_mutex::raw::{CriticalSectionRawMutex, RawMutex}, channel::{Channel, Receiver}, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
 use esp_backtrace as _;
 use esp_println::logger::init_logger;
 use haviliar_iot::{
-    controller::mqtt::{self, MqttController}, hal::{peripheral_manager::PeripheralManagerStatic, servo_motor::ServoMotor, wifi::Wifi}
+    controller::{gate::{GateController, GateTiming}, provisioning::run_captive_portal, mqtt::{self, ConnectionStateWatch, MqttBuffers, MqttChannel, MqttConfig, MqttController, MqttRequestChannel, MqttRoute, MqttSocket}, resolver::{BrokerAddress, BrokerResolver}}, hal::{peripheral_manager::PeripheralManagerStatic, servo_motor::ServoMotor, wifi::{ActiveNetworkWatch, NetworkAddresses, Wifi, WifiEvent, WifiEventChannel, WifiRoamer, WifiSupervisor}, config_store::{keys, ConfigStore}}
 };
 use log::*;
 use esp_hal::{clock::CpuClock};
 use static_cell::StaticCell;
 use esp_alloc as _;
 use esp_wifi::{
-    wifi::{ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState}
+    wifi::WifiDevice
 };
 use embassy_net::{
     Runner, dns::Socket, tcp::TcpSocket
@@ -28,6 +28,9 @@ use rust_mqtt::{
 
 esp_bootloader_esp_idf::esp_app_desc!();
 
+/// Rede aberta criada quando nao ha WiFi configurado.
+const SETUP_AP_SSID: &str = "Haviliar-Setup";
+
 //static WIFI: StaticCell<AsyncMutex<CriticalSectionRawMutex, Wifi>> = StaticCell::new();
 //static WIFI: StaticCell<Wifi> = StaticCell::new();
 //static WIFI_CONTROLLER: StaticCell<WifiController<'static>> = StaticCell::new();
@@ -35,47 +38,19 @@ esp_bootloader_esp_idf::esp_app_desc!();
 static SERVO_CHANNEL: StaticCell<Channel<CriticalSectionRawMutex, i16, 4>> = StaticCell::new();
 static RX_BUFFER_CELL: StaticCell<[u8; 4096]> = StaticCell::new();
 static TX_BUFFER_CELL: StaticCell<[u8; 4096]> = StaticCell::new();
-static SOCKET_CELL: StaticCell<TcpSocket<'static>> = StaticCell::new();
-static MQTT_CLIENT_CELL: StaticCell<Mutex<CriticalSectionRawMutex, MqttController>> = StaticCell::new();
+static SOCKET_CELL: StaticCell<MqttSocket<'static>> = StaticCell::new();
+static MQTT_RECV_BUFFER_CELL: StaticCell<[u8; 256]> = StaticCell::new();
+static MQTT_WRITE_BUFFER_CELL: StaticCell<[u8; 256]> = StaticCell::new();
+static MQTT_STATE: ConnectionStateWatch = ConnectionStateWatch::new();
+static WIFI_NETWORK: ActiveNetworkWatch = ActiveNetworkWatch::new();
+static WIFI_EVENTS: WifiEventChannel = WifiEventChannel::new();
+static MQTT_INBOUND_CHANNEL: MqttChannel = MqttChannel::new();
+static MQTT_OUTBOUND_CHANNEL: MqttChannel = MqttChannel::new();
+static MQTT_REQUEST_CHANNEL: MqttRequestChannel = MqttRequestChannel::new();
 
 #[embassy_executor::task]
-async fn connection(mut controller: WifiController<'static>, ssid: &'static str, password: &'static str) {
-    info!("start connection task");
-    debug!("Device capabilities: {:?}", controller.capabilities());
-    loop {
-        match esp_wifi::wifi::wifi_state() {
-            WifiState::StaConnected => {
-                // wait until we're no longer connected
-                controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                Timer::after(Duration::from_millis(5000)).await;
-                continue; // Skip to next iteration after disconnect
-            }
-            _ => {}
-        }
-        if !matches!(controller.is_started(), Ok(true)) {
-            let client_config = Configuration::Client(ClientConfiguration {
-                ssid: ssid.try_into().unwrap(),
-                password: password.try_into().unwrap(),
-                ..Default::default()
-            });
-            controller.set_configuration(&client_config).unwrap();
-            info!("Starting wifi");
-            controller.start_async().await.unwrap();
-            info!("Wifi started!");
-        }
-        info!("About to connect...");
-
-        match controller.connect_async().await {
-            Ok(_) => {
-                info!("Wifi connected!");
-                // Wait here while connected, don't loop immediately
-            },
-            Err(e) => {
-                error!("Failed to connect to wifi: {e:?}");
-                Timer::after(Duration::from_millis(5000)).await
-            }
-        }
-    }
+async fn wifi_task(mut supervisor: WifiSupervisor) -> ! {
+    supervisor.run().await
 }
 
 // A background task, to process network events - when new packets, they need to processed, embassy-net, wraps smoltcp
@@ -85,79 +60,59 @@ async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
 }
 
 #[embassy_executor::task]
-async fn servo_task(receiver: Receiver<'static, CriticalSectionRawMutex, i16, 4>, mut servo: ServoMotor) {
+async fn servo_task(receiver: Receiver<'static, CriticalSectionRawMutex, i16, 4>, servo: ServoMotor) {
     
     info!("Servo task started");
-    servo.close();
+    // Fica aberta 3 s; um novo comando nesse tempo estende o prazo
+    let timing = GateTiming { hold_ms: Some(3_000), ..GateTiming::default() };
+    let mut gate = GateController::new(servo, timing);
+    if let Err(e) = gate.reset(Instant::now().as_millis()) {
+        error!("Failed to close gate: {:?}", e);
+    }
 
     loop {
-        match receiver.try_receive() {
-            Ok(message) => {
-                let angle = message as u32;
-                //info!("Servo requested angle: {}", angle);
-                servo.open();
-                Timer::after(Duration::from_secs(3)).await;
-                servo.close();
-            }
-            Err(e) => {
-                //error!("Failed to receive from Channel: {:?}", e);
+        if receiver.try_receive().is_ok() {
+            if let Err(e) = gate.open(Instant::now().as_millis()) {
+                error!("Failed to open gate: {:?}", e);
             }
         }
+        gate.update(Instant::now().as_millis());
+        while let Some(event) = gate.poll_event() {
+            info!("Gate event: {:?}", event);
+        }
         Timer::after(Duration::from_millis(10)).await;
     }
 }
 
 #[embassy_executor::task]
-async fn watch_mqtt_messages(mqtt_controller_mutex: &'static Mutex<CriticalSectionRawMutex, MqttController<'static>>, sender: embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, i16, 4>) {
-    loop {
-
-        let mut mqtt_controller = mqtt_controller_mutex.lock().await;
-        match mqtt_controller.receive_message().await {
-                Ok((topic, payload)) => {
-                    let msg = core::str::from_utf8(&payload).unwrap_or("<invalid utf8>");
-                    info!("Received message on topic '{}': {}", topic, msg);
-
-                    // Tentar parsear como inteiro (ângulo)
-                    if let Ok(angle) = msg.trim().parse::<i16>() {
-                        // limitar faixa (0..=180)
-                        let angle = angle.clamp(0, 180);
-                        info!("Setting servo angle to: {}", angle);
-                        match sender.try_send(angle) {
-                            Ok(_) => info!("Sent angle to servo task"),
-                            Err(e) => error!("Failed to send angle to servo task: {:?}", e),
-                        }                         
-                    } else {
-                        info!("Payload não é um inteiro válido para ângulo: '{}'", msg);
-                    }
-                }
-                Err(mqtt_error) => {
-                    error!("Receive message error: {:?}", mqtt_error);
-                    //break;
-                }
-        }
-        Timer::after(Duration::from_secs(1)).await;
-    }
+async fn mqtt_task(mut mqtt_controller: MqttController<'static>, outbound: &'static MqttChannel, requests: &'static MqttRequestChannel) {
+    // keep-alive pings are sent by the controller itself
+    mqtt_controller.run(outbound, requests).await
 }
 
 #[embassy_executor::task]
-async fn send_ping_task(mqtt_controller: &'static Mutex<CriticalSectionRawMutex, MqttController<'static>>) {
+async fn watch_mqtt_messages(inbound: &'static MqttChannel, sender: embassy_sync::channel::Sender<'static, CriticalSectionRawMutex, i16, 4>) {
     loop {
-        {
-            let mut controller = mqtt_controller.lock().await;
-            match controller.send_ping().await {
-                Ok(()) => {
-                    info!("Ping sent successfully");
-                }
-                Err(mqtt_error) => {
-                    error!("Ping error: {:?}", mqtt_error);
-                }
-            };
+        let message = inbound.receive().await;
+
+        let msg = core::str::from_utf8(&message.payload).unwrap_or("<invalid utf8>");
+        info!("Received message on topic '{}': {}", message.topic, msg);
+
+        // Tentar parsear como inteiro (ângulo)
+        if let Ok(angle) = msg.trim().parse::<i16>() {
+            // limitar faixa (0..=180)
+            let angle = angle.clamp(0, 180);
+            info!("Setting servo angle to: {}", angle);
+            match sender.try_send(angle) {
+                Ok(_) => info!("Sent angle to servo task"),
+                Err(e) => error!("Failed to send angle to servo task: {:?}", e),
+            }                         
+        } else {
+            info!("Payload não é um inteiro válido para ângulo: '{}'", msg);
         }
-        Timer::after(Duration::from_secs(30)).await;
     }
 }
 
-
 use core::mem::MaybeUninit;
 
 const HEAP_SIZE: usize = 72 * 1024; // 72KB heap
@@ -185,29 +140,55 @@ async fn main(_spawner: Spawner) {
 
     info!("Taking Wifi Peripherals...");
     let wifi_peripherals = peripheral_manager.take_wifi_peripherals().unwrap();
-    let wifi = Wifi::new(wifi_peripherals);
-    
-    let ssid = wifi.ssid;
-    let password = wifi.password;
+    // Credenciais vem da flash; as de build so valem no primeiro boot
+    let mut config_store = ConfigStore::load().expect("Failed to read config from flash");
+    let seeded_ssid = config_store.seed(keys::WIFI_SSID, option_env!("SSID")).unwrap_or(false);
+    let seeded_password = config_store.seed(keys::WIFI_PASSWORD, option_env!("PASSWORD")).unwrap_or(false);
+    if seeded_ssid || seeded_password {
+        if let Err(e) = config_store.commit() {
+            error!("Failed to store config: {:?}", e);
+        }
+    }
+    let time_per =  peripheral_manager.time_per();
+    esp_hal_embassy::init(time_per.timer0);
+
+    let networks = config_store.wifi_networks();
+    if networks.is_empty() {
+        // Sem WiFi gravado nem SSID de build: abre o portal de configuracao
+        warn!("Credenciais WiFi nao configuradas, iniciando portal em {}", SETUP_AP_SSID);
+        let mut wifi = Wifi::new_access_point(wifi_peripherals, SETUP_AP_SSID);
+        if let Err(e) = wifi.start_access_point().await {
+            panic!("Falha ao iniciar ponto de acesso: {}", e);
+        }
+        // O controlador precisa viver enquanto o ponto de acesso estiver ativo
+        let (_wifi_controller, runner, stack) = wifi.take_components();
+        let _ = _spawner.spawn(net_task(runner));
+        run_captive_portal(stack, &mut config_store).await;
+
+        info!("Configuracao salva, reiniciando em modo estacao");
+        Timer::after_secs(2).await;
+        esp_hal::system::software_reset();
+    }
+    let wifi = Wifi::new(wifi_peripherals, networks.clone(), config_store.network_settings());
+    let seed = wifi.rng().random();
     
     let (wifi_controller, runner, stack) = wifi.take_components();
+    let supervisor = WifiSupervisor::new(wifi_controller, stack, WifiRoamer::new(networks, &WIFI_NETWORK), &WIFI_EVENTS, seed);
+    let mut wifi_events = WIFI_EVENTS.subscriber().unwrap();
 
     info!("Spawning tasks...");
 
-    let _ = _spawner.spawn(connection(wifi_controller, ssid, password));
+    let _ = _spawner.spawn(wifi_task(supervisor));
     let _ = _spawner.spawn(net_task(runner));
     
-    let time_per =  peripheral_manager.time_per();
-    esp_hal_embassy::init(time_per.timer0);
-    
     info!("Waiting to get IP address...");
-    loop {
-        if let Some(config) = stack.config_v4() {
-            info!("Got IP: {}", config.address); //dhcp IP address
-            break;
+    while stack.config_v4().is_none() {
+        if let WifiEvent::GotIp(address) = wifi_events.next_message_pure().await {
+            info!("Got IP: {}", address); //dhcp IP address
         }
-        Timer::after(Duration::from_millis(500)).await;
     }
+    drop(wifi_events);
+    info!("Addresses in use: {:?}", NetworkAddresses::of(&stack));
 
     // INICIAR SERVO MOTOR
     info!("Initializing Servo Motor...");
@@ -240,34 +221,21 @@ async fn main(_spawner: Spawner) {
     socket.set_timeout(Some(embassy_time::Duration::from_secs(60))); 
     socket.set_keep_alive(Some(embassy_time::Duration::from_secs(30)));
 
-    let socket = SOCKET_CELL.init(socket);
-
-    let address = embassy_net::Ipv4Address::new(192, 168, 1, 21);
-    let remote_endpoint = (address, 1883);
+    let socket = SOCKET_CELL.init(Mutex::new(socket));
 
-    loop {
-        let connection = socket.connect(remote_endpoint).await;
-        if let Err(e) = connection {
-            error!("TCP connect error: {:?}", e);
-            error!("Waiting 10 seconds before retry...");
-            Timer::after(Duration::from_secs(1)).await;
-            continue;
-        }
-        info!("TCP connected successfully!");
-        break;
-    }
+    // Resolvido por DNS a cada conexao; sem resposta, procura _mqtt._tcp.local via mDNS
+    let broker = BrokerResolver::new(stack, BrokerAddress::hostname("haviliar-broker", 1883));
 
-    let mqtt_controller = match MqttController::new(socket, "esp32/open", "esp32-haviliar").await {
-        Ok(controller) => controller,
-        Err(e) => {
-            error!("Failed to create MQTT controller: {:?}", e);
-            return;
-        }
+    let mqtt_buffers = MqttBuffers {
+        recv: MQTT_RECV_BUFFER_CELL.init([0; 256]),
+        write: MQTT_WRITE_BUFFER_CELL.init([0; 256]),
     };
+    let mqtt_settings = MqttConfig::new("esp32/open", "esp32-haviliar");
+    let mut mqtt_controller = MqttController::new(socket, broker, mqtt_settings, mqtt_buffers, &MQTT_STATE);
+    mqtt_controller.subscribe("esp32/open", MqttRoute::Channel(&MQTT_INBOUND_CHANNEL)).unwrap();
 
-    let mqtt_controller_mutex = MQTT_CLIENT_CELL.init(Mutex::new(mqtt_controller));
-    let _ = _spawner.spawn(send_ping_task(mqtt_controller_mutex));
-    let _ = _spawner.spawn(watch_mqtt_messages(mqtt_controller_mutex, sender));
+    let _ = _spawner.spawn(mqtt_task(mqtt_controller, &MQTT_OUTBOUND_CHANNEL, &MQTT_REQUEST_CHANNEL));
+    let _ = _spawner.spawn(watch_mqtt_messages(&MQTT_INBOUND_CHANNEL, sender));
 
     // Main loop
     loop {
@@ -0,0 +1,62 @@
+use embassy_time::Duration;
+
+/// Exponential backoff with jitter used by the reconnect loops.
+///
+/// The delay doubles on every failed attempt up to `max`, and a random
+/// amount of up to half the delay is added so that several gateways
+/// restarting together do not hit the broker at the same instant.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    seed: u32,
+}
+
+impl Backoff {
+    pub const fn new(base: Duration, max: Duration, seed: u32) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+            // xorshift never leaves zero
+            seed: if seed == 0 { 0x9E37_79B9 } else { seed },
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let shift = self.attempt.min(16);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let delay_ms = self
+            .base
+            .as_millis()
+            .saturating_mul(1 << shift)
+            .min(self.max.as_millis());
+
+        let jitter_range = delay_ms / 2;
+        let jitter_ms = if jitter_range == 0 {
+            0
+        } else {
+            self.next_random() as u64 % jitter_range
+        };
+
+        Duration::from_millis(delay_ms + jitter_ms)
+    }
+
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.seed;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.seed = x;
+        x
+    }
+}
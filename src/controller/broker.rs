@@ -0,0 +1,381 @@
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_net::tcp::TcpSocket;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use log::{error, info, warn};
+
+use crate::{
+    controller::mqtt::{Delivery, MqttChannel, MqttMessage, MqttRoute},
+    protocol::{
+        mqtt_codec::{self as codec, CodecError, Packet, ProtocolLevel},
+        topic::{is_valid_filter, topic_matches, TopicError, TopicRouter},
+    },
+};
+
+pub const BROKER_PORT: u16 = 1883;
+pub const MAX_BROKER_CLIENTS: usize = 3;
+pub const MAX_CLIENT_SUBSCRIPTIONS: usize = 8;
+pub const MAX_RETAINED: usize = 8;
+const MAX_LOCAL_ROUTES: usize = 4;
+const MAX_SUBSCRIBE_FILTERS: usize = 8;
+const BROKER_PACKET_SIZE: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNACK_ACCEPTED: u8 = 0x00;
+const CONNACK_BAD_CREDENTIALS_V311: u8 = 0x04;
+const CONNACK_BAD_CREDENTIALS_V5: u8 = 0x86;
+const SUBACK_FAILURE: u8 = 0x80;
+
+#[derive(Debug)]
+enum SessionEnd {
+    Closed,
+    Protocol(CodecError),
+    Network,
+    Refused,
+}
+
+struct BrokerState {
+    /// Granted QoS per filter, one router per client slot.
+    subscriptions: [TopicRouter<u8, MAX_CLIENT_SUBSCRIPTIONS>; MAX_BROKER_CLIENTS],
+    connected: [bool; MAX_BROKER_CLIENTS],
+    retained: heapless::Vec<MqttMessage, MAX_RETAINED>,
+    local_routes: TopicRouter<MqttRoute, MAX_LOCAL_ROUTES>,
+    username: Option<&'static str>,
+    password: Option<&'static str>,
+}
+
+/// Small MQTT 3.1.1/5 broker for LAN-only operation. Each client slot is
+/// served by its own task through `serve`; messages published by clients or
+/// by the gateway itself (`publish`) reach matching client subscriptions and
+/// the local routes registered with `route`, which feed the same channels as
+/// the remote broker's subscriptions.
+///
+/// QoS 1 is acknowledged on receipt and delivered once without waiting for
+/// the client's PUBACK; QoS 2 is refused. Last Wills are not published.
+/// Once `set_credentials` is given a username or password, clients must
+/// present the same ones.
+pub struct LocalBroker {
+    state: Mutex<CriticalSectionRawMutex, RefCell<BrokerState>>,
+    outboxes: [MqttChannel; MAX_BROKER_CLIENTS],
+}
+
+impl LocalBroker {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(BrokerState {
+                subscriptions: [const { TopicRouter::new() }; MAX_BROKER_CLIENTS],
+                connected: [false; MAX_BROKER_CLIENTS],
+                retained: heapless::Vec::new(),
+                local_routes: TopicRouter::new(),
+                username: None,
+                password: None,
+            })),
+            outboxes: [const { MqttChannel::new() }; MAX_BROKER_CLIENTS],
+        }
+    }
+
+    /// Credentials every client must present; with both `None` any client
+    /// is accepted.
+    pub fn set_credentials(&self, username: Option<&'static str>, password: Option<&'static str>) {
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            state.username = username;
+            state.password = password;
+        });
+    }
+
+    fn accepts(&self, username: Option<&str>, password: Option<&[u8]>) -> bool {
+        self.state.lock(|cell| {
+            let state = cell.borrow();
+            if state.username.is_none() && state.password.is_none() {
+                return true;
+            }
+            username == state.username && password == state.password.map(str::as_bytes)
+        })
+    }
+
+    /// Delivers messages published by LAN clients on `filter` to `route`.
+    pub fn route(&self, filter: &str, route: MqttRoute) -> Result<(), TopicError> {
+        self.state.lock(|cell| cell.borrow_mut().local_routes.add(filter, route))
+    }
+
+    /// Publishes `message` (full topic) to every matching client. Retained
+    /// messages replace the stored one for the topic; an empty one clears it.
+    pub fn publish(&self, message: &MqttMessage) {
+        let (local, recipients) = self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            if message.retain {
+                store_retained(&mut state.retained, message);
+            }
+
+            let mut recipients = [false; MAX_BROKER_CLIENTS];
+            for (slot, router) in state.subscriptions.iter().enumerate() {
+                recipients[slot] = state.connected[slot] && router.routes(&message.topic).next().is_some();
+            }
+            let local: heapless::Vec<MqttRoute, MAX_LOCAL_ROUTES> = state.local_routes.routes(&message.topic).copied().collect();
+            (local, recipients)
+        });
+
+        for (slot, _) in recipients.iter().enumerate().filter(|(_, &wanted)| wanted) {
+            if self.outboxes[slot].try_send(message.clone()).is_err() {
+                warn!("Local broker client {} is not keeping up, message dropped", slot);
+            }
+        }
+
+        for route in local {
+            match route.deliver(message) {
+                Delivery::Delivered => {}
+                Delivery::Dropped => warn!("Local route for '{}' is full, message dropped", message.topic),
+                Delivery::Rejected(rejection) => {
+                    warn!("Local route for '{}' is full, message rejected", message.topic);
+                    // Absolute by contract, so it can go straight back to local clients
+                    self.publish(&rejection);
+                }
+            }
+        }
+    }
+
+    /// Mirrors a message queued for the remote broker, whose topic is relative
+    /// to `main_topic` unless it is `absolute`.
+    pub fn publish_outbound(&self, main_topic: &str, message: &MqttMessage) {
+        if message.absolute {
+            self.publish(message);
+            return;
+        }
+
+        let mut full = message.clone();
+        full.topic.clear();
+        if full.topic.push_str(main_topic).and_then(|_| full.topic.push('/')).and_then(|_| full.topic.push_str(&message.topic)).is_err() {
+            warn!("Topic '{}/{}' too long for the local broker", main_topic, message.topic);
+            return;
+        }
+        self.publish(&full);
+    }
+
+    /// Accepts and serves one client at a time on `slot` forever.
+    pub async fn serve(&self, slot: usize, socket: &mut TcpSocket<'_>) -> ! {
+        let mut rx = [0u8; BROKER_PACKET_SIZE];
+        let mut tx = [0u8; BROKER_PACKET_SIZE];
+
+        loop {
+            if let Err(e) = socket.accept(BROKER_PORT).await {
+                error!("Local broker accept error: {:?}", e);
+                continue;
+            }
+            info!("Local broker client {} connected from {:?}", slot, socket.remote_endpoint());
+
+            let end = self.session(slot, socket, &mut rx, &mut tx).await;
+            info!("Local broker client {} left: {:?}", slot, end);
+
+            self.state.lock(|cell| {
+                let mut state = cell.borrow_mut();
+                state.connected[slot] = false;
+                state.subscriptions[slot] = TopicRouter::new();
+            });
+            self.outboxes[slot].clear();
+
+            socket.close();
+            let _ = socket.flush().await;
+            socket.abort();
+        }
+    }
+
+    async fn session(&self, slot: usize, socket: &mut TcpSocket<'_>, rx: &mut [u8], tx: &mut [u8]) -> SessionEnd {
+        let mut level = ProtocolLevel::V311;
+        let mut filled = 0;
+        let mut connected = false;
+        let mut next_packet_id: u16 = 1;
+        socket.set_timeout(Some(CONNECT_TIMEOUT));
+
+        loop {
+            // Handle every complete packet already buffered
+            loop {
+                let (packet, used) = match codec::decode(&rx[..filled], level) {
+                    Ok(Some(decoded)) => decoded,
+                    Ok(None) if filled == rx.len() => return SessionEnd::Protocol(CodecError::BufferTooSmall),
+                    Ok(None) => break,
+                    Err(e) => return SessionEnd::Protocol(e),
+                };
+
+                if !connected && !matches!(packet, Packet::Connect { .. }) {
+                    return SessionEnd::Protocol(CodecError::Malformed);
+                }
+
+                let reply = match packet {
+                    Packet::Connect { level: client_level, client_id, keep_alive, username, password } => {
+                        level = client_level;
+                        if !self.accepts(username, password) {
+                            warn!("Local broker client {} ('{}') refused: bad username or password", slot, client_id);
+                            let code = match level {
+                                ProtocolLevel::V311 => CONNACK_BAD_CREDENTIALS_V311,
+                                ProtocolLevel::V5 => CONNACK_BAD_CREDENTIALS_V5,
+                            };
+                            if let Ok(len) = codec::encode_connack(level, code, tx) {
+                                let _ = socket.write_all(&tx[..len]).await;
+                            }
+                            return SessionEnd::Refused;
+                        }
+                        connected = true;
+                        info!("Local broker client {} is '{}' ({:?})", slot, client_id, level);
+                        socket.set_timeout((keep_alive > 0).then(|| Duration::from_secs(keep_alive as u64 * 3 / 2)));
+                        self.state.lock(|cell| cell.borrow_mut().connected[slot] = true);
+                        codec::encode_connack(level, CONNACK_ACCEPTED, tx).map(Some)
+                    }
+                    Packet::Publish { qos: 2, .. } => return SessionEnd::Protocol(CodecError::Malformed),
+                    Packet::Publish { topic, payload, retain, packet_id, .. } => {
+                        match MqttMessage::new(topic, payload) {
+                            Some(mut message) => {
+                                message.retain = retain;
+                                self.publish(&message);
+                            }
+                            None => warn!("Local broker message on '{}' too large, dropped", topic),
+                        }
+                        packet_id.map(|id| codec::encode_puback(id, tx)).transpose()
+                    }
+                    Packet::PubAck { .. } => Ok(None),
+                    Packet::Subscribe { packet_id, filters } => {
+                        let mut granted: heapless::Vec<u8, MAX_SUBSCRIBE_FILTERS> = heapless::Vec::new();
+                        for filter in filters {
+                            let (filter, qos) = match filter {
+                                Ok(filter) => filter,
+                                Err(e) => return SessionEnd::Protocol(e),
+                            };
+                            let code = self.subscribe(slot, filter, qos.min(1));
+                            if granted.push(code).is_err() {
+                                return SessionEnd::Protocol(CodecError::BufferTooSmall);
+                            }
+                        }
+                        match codec::encode_suback(level, packet_id, &granted, tx) {
+                            Ok(len) => {
+                                if socket.write_all(&tx[..len]).await.is_err() {
+                                    return SessionEnd::Network;
+                                }
+                            }
+                            Err(e) => return SessionEnd::Protocol(e),
+                        }
+                        // Retained messages go out after the SUBACK
+                        for ((filter, _), qos) in filters.flatten().zip(granted.iter().copied()) {
+                            if qos == SUBACK_FAILURE {
+                                continue;
+                            }
+                            for message in self.retained_for(filter) {
+                                if let Err(end) = self.send_publish(socket, level, &message, qos, true, &mut next_packet_id, tx).await {
+                                    return end;
+                                }
+                            }
+                        }
+                        Ok(None)
+                    }
+                    Packet::Unsubscribe { packet_id, filters } => {
+                        let mut count = 0;
+                        for filter in filters.flatten() {
+                            self.state.lock(|cell| cell.borrow_mut().subscriptions[slot].remove(filter.0));
+                            count += 1;
+                        }
+                        codec::encode_unsuback(level, packet_id, count, tx).map(Some)
+                    }
+                    Packet::PingReq => codec::encode_pingresp(tx).map(Some),
+                    Packet::Disconnect => return SessionEnd::Closed,
+                };
+
+                match reply {
+                    Ok(Some(len)) => {
+                        if socket.write_all(&tx[..len]).await.is_err() {
+                            return SessionEnd::Network;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => return SessionEnd::Protocol(e),
+                }
+
+                rx.copy_within(used..filled, 0);
+                filled -= used;
+            }
+
+            match select(socket.read(&mut rx[filled..]), self.outboxes[slot].receive()).await {
+                Either::First(Ok(0)) => return SessionEnd::Closed,
+                Either::First(Ok(n)) => filled += n,
+                Either::First(Err(_)) => return SessionEnd::Network,
+                Either::Second(message) => {
+                    // Live deliveries never carry the retain flag
+                    let qos = self.granted_qos(slot, &message.topic);
+                    if let Err(end) = self.send_publish(socket, level, &message, qos, false, &mut next_packet_id, tx).await {
+                        return end;
+                    }
+                }
+            }
+        }
+    }
+
+    fn subscribe(&self, slot: usize, filter: &str, qos: u8) -> u8 {
+        if !is_valid_filter(filter) {
+            return SUBACK_FAILURE;
+        }
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            let router = &mut state.subscriptions[slot];
+            router.remove(filter);
+            match router.add(filter, qos) {
+                Ok(()) => qos,
+                Err(_) => SUBACK_FAILURE,
+            }
+        })
+    }
+
+    /// Highest QoS among the slot's filters matching `topic`.
+    fn granted_qos(&self, slot: usize, topic: &str) -> u8 {
+        self.state.lock(|cell| cell.borrow().subscriptions[slot].routes(topic).copied().max().unwrap_or(0))
+    }
+
+    fn retained_for(&self, filter: &str) -> heapless::Vec<MqttMessage, MAX_RETAINED> {
+        self.state.lock(|cell| {
+            cell.borrow()
+                .retained
+                .iter()
+                .filter(|message| topic_matches(filter, &message.topic))
+                .cloned()
+                .collect()
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn send_publish(
+        &self,
+        socket: &mut TcpSocket<'_>,
+        level: ProtocolLevel,
+        message: &MqttMessage,
+        qos: u8,
+        retain: bool,
+        next_packet_id: &mut u16,
+        tx: &mut [u8],
+    ) -> Result<(), SessionEnd> {
+        let packet_id = (qos > 0).then(|| {
+            let id = *next_packet_id;
+            *next_packet_id = next_packet_id.checked_add(1).unwrap_or(1);
+            id
+        });
+
+        let len = codec::encode_publish(level, &message.topic, &message.payload, packet_id, retain, tx)
+            .map_err(SessionEnd::Protocol)?;
+        socket.write_all(&tx[..len]).await.map_err(|_| SessionEnd::Network)
+    }
+}
+
+impl Default for LocalBroker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn store_retained(retained: &mut heapless::Vec<MqttMessage, MAX_RETAINED>, message: &MqttMessage) {
+    retained.retain(|stored| stored.topic != message.topic);
+    if message.payload.is_empty() {
+        return;
+    }
+    if retained.push(message.clone()).is_err() {
+        warn!("Local broker retained store full, '{}' not retained", message.topic);
+    }
+}
@@ -0,0 +1,94 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use log::warn;
+
+use crate::{
+    controller::mqtt::MAX_TOPIC_LENGTH,
+    protocol::command::{self, CommandOutcome, CommandRequest, MAX_REQUEST_ID_LENGTH},
+};
+
+pub const MAX_PENDING_COMMANDS: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct PendingCommand {
+    pub seq: u16,
+    pub request_id: Option<heapless::String<MAX_REQUEST_ID_LENGTH>>,
+    pub reply_to: Option<heapless::String<MAX_TOPIC_LENGTH>>,
+    pub issued_at: Instant,
+}
+
+/// Step of a command, ready to be reported to whoever asked for it.
+#[derive(Debug, Clone)]
+pub struct CommandReport {
+    pub command: PendingCommand,
+    pub outcome: CommandOutcome,
+    pub latency_ms: u64,
+}
+
+/// Correlates the LoRa `seq` of forwarded commands with the MQTT request
+/// that caused them. Entries are dropped once a final outcome is reported.
+pub struct CommandTracker {
+    pending: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<PendingCommand, MAX_PENDING_COMMANDS>>>,
+    /// `replyTo` topics must be under it.
+    main_topic: &'static str,
+}
+
+impl CommandTracker {
+    pub const fn new(main_topic: &'static str) -> Self {
+        Self {
+            pending: Mutex::new(RefCell::new(heapless::Vec::new())),
+            main_topic,
+        }
+    }
+
+    /// Starts tracking `seq`. When the table is full the oldest entry is
+    /// evicted, since its requester has almost certainly given up. A
+    /// `replyTo` outside the main topic is dropped, so the result goes to the
+    /// status topic instead.
+    pub fn track(&self, seq: u16, request: &CommandRequest) {
+        let reply_to = request.reply_to.filter(|topic| {
+            let valid = command::is_valid_reply_topic(self.main_topic, topic);
+            if !valid {
+                warn!("replyTo '{}' is not under '{}/', ignored", topic, self.main_topic);
+            }
+            valid
+        });
+        let command = PendingCommand {
+            seq,
+            request_id: request.request_id.and_then(|id| heapless::String::try_from(id).ok()),
+            reply_to: reply_to.and_then(|topic| heapless::String::try_from(topic).ok()),
+            issued_at: Instant::now(),
+        };
+
+        self.pending.lock(|cell| {
+            let mut pending = cell.borrow_mut();
+            pending.retain(|c| c.seq != seq);
+            if pending.is_full() {
+                pending.remove(0);
+            }
+            let _ = pending.push(command);
+        });
+    }
+
+    /// Returns the report for `seq`, or `None` if it is not a tracked command.
+    pub fn report(&self, seq: u16, outcome: CommandOutcome) -> Option<CommandReport> {
+        let now = Instant::now();
+        self.pending.lock(|cell| {
+            let mut pending = cell.borrow_mut();
+            let index = pending.iter().position(|c| c.seq == seq)?;
+            let command = if outcome.is_final() {
+                pending.remove(index)
+            } else {
+                pending[index].clone()
+            };
+
+            Some(CommandReport {
+                latency_ms: (now - command.issued_at).as_millis(),
+                command,
+                outcome,
+            })
+        })
+    }
+}
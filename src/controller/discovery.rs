@@ -0,0 +1,65 @@
+use embassy_futures::select::{select, Either};
+use log::{error, info};
+
+use crate::{
+    controller::{
+        gate_registry::GateRegistry,
+        mqtt::{ConnectionState, ConnectionStateWatch, MqttChannel, MqttMessage, MAX_MESSAGE_PAYLOAD},
+    },
+    hal::lora::RADIO_PARAMETERS,
+    protocol::discovery::DiscoveryPayload,
+};
+
+pub const DISCOVERY_SUBTOPIC: &str = "discovery";
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Publishes the retained discovery document every time the MQTT session
+/// comes up, and again whenever the gate registry changes while connected.
+pub async fn run_discovery(registry: &GateRegistry, state: &ConnectionStateWatch, outbound: &MqttChannel) -> ! {
+    let mut state_receiver = state.receiver().expect("no free ConnectionStateWatch receiver");
+    let mut connected = false;
+
+    loop {
+        match select(state_receiver.changed(), registry.wait_changed()).await {
+            Either::First(new_state) => {
+                let was_connected = connected;
+                connected = new_state == ConnectionState::Connected;
+                if !connected || was_connected {
+                    continue;
+                }
+            }
+            Either::Second(()) => {
+                // Published on the next connect otherwise
+                if !connected {
+                    continue;
+                }
+            }
+        }
+
+        match build_discovery_message(registry) {
+            Some(message) => {
+                info!("Publishing discovery ({} bytes)", message.payload.len());
+                outbound.send(message).await;
+            }
+            None => error!("Discovery document does not fit in an MQTT message"),
+        }
+    }
+}
+
+pub fn build_discovery_message(registry: &GateRegistry) -> Option<MqttMessage> {
+    let mut buffer = [0u8; MAX_MESSAGE_PAYLOAD];
+
+    let len = registry.with(|operation_center_name, network_name, gates| {
+        DiscoveryPayload {
+            operation_center_name,
+            network_name,
+            firmware_version: FIRMWARE_VERSION,
+            radio: RADIO_PARAMETERS,
+            gates,
+        }
+        .encode(&mut buffer)
+        .ok()
+    })?;
+
+    MqttMessage::retained(DISCOVERY_SUBTOPIC, &buffer[..len])
+}
@@ -0,0 +1,212 @@
+use alloc::vec::Vec;
+
+use esp_wifi::esp_now::{EspNow, EspNowError, EspNowWifiInterface, PeerInfo, BROADCAST_ADDRESS};
+use log::{info, warn};
+
+use crate::{
+    controller::transport::{LinkHealth, Received, Transport, TransportError, TransportKind},
+    protocol::{
+        lora::{LoraEnvelope, LoraParser, NodeAddress},
+        message_type::MessageType,
+        routing::LinkQuality,
+    },
+};
+
+/// Largest payload of one ESP-NOW frame.
+pub const ESP_NOW_MAX_DATA: usize = 250;
+/// The driver allows 20 peers; a few encrypted slots are left to other users.
+pub const MAX_ESP_NOW_PEERS: usize = 16;
+
+pub type MacAddress = [u8; 6];
+
+/// Keys for encrypted unicast. The PMK encrypts the LMKs exchanged with
+/// every peer; broadcast frames (discovery) are never encrypted.
+#[derive(Clone)]
+pub struct EspNowSecurity {
+    pub pmk: [u8; 16],
+    pub lmk: [u8; 16],
+}
+
+#[derive(Debug)]
+pub enum EspNowTransportError {
+    /// The encoded envelope does not fit one ESP-NOW frame.
+    TooLarge,
+    /// No MAC known for the destination node yet; see `announce`.
+    UnknownPeer(NodeAddress),
+    PeerTableFull,
+    Driver(EspNowError),
+}
+
+impl From<EspNowError> for EspNowTransportError {
+    fn from(e: EspNowError) -> Self {
+        EspNowTransportError::Driver(e)
+    }
+}
+
+/// Radio-level details of a received frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspNowLink {
+    pub mac: MacAddress,
+    pub rssi: i16,
+}
+
+/// Carries the same `LoraEnvelope` frames as LoRa over ESP-NOW, for gates
+/// within WiFi range of the gateway. Peers are learned from `Discovery`
+/// frames: `announce` broadcasts one, and every node that hears it answers
+/// with its own, unicast.
+///
+/// ESP-NOW shares the radio with the station, so both ends must be on the
+/// channel of the access point the gateway is joined to.
+pub struct EspNowTransport {
+    esp_now: EspNow<'static>,
+    local: NodeAddress,
+    security: Option<EspNowSecurity>,
+    peers: heapless::Vec<(NodeAddress, MacAddress), MAX_ESP_NOW_PEERS>,
+    health: LinkHealth,
+}
+
+impl EspNowTransport {
+    /// `local` goes in the `src` of every frame sent.
+    pub fn new(esp_now: EspNow<'static>, local: NodeAddress, security: Option<EspNowSecurity>) -> Result<Self, EspNowTransportError> {
+        if let Some(security) = &security {
+            esp_now.set_pmk(&security.pmk)?;
+        }
+        info!("ESP-NOW ready as node {} (encrypted: {})", local, security.is_some());
+
+        Ok(Self {
+            esp_now,
+            local,
+            security,
+            peers: heapless::Vec::new(),
+            health: LinkHealth::new(true),
+        })
+    }
+
+    pub fn peer(&self, node: NodeAddress) -> Option<MacAddress> {
+        self.peers.iter().find(|(known, _)| *known == node).map(|(_, mac)| *mac)
+    }
+
+    pub fn peers(&self) -> impl Iterator<Item = (NodeAddress, MacAddress)> + '_ {
+        self.peers.iter().copied()
+    }
+
+    /// Registers `node` at `mac`, replacing the MAC it had before.
+    pub fn register_peer(&mut self, node: NodeAddress, mac: MacAddress) -> Result<(), EspNowTransportError> {
+        match self.peers.iter().position(|(known, _)| *known == node) {
+            Some(index) if self.peers[index].1 == mac => return Ok(()),
+            Some(index) => {
+                let (_, previous) = self.peers.swap_remove(index);
+                let _ = self.esp_now.remove_peer(&previous);
+            }
+            None if self.peers.is_full() => return Err(EspNowTransportError::PeerTableFull),
+            None => {}
+        }
+
+        if !self.esp_now.peer_exists(&mac) {
+            self.esp_now.add_peer(PeerInfo {
+                interface: EspNowWifiInterface::Sta,
+                peer_address: mac,
+                lmk: self.security.as_ref().map(|security| security.lmk),
+                channel: None,
+                encrypt: self.security.is_some(),
+            })?;
+        }
+        let _ = self.peers.push((node, mac));
+        info!("ESP-NOW peer {} at {:02x?}", node, mac);
+        Ok(())
+    }
+
+    /// Broadcasts a `Discovery` frame; nodes in range answer with theirs.
+    pub async fn announce(&mut self) -> Result<(), EspNowTransportError> {
+        let discovery = self.discovery_frame();
+        self.send_to(&BROADCAST_ADDRESS, &discovery).await
+    }
+
+    fn discovery_frame(&self) -> LoraEnvelope {
+        LoraEnvelope::new(MessageType::Discovery, 0, 0, 0, Vec::new()).with_source(self.local)
+    }
+
+    /// Unicast to the envelope's next hop (`next_hop`, else `dst`), or
+    /// broadcast when it has neither.
+    pub async fn send(&mut self, envelope: &LoraEnvelope) -> Result<(), EspNowTransportError> {
+        let mac = match envelope.next_hop.or(envelope.dst) {
+            Some(node) => self.peer(node).ok_or(EspNowTransportError::UnknownPeer(node))?,
+            None => BROADCAST_ADDRESS,
+        };
+        self.send_to(&mac, envelope).await
+    }
+
+    async fn send_to(&mut self, mac: &MacAddress, envelope: &LoraEnvelope) -> Result<(), EspNowTransportError> {
+        let frame = envelope.into_outgoing().ok_or(EspNowTransportError::TooLarge)?;
+        let data = frame.as_slice();
+        if data.len() > ESP_NOW_MAX_DATA {
+            return Err(EspNowTransportError::TooLarge);
+        }
+        self.esp_now.send_async(mac, data).await?;
+        Ok(())
+    }
+
+    /// Waits for the next application frame. `Discovery` frames are handled
+    /// here: the sender becomes a peer and a broadcast one is answered.
+    pub async fn receive(&mut self) -> (LoraEnvelope, EspNowLink) {
+        loop {
+            let received = self.esp_now.receive_async().await;
+            let link = EspNowLink {
+                mac: received.info.src_address,
+                rssi: received.info.rx_control.rssi as i16,
+            };
+            let Some(envelope) = LoraParser::decode_envelope(received.data()) else {
+                warn!("ESP-NOW frame from {:02x?} is not an envelope", link.mac);
+                continue;
+            };
+
+            if let MessageType::Discovery = envelope.msg_type {
+                let Some(node) = envelope.src else {
+                    continue;
+                };
+                if let Err(e) = self.register_peer(node, link.mac) {
+                    warn!("ESP-NOW peer {} not registered: {:?}", node, e);
+                    continue;
+                }
+                if received.info.dst_address == BROADCAST_ADDRESS {
+                    let reply = self.discovery_frame().with_destination(node);
+                    if let Err(e) = self.send(&reply).await {
+                        warn!("ESP-NOW discovery reply to {} failed: {:?}", node, e);
+                    }
+                }
+                continue;
+            }
+
+            return (envelope, link);
+        }
+    }
+}
+
+impl Transport for EspNowTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::EspNow
+    }
+
+    fn health(&self) -> LinkHealth {
+        self.health
+    }
+
+    async fn send(&mut self, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        let result = EspNowTransport::send(self, envelope).await;
+        // A peer not discovered yet says nothing about the link
+        if !matches!(result, Err(EspNowTransportError::UnknownPeer(_))) {
+            self.health.record_send(&result);
+        }
+        result.map_err(TransportError::from)
+    }
+
+    async fn receive(&mut self) -> Result<Received, TransportError> {
+        let (envelope, link) = EspNowTransport::receive(self).await;
+        self.health.record_receive(Some(link.rssi), None);
+        Ok(Received {
+            envelope,
+            link: LinkQuality { rssi: link.rssi, snr: 0 },
+            transport: TransportKind::EspNow,
+        })
+    }
+}
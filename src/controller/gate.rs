@@ -0,0 +1,633 @@
+//! Barrier state machine above a `GateActuator`. Time is passed in as
+//! milliseconds, so the whole cycle can be run on the host with a fake
+//! actuator and a fake clock.
+//!
+//! ```text
+//! Closed --open--> Opening --arrived--> Open --hold over / close--> Closing --arrived--> Closed
+//!                     ^                                                  |
+//!                     +------------------------ open --------------------+
+//! Opening/Closing --timeout or actuator error--> Fault --reset--> Closing
+//! ```
+//!
+//! With a presence sensor, a vehicle under the arm blocks every close (the
+//! command, the hold time, `reset`) and turns `Closing` back into `Opening`.
+//! Each time raises `GateEvent::Obstructed`; the blocked close runs once the
+//! vehicle has gone.
+
+use crate::{
+    hal::{
+        actuator::{GateActuator, GateTarget, Motion},
+        presence::{Debounced, NoPresence, PresenceSensor},
+    },
+    protocol::gate::GateState,
+};
+
+pub const MAX_PENDING_EVENTS: usize = 8;
+/// Sensor reading period while the gate is still.
+pub const PRESENCE_POLL_MS: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GateTiming {
+    /// Time the gate stays open before closing by itself; `None` keeps it
+    /// open until `close`.
+    pub hold_ms: Option<u64>,
+    /// A move not finished within this time is a fault.
+    pub movement_timeout_ms: u64,
+    /// Closes this long after a vehicle has left, instead of waiting for the
+    /// rest of the hold time. Needs a presence sensor.
+    pub close_after_clear_ms: Option<u64>,
+}
+
+impl Default for GateTiming {
+    fn default() -> Self {
+        Self {
+            hold_ms: Some(5_000),
+            movement_timeout_ms: 10_000,
+            close_after_clear_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateFault {
+    MovementTimeout,
+    Actuator,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateEvent {
+    Changed { previous: GateState, state: GateState, at_ms: u64 },
+    Fault { fault: GateFault, at_ms: u64 },
+    /// A close was blocked (`state` is where the gate stayed) or, when
+    /// `state` is `Closing`, reversed.
+    Obstructed { state: GateState, at_ms: u64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateCommandError {
+    /// The gate is in `Fault`; `reset` first.
+    Faulted,
+    /// The actuator refused the command; the gate is now in `Fault`.
+    Actuator,
+    /// A vehicle is under the arm; the gate closes once it has gone.
+    Obstructed,
+}
+
+pub struct GateController<A: GateActuator, S: PresenceSensor = NoPresence> {
+    actuator: A,
+    presence: Option<Debounced<S>>,
+    timing: GateTiming,
+    state: GateState,
+    /// When the current state was entered.
+    since_ms: u64,
+    close_at_ms: Option<u64>,
+    events: heapless::Deque<GateEvent, MAX_PENDING_EVENTS>,
+    /// A close is waiting for the vehicle to leave.
+    close_on_clear: bool,
+    /// That close is a `reset`, the only one that may leave `Fault`.
+    reset_on_clear: bool,
+    last_update_ms: u64,
+}
+
+impl<A: GateActuator> GateController<A> {
+    /// The position is unknown until the first command; `reset` closes.
+    pub fn new(actuator: A, timing: GateTiming) -> Self {
+        Self::with_presence(actuator, None, timing)
+    }
+}
+
+impl<A: GateActuator, S: PresenceSensor> GateController<A, S> {
+    /// `None` when the sensor is chosen at runtime and there is none.
+    pub fn with_presence(actuator: A, presence: Option<Debounced<S>>, timing: GateTiming) -> Self {
+        Self {
+            actuator,
+            presence,
+            timing,
+            state: GateState::Unknown,
+            since_ms: 0,
+            close_at_ms: None,
+            events: heapless::Deque::new(),
+            close_on_clear: false,
+            reset_on_clear: false,
+            last_update_ms: 0,
+        }
+    }
+
+    pub fn state(&self) -> GateState {
+        self.state
+    }
+
+    pub fn timing(&self) -> GateTiming {
+        self.timing
+    }
+
+    pub fn set_timing(&mut self, timing: GateTiming) {
+        self.timing = timing;
+    }
+
+    pub fn actuator(&mut self) -> &mut A {
+        &mut self.actuator
+    }
+
+    pub fn is_moving(&self) -> bool {
+        matches!(self.state, GateState::Opening | GateState::Closing)
+    }
+
+    /// The sensor, e.g. to take a reading ahead of `update`.
+    pub fn presence(&mut self) -> Option<&mut Debounced<S>> {
+        self.presence.as_mut()
+    }
+
+    /// Settled sensor state; always false without a sensor.
+    pub fn is_obstructed(&self) -> bool {
+        self.presence.as_ref().is_some_and(Debounced::is_present)
+    }
+
+    /// Opens, or keeps the gate open for another hold time when it already
+    /// is. A gate closing reverses.
+    pub fn open(&mut self, now_ms: u64) -> Result<(), GateCommandError> {
+        match self.state {
+            GateState::Fault => Err(GateCommandError::Faulted),
+            GateState::Opening => Ok(()),
+            GateState::Open => {
+                if !self.close_on_clear {
+                    self.close_at_ms = self.timing.hold_ms.map(|hold_ms| now_ms + hold_ms);
+                }
+                Ok(())
+            }
+            GateState::Closed | GateState::Closing | GateState::Unknown => self.start(GateTarget::Open, now_ms),
+        }
+    }
+
+    pub fn close(&mut self, now_ms: u64) -> Result<(), GateCommandError> {
+        match self.state {
+            GateState::Fault => Err(GateCommandError::Faulted),
+            GateState::Closing | GateState::Closed => Ok(()),
+            GateState::Open | GateState::Opening | GateState::Unknown => self.start_close(now_ms),
+        }
+    }
+
+    /// Leaves `Fault` (or the initial unknown position) by closing.
+    pub fn reset(&mut self, now_ms: u64) -> Result<(), GateCommandError> {
+        let result = self.start_close(now_ms);
+        self.reset_on_clear = result == Err(GateCommandError::Obstructed);
+        result
+    }
+
+    /// Advances moves, the hold time and the movement timeout. Call it
+    /// every few milliseconds while `is_moving`, and by `next_deadline`
+    /// otherwise.
+    pub fn update(&mut self, now_ms: u64) {
+        self.last_update_ms = now_ms;
+        if let Some(present) = self.presence.as_mut().and_then(|presence| presence.update(now_ms)) {
+            self.presence_changed(present, now_ms);
+        }
+
+        match self.state {
+            GateState::Opening | GateState::Closing => match self.actuator.update(now_ms) {
+                Ok(Motion::Arrived) if self.state == GateState::Opening => {
+                    self.close_at_ms = match self.close_on_clear {
+                        // The vehicle left while the arm was still rising
+                        true if !self.is_obstructed() => Some(now_ms + self.timing.close_after_clear_ms.unwrap_or(0)),
+                        true => None,
+                        false => self.timing.hold_ms.map(|hold_ms| now_ms + hold_ms),
+                    };
+                    self.enter(GateState::Open, now_ms);
+                }
+                Ok(Motion::Arrived) => self.enter(GateState::Closed, now_ms),
+                Ok(Motion::Moving) if now_ms.saturating_sub(self.since_ms) > self.timing.movement_timeout_ms => {
+                    let _ = self.actuator.stop();
+                    self.fault(GateFault::MovementTimeout, now_ms);
+                }
+                Ok(Motion::Moving) => {}
+                Err(_) => {
+                    let _ = self.actuator.stop();
+                    self.fault(GateFault::Actuator, now_ms);
+                }
+            },
+            GateState::Fault if !self.reset_on_clear => {}
+            // Also where a `reset` blocked by a vehicle left the gate
+            GateState::Open | GateState::Unknown | GateState::Fault
+                if self.close_at_ms.is_some_and(|close_at_ms| now_ms >= close_at_ms) =>
+            {
+                self.close_at_ms = None;
+                let _ = self.start_close(now_ms);
+            }
+            _ => {}
+        }
+    }
+
+    /// Next time `update` has something to do when the gate is not moving.
+    /// With a sensor, that is at least every `PRESENCE_POLL_MS`.
+    pub fn next_deadline(&self) -> Option<u64> {
+        let close_at_ms = match self.state {
+            GateState::Open | GateState::Unknown | GateState::Fault => self.close_at_ms,
+            _ => None,
+        };
+        let poll_at_ms = self.presence.as_ref().map(|_| self.last_update_ms + PRESENCE_POLL_MS);
+        match (close_at_ms, poll_at_ms) {
+            (Some(close_at_ms), Some(poll_at_ms)) => Some(close_at_ms.min(poll_at_ms)),
+            (close_at_ms, poll_at_ms) => close_at_ms.or(poll_at_ms),
+        }
+    }
+
+    /// Oldest event not taken yet. Only the newest `MAX_PENDING_EVENTS` are kept.
+    pub fn poll_event(&mut self) -> Option<GateEvent> {
+        self.events.pop_front()
+    }
+
+    fn presence_changed(&mut self, present: bool, now_ms: u64) {
+        match (present, self.state) {
+            // A vehicle passing under a faulted gate closes nothing
+            (_, GateState::Fault) if !self.reset_on_clear => {}
+            (true, GateState::Closing) => {
+                self.push(GateEvent::Obstructed { state: GateState::Closing, at_ms: now_ms });
+                self.close_on_clear = true;
+                let reversed = self.actuator.reverse(GateTarget::Open, now_ms);
+                let _ = self.started(reversed, GateTarget::Open, now_ms);
+            }
+            (true, _) => self.close_on_clear |= self.timing.close_after_clear_ms.is_some(),
+            // Reaching `Open` decides
+            (false, GateState::Opening) => {}
+            // Turned away without the gate opening
+            (false, GateState::Closed) => self.close_on_clear = false,
+            (false, _) if self.close_on_clear => {
+                self.close_at_ms = Some(now_ms + self.timing.close_after_clear_ms.unwrap_or(0));
+            }
+            (false, _) => {}
+        }
+    }
+
+    /// Closes unless a vehicle is there; then the close waits for it to go.
+    fn start_close(&mut self, now_ms: u64) -> Result<(), GateCommandError> {
+        if self.is_obstructed() {
+            self.close_at_ms = None;
+            self.close_on_clear = true;
+            self.push(GateEvent::Obstructed { state: self.state, at_ms: now_ms });
+            return Err(GateCommandError::Obstructed);
+        }
+        self.close_on_clear = false;
+        self.start(GateTarget::Closed, now_ms)
+    }
+
+    fn start(&mut self, target: GateTarget, now_ms: u64) -> Result<(), GateCommandError> {
+        let driven = self.actuator.drive(target, now_ms);
+        self.started(driven, target, now_ms)
+    }
+
+    /// Follows up on the actuator taking (or refusing) a move.
+    fn started(&mut self, driven: Result<(), A::Error>, target: GateTarget, now_ms: u64) -> Result<(), GateCommandError> {
+        self.close_at_ms = None;
+        self.reset_on_clear = false;
+        if driven.is_err() {
+            self.fault(GateFault::Actuator, now_ms);
+            return Err(GateCommandError::Actuator);
+        }
+        let moving = match target {
+            GateTarget::Open => GateState::Opening,
+            GateTarget::Closed => GateState::Closing,
+        };
+        self.enter(moving, now_ms);
+        Ok(())
+    }
+
+    fn fault(&mut self, fault: GateFault, now_ms: u64) {
+        self.close_at_ms = None;
+        // Only `reset` leaves `Fault`, even once the vehicle has gone
+        self.close_on_clear = false;
+        self.reset_on_clear = false;
+        self.push(GateEvent::Fault { fault, at_ms: now_ms });
+        self.enter(GateState::Fault, now_ms);
+    }
+
+    fn enter(&mut self, state: GateState, now_ms: u64) {
+        let previous = self.state;
+        self.state = state;
+        self.since_ms = now_ms;
+        if previous != state {
+            self.push(GateEvent::Changed { previous, state, at_ms: now_ms });
+        }
+    }
+
+    fn push(&mut self, event: GateEvent) {
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::presence::PresenceSettings;
+
+    const TRAVEL_MS: u64 = 1_000;
+
+    /// Arrives `travel_ms` after each `drive`; `None` never arrives.
+    struct FakeActuator {
+        target: Option<GateTarget>,
+        started_ms: u64,
+        travel_ms: Option<u64>,
+        drives: u32,
+        reverses: u32,
+        stopped: bool,
+        fail_drive: bool,
+        fail_update: bool,
+    }
+
+    impl FakeActuator {
+        fn new(travel_ms: Option<u64>) -> Self {
+            Self {
+                target: None,
+                started_ms: 0,
+                travel_ms,
+                drives: 0,
+                reverses: 0,
+                stopped: false,
+                fail_drive: false,
+                fail_update: false,
+            }
+        }
+    }
+
+    impl GateActuator for FakeActuator {
+        type Error = ();
+
+        fn drive(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+            if self.fail_drive {
+                return Err(());
+            }
+            self.target = Some(target);
+            self.started_ms = now_ms;
+            self.drives += 1;
+            self.stopped = false;
+            Ok(())
+        }
+
+        fn reverse(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+            self.reverses += 1;
+            self.drive(target, now_ms)
+        }
+
+        fn update(&mut self, now_ms: u64) -> Result<Motion, Self::Error> {
+            if self.fail_update {
+                return Err(());
+            }
+            match self.travel_ms {
+                Some(travel_ms) if now_ms >= self.started_ms + travel_ms => Ok(Motion::Arrived),
+                _ => Ok(Motion::Moving),
+            }
+        }
+
+        fn stop(&mut self) -> Result<(), Self::Error> {
+            self.stopped = true;
+            Ok(())
+        }
+    }
+
+    struct FakeSensor {
+        present: bool,
+    }
+
+    impl PresenceSensor for FakeSensor {
+        type Error = ();
+
+        fn is_present(&mut self, _now_ms: u64) -> Result<bool, Self::Error> {
+            Ok(self.present)
+        }
+    }
+
+    fn gate(travel_ms: Option<u64>) -> GateController<FakeActuator> {
+        GateController::new(FakeActuator::new(travel_ms), GateTiming::default())
+    }
+
+    /// Settles clear at 500 ms, like a sensor read since boot.
+    fn gate_with_presence(travel_ms: Option<u64>, timing: GateTiming) -> GateController<FakeActuator, FakeSensor> {
+        let presence = Debounced::new(FakeSensor { present: false }, PresenceSettings::default());
+        let mut gate = GateController::with_presence(FakeActuator::new(travel_ms), Some(presence), timing);
+        run(&mut gate, 0, 500);
+        assert!(!gate.is_obstructed());
+        gate
+    }
+
+    fn set_present(gate: &mut GateController<FakeActuator, FakeSensor>, present: bool) {
+        gate.presence.as_mut().unwrap().sensor().present = present;
+    }
+
+    /// Calls `update` every `PRESENCE_POLL_MS` from `from_ms` to `to_ms`.
+    fn run<A: GateActuator, S: PresenceSensor>(gate: &mut GateController<A, S>, from_ms: u64, to_ms: u64) {
+        for now_ms in (from_ms..=to_ms).step_by(PRESENCE_POLL_MS as usize) {
+            gate.update(now_ms);
+        }
+    }
+
+    /// States entered since the last call, in order.
+    fn changes<A: GateActuator, S: PresenceSensor>(gate: &mut GateController<A, S>) -> heapless::Vec<GateState, MAX_PENDING_EVENTS> {
+        let mut states = heapless::Vec::new();
+        while let Some(event) = gate.poll_event() {
+            if let GateEvent::Changed { state, .. } = event {
+                states.push(state).unwrap();
+            }
+        }
+        states
+    }
+
+    #[test_case]
+    fn opens_holds_and_closes() {
+        let mut gate = gate(Some(TRAVEL_MS));
+        gate.open(0).unwrap();
+        assert_eq!(gate.state(), GateState::Opening);
+        assert_eq!(gate.actuator().target, Some(GateTarget::Open));
+
+        gate.update(TRAVEL_MS - 1);
+        assert_eq!(gate.state(), GateState::Opening);
+        gate.update(TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Open);
+        assert_eq!(gate.next_deadline(), Some(TRAVEL_MS + 5_000));
+
+        // Another open restarts the hold time
+        gate.open(3_000).unwrap();
+        assert_eq!(gate.next_deadline(), Some(8_000));
+        assert_eq!(gate.actuator().drives, 1);
+
+        gate.update(7_999);
+        assert_eq!(gate.state(), GateState::Open);
+        gate.update(8_000);
+        assert_eq!(gate.state(), GateState::Closing);
+        assert_eq!(gate.actuator().target, Some(GateTarget::Closed));
+        assert_eq!(gate.next_deadline(), None);
+
+        gate.update(8_000 + TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Closed);
+        assert_eq!(
+            changes(&mut gate)[..],
+            [GateState::Opening, GateState::Open, GateState::Closing, GateState::Closed]
+        );
+    }
+
+    #[test_case]
+    fn stays_open_without_hold_time() {
+        let mut gate = gate(Some(TRAVEL_MS));
+        gate.set_timing(GateTiming { hold_ms: None, ..GateTiming::default() });
+        gate.open(0).unwrap();
+        gate.update(TRAVEL_MS);
+        assert_eq!(gate.next_deadline(), None);
+        gate.update(60_000);
+        assert_eq!(gate.state(), GateState::Open);
+
+        gate.close(60_000).unwrap();
+        gate.update(60_000 + TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Closed);
+    }
+
+    #[test_case]
+    fn reopens_while_closing() {
+        let mut gate = gate(Some(TRAVEL_MS));
+        gate.open(0).unwrap();
+        gate.update(TRAVEL_MS);
+        gate.close(2_000).unwrap();
+        gate.update(2_400);
+        assert_eq!(gate.state(), GateState::Closing);
+
+        gate.open(2_500).unwrap();
+        assert_eq!(gate.state(), GateState::Opening);
+        assert_eq!(gate.actuator().target, Some(GateTarget::Open));
+        assert_eq!(gate.actuator().drives, 3);
+
+        // The move timer starts again from the reversal
+        gate.update(2_500 + TRAVEL_MS - 1);
+        assert_eq!(gate.state(), GateState::Opening);
+        gate.update(2_500 + TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Open);
+        assert_eq!(gate.next_deadline(), Some(2_500 + TRAVEL_MS + 5_000));
+        assert_eq!(
+            changes(&mut gate)[..],
+            [GateState::Opening, GateState::Open, GateState::Closing, GateState::Opening, GateState::Open]
+        );
+    }
+
+    #[test_case]
+    fn movement_timeout_faults_until_reset() {
+        let mut gate = gate(None);
+        gate.open(0).unwrap();
+        gate.update(10_000);
+        assert_eq!(gate.state(), GateState::Opening);
+        gate.update(10_001);
+        assert_eq!(gate.state(), GateState::Fault);
+        assert!(gate.actuator().stopped);
+
+        let mut faults = 0;
+        while let Some(event) = gate.poll_event() {
+            if let GateEvent::Fault { fault, at_ms } = event {
+                assert_eq!((fault, at_ms), (GateFault::MovementTimeout, 10_001));
+                faults += 1;
+            }
+        }
+        assert_eq!(faults, 1);
+
+        assert_eq!(gate.open(11_000), Err(GateCommandError::Faulted));
+        assert_eq!(gate.close(11_000), Err(GateCommandError::Faulted));
+        assert_eq!(gate.next_deadline(), None);
+        gate.update(60_000);
+        assert_eq!(gate.state(), GateState::Fault);
+
+        // Fixed on site
+        gate.actuator().travel_ms = Some(TRAVEL_MS);
+        gate.reset(60_000).unwrap();
+        assert_eq!(gate.state(), GateState::Closing);
+        assert_eq!(gate.actuator().target, Some(GateTarget::Closed));
+        gate.update(60_000 + TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Closed);
+        assert_eq!(changes(&mut gate)[..], [GateState::Closing, GateState::Closed]);
+    }
+
+    #[test_case]
+    fn actuator_errors_fault() {
+        let mut gate = gate(Some(TRAVEL_MS));
+        gate.actuator().fail_drive = true;
+        assert_eq!(gate.open(0), Err(GateCommandError::Actuator));
+        assert_eq!(gate.state(), GateState::Fault);
+        assert_eq!(gate.poll_event(), Some(GateEvent::Fault { fault: GateFault::Actuator, at_ms: 0 }));
+        assert_eq!(gate.actuator().drives, 0);
+
+        gate.actuator().fail_drive = false;
+        gate.reset(100).unwrap();
+        gate.update(100 + TRAVEL_MS);
+        assert_eq!(gate.state(), GateState::Closed);
+        changes(&mut gate);
+
+        // Failing in the middle of a move stops the actuator
+        gate.open(5_000).unwrap();
+        gate.actuator().fail_update = true;
+        gate.update(5_100);
+        assert_eq!(gate.state(), GateState::Fault);
+        assert!(gate.actuator().stopped);
+        assert_eq!(
+            gate.poll_event(),
+            Some(GateEvent::Changed { previous: GateState::Closed, state: GateState::Opening, at_ms: 5_000 })
+        );
+        assert_eq!(gate.poll_event(), Some(GateEvent::Fault { fault: GateFault::Actuator, at_ms: 5_100 }));
+    }
+
+    #[test_case]
+    fn vehicle_under_faulted_gate_does_not_close_it() {
+        let timing = GateTiming {
+            close_after_clear_ms: Some(2_000),
+            ..GateTiming::default()
+        };
+        let mut gate = gate_with_presence(None, timing);
+        gate.open(1_000).unwrap();
+        run(&mut gate, 1_000, 11_100);
+        assert_eq!(gate.state(), GateState::Fault);
+
+        // A vehicle passes under the stuck arm
+        set_present(&mut gate, true);
+        run(&mut gate, 12_000, 13_000);
+        assert!(gate.is_obstructed());
+        set_present(&mut gate, false);
+        run(&mut gate, 14_000, 20_000);
+        assert!(!gate.is_obstructed());
+        assert_eq!(gate.state(), GateState::Fault);
+        assert_eq!(gate.actuator().drives, 1);
+        assert_eq!(gate.next_deadline(), Some(20_000 + PRESENCE_POLL_MS));
+
+        // A reset blocked by a vehicle closes once it has gone
+        gate.actuator().travel_ms = Some(TRAVEL_MS);
+        set_present(&mut gate, true);
+        run(&mut gate, 21_000, 21_500);
+        assert_eq!(gate.reset(21_500), Err(GateCommandError::Obstructed));
+        assert_eq!(gate.state(), GateState::Fault);
+        set_present(&mut gate, false);
+        run(&mut gate, 22_000, 24_400);
+        assert_eq!(gate.state(), GateState::Fault);
+        run(&mut gate, 24_450, 24_550);
+        assert_eq!(gate.state(), GateState::Closing);
+        run(&mut gate, 24_600, 26_000);
+        assert_eq!(gate.state(), GateState::Closed);
+        assert_eq!(gate.actuator().drives, 2);
+    }
+
+    #[test_case]
+    fn vehicle_under_closing_arm_reverses_at_once() {
+        let mut gate = gate_with_presence(Some(TRAVEL_MS), GateTiming::default());
+        gate.open(1_000).unwrap();
+        run(&mut gate, 1_000, 2_000);
+        gate.close(3_000).unwrap();
+        run(&mut gate, 3_000, 3_300);
+        assert_eq!(gate.state(), GateState::Closing);
+        changes(&mut gate);
+
+        set_present(&mut gate, true);
+        run(&mut gate, 3_350, 3_400);
+        assert_eq!(gate.state(), GateState::Opening);
+        assert_eq!(gate.actuator().target, Some(GateTarget::Open));
+        assert_eq!((gate.actuator().drives, gate.actuator().reverses), (3, 1));
+        assert_eq!(gate.poll_event(), Some(GateEvent::Obstructed { state: GateState::Closing, at_ms: 3_400 }));
+
+        // Stays open while the vehicle is there, whatever the hold time
+        run(&mut gate, 3_450, 10_000);
+        assert_eq!(gate.state(), GateState::Open);
+        assert_eq!(gate.actuator().drives, 3);
+    }
+}
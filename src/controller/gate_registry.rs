@@ -0,0 +1,120 @@
+use core::cell::RefCell;
+
+use embassy_sync::{blocking_mutex::{raw::CriticalSectionRawMutex, Mutex}, signal::Signal};
+
+use crate::protocol::{discovery::{GateInfo, MAX_GATE_NAME_LENGTH}, gate::GateState, lora::NodeAddress};
+
+pub const MAX_GATES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateRegistryError {
+    Full,
+    NameTooLong,
+}
+
+struct RegistryState {
+    operation_center_name: heapless::String<MAX_GATE_NAME_LENGTH>,
+    network_name: heapless::String<MAX_GATE_NAME_LENGTH>,
+    gates: heapless::Vec<GateInfo, MAX_GATES>,
+}
+
+/// Site description and gates served by this gateway, editable at runtime.
+/// Every change raises `changed` so the discovery document can be re-sent.
+pub struct GateRegistry {
+    state: Mutex<CriticalSectionRawMutex, RefCell<RegistryState>>,
+    changed: Signal<CriticalSectionRawMutex, ()>,
+}
+
+impl GateRegistry {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(RegistryState {
+                operation_center_name: heapless::String::new(),
+                network_name: heapless::String::new(),
+                gates: heapless::Vec::new(),
+            })),
+            changed: Signal::new(),
+        }
+    }
+
+    pub fn set_site(&self, operation_center_name: &str, network_name: &str) -> Result<(), GateRegistryError> {
+        let operation_center_name = heapless::String::try_from(operation_center_name).map_err(|_| GateRegistryError::NameTooLong)?;
+        let network_name = heapless::String::try_from(network_name).map_err(|_| GateRegistryError::NameTooLong)?;
+
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            state.operation_center_name = operation_center_name;
+            state.network_name = network_name;
+        });
+        self.changed.signal(());
+        Ok(())
+    }
+
+    /// Adds a gate, or replaces the one with the same `external_id` keeping
+    /// its last reported state.
+    pub fn upsert_gate(&self, mut gate: GateInfo) -> Result<(), GateRegistryError> {
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            if let Some(existing) = state.gates.iter_mut().find(|g| g.external_id == gate.external_id) {
+                gate.state = existing.state;
+                *existing = gate;
+                return Ok(());
+            }
+            state.gates.push(gate).map_err(|_| GateRegistryError::Full)
+        })?;
+        self.changed.signal(());
+        Ok(())
+    }
+
+    pub fn remove_gate(&self, external_id: &str) -> Option<GateInfo> {
+        let removed = self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            let index = state.gates.iter().position(|g| g.external_id.as_str() == external_id)?;
+            Some(state.gates.swap_remove(index))
+        });
+        if removed.is_some() {
+            self.changed.signal(());
+        }
+        removed
+    }
+
+    pub fn gate(&self, external_id: &str) -> Option<GateInfo> {
+        self.state.lock(|cell| {
+            cell.borrow().gates.iter().find(|g| g.external_id.as_str() == external_id).cloned()
+        })
+    }
+
+    pub fn gate_for_node(&self, node: NodeAddress) -> Option<GateInfo> {
+        self.state.lock(|cell| {
+            cell.borrow().gates.iter().find(|g| g.node == Some(node)).cloned()
+        })
+    }
+
+    /// Records the state reported by the gate's node and returns the previous
+    /// one. Not a discovery change, so `changed` is not raised.
+    pub fn set_gate_state(&self, external_id: &str, gate_state: GateState) -> Option<GateState> {
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            let gate = state.gates.iter_mut().find(|g| g.external_id.as_str() == external_id)?;
+            Some(core::mem::replace(&mut gate.state, gate_state))
+        })
+    }
+
+    /// Runs `f` with the site names and the gate list while the registry is locked.
+    pub fn with<R>(&self, f: impl FnOnce(&str, &str, &[GateInfo]) -> R) -> R {
+        self.state.lock(|cell| {
+            let state = cell.borrow();
+            f(&state.operation_center_name, &state.network_name, &state.gates)
+        })
+    }
+
+    pub async fn wait_changed(&self) {
+        self.changed.wait().await
+    }
+}
+
+impl Default for GateRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,212 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_time::{Duration, Ticker};
+use log::{error, info};
+
+use crate::{
+    controller::{
+        discovery::FIRMWARE_VERSION,
+        gate_registry::{GateRegistry, MAX_GATES},
+        mqtt::{ConnectionState, ConnectionStateWatch, MqttChannel, MqttMessage, MAX_MESSAGE_PAYLOAD, STATUS_SUBTOPIC},
+        node_registry::{NodeHealth, NodeRegistry},
+    },
+    protocol::{
+        discovery::GateInfo,
+        home_assistant::{self as ha, HaButtonConfig, HaDevice, HaId, HaNodeState, HaSensorConfig, HaTopic, NodeSensor},
+        lora::NodeAddress,
+    },
+};
+
+/// Gate buttons publish this on `{main_topic}/ha/{gate}/open`.
+pub const HA_OPEN_PAYLOAD: &str = "OPEN";
+const NODE_STATE_INTERVAL: Duration = Duration::from_secs(30);
+const MANUFACTURER: &str = "Haviliar";
+
+/// Topics the entities use. `main_topic` and `gateway_id` must be the same
+/// ones the MQTT controller was configured with.
+pub struct HomeAssistant<'a> {
+    pub main_topic: &'a str,
+    pub gateway_id: &'a str,
+    pub gates: &'a GateRegistry,
+    pub nodes: &'a NodeRegistry,
+}
+
+impl HomeAssistant<'_> {
+    /// Filter to subscribe for gate button presses; the gate id is level
+    /// `command_gate_level()` of the received topic.
+    pub fn command_filter(&self) -> HaTopic {
+        let mut filter = HaTopic::new();
+        let _ = write!(filter, "{}/ha/+/open", self.main_topic);
+        filter
+    }
+
+    pub fn command_gate_level(&self) -> usize {
+        self.main_topic.split('/').count() + 1
+    }
+
+    /// Publishes every config on connect, configs for newly heard nodes, and
+    /// the node health states periodically.
+    pub async fn run(&self, state: &ConnectionStateWatch, outbound: &MqttChannel) -> ! {
+        let mut state_receiver = state.receiver().expect("no free ConnectionStateWatch receiver");
+        let mut ticker = Ticker::every(NODE_STATE_INTERVAL);
+        let mut connected = false;
+
+        loop {
+            match select3(state_receiver.changed(), self.nodes.wait_new_node(), ticker.next()).await {
+                Either3::First(new_state) => {
+                    let was_connected = connected;
+                    connected = new_state == ConnectionState::Connected;
+                    if connected && !was_connected {
+                        self.publish_all(outbound).await;
+                    }
+                }
+                Either3::Second(node) => {
+                    if connected {
+                        if let Some(health) = self.nodes.get(node) {
+                            self.publish_node(&health, outbound).await;
+                        }
+                    }
+                }
+                Either3::Third(()) => {
+                    if connected {
+                        for health in self.nodes.snapshot().iter() {
+                            self.publish_node_state(health, outbound).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn publish_all(&self, outbound: &MqttChannel) {
+        info!("Publishing Home Assistant discovery");
+
+        let gates: heapless::Vec<GateInfo, MAX_GATES> = self.gates.with(|_, _, gates| gates.iter().cloned().collect());
+        for gate in gates.iter() {
+            self.publish_gate(gate, outbound).await;
+        }
+
+        for health in self.nodes.snapshot().iter() {
+            self.publish_node(health, outbound).await;
+        }
+    }
+
+    async fn publish_gate(&self, gate: &GateInfo, outbound: &MqttChannel) {
+        let mut object_id = HaId::new();
+        let mut unique_id = HaId::new();
+        let mut command_topic = HaTopic::new();
+        let availability_topic = self.availability_topic();
+        let _ = write!(object_id, "gate_{}", gate.external_id);
+        let _ = write!(unique_id, "{}_gate_{}", self.gateway_id, gate.external_id);
+        let _ = write!(command_topic, "{}/ha/{}/open", self.main_topic, gate.external_id);
+
+        let config = HaButtonConfig {
+            name: &gate.name,
+            unique_id: &unique_id,
+            command_topic: &command_topic,
+            payload_press: HA_OPEN_PAYLOAD,
+            availability_topic: &availability_topic,
+            icon: "mdi:boom-gate-up",
+            device: self.gateway_device(),
+        };
+
+        let Some(topic) = ha::config_topic("button", self.gateway_id, &object_id) else {
+            error!("Home Assistant topic too long for gate {}", gate.external_id);
+            return;
+        };
+        publish_json(&topic, &config, outbound).await;
+    }
+
+    async fn publish_node(&self, health: &NodeHealth, outbound: &MqttChannel) {
+        let node_name = node_device_id(self.gateway_id, health.node);
+        let state_topic = self.node_state_topic(health.node);
+        let availability_topic = self.availability_topic();
+
+        for sensor in NodeSensor::ALL {
+            let mut unique_id = HaId::new();
+            let _ = write!(unique_id, "{}_{}", node_name, sensor.object_id());
+
+            let config = HaSensorConfig {
+                name: sensor.name(),
+                unique_id: &unique_id,
+                state_topic: &state_topic,
+                value_template: sensor.value_template(),
+                unit_of_measurement: sensor.unit(),
+                device_class: sensor.device_class(),
+                state_class: "measurement",
+                entity_category: "diagnostic",
+                availability_topic: &availability_topic,
+                device: HaDevice {
+                    identifiers: [&node_name],
+                    name: &node_name,
+                    manufacturer: MANUFACTURER,
+                    model: "LoRa gate node",
+                    sw_version: None,
+                    via_device: Some(self.gateway_id),
+                },
+            };
+
+            let Some(topic) = ha::config_topic("sensor", &node_name, sensor.object_id()) else {
+                error!("Home Assistant topic too long for node {}", health.node);
+                return;
+            };
+            publish_json(&topic, &config, outbound).await;
+        }
+
+        self.publish_node_state(health, outbound).await;
+    }
+
+    async fn publish_node_state(&self, health: &NodeHealth, outbound: &MqttChannel) {
+        let state = HaNodeState {
+            rssi: health.rssi,
+            snr: health.snr,
+            battery: health.battery_percent,
+            last_seen: health.seconds_since_seen(),
+        };
+        publish_json(&self.node_state_topic(health.node), &state, outbound).await;
+    }
+
+    fn gateway_device(&self) -> HaDevice<'_> {
+        HaDevice {
+            identifiers: [self.gateway_id],
+            name: self.gateway_id,
+            manufacturer: MANUFACTURER,
+            model: "LoRa gateway",
+            sw_version: Some(FIRMWARE_VERSION),
+            via_device: None,
+        }
+    }
+
+    /// Retained `online`/`offline` maintained by the MQTT controller.
+    fn availability_topic(&self) -> HaTopic {
+        let mut topic = HaTopic::new();
+        let _ = write!(topic, "{}/{}", self.main_topic, STATUS_SUBTOPIC);
+        topic
+    }
+
+    fn node_state_topic(&self, node: NodeAddress) -> HaTopic {
+        let mut topic = HaTopic::new();
+        let _ = write!(topic, "{}/node/{}/state", self.main_topic, node);
+        topic
+    }
+}
+
+fn node_device_id(gateway_id: &str, node: NodeAddress) -> HaId {
+    let mut id = HaId::new();
+    let _ = write!(id, "{}_node_{}", gateway_id, node);
+    id
+}
+
+async fn publish_json<T: serde::Serialize>(topic: &str, value: &T, outbound: &MqttChannel) {
+    let mut buffer = [0u8; MAX_MESSAGE_PAYLOAD];
+    let Some(len) = ha::encode(value, &mut buffer) else {
+        error!("Home Assistant payload for '{}' does not fit", topic);
+        return;
+    };
+
+    match MqttMessage::absolute(topic, &buffer[..len], true) {
+        Some(message) => outbound.send(message).await,
+        None => error!("Home Assistant message for '{}' does not fit", topic),
+    }
+}
@@ -0,0 +1,302 @@
+use core::{
+    fmt::Write as _,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_net::tcp::TcpSocket;
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    controller::{
+        gate_registry::{GateRegistry, MAX_GATES},
+        mqtt::{ConnectionState, ConnectionStateWatch, MqttChannel, MqttMessage, MAX_TOPIC_LENGTH},
+        node_registry::NodeRegistry,
+    },
+    hal::wifi::{ActiveNetwork, ActiveNetworkWatch},
+    protocol::{
+        discovery::{GateDirection, GateInfo},
+        gate::{self, GateState, GateTopicKind},
+        http::{self, ParseError, Route, RouteError, StatusCode, CONTENT_TYPE_JSON, CONTENT_TYPE_TEXT},
+        lora::NodeAddress,
+    },
+};
+
+pub const HTTP_PORT: u16 = 80;
+const REQUEST_BUFFER_SIZE: usize = 1024;
+const BODY_BUFFER_SIZE: usize = 1536;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Numbers the `http-N` request ids across every server task.
+static REQUEST_COUNT: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GateView<'a> {
+    external_id: &'a str,
+    name: &'a str,
+    direction: GateDirection,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<NodeAddress>,
+    state: GateState,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusView<'a> {
+    network_name: &'a str,
+    uptime_secs: u64,
+    mqtt_connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wifi_ssid: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wifi_rssi: Option<i8>,
+    gates: usize,
+    nodes: usize,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OpenAccepted<'a> {
+    request_id: &'a str,
+    event_topic: &'a str,
+}
+
+/// Local REST API. A gate opened here is injected into `inbound` as a
+/// message on the gate's `cmd` topic, so it takes exactly the same path as
+/// a command from the broker; its outcome goes to the gate's `event` topic.
+pub struct HttpApi<'a> {
+    pub token: &'a str,
+    pub main_topic: &'a str,
+    pub network_name: &'a str,
+    pub gates: &'a GateRegistry,
+    pub nodes: &'a NodeRegistry,
+    pub mqtt_state: &'a ConnectionStateWatch,
+    pub wifi: &'a ActiveNetworkWatch,
+    pub inbound: &'a MqttChannel,
+}
+
+impl HttpApi<'_> {
+    /// Accepts and answers one request per connection, forever.
+    pub async fn serve(&self, socket: &mut TcpSocket<'_>) -> ! {
+        let mut request = [0u8; REQUEST_BUFFER_SIZE];
+        let mut body = heapless::String::<BODY_BUFFER_SIZE>::new();
+
+        loop {
+            socket.set_timeout(None);
+            if let Err(e) = socket.accept(HTTP_PORT).await {
+                error!("HTTP accept error: {:?}", e);
+                continue;
+            }
+            socket.set_timeout(Some(REQUEST_TIMEOUT));
+
+            body.clear();
+            let request_count = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+            let (status, content_type) = match read_request(socket, &mut request).await {
+                Ok(len) => self.handle(&request[..len], request_count, &mut body),
+                Err(status) => error_body(status, "malformed request", &mut body),
+            };
+
+            let mut head = heapless::String::<256>::new();
+            if http::write_response_head(&mut head, status, content_type, body.len()).is_ok() {
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(body.as_bytes()).await;
+            }
+
+            socket.close();
+            let _ = socket.flush().await;
+            socket.abort();
+        }
+    }
+
+    fn handle(&self, raw: &[u8], request_count: u32, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+        let request = match http::parse_request(raw) {
+            Ok(request) => request,
+            Err(_) => return error_body(StatusCode::BAD_REQUEST, "malformed request", body),
+        };
+
+        // Before routing, so an unauthenticated client cannot probe the routes
+        if !http::is_authorized(request.authorization, self.token) {
+            warn!("HTTP {:?} {} rejected: bad token", request.method, request.path);
+            return error_body(StatusCode::UNAUTHORIZED, "unauthorized", body);
+        }
+        info!("HTTP {:?} {}", request.method, request.path);
+
+        let route = match http::route(request.method, request.path) {
+            Ok(route) => route,
+            Err(RouteError::NotFound) => return error_body(StatusCode::NOT_FOUND, "not found", body),
+            Err(RouteError::MethodNotAllowed) => return error_body(StatusCode::METHOD_NOT_ALLOWED, "method not allowed", body),
+        };
+
+        match route {
+            Route::ListGates => self.list_gates(body),
+            Route::OpenGate(id) => self.open_gate(id, request_count, body),
+            Route::Status => self.status(body),
+            Route::Metrics => self.metrics(body),
+        }
+    }
+
+    fn list_gates(&self, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+        let gates: heapless::Vec<GateInfo, MAX_GATES> = self.gates.with(|_, _, gates| gates.iter().cloned().collect());
+        let views: heapless::Vec<GateView, MAX_GATES> = gates
+            .iter()
+            .map(|gate| GateView {
+                external_id: &gate.external_id,
+                name: &gate.name,
+                direction: gate.direction,
+                node: gate.node,
+                state: gate.state,
+            })
+            .collect();
+        json_body(StatusCode::OK, views.as_slice(), body)
+    }
+
+    fn open_gate(&self, id: &str, request_count: u32, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+        let Some(gate) = self.gates.gate(id) else {
+            return error_body(StatusCode::NOT_FOUND, "unknown gate", body);
+        };
+        if gate.node.is_none() {
+            return error_body(StatusCode::CONFLICT, "gate has no LoRa node", body);
+        }
+
+        let mut request_id = heapless::String::<24>::new();
+        let _ = write!(request_id, "http-{}", request_count);
+        let mut payload = heapless::String::<48>::new();
+        let _ = write!(payload, "{{\"requestId\":\"{}\"}}", request_id);
+
+        let (Some(cmd), Some(event)) = (
+            gate::gate_subtopic(self.network_name, id, GateTopicKind::Cmd),
+            gate::gate_subtopic(self.network_name, id, GateTopicKind::Event),
+        ) else {
+            return error_body(StatusCode::BAD_REQUEST, "gate id too long", body);
+        };
+        let mut cmd_topic = heapless::String::<MAX_TOPIC_LENGTH>::new();
+        let mut event_topic = heapless::String::<MAX_TOPIC_LENGTH>::new();
+        if write!(cmd_topic, "{}/{}", self.main_topic, cmd).is_err() || write!(event_topic, "{}/{}", self.main_topic, event).is_err() {
+            return error_body(StatusCode::BAD_REQUEST, "gate id too long", body);
+        }
+
+        let Some(message) = MqttMessage::new(&cmd_topic, payload.as_bytes()) else {
+            return error_body(StatusCode::BAD_REQUEST, "gate id too long", body);
+        };
+        if self.inbound.try_send(message).is_err() {
+            return error_body(StatusCode::SERVICE_UNAVAILABLE, "command queue full", body);
+        }
+
+        json_body(
+            StatusCode::ACCEPTED,
+            &OpenAccepted {
+                request_id: &request_id,
+                event_topic: &event_topic,
+            },
+            body,
+        )
+    }
+
+    fn status(&self, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+        let wifi = self.wifi_network();
+        let status = StatusView {
+            network_name: self.network_name,
+            uptime_secs: Instant::now().as_secs(),
+            mqtt_connected: self.mqtt_connected(),
+            wifi_ssid: wifi.as_ref().map(|network| network.ssid.as_str()),
+            wifi_rssi: wifi.as_ref().map(|network| network.rssi),
+            gates: self.gates.with(|_, _, gates| gates.len()),
+            nodes: self.nodes.snapshot().len(),
+        };
+        json_body(StatusCode::OK, &status, body)
+    }
+
+    /// Prometheus text exposition format.
+    fn metrics(&self, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+        if self.write_metrics(body).is_err() {
+            return error_body(StatusCode::SERVICE_UNAVAILABLE, "metrics too large", body);
+        }
+        (StatusCode::OK, CONTENT_TYPE_TEXT)
+    }
+
+    fn write_metrics(&self, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> core::fmt::Result {
+        let nodes = self.nodes.snapshot();
+
+        writeln!(body, "# TYPE gateway_uptime_seconds counter")?;
+        writeln!(body, "gateway_uptime_seconds {}", Instant::now().as_secs())?;
+        writeln!(body, "# TYPE gateway_mqtt_connected gauge")?;
+        writeln!(body, "gateway_mqtt_connected {}", self.mqtt_connected() as u8)?;
+        if let Some(network) = self.wifi_network() {
+            writeln!(body, "# TYPE gateway_wifi_rssi_dbm gauge")?;
+            writeln!(body, "gateway_wifi_rssi_dbm{{ssid=\"{}\"}} {}", network.ssid, network.rssi)?;
+        }
+        writeln!(body, "# TYPE gateway_nodes gauge")?;
+        writeln!(body, "gateway_nodes {}", nodes.len())?;
+        writeln!(body, "# TYPE gateway_node_rssi_dbm gauge")?;
+        for health in nodes.iter() {
+            writeln!(body, "gateway_node_rssi_dbm{{node=\"{}\"}} {}", health.node, health.rssi)?;
+        }
+        writeln!(body, "# TYPE gateway_node_snr_db gauge")?;
+        for health in nodes.iter() {
+            writeln!(body, "gateway_node_snr_db{{node=\"{}\"}} {}", health.node, health.snr)?;
+        }
+        writeln!(body, "# TYPE gateway_node_last_seen_seconds gauge")?;
+        for health in nodes.iter() {
+            writeln!(body, "gateway_node_last_seen_seconds{{node=\"{}\"}} {}", health.node, health.seconds_since_seen())?;
+        }
+        writeln!(body, "# TYPE gateway_node_battery_percent gauge")?;
+        for health in nodes.iter().filter_map(|h| h.battery_percent.map(|battery| (h.node, battery))) {
+            writeln!(body, "gateway_node_battery_percent{{node=\"{}\"}} {}", health.0, health.1)?;
+        }
+        Ok(())
+    }
+
+    fn mqtt_connected(&self) -> bool {
+        self.mqtt_state.try_get() == Some(ConnectionState::Connected)
+    }
+
+    fn wifi_network(&self) -> Option<ActiveNetwork> {
+        self.wifi.try_get().flatten()
+    }
+}
+
+/// Reads until the headers and the announced body have arrived; the body
+/// itself is not used by any route.
+async fn read_request(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<usize, StatusCode> {
+    let mut filled = 0;
+    loop {
+        match http::parse_request(&buffer[..filled]) {
+            Ok(request) if filled >= request.header_length + request.content_length => return Ok(filled),
+            Ok(request) if request.header_length + request.content_length > buffer.len() => {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            Ok(_) | Err(ParseError::Incomplete) => {}
+            Err(ParseError::Malformed) => return Err(StatusCode::BAD_REQUEST),
+        }
+        if filled == buffer.len() {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        match socket.read(&mut buffer[filled..]).await {
+            Ok(0) | Err(_) => return Err(StatusCode::BAD_REQUEST),
+            Ok(n) => filled += n,
+        }
+    }
+}
+
+fn json_body<T: Serialize + ?Sized>(status: StatusCode, value: &T, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+    let mut json = [0u8; BODY_BUFFER_SIZE];
+    let written = serde_json_core::to_slice(value, &mut json)
+        .ok()
+        .and_then(|len| core::str::from_utf8(&json[..len]).ok())
+        .map(|text| body.push_str(text));
+
+    match written {
+        Some(Ok(())) => (status, CONTENT_TYPE_JSON),
+        _ => error_body(StatusCode::SERVICE_UNAVAILABLE, "response too large", body),
+    }
+}
+
+fn error_body(status: StatusCode, message: &str, body: &mut heapless::String<BODY_BUFFER_SIZE>) -> (StatusCode, &'static str) {
+    body.clear();
+    let _ = write!(body, "{{\"error\":\"{}\"}}", message);
+    (status, CONTENT_TYPE_JSON)
+}
@@ -1,17 +1,18 @@
 use log::{error, info};
 use lora_phy::mod_params::{PacketStatus, RadioError};
 
-use crate::{hal::lora::{Lora, PAYLOAD_LENGTH}, protocol::{lora::{LoraEnvelope, LoraParser}, message_type}};
+use crate::{controller::transport::{LinkHealth, Received, Transport, TransportError, TransportKind}, hal::lora::{Lora, PAYLOAD_LENGTH}, protocol::{lora::{LoraEnvelope, LoraParser}, message_type, routing::LinkQuality}};
 
 
 
 pub struct LoraController {
     lora: Lora<'static>,
+    health: LinkHealth,
 }
 
 impl LoraController {
     pub fn new(lora: Lora<'static>) -> Self {
-        Self { lora }
+        Self { lora, health: LinkHealth::new(true) }
     }
 
     pub async fn send_message(&mut self, 
@@ -104,4 +105,32 @@ impl LoraController {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+impl Transport for LoraController {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Lora
+    }
+
+    fn health(&self) -> LinkHealth {
+        self.health
+    }
+
+    async fn send(&mut self, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        let result = self.send_message_envelope(envelope).await;
+        self.health.record_send(&result);
+        result.map_err(TransportError::Lora)
+    }
+
+    async fn receive(&mut self) -> Result<Received, TransportError> {
+        let mut recv_buffer = [0u8; PAYLOAD_LENGTH];
+        // Frames that fail to decode come back as `RadioError::Irq` too
+        let (envelope, status) = self.receive_message(&mut recv_buffer).await.map_err(TransportError::Lora)?;
+        self.health.record_receive(Some(status.rssi), Some(status.snr));
+        Ok(Received {
+            envelope,
+            link: LinkQuality { rssi: status.rssi, snr: status.snr },
+            transport: TransportKind::Lora,
+        })
+    }
+}
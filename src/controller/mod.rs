@@ -1,2 +1,17 @@
 pub mod mqtt;
-pub mod lora;
\ No newline at end of file
+pub mod lora;
+pub mod backoff;
+pub mod tls;
+pub mod gate_registry;
+pub mod discovery;
+pub mod node_registry;
+pub mod home_assistant;
+pub mod command_tracker;
+pub mod resolver;
+pub mod broker;
+pub mod http_api;
+pub mod provisioning;
+pub mod espnow;
+pub mod transport;
+pub mod mqtt_link;
+pub mod gate;
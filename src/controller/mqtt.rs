@@ -1,140 +1,706 @@
-use core::net::Ipv4Addr;
+use core::future::pending;
 
-use alloc::format;
-use embassy_net::tcp::{TcpSocket, client};
-use embassy_time::{Duration, WithTimeout};
-use log::{error, info};
-use rust_mqtt::{client::{client::MqttClient, client_config::ClientConfig}, packet::v5::reason_codes::ReasonCode, utils::rng_generator::CountingRng};
-use static_cell::StaticCell;
+use alloc::{format, string::String};
+use embassy_net::tcp::{self, TcpSocket};
+use embassy_futures::select::{select4, Either4};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex, watch::Watch};
+use embassy_time::{Duration, Ticker, Timer, WithTimeout};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use embedded_tls::TlsError;
+use log::{error, info, warn};
+use rust_mqtt::{
+    client::{client_config::ClientConfig, raw_client::{Event, RawMqttClient}},
+    packet::v5::{publish_packet::QualityOfService, reason_codes::ReasonCode},
+    utils::rng_generator::CountingRng,
+};
 
-static RECV_BUFFER_CELL: StaticCell<[u8; 256]> = StaticCell::new();
-static WRITE_BUFFER_CELL: StaticCell<[u8; 256]> = StaticCell::new();
+use crate::{controller::{backoff::Backoff, resolver::BrokerResolver, tls::TlsLink}, protocol::topic::{TopicError, TopicRouter, MAX_FILTER_LENGTH}};
+
+const MAX_SUBSCRIPTIONS: usize = 8;
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest wait for the broker to answer a CONNECT, PUBLISH or (UN)SUBSCRIBE.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const MAX_TOPIC_LENGTH: usize = 128;
+pub const MAX_MESSAGE_PAYLOAD: usize = 768;
+pub const MQTT_CHANNEL_DEPTH: usize = 4;
+pub const MQTT_REQUEST_DEPTH: usize = 2;
+
+pub const STATUS_SUBTOPIC: &str = "status";
+const STATUS_ONLINE: &[u8] = b"online";
+const STATUS_OFFLINE: &[u8] = b"offline";
+
+/// Session settings. The Last Will (`offline`) and the birth message
+/// (`online`) are both retained on `{main_topic}/status`.
+pub struct MqttConfig {
+    pub main_topic: &'static str,
+    pub client_id: &'static str,
+    pub username: Option<&'static str>,
+    pub password: Option<&'static str>,
+    /// `0` disables keep-alive, as in MQTT: no PINGREQ is ever sent.
+    pub keep_alive_secs: u16,
+}
+
+impl MqttConfig {
+    pub const fn new(main_topic: &'static str, client_id: &'static str) -> Self {
+        Self {
+            main_topic,
+            client_id,
+            username: None,
+            password: None,
+            keep_alive_secs: 60,
+        }
+    }
+}
+
+/// Packet buffers for the MQTT client; their lengths bound the largest packet
+/// that can be sent or received.
+pub struct MqttBuffers<'a> {
+    pub recv: &'a mut [u8],
+    pub write: &'a mut [u8],
+}
+
+pub type MqttSocket<'a> = Mutex<CriticalSectionRawMutex, TcpSocket<'a>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Connecting,
+    Connected,
+}
+
+/// Observable connection state; tasks call `receiver()` and wait on `changed()`.
+pub type ConnectionStateWatch = Watch<CriticalSectionRawMutex, ConnectionState, 4>;
+
+/// Inbound messages carry the full topic they arrived on; outbound ones carry
+/// the subtopic under `main_topic`, unless `absolute` is set.
+#[derive(Debug, Clone)]
+pub struct MqttMessage {
+    pub topic: heapless::String<MAX_TOPIC_LENGTH>,
+    pub payload: heapless::Vec<u8, MAX_MESSAGE_PAYLOAD>,
+    pub retain: bool,
+    pub absolute: bool,
+}
+
+impl MqttMessage {
+    pub fn new(topic: &str, payload: &[u8]) -> Option<Self> {
+        Some(Self {
+            topic: heapless::String::try_from(topic).ok()?,
+            payload: heapless::Vec::from_slice(payload).ok()?,
+            retain: false,
+            absolute: false,
+        })
+    }
+
+    pub fn retained(topic: &str, payload: &[u8]) -> Option<Self> {
+        let mut message = Self::new(topic, payload)?;
+        message.retain = true;
+        Some(message)
+    }
+
+    /// Outbound message published on `topic` as is, outside `main_topic`
+    /// (e.g. the `homeassistant/...` discovery tree).
+    pub fn absolute(topic: &str, payload: &[u8], retain: bool) -> Option<Self> {
+        let mut message = Self::new(topic, payload)?;
+        message.retain = retain;
+        message.absolute = true;
+        Some(message)
+    }
+}
+
+pub type MqttChannel = Channel<CriticalSectionRawMutex, MqttMessage, MQTT_CHANNEL_DEPTH>;
+
+/// Where a message matching a subscription filter is delivered. Handlers run
+/// inline in the I/O loop, so they must not block. A channel that is full
+/// drops the message rather than stall the loop.
+#[derive(Clone, Copy)]
+pub enum MqttRoute {
+    Channel(&'static MqttChannel),
+    /// A `Channel` for commands: the broker already has its PUBACK, so a
+    /// command that does not fit is answered with the `absolute` message the
+    /// function builds from it (e.g. a failed result) instead of vanishing.
+    Command(&'static MqttChannel, fn(&MqttMessage) -> Option<MqttMessage>),
+    Handler(fn(&MqttMessage)),
+}
+
+pub enum Delivery {
+    Delivered,
+    Dropped,
+    /// Dropped; publish this instead.
+    Rejected(MqttMessage),
+}
+
+impl MqttRoute {
+    /// Hands `message` over without waiting.
+    pub fn deliver(&self, message: &MqttMessage) -> Delivery {
+        match *self {
+            MqttRoute::Channel(channel) => match channel.try_send(message.clone()) {
+                Ok(()) => Delivery::Delivered,
+                Err(_) => Delivery::Dropped,
+            },
+            MqttRoute::Command(channel, reject) => match channel.try_send(message.clone()) {
+                Ok(()) => Delivery::Delivered,
+                Err(_) => reject(message).map_or(Delivery::Dropped, Delivery::Rejected),
+            },
+            MqttRoute::Handler(handler) => {
+                handler(message);
+                Delivery::Delivered
+            }
+        }
+    }
+}
+
+/// Subscription changes requested by other tasks while `run` owns the controller.
+pub enum MqttRequest {
+    Subscribe(heapless::String<MAX_FILTER_LENGTH>, MqttRoute),
+    Unsubscribe(heapless::String<MAX_FILTER_LENGTH>),
+}
+
+impl MqttRequest {
+    pub fn subscribe(filter: &str, route: MqttRoute) -> Option<Self> {
+        Some(MqttRequest::Subscribe(heapless::String::try_from(filter).ok()?, route))
+    }
+
+    pub fn unsubscribe(filter: &str) -> Option<Self> {
+        Some(MqttRequest::Unsubscribe(heapless::String::try_from(filter).ok()?))
+    }
+}
+
+pub type MqttRequestChannel = Channel<CriticalSectionRawMutex, MqttRequest, MQTT_REQUEST_DEPTH>;
+
+/// Network driver handed to the MQTT client. The socket stays reachable
+/// through the mutex so the controller can re-open it after a failure.
+pub struct MqttTransport<'a> {
+    socket: &'a MqttSocket<'a>,
+}
+
+impl<'a> MqttTransport<'a> {
+    pub fn new(socket: &'a MqttSocket<'a>) -> Self {
+        Self { socket }
+    }
+}
+
+impl ErrorType for MqttTransport<'_> {
+    type Error = tcp::Error;
+}
+
+impl Read for MqttTransport<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.socket.lock().await.read(buf).await
+    }
+}
+
+impl Write for MqttTransport<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.socket.lock().await.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.lock().await.flush().await
+    }
+}
+
+#[derive(Debug)]
+pub enum MqttStreamError {
+    Tcp(tcp::Error),
+    Tls(TlsError),
+}
+
+impl embedded_io_async::Error for MqttStreamError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            MqttStreamError::Tcp(e) => e.kind(),
+            MqttStreamError::Tls(_) => ErrorKind::Other,
+        }
+    }
+}
+
+/// What the MQTT client actually reads and writes: the plain socket, or the
+/// TLS session layered on it.
+pub enum MqttStream<'a> {
+    Plain(MqttTransport<'a>),
+    Tls(&'a TlsLink<'a>),
+}
+
+impl ErrorType for MqttStream<'_> {
+    type Error = MqttStreamError;
+}
+
+impl Read for MqttStream<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        match self {
+            MqttStream::Plain(transport) => transport.read(buf).await.map_err(MqttStreamError::Tcp),
+            MqttStream::Tls(link) => link.read(buf).await.map_err(MqttStreamError::Tls),
+        }
+    }
+}
+
+impl Write for MqttStream<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        match self {
+            MqttStream::Plain(transport) => transport.write(buf).await.map_err(MqttStreamError::Tcp),
+            MqttStream::Tls(link) => link.session().lock().await.write(buf).await.map_err(MqttStreamError::Tls),
+        }
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        match self {
+            MqttStream::Plain(transport) => transport.flush().await.map_err(MqttStreamError::Tcp),
+            MqttStream::Tls(link) => link.session().lock().await.flush().await.map_err(MqttStreamError::Tls),
+        }
+    }
+}
+
+/// Broker answers the controller waits for, by packet identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ack {
+    Connect,
+    Publish(u16),
+    Subscribe(u16),
+    Unsubscribe(u16),
+}
 
 pub struct MqttController<'a>{
-    //socket: &'a mut TcpSocket<'a>,
-    //address: Ipv4Addr,
-    client: MqttClient<'a, &'a mut TcpSocket<'a>, 5, CountingRng>,
+    socket: &'a MqttSocket<'a>,
+    broker: BrokerResolver<'a>,
+    tls: Option<&'a TlsLink<'a>>,
+    // The raw client: `MqttClient` takes whatever packet follows a request
+    // as its answer and fails on an inbound publish arriving first
+    client: RawMqttClient<'a, MqttStream<'a>, 5, CountingRng>,
     main_topic: &'static str,
+    status_topic: &'static str,
+    keep_alive: Option<Duration>,
+    router: TopicRouter<MqttRoute, MAX_SUBSCRIPTIONS>,
+    state: &'a ConnectionStateWatch,
+    backoff: Backoff,
     is_connected: bool,
+    dropped_inbound: u32,
+    /// Answer to a rejected command, published once the current exchange is over.
+    rejection: Option<MqttMessage>,
+    ping_outstanding: bool,
 }
 
-// impl MqttController {
-//     pub fn new(socket: TcpSocket<'static>, address: Ipv4Addr, main_topic: &'static str, cliend_id: &'static str) -> Self {
-//         let mut write_buffer = [0u8; 256];
 impl<'a> MqttController<'a> {
-    pub async fn new(socket: &'a mut TcpSocket<'a>, main_topic: &'static str, cliend_id: &'static str) -> Result<Self, ReasonCode> {
-        
-        let recv_buffer = RECV_BUFFER_CELL.init([0u8; 256]);
-        let write_buffer = WRITE_BUFFER_CELL.init([0u8; 256]);
+    /// Builds the controller without connecting; the first session is opened
+    /// by `run`, after the initial subscriptions have been registered.
+    pub fn new(
+        socket: &'a MqttSocket<'a>,
+        broker: BrokerResolver<'a>,
+        settings: MqttConfig,
+        buffers: MqttBuffers<'a>,
+        state: &'a ConnectionStateWatch,
+    ) -> Self {
+        Self::build(socket, None, broker, settings, buffers, state)
+    }
+
+    /// Same as `new`, but every session runs over `tls`, which must wrap the
+    /// same `socket`. The handshake is repeated on each reconnect.
+    pub fn new_tls(
+        socket: &'a MqttSocket<'a>,
+        tls: &'a TlsLink<'a>,
+        broker: BrokerResolver<'a>,
+        settings: MqttConfig,
+        buffers: MqttBuffers<'a>,
+        state: &'a ConnectionStateWatch,
+    ) -> Self {
+        Self::build(socket, Some(tls), broker, settings, buffers, state)
+    }
+
+    fn build(
+        socket: &'a MqttSocket<'a>,
+        tls: Option<&'a TlsLink<'a>>,
+        broker: BrokerResolver<'a>,
+        settings: MqttConfig,
+        buffers: MqttBuffers<'a>,
+        state: &'a ConnectionStateWatch,
+    ) -> Self {
+        // Lives for the whole firmware run, like the controller itself
+        let status_topic: &'static str = String::leak(format!("{}/{}", settings.main_topic, STATUS_SUBTOPIC));
 
         let mut config: ClientConfig<'_, 5, CountingRng> = ClientConfig::new(
             rust_mqtt::client::client_config::MqttVersion::MQTTv5,
             CountingRng(20000),
         );
-        config.add_max_subscribe_qos(rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1);
-        config.add_client_id(cliend_id);
-        config.max_packet_size = 255;
-        //config.keep_alive = 10;
+        config.add_max_subscribe_qos(QualityOfService::QoS1);
+        config.add_client_id(settings.client_id);
+        config.max_packet_size = buffers.recv.len().min(buffers.write.len()) as u32;
+        config.keep_alive = settings.keep_alive_secs;
+        config.add_will(status_topic, STATUS_OFFLINE, true);
+        if let Some(username) = settings.username {
+            config.add_username(username);
+        }
+        if let Some(password) = settings.password {
+            config.add_password(password);
+        }
 
-        let mut client = MqttClient::<_, 5, _>::new(socket, write_buffer, 255, recv_buffer, 255, config);
+        let recv_len = buffers.recv.len();
+        let write_len = buffers.write.len();
+        let stream = match tls {
+            Some(link) => MqttStream::Tls(link),
+            None => MqttStream::Plain(MqttTransport::new(socket)),
+        };
+        let client = RawMqttClient::<_, 5, _>::new(stream, buffers.write, write_len, buffers.recv, recv_len, config);
 
-        match client.connect_to_broker().await {
-            Ok(()) => {
-                info!("✓ MQTT connected!");
-            }
-            Err(mqtt_error) => {
-                error!("MQTT connect error: {:?}", mqtt_error);
-                return Err(mqtt_error);
-            }
+        let seed = settings.client_id.bytes().fold(0x811C_9DC5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193));
+
+        MqttController {
+            socket,
+            broker,
+            tls,
+            client,
+            main_topic: settings.main_topic,
+            status_topic,
+            // Ping well before the broker's 1.5x keep-alive deadline
+            keep_alive: match settings.keep_alive_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs.max(2) as u64 / 2)),
+            },
+            router: TopicRouter::new(),
+            state,
+            backoff: Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY, seed),
+            is_connected: false,
+            dropped_inbound: 0,
+            rejection: None,
+            ping_outstanding: false,
         }
-    
-        match client.subscribe_to_topic("esp32/open").await {
-                Ok(()) => {
-                    info!("✓ Subscribed to topic 'esp32/open' successfully!");
+    }
+
+    /// Registers a subscription filter (`+` and `#` allowed) before `run`.
+    /// Use an `MqttRequest` once the controller is running.
+    pub fn subscribe(&mut self, filter: &str, route: MqttRoute) -> Result<(), TopicError> {
+        self.router.add(filter, route)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.is_connected
+    }
+
+    /// Inbound messages dropped (or rejected) so far because a route's channel
+    /// was full.
+    pub fn dropped_inbound(&self) -> u32 {
+        self.dropped_inbound
+    }
+
+    /// Drives the session forever: inbound publishes are routed by topic filter,
+    /// messages queued on `outbound` are published, subscription requests are
+    /// applied, and a PINGREQ goes out on every keep-alive tick. Only one of
+    /// these touches the socket at a time, but a receive is only started once
+    /// data is already waiting, so a publish never sits behind an idle read.
+    /// Publishes that arrive while waiting for an acknowledgement are routed
+    /// as well.
+    pub async fn run(&mut self, outbound: &MqttChannel, requests: &MqttRequestChannel) -> ! {
+        let mut keep_alive = self.keep_alive.map(Ticker::every);
+
+        loop {
+            self.ensure_connected().await;
+
+            // Left for now by the last exchange, which may have routed publishes
+            while let Some(rejection) = self.rejection.take() {
+                if self.publish(&rejection).await.is_err() {
+                    break;
                 }
-                Err(mqtt_error) => {
-                    error!("Subscribe error: {:?}", mqtt_error);
-                    return Err(mqtt_error);
+            }
+
+            let (socket, tls) = (self.socket, self.tls);
+            let readable = async {
+                // Records already decrypted never make the socket readable again
+                if tls.is_some_and(TlsLink::has_buffered) {
+                    return;
                 }
+                socket.lock().await.wait_read_ready().await
+            };
+
+            let ping_due = async {
+                match keep_alive.as_mut() {
+                    Some(ticker) => ticker.next().await,
+                    None => pending().await,
+                }
+            };
+
+            match select4(readable, outbound.receive(), requests.receive(), ping_due).await {
+                Either4::First(()) => self.dispatch_inbound().await,
+                Either4::Second(message) => {
+                    let _ = self.publish(&message).await;
+                }
+                Either4::Third(request) => self.apply_request(request).await,
+                Either4::Fourth(()) => {
+                    let _ = self.send_ping().await;
+                }
+            }
+
+        }
+    }
+
+    async fn dispatch_inbound(&mut self) {
+        match self.read_packet().with_timeout(RECEIVE_TIMEOUT).await {
+            Ok(Ok(None)) => {}
+            Ok(Ok(Some(ack))) => warn!("Unexpected {:?} from the broker", ack),
+            Ok(Err(mqtt_error)) => {
+                error!("Receive message error: {:?}", mqtt_error);
+                self.mark_disconnected();
+            }
+            Err(_) => {
+                // Data was already waiting, so a stalled read means the framing is lost
+                error!("Timeout while reading a pending MQTT packet");
+                self.mark_disconnected();
             }
+        }
+    }
 
-        Ok(
-            MqttController {
-                //socket,
-                //address,
-                client,
-                main_topic,
-                is_connected: true,
+    /// Reads one packet. Publishes are routed here; acknowledgements are
+    /// returned to whoever waits for them.
+    async fn read_packet(&mut self) -> Result<Option<Ack>, ReasonCode> {
+        let message = match self.client.poll::<1>().await? {
+            Event::Message(topic, payload) => {
+                info!("Received message on topic '{}': {:?}", topic, payload);
+                MqttMessage::new(topic, payload)
+            }
+            Event::Connack => return Ok(Some(Ack::Connect)),
+            Event::Puback(identifier) => return Ok(Some(Ack::Publish(identifier))),
+            Event::Suback(identifier) => return Ok(Some(Ack::Subscribe(identifier))),
+            Event::Unsuback(identifier) => return Ok(Some(Ack::Unsubscribe(identifier))),
+            Event::Pingresp => {
+                self.ping_outstanding = false;
+                return Ok(None);
             }
-        )
+            Event::Disconnect(reason) => return Err(reason),
+        };
+
+        match message {
+            Some(message) => self.route(&message),
+            None => warn!("Inbound MQTT message dropped: topic or payload too large"),
+        }
+        Ok(None)
     }
 
-    pub async fn receive_message(&mut self) -> Result<(&str, &[u8]), ReasonCode> {
-        let _ = self.send_ping().await;
+    fn route(&mut self, message: &MqttMessage) {
+        let mut delivered = false;
+        for route in self.router.routes(&message.topic) {
+            delivered = true;
+            // Waiting here would stall reads and pings, or deadlock with
+            // a consumer blocked on `outbound`
+            let rejection = match route.deliver(message) {
+                Delivery::Delivered => continue,
+                Delivery::Dropped => None,
+                Delivery::Rejected(rejection) => Some(rejection),
+            };
+            self.dropped_inbound = self.dropped_inbound.wrapping_add(1);
+            warn!(
+                "Inbound channel full, message on '{}' dropped ({} so far)",
+                message.topic, self.dropped_inbound
+            );
+            if self.rejection.is_none() {
+                self.rejection = rejection;
+            }
+        }
 
-        match self.client.receive_message().with_timeout(Duration::from_secs(10)).await {
-            Ok(result ) => {
-                match result {
-                    Ok((topic, payload)) => {
-                        info!("Received message on topic '{}': {:?}", topic, payload);
-                        Ok((topic, payload))
-                    }
-                    Err(mqtt_error) => {
-                        error!("Receive message error: {:?}", mqtt_error);
-                        return Err(mqtt_error);
+        if !delivered {
+            warn!("No route for MQTT topic '{}'", message.topic);
+        }
+    }
+
+    /// Reads packets until `ack` arrives, routing publishes meanwhile.
+    async fn wait_for(&mut self, ack: Ack) -> Result<(), ReasonCode> {
+        match self.read_until(ack).with_timeout(ACK_TIMEOUT).await {
+            Ok(result) => result,
+            Err(_) => {
+                error!("No {:?} from the broker", ack);
+                Err(ReasonCode::NetworkError)
+            }
+        }
+    }
+
+    async fn read_until(&mut self, ack: Ack) -> Result<(), ReasonCode> {
+        loop {
+            match self.read_packet().await? {
+                Some(received) if received == ack => return Ok(()),
+                Some(received) => warn!("Unexpected {:?} while waiting for {:?}", received, ack),
+                None => {}
+            }
+        }
+    }
+
+    async fn subscribe_to(&mut self, filter: &str) -> Result<(), ReasonCode> {
+        let mut filters = heapless08::Vec::<&str, 1>::new();
+        let _ = filters.push(filter);
+        let identifier = self.client.subscribe_to_topics(&filters).await?;
+        self.wait_for(Ack::Subscribe(identifier)).await
+    }
+
+    async fn apply_request(&mut self, request: MqttRequest) {
+        match request {
+            MqttRequest::Subscribe(filter, route) => {
+                if let Err(e) = self.router.add(&filter, route) {
+                    error!("Cannot register filter '{}': {:?}", filter, e);
+                    return;
+                }
+
+                // Otherwise it is subscribed when the next session opens
+                if self.is_connected {
+                    match self.subscribe_to(&filter).await {
+                        Ok(()) => info!("✓ Subscribed to topic '{}' successfully!", filter),
+                        Err(mqtt_error) => {
+                            error!("Subscribe error: {:?}", mqtt_error);
+                            self.mark_disconnected();
+                        }
                     }
                 }
             }
-            Err(e) => {
-                error!("Timeout: {:?}", e);
-                Err(ReasonCode::MaximumConnectTime)
+            MqttRequest::Unsubscribe(filter) => {
+                if self.router.remove(&filter).is_none() {
+                    warn!("Filter '{}' was not subscribed", filter);
+                    return;
+                }
+
+                if self.is_connected {
+                    let unsubscribed = match self.client.unsubscribe_from_topic(&filter).await {
+                        Ok(identifier) => self.wait_for(Ack::Unsubscribe(identifier)).await,
+                        Err(mqtt_error) => Err(mqtt_error),
+                    };
+                    if let Err(mqtt_error) = unsubscribed {
+                        error!("Unsubscribe error: {:?}", mqtt_error);
+                        self.mark_disconnected();
+                    }
+                }
             }
         }
     }
 
-    pub async fn publish_message(&mut self, subtopic: &str, payload: &[u8]) -> Result<(), ReasonCode> {
-        let full_topic = format!("{}/{}", self.main_topic, subtopic);
-        
-        match self.client.send_message(&full_topic, payload, rust_mqtt::packet::v5::publish_packet::QualityOfService::QoS1, false).await {
+    async fn publish(&mut self, message: &MqttMessage) -> Result<(), ReasonCode> {
+        if message.absolute {
+            return self.publish_to(&message.topic, &message.payload, message.retain).await;
+        }
+        let full_topic = format!("{}/{}", self.main_topic, message.topic);
+        self.publish_to(&full_topic, &message.payload, message.retain).await
+    }
+
+    async fn publish_to(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<(), ReasonCode> {
+        let published = match self.client.send_message(topic, payload, QualityOfService::QoS1, retain).await {
+            Ok(identifier) => self.wait_for(Ack::Publish(identifier)).await,
+            Err(mqtt_error) => Err(mqtt_error),
+        };
+        match published {
             Ok(()) => {
-                info!("Published message to topic '{}': {:?}", full_topic, payload);
+                info!("Published message to topic '{}': {:?}", topic, payload);
                 Ok(())
             }
             Err(mqtt_error) => {
                 error!("Publish message error: {:?}", mqtt_error);
-                return Err(mqtt_error);
+                self.mark_disconnected();
+                Err(mqtt_error)
             }
         }
     }
 
-    pub async fn send_ping(&mut self) -> Result<(), ReasonCode> {
+    /// The PINGRESP is taken by whichever read comes next; a ping still
+    /// unanswered a tick later means the session is gone.
+    async fn send_ping(&mut self) -> Result<(), ReasonCode> {
+        if self.ping_outstanding {
+            error!("No PINGRESP since the last ping");
+            self.mark_disconnected();
+            return Err(ReasonCode::NetworkError);
+        }
         match self.client.send_ping().await {
             Ok(()) => {
                 info!("Ping sent successfully");
+                self.ping_outstanding = true;
                 Ok(())
             }
             Err(mqtt_error) => {
                 error!("Ping error: {:?}", mqtt_error);
+                self.mark_disconnected();
                 Err(mqtt_error)
             }
         }
     }
 
-    // pub async fn resolve_dns(){
-    //     let address = match stack
-    //         .dns_query("test.mosquitto.org", DnsQueryType::A)
-    //         .await
-    //         .map(|a| a[0])
-    //     {
-    //         Ok(address) => {
-    //             info!("DNS resolved to: {:?}", address);
-    //             address
-    //         },
-    //         Err(e) => {
-    //             error!("DNS lookup error: {:?}", e);
-    //             Timer::after(Duration::from_secs(5)).await;
-    //             continue;
-    //         }
-    //     };
-    // }
-}
\ No newline at end of file
+    async fn ensure_connected(&mut self) {
+        if !self.is_connected {
+            self.reconnect().await;
+        }
+    }
+
+    fn mark_disconnected(&mut self) {
+        if self.is_connected {
+            warn!("MQTT session lost, will reconnect");
+        }
+        self.is_connected = false;
+        self.state.sender().send(ConnectionState::Disconnected);
+    }
+
+    async fn reconnect(&mut self) {
+        loop {
+            self.state.sender().send(ConnectionState::Connecting);
+
+            match self.open_session().await {
+                Ok(()) => {
+                    info!("✓ MQTT connected!");
+                    self.backoff.reset();
+                    self.is_connected = true;
+                    self.state.sender().send(ConnectionState::Connected);
+                    return;
+                }
+                Err(e) => {
+                    self.state.sender().send(ConnectionState::Disconnected);
+                    let delay = self.backoff.next_delay();
+                    error!(
+                        "MQTT connect attempt {} failed: {:?}, retrying in {} ms",
+                        self.backoff.attempt(),
+                        e,
+                        delay.as_millis()
+                    );
+                    Timer::after(delay).await;
+                }
+            }
+        }
+    }
+
+    async fn open_session(&mut self) -> Result<(), ReasonCode> {
+        // Resolved again on every attempt so a broker that moved is followed
+        let Some(broker) = self.broker.resolve().await else {
+            error!("Broker address could not be resolved");
+            return Err(ReasonCode::NetworkError);
+        };
+
+        {
+            let mut socket = self.socket.lock().await;
+            // Drop whatever is left of the previous connection before dialing again
+            socket.abort();
+            let _ = socket.flush().await;
+
+            if let Err(e) = socket.connect(broker).await {
+                error!("TCP connect error: {:?}", e);
+                return Err(ReasonCode::NetworkError);
+            }
+        }
+
+        if let Some(tls) = self.tls {
+            tls.handshake().await.map_err(|_| ReasonCode::NetworkError)?;
+        }
+
+        self.ping_outstanding = false;
+        self.client.connect_to_broker().await?;
+        self.wait_for(Ack::Connect).await?;
+
+        // Copied out: routing the retained messages each one brings needs `self`
+        let filters: heapless::Vec<heapless::String<MAX_FILTER_LENGTH>, MAX_SUBSCRIPTIONS> =
+            self.router.filters().filter_map(|filter| heapless::String::try_from(filter).ok()).collect();
+        for filter in &filters {
+            match self.subscribe_to(filter).await {
+                Ok(()) => {
+                    info!("✓ Subscribed to topic '{}' successfully!", filter);
+                }
+                Err(mqtt_error) => {
+                    error!("Subscribe error: {:?}", mqtt_error);
+                    return Err(mqtt_error);
+                }
+            }
+        }
+
+        // Birth message: overwrites the retained Last Will left by a previous drop
+        let identifier = self.client.send_message(self.status_topic, STATUS_ONLINE, QualityOfService::QoS1, true).await?;
+        self.wait_for(Ack::Publish(identifier)).await
+    }
+}
@@ -0,0 +1,114 @@
+use core::fmt::Write;
+
+use log::warn;
+
+use crate::{
+    controller::{
+        mqtt::{ConnectionState, ConnectionStateWatch, MqttChannel, MqttMessage, MAX_TOPIC_LENGTH},
+        transport::{LinkHealth, Received, Transport, TransportError, TransportKind},
+    },
+    protocol::{
+        lora::{LoraEnvelope, LoraParser, NodeAddress},
+        routing::LinkQuality,
+        topic::MAX_FILTER_LENGTH,
+    },
+};
+
+pub const MESH_SUBTOPIC: &str = "mesh";
+/// Last level of the topic of frames without a destination.
+pub const MESH_BROADCAST: &str = "all";
+
+/// Filter that must be routed to the link's inbound channel.
+pub fn mesh_filter(main_topic: &str) -> Option<heapless::String<MAX_FILTER_LENGTH>> {
+    let mut filter = heapless::String::new();
+    write!(filter, "{}/{}/#", main_topic, MESH_SUBTOPIC).ok()?;
+    Some(filter)
+}
+
+/// Carries envelopes through the broker, for nodes out of radio reach (e.g.
+/// the gateway of another site). Frames go out on `{main}/mesh/{node}`, or
+/// `{main}/mesh/all`, framed as over the radio; everything under
+/// `mesh_filter` comes back through `inbound` and frames for other nodes
+/// are skipped.
+pub struct MqttLink<'a> {
+    local: NodeAddress,
+    outbound: &'a MqttChannel,
+    inbound: &'a MqttChannel,
+    state: &'a ConnectionStateWatch,
+    health: LinkHealth,
+}
+
+impl<'a> MqttLink<'a> {
+    pub fn new(local: NodeAddress, outbound: &'a MqttChannel, inbound: &'a MqttChannel, state: &'a ConnectionStateWatch) -> Self {
+        Self {
+            local,
+            outbound,
+            inbound,
+            state,
+            health: LinkHealth::new(false),
+        }
+    }
+
+    fn is_connected(&self) -> bool {
+        self.state.try_get() == Some(ConnectionState::Connected)
+    }
+
+    fn is_for_us(&self, envelope: &LoraEnvelope) -> bool {
+        envelope.src != Some(self.local) && envelope.dst.is_none_or(|dst| dst == self.local)
+    }
+}
+
+impl Transport for MqttLink<'_> {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Mqtt
+    }
+
+    fn health(&self) -> LinkHealth {
+        LinkHealth {
+            connected: self.is_connected(),
+            ..self.health
+        }
+    }
+
+    async fn send(&mut self, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        if !self.is_connected() {
+            return Err(TransportError::Down);
+        }
+        let frame = envelope.into_outgoing().ok_or(TransportError::TooLarge)?;
+
+        let mut topic = heapless::String::<MAX_TOPIC_LENGTH>::new();
+        let written = match envelope.next_hop.or(envelope.dst) {
+            Some(node) => write!(topic, "{}/{}", MESH_SUBTOPIC, node),
+            None => write!(topic, "{}/{}", MESH_SUBTOPIC, MESH_BROADCAST),
+        };
+        let message = written
+            .ok()
+            .and_then(|()| MqttMessage::new(&topic, &frame.payload[..frame.len]))
+            .ok_or(TransportError::TooLarge)?;
+
+        self.outbound.send(message).await;
+        self.health.failures = 0;
+        Ok(())
+    }
+
+    async fn receive(&mut self) -> Result<Received, TransportError> {
+        loop {
+            let message = self.inbound.receive().await;
+            let Some(envelope) = LoraParser::decode_envelope(&message.payload) else {
+                warn!("MQTT mesh frame on '{}' is not an envelope", message.topic);
+                continue;
+            };
+            // The broker echoes our own broadcasts back
+            if !self.is_for_us(&envelope) {
+                continue;
+            }
+
+            self.health.record_receive(None, None);
+            return Ok(Received {
+                envelope,
+                link: LinkQuality::default(),
+                transport: TransportKind::Mqtt,
+            });
+        }
+    }
+}
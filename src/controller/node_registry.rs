@@ -0,0 +1,108 @@
+use core::cell::RefCell;
+
+use embassy_sync::{blocking_mutex::{raw::CriticalSectionRawMutex, Mutex}, channel::Channel};
+use embassy_time::Instant;
+use lora_phy::mod_params::PacketStatus;
+
+use crate::protocol::{lora::NodeAddress, routing::LinkQuality};
+
+pub const MAX_NODES: usize = 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct NodeHealth {
+    pub node: NodeAddress,
+    pub rssi: i16,
+    pub snr: i16,
+    pub battery_percent: Option<u8>,
+    pub last_seen: Instant,
+}
+
+impl NodeHealth {
+    pub fn seconds_since_seen(&self) -> u64 {
+        (Instant::now() - self.last_seen).as_secs()
+    }
+}
+
+/// Link quality and battery of every node heard by the gateway. A node heard
+/// for the first time is announced on `new_nodes`.
+pub struct NodeRegistry {
+    nodes: Mutex<CriticalSectionRawMutex, RefCell<heapless::Vec<NodeHealth, MAX_NODES>>>,
+    /// Nodes are never removed, so it can hold every announcement even if
+    /// nobody has taken one yet.
+    new_nodes: Channel<CriticalSectionRawMutex, NodeAddress, MAX_NODES>,
+}
+
+impl NodeRegistry {
+    pub const fn new() -> Self {
+        Self {
+            nodes: Mutex::new(RefCell::new(heapless::Vec::new())),
+            new_nodes: Channel::new(),
+        }
+    }
+
+    /// Records a frame received from `node`. Returns `false` when the table is full.
+    pub fn record_packet(&self, node: NodeAddress, status: &PacketStatus) -> bool {
+        self.record_link(node, LinkQuality { rssi: status.rssi, snr: status.snr })
+    }
+
+    /// Same as `record_packet`, for frames from any transport.
+    pub fn record_link(&self, node: NodeAddress, link: LinkQuality) -> bool {
+        self.update(node, |health| {
+            health.rssi = link.rssi;
+            health.snr = link.snr;
+        })
+    }
+
+    pub fn record_battery(&self, node: NodeAddress, battery_percent: u8) -> bool {
+        self.update(node, |health| health.battery_percent = Some(battery_percent.min(100)))
+    }
+
+    pub fn get(&self, node: NodeAddress) -> Option<NodeHealth> {
+        self.nodes.lock(|cell| cell.borrow().iter().find(|h| h.node == node).copied())
+    }
+
+    pub fn snapshot(&self) -> heapless::Vec<NodeHealth, MAX_NODES> {
+        self.nodes.lock(|cell| cell.borrow().clone())
+    }
+
+    pub async fn wait_new_node(&self) -> NodeAddress {
+        self.new_nodes.receive().await
+    }
+
+    fn update(&self, node: NodeAddress, f: impl FnOnce(&mut NodeHealth)) -> bool {
+        let now = Instant::now();
+        let inserted = self.nodes.lock(|cell| {
+            let mut nodes = cell.borrow_mut();
+            if let Some(health) = nodes.iter_mut().find(|h| h.node == node) {
+                health.last_seen = now;
+                f(health);
+                return Some(false);
+            }
+
+            let mut health = NodeHealth {
+                node,
+                rssi: 0,
+                snr: 0,
+                battery_percent: None,
+                last_seen: now,
+            };
+            f(&mut health);
+            nodes.push(health).ok().map(|()| true)
+        });
+
+        match inserted {
+            Some(true) => {
+                let _ = self.new_nodes.try_send(node);
+                true
+            }
+            Some(false) => true,
+            None => false,
+        }
+    }
+}
+
+impl Default for NodeRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
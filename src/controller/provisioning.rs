@@ -0,0 +1,234 @@
+use core::fmt::Write as _;
+
+use embassy_futures::select::select3;
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    Ipv4Address, Stack,
+};
+use embassy_time::Duration;
+use embedded_io_async::Write;
+use log::{error, info, warn};
+
+use crate::{
+    hal::{
+        config_store::{keys, ConfigStore},
+        wifi::ACCESS_POINT_ADDRESS,
+    },
+    protocol::{
+        captive::{self, DhcpMessageType, LeasePool},
+        http::{self, Method, ParseError, StatusCode},
+        provisioning::{self, ProvisioningForm},
+    },
+};
+
+const HTTP_PORT: u16 = 80;
+const REQUEST_BUFFER_SIZE: usize = 1536;
+const PAGE_BUFFER_SIZE: usize = 2048;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONTENT_TYPE_HTML: &str = "text/html; charset=utf-8";
+/// First address handed to setup clients.
+const FIRST_LEASE: u8 = 100;
+
+/// Runs the setup network on an access-point stack: DHCP, a DNS server that
+/// sends every name to the gateway, and the setup form on port 80. Returns
+/// once a valid form has been committed to `store`; the caller then restarts
+/// into station mode.
+pub async fn run_captive_portal(stack: Stack<'static>, store: &mut ConfigStore) {
+    info!("Setup portal on {:?}", ACCESS_POINT_ADDRESS);
+    select3(serve_dhcp(stack), serve_dns(stack), serve_form(stack, store)).await;
+}
+
+async fn serve_dhcp(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(captive::DHCP_SERVER_PORT) {
+        error!("DHCP bind error: {:?}", e);
+    }
+
+    let mut leases = LeasePool::new(ACCESS_POINT_ADDRESS, FIRST_LEASE);
+    let mut packet = [0u8; 576];
+    let mut reply = [0u8; 300];
+    // Clients have no address yet, so every reply is broadcast
+    let broadcast = (Ipv4Address::BROADCAST, captive::DHCP_CLIENT_PORT);
+
+    loop {
+        let Ok((len, _)) = socket.recv_from(&mut packet).await else {
+            continue;
+        };
+        let Some(request) = captive::parse_dhcp_request(&packet[..len]) else {
+            continue;
+        };
+
+        let (message_type, address) = match request.message_type {
+            DhcpMessageType::Discover => (DhcpMessageType::Offer, leases.lease(request.mac)),
+            DhcpMessageType::Request => {
+                if request.server_id.is_some_and(|server| server != ACCESS_POINT_ADDRESS) {
+                    // Another server was chosen
+                    continue;
+                }
+                let address = leases.lease(request.mac);
+                match request.requested_ip {
+                    Some(requested) if requested != address => (DhcpMessageType::Nak, address),
+                    _ => (DhcpMessageType::Ack, address),
+                }
+            }
+            DhcpMessageType::Release | DhcpMessageType::Decline => {
+                leases.release(request.mac);
+                continue;
+            }
+            _ => continue,
+        };
+
+        if let Some(len) = captive::encode_dhcp_reply(&request, message_type, address, ACCESS_POINT_ADDRESS, &mut reply) {
+            if let Err(e) = socket.send_to(&reply[..len], broadcast).await {
+                warn!("DHCP send error: {:?}", e);
+            }
+        }
+    }
+}
+
+async fn serve_dns(stack: Stack<'static>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0u8; 1024];
+    let mut tx_buffer = [0u8; 1024];
+    let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+    if let Err(e) = socket.bind(captive::DNS_PORT) {
+        error!("DNS bind error: {:?}", e);
+    }
+
+    let mut query = [0u8; 512];
+    let mut answer = [0u8; 512];
+    loop {
+        let Ok((len, meta)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        if let Some(len) = captive::dns_catch_all(&query[..len], ACCESS_POINT_ADDRESS, &mut answer) {
+            let _ = socket.send_to(&answer[..len], meta.endpoint).await;
+        }
+    }
+}
+
+/// Any `GET` shows the form, so OS captive-portal probes open it; `POST
+/// /save` stores it.
+async fn serve_form(stack: Stack<'static>, store: &mut ConfigStore) {
+    let mut rx_buffer = [0u8; 1536];
+    let mut tx_buffer = [0u8; 2048];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    let mut request = [0u8; REQUEST_BUFFER_SIZE];
+    let mut page = heapless::String::<PAGE_BUFFER_SIZE>::new();
+
+    loop {
+        socket.set_timeout(None);
+        if let Err(e) = socket.accept(HTTP_PORT).await {
+            error!("Setup HTTP accept error: {:?}", e);
+            continue;
+        }
+        socket.set_timeout(Some(REQUEST_TIMEOUT));
+
+        page.clear();
+        let mut saved = false;
+        let status = match read_request(&mut socket, &mut request).await {
+            Ok(len) => handle(&request[..len], store, &mut page, &mut saved),
+            Err(status) => status,
+        };
+
+        let mut head = heapless::String::<256>::new();
+        if http::write_response_head(&mut head, status, CONTENT_TYPE_HTML, page.len()).is_ok() {
+            let _ = socket.write_all(head.as_bytes()).await;
+            let _ = socket.write_all(page.as_bytes()).await;
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+
+        if saved {
+            return;
+        }
+    }
+}
+
+fn handle(raw: &[u8], store: &mut ConfigStore, page: &mut heapless::String<PAGE_BUFFER_SIZE>, saved: &mut bool) -> StatusCode {
+    let Ok(request) = http::parse_request(raw) else {
+        return StatusCode::BAD_REQUEST;
+    };
+
+    match (request.method, request.path) {
+        (Method::Post, "/save") => {
+            let body = &raw[request.header_length..request.header_length + request.content_length];
+            match ProvisioningForm::parse(body) {
+                Ok(form) => match save(store, &form) {
+                    Ok(()) => {
+                        info!("Setup saved: {:?}", form);
+                        *saved = true;
+                        let _ = page.push_str(provisioning::SAVED_PAGE);
+                        StatusCode::OK
+                    }
+                    Err(e) => {
+                        error!("Failed to save setup: {:?}", e);
+                        let _ = write!(page, "<p>Failed to save settings</p>");
+                        StatusCode::SERVICE_UNAVAILABLE
+                    }
+                },
+                Err(e) => {
+                    warn!("Setup form rejected: {:?}", e);
+                    let _ = provisioning::render_form(page, Some(e));
+                    StatusCode::BAD_REQUEST
+                }
+            }
+        }
+        (Method::Get, _) => {
+            let _ = provisioning::render_form(page, None);
+            StatusCode::OK
+        }
+        _ => StatusCode::METHOD_NOT_ALLOWED,
+    }
+}
+
+/// Writes the form to the store; fields left blank remove the stored value.
+fn save(store: &mut ConfigStore, form: &ProvisioningForm) -> anyhow::Result<()> {
+    store.set(keys::WIFI_SSID, &form.ssid)?;
+    store.set(keys::WIFI_PASSWORD, &form.password)?;
+    for (key, value) in [
+        (keys::MQTT_HOST, &form.mqtt_host),
+        (keys::MQTT_PORT, &form.mqtt_port),
+        (keys::MQTT_USERNAME, &form.mqtt_username),
+        (keys::MQTT_PASSWORD, &form.mqtt_password),
+        (keys::HTTP_TOKEN, &form.http_token),
+        (keys::SITE_GATES, &form.gates),
+    ] {
+        match value {
+            Some(value) => store.set(key, value)?,
+            None => {
+                store.remove(key);
+            }
+        }
+    }
+    store.commit()
+}
+
+async fn read_request(socket: &mut TcpSocket<'_>, buffer: &mut [u8]) -> Result<usize, StatusCode> {
+    let mut filled = 0;
+    loop {
+        match http::parse_request(&buffer[..filled]) {
+            Ok(request) if filled >= request.header_length + request.content_length => return Ok(filled),
+            Ok(request) if request.header_length + request.content_length > buffer.len() => {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            Ok(_) | Err(ParseError::Incomplete) => {}
+            Err(ParseError::Malformed) => return Err(StatusCode::BAD_REQUEST),
+        }
+        if filled == buffer.len() {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+
+        match socket.read(&mut buffer[filled..]).await {
+            Ok(0) | Err(_) => return Err(StatusCode::BAD_REQUEST),
+            Ok(n) => filled += n,
+        }
+    }
+}
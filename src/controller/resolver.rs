@@ -0,0 +1,138 @@
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_time::{Duration, Instant, WithTimeout};
+use log::{info, warn};
+
+use crate::protocol::mdns::{self, MDNS_GROUP, MDNS_PORT};
+
+const MDNS_TIMEOUT: Duration = Duration::from_secs(2);
+const MDNS_LOCAL_PORT: u16 = 53535;
+const MDNS_BUFFER_SIZE: usize = 512;
+
+/// Where the broker is, as configured.
+#[derive(Debug, Clone, Copy)]
+pub enum BrokerAddress {
+    Ip(IpEndpoint),
+    /// Resolved through the network's DNS server; when that fails and
+    /// `mdns_fallback` is set, `_mqtt._tcp.local` is looked up on the LAN.
+    Hostname {
+        host: &'static str,
+        port: u16,
+        mdns_fallback: bool,
+    },
+}
+
+impl BrokerAddress {
+    pub const fn hostname(host: &'static str, port: u16) -> Self {
+        Self::Hostname {
+            host,
+            port,
+            mdns_fallback: true,
+        }
+    }
+}
+
+impl From<IpEndpoint> for BrokerAddress {
+    fn from(endpoint: IpEndpoint) -> Self {
+        Self::Ip(endpoint)
+    }
+}
+
+impl From<(Ipv4Address, u16)> for BrokerAddress {
+    fn from(endpoint: (Ipv4Address, u16)) -> Self {
+        Self::Ip(endpoint.into())
+    }
+}
+
+/// Resolves the broker before every connection attempt. The last good
+/// address is kept and used when neither DNS nor mDNS answers.
+pub struct BrokerResolver<'a> {
+    stack: Stack<'a>,
+    address: BrokerAddress,
+    cached: Option<IpEndpoint>,
+}
+
+impl<'a> BrokerResolver<'a> {
+    pub fn new(stack: Stack<'a>, address: impl Into<BrokerAddress>) -> Self {
+        Self {
+            stack,
+            address: address.into(),
+            cached: None,
+        }
+    }
+
+    pub fn cached(&self) -> Option<IpEndpoint> {
+        self.cached
+    }
+
+    pub async fn resolve(&mut self) -> Option<IpEndpoint> {
+        let (host, port, mdns_fallback) = match self.address {
+            BrokerAddress::Ip(endpoint) => return Some(endpoint),
+            BrokerAddress::Hostname { host, port, mdns_fallback } => (host, port, mdns_fallback),
+        };
+
+        let resolved = match self.stack.dns_query(host, DnsQueryType::A).await {
+            Ok(addresses) if !addresses.is_empty() => {
+                info!("DNS resolved '{}' to {:?}", host, addresses[0]);
+                Some(IpEndpoint::new(addresses[0], port))
+            }
+            result => {
+                warn!("DNS lookup of '{}' failed: {:?}", host, result.err());
+                if mdns_fallback {
+                    self.query_mdns().await
+                } else {
+                    None
+                }
+            }
+        };
+
+        match resolved {
+            Some(endpoint) => {
+                self.cached = Some(endpoint);
+                Some(endpoint)
+            }
+            None => {
+                if let Some(cached) = self.cached {
+                    warn!("Using cached broker address {:?}", cached);
+                }
+                self.cached
+            }
+        }
+    }
+
+    async fn query_mdns(&self) -> Option<IpEndpoint> {
+        let mut rx_meta = [PacketMetadata::EMPTY; 2];
+        let mut tx_meta = [PacketMetadata::EMPTY; 2];
+        let mut rx_buffer = [0u8; MDNS_BUFFER_SIZE];
+        let mut tx_buffer = [0u8; MDNS_BUFFER_SIZE];
+        let mut packet = [0u8; MDNS_BUFFER_SIZE];
+
+        let mut socket = UdpSocket::new(self.stack, &mut rx_meta, &mut rx_buffer, &mut tx_meta, &mut tx_buffer);
+        if let Err(e) = socket.bind(MDNS_LOCAL_PORT) {
+            warn!("mDNS bind error: {:?}", e);
+            return None;
+        }
+
+        let id = Instant::now().as_ticks() as u16;
+        let len = mdns::build_ptr_query(id, mdns::MQTT_SERVICE, &mut packet)?;
+        if let Err(e) = socket.send_to(&packet[..len], (MDNS_GROUP, MDNS_PORT)).await {
+            warn!("mDNS send error: {:?}", e);
+            return None;
+        }
+
+        let deadline = Instant::now() + MDNS_TIMEOUT;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let (len, _) = socket.recv_from(&mut packet).with_timeout(remaining).await.ok()?.ok()?;
+
+            // Other queriers' traffic and foreign answers are skipped
+            if let Some(service) = mdns::parse_service_response(id, &packet[..len]) {
+                info!("mDNS found broker at {}:{}", service.address, service.port);
+                return Some(IpEndpoint::new(IpAddress::Ipv4(service.address), service.port));
+            }
+        }
+    }
+}
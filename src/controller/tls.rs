@@ -0,0 +1,264 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_net::tcp;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_tls::{
+    pki::CertVerifier, Aes128GcmSha256, Certificate, CryptoProvider, MaxFragmentLength, NoClock,
+    SignatureScheme, TlsConfig, TlsConnection, TlsContext, TlsError, TlsVerifier,
+};
+use esp_hal::rng::Rng;
+use log::{error, info};
+use p256::{ecdsa::{signature::SignerMut, DerSignature, SigningKey}, pkcs8::DecodePrivateKey};
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+
+use crate::controller::mqtt::{MqttSocket, MqttTransport};
+
+/// Largest certificate the verifier keeps while walking the chain.
+const MAX_CERT_SIZE: usize = 2048;
+
+/// Content type, legacy version and length.
+const RECORD_HEADER_LENGTH: usize = 5;
+const HANDSHAKE_RECORD: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+
+pub type TlsSession<'a> = TlsConnection<'a, RecordTransport<'a>, Aes128GcmSha256>;
+
+/// Socket side of the TLS session. A read never goes past the end of the
+/// record being received, so embedded-tls cannot buffer the next record
+/// ahead: ciphertext not decrypted yet is still in the socket, where
+/// `wait_read_ready` sees it.
+pub struct RecordTransport<'a> {
+    socket: MqttTransport<'a>,
+    header: [u8; RECORD_HEADER_LENGTH],
+    header_len: usize,
+    body_left: usize,
+}
+
+impl<'a> RecordTransport<'a> {
+    pub fn new(socket: &'a MqttSocket<'a>) -> Self {
+        Self {
+            socket: MqttTransport::new(socket),
+            header: [0; RECORD_HEADER_LENGTH],
+            header_len: 0,
+            body_left: 0,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.header_len = 0;
+        self.body_left = 0;
+    }
+}
+
+impl ErrorType for RecordTransport<'_> {
+    type Error = tcp::Error;
+}
+
+impl Read for RecordTransport<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let limit = match self.body_left {
+            0 => RECORD_HEADER_LENGTH - self.header_len,
+            body_left => body_left,
+        };
+        let len = buf.len().min(limit);
+        let read = match self.socket.read(&mut buf[..len]).await {
+            Ok(read) if read > 0 => read,
+            other => {
+                self.restart();
+                return other;
+            }
+        };
+
+        if self.body_left > 0 {
+            self.body_left -= read;
+        } else {
+            self.header[self.header_len..self.header_len + read].copy_from_slice(&buf[..read]);
+            self.header_len += read;
+            if self.header_len == RECORD_HEADER_LENGTH {
+                self.body_left = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                self.header_len = 0;
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl Write for RecordTransport<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        // A ClientHello opens every handshake, on a freshly dialed socket
+        if buf.len() > RECORD_HEADER_LENGTH && buf[..3] == [HANDSHAKE_RECORD, 3, 1] && buf[RECORD_HEADER_LENGTH] == CLIENT_HELLO {
+            self.restart();
+        }
+        self.socket.write(buf).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.socket.flush().await
+    }
+}
+
+/// What the server must prove, and optionally what we prove back.
+/// Certificates and the key are DER encoded; only P-256 ECDSA is supported.
+pub struct TlsSettings<'a> {
+    pub server_name: &'a str,
+    pub ca_cert: &'a [u8],
+    pub client_cert: Option<&'a [u8]>,
+    pub client_key: Option<&'a [u8]>,
+}
+
+/// TLS record buffers. With the 4 KiB max fragment length negotiated below,
+/// 4096 + 256 bytes each is enough.
+pub struct TlsBuffers<'a> {
+    pub read: &'a mut [u8],
+    pub write: &'a mut [u8],
+}
+
+/// Hardware RNG wrapper; the ESP32 RNG is cryptographically secure while the
+/// radio is running, which is always the case once WiFi is up.
+#[derive(Clone, Copy)]
+pub struct HardwareRng(pub Rng);
+
+impl RngCore for HardwareRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.random()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let random = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for HardwareRng {}
+
+/// Verifies the server chain against the pinned CA from `TlsConfig` and signs
+/// the client certificate proof when one is configured.
+pub struct PinnedCaProvider {
+    rng: HardwareRng,
+    verifier: CertVerifier<Aes128GcmSha256, NoClock, MAX_CERT_SIZE>,
+}
+
+impl PinnedCaProvider {
+    pub fn new(rng: HardwareRng) -> Self {
+        Self {
+            rng,
+            verifier: CertVerifier::new(),
+        }
+    }
+}
+
+impl CryptoProvider for PinnedCaProvider {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = DerSignature;
+
+    fn rng(&mut self) -> impl CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+        key_der: &[u8],
+    ) -> Result<(impl SignerMut<Self::Signature>, SignatureScheme), TlsError> {
+        let key = SigningKey::from_pkcs8_der(key_der).map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((key, SignatureScheme::EcdsaSecp256r1Sha256))
+    }
+}
+
+/// TLS 1.3 session on top of the MQTT socket. The connection object is kept
+/// across reconnects so its record buffers are reused; `handshake` is run
+/// again every time the TCP socket is re-opened.
+pub struct TlsLink<'a> {
+    session: Mutex<CriticalSectionRawMutex, TlsSession<'a>>,
+    /// Decrypted bytes are waiting in the session, not in the socket.
+    buffered: AtomicBool,
+    config: TlsConfig<'a>,
+    server_name: &'a str,
+    rng: HardwareRng,
+}
+
+impl<'a> TlsLink<'a> {
+    pub fn new(socket: &'a MqttSocket<'a>, settings: TlsSettings<'a>, buffers: TlsBuffers<'a>, rng: Rng) -> Self {
+        let mut config = TlsConfig::new()
+            .with_server_name(settings.server_name)
+            .with_ca(Certificate::X509(settings.ca_cert))
+            .with_max_fragment_length(MaxFragmentLength::Bits12);
+
+        if let (Some(cert), Some(key)) = (settings.client_cert, settings.client_key) {
+            config = config.with_cert(Certificate::X509(cert)).with_priv_key(key);
+        }
+
+        let session = TlsConnection::new(RecordTransport::new(socket), buffers.read, buffers.write);
+
+        Self {
+            session: Mutex::new(session),
+            buffered: AtomicBool::new(false),
+            config,
+            server_name: settings.server_name,
+            rng: HardwareRng(rng),
+        }
+    }
+
+    pub fn session(&self) -> &Mutex<CriticalSectionRawMutex, TlsSession<'a>> {
+        &self.session
+    }
+
+    /// Whether a read would return at once without touching the socket.
+    pub fn has_buffered(&self) -> bool {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    /// Plaintext read; keeps `has_buffered` up to date, since whatever is
+    /// left of the record is invisible to the socket's readiness.
+    pub async fn read(&self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut session = self.session.lock().await;
+        let mut record = match session.read_buffered().await {
+            Ok(record) => record,
+            Err(e) => {
+                self.buffered.store(false, Ordering::Relaxed);
+                return Err(e);
+            }
+        };
+        let read = record.pop_into(buf);
+        self.buffered.store(!record.is_empty(), Ordering::Relaxed);
+        Ok(read)
+    }
+
+    pub async fn handshake(&self) -> Result<(), TlsError> {
+        self.buffered.store(false, Ordering::Relaxed);
+        let provider = PinnedCaProvider::new(self.rng);
+        let context = TlsContext::new(&self.config, provider);
+
+        match self.session.lock().await.open(context).await {
+            Ok(()) => {
+                info!("✓ TLS session established with '{}'", self.server_name);
+                Ok(())
+            }
+            Err(e) => {
+                error!("TLS handshake error: {:?}", e);
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Common interface of the links that carry `LoraEnvelope` frames (LoRa,
+//! ESP-NOW, MQTT), and a dispatcher that picks one per destination.
+
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_time::Instant;
+use log::{debug, warn};
+use lora_phy::mod_params::RadioError;
+
+use crate::{
+    controller::espnow::EspNowTransportError,
+    protocol::{
+        lora::{LoraEnvelope, NodeAddress},
+        paths::{PathHealth, PathTable, MAX_TRANSPORTS},
+        routing::LinkQuality,
+    },
+};
+
+/// Consecutive failed sends after which a link counts as down, until it
+/// sends or receives again.
+pub const LINK_DOWN_AFTER: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Lora,
+    EspNow,
+    Mqtt,
+}
+
+#[derive(Debug)]
+pub enum TransportError {
+    /// The link is not connected (e.g. MQTT session closed).
+    Down,
+    /// The encoded envelope does not fit one frame of the link.
+    TooLarge,
+    /// The link does not know how to reach this node.
+    UnknownDestination(NodeAddress),
+    /// A frame arrived that is not an envelope.
+    InvalidFrame,
+    Lora(RadioError),
+    EspNow(EspNowTransportError),
+}
+
+impl From<EspNowTransportError> for TransportError {
+    fn from(e: EspNowTransportError) -> Self {
+        match e {
+            EspNowTransportError::TooLarge => TransportError::TooLarge,
+            EspNowTransportError::UnknownPeer(node) => TransportError::UnknownDestination(node),
+            e => TransportError::EspNow(e),
+        }
+    }
+}
+
+/// Health of a link as seen by its transport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinkHealth {
+    /// Link-level state, e.g. the MQTT session; radios are always connected.
+    pub connected: bool,
+    /// Signal of the last frame received, where the link reports it.
+    pub rssi: Option<i16>,
+    pub snr: Option<i16>,
+    pub last_rx: Option<Instant>,
+    /// Failed sends since the last success.
+    pub failures: u8,
+}
+
+impl LinkHealth {
+    pub const fn new(connected: bool) -> Self {
+        Self {
+            connected,
+            rssi: None,
+            snr: None,
+            last_rx: None,
+            failures: 0,
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.connected && self.failures < LINK_DOWN_AFTER
+    }
+
+    pub fn record_send<T, E>(&mut self, result: &Result<T, E>) {
+        match result {
+            Ok(_) => self.failures = 0,
+            Err(_) => self.failures = self.failures.saturating_add(1),
+        }
+    }
+
+    pub fn record_receive(&mut self, rssi: Option<i16>, snr: Option<i16>) {
+        self.rssi = rssi;
+        self.snr = snr;
+        self.last_rx = Some(Instant::now());
+        self.failures = 0;
+    }
+}
+
+/// An envelope and the quality of the link it arrived on (zero where the
+/// link has no signal to report).
+#[derive(Debug)]
+pub struct Received {
+    pub envelope: LoraEnvelope,
+    pub link: LinkQuality,
+    pub transport: TransportKind,
+}
+
+/// A link that carries envelopes. `receive` must be safe to cancel: the
+/// dispatcher drops it when another link receives first.
+// Futures run on the single-threaded embassy executor and need not be `Send`
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    fn kind(&self) -> TransportKind;
+
+    fn health(&self) -> LinkHealth;
+
+    /// Sends to the envelope's next hop (`next_hop`, else `dst`), or to
+    /// every node in reach when it has neither.
+    async fn send(&mut self, envelope: &LoraEnvelope) -> Result<(), TransportError>;
+
+    /// Waits for the next envelope addressed to this node or broadcast.
+    async fn receive(&mut self) -> Result<Received, TransportError>;
+}
+
+/// Transports owned by a `Dispatcher`, as a tuple in priority order.
+#[allow(async_fn_in_trait)]
+pub trait TransportSet {
+    const LEN: usize;
+
+    fn kind(&self, index: usize) -> TransportKind;
+
+    fn health(&self, index: usize) -> LinkHealth;
+
+    async fn send(&mut self, index: usize, envelope: &LoraEnvelope) -> Result<(), TransportError>;
+
+    /// First envelope received on any of the transports, with its index.
+    async fn receive(&mut self) -> (usize, Result<Received, TransportError>);
+}
+
+impl<A: Transport> TransportSet for (A,) {
+    const LEN: usize = 1;
+
+    fn kind(&self, _index: usize) -> TransportKind {
+        self.0.kind()
+    }
+
+    fn health(&self, _index: usize) -> LinkHealth {
+        self.0.health()
+    }
+
+    async fn send(&mut self, _index: usize, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        self.0.send(envelope).await
+    }
+
+    async fn receive(&mut self) -> (usize, Result<Received, TransportError>) {
+        (0, self.0.receive().await)
+    }
+}
+
+impl<A: Transport, B: Transport> TransportSet for (A, B) {
+    const LEN: usize = 2;
+
+    fn kind(&self, index: usize) -> TransportKind {
+        match index {
+            0 => self.0.kind(),
+            _ => self.1.kind(),
+        }
+    }
+
+    fn health(&self, index: usize) -> LinkHealth {
+        match index {
+            0 => self.0.health(),
+            _ => self.1.health(),
+        }
+    }
+
+    async fn send(&mut self, index: usize, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        match index {
+            0 => self.0.send(envelope).await,
+            _ => self.1.send(envelope).await,
+        }
+    }
+
+    async fn receive(&mut self) -> (usize, Result<Received, TransportError>) {
+        match select(self.0.receive(), self.1.receive()).await {
+            Either::First(received) => (0, received),
+            Either::Second(received) => (1, received),
+        }
+    }
+}
+
+impl<A: Transport, B: Transport, C: Transport> TransportSet for (A, B, C) {
+    const LEN: usize = 3;
+
+    fn kind(&self, index: usize) -> TransportKind {
+        match index {
+            0 => self.0.kind(),
+            1 => self.1.kind(),
+            _ => self.2.kind(),
+        }
+    }
+
+    fn health(&self, index: usize) -> LinkHealth {
+        match index {
+            0 => self.0.health(),
+            1 => self.1.health(),
+            _ => self.2.health(),
+        }
+    }
+
+    async fn send(&mut self, index: usize, envelope: &LoraEnvelope) -> Result<(), TransportError> {
+        match index {
+            0 => self.0.send(envelope).await,
+            1 => self.1.send(envelope).await,
+            _ => self.2.send(envelope).await,
+        }
+    }
+
+    async fn receive(&mut self) -> (usize, Result<Received, TransportError>) {
+        match select3(self.0.receive(), self.1.receive(), self.2.receive()).await {
+            Either3::First(received) => (0, received),
+            Either3::Second(received) => (1, received),
+            Either3::Third(received) => (2, received),
+        }
+    }
+}
+
+/// Sends each envelope over the best transport for its destination and
+/// receives from all of them. Transports are tried in the order of the
+/// tuple, except that a destination goes first over the transports it was
+/// recently heard on (or the one it is pinned to); when a send fails the
+/// next candidate is tried. Broadcasts go out on every healthy transport.
+pub struct Dispatcher<T: TransportSet> {
+    transports: T,
+    paths: PathTable,
+}
+
+impl<T: TransportSet> Dispatcher<T> {
+    pub fn new(transports: T) -> Self {
+        Self {
+            transports,
+            paths: PathTable::new(),
+        }
+    }
+
+    pub fn transports(&mut self) -> &mut T {
+        &mut self.transports
+    }
+
+    pub fn health(&self, kind: TransportKind) -> Option<LinkHealth> {
+        self.index_of(kind).map(|index| self.transports.health(index))
+    }
+
+    /// Sends to `node` over `kind` first while that link is up; `None`
+    /// returns the node to automatic selection.
+    pub fn pin(&mut self, node: NodeAddress, kind: Option<TransportKind>) {
+        let index = kind.and_then(|kind| self.index_of(kind));
+        self.paths.pin(node, index);
+    }
+
+    /// Returns the transport the envelope left on.
+    pub async fn send(&mut self, envelope: &LoraEnvelope) -> Result<TransportKind, TransportError> {
+        let health = self.path_health();
+        // Paths are kept per neighbour: the next hop, or the destination itself
+        let Some(dst) = envelope.next_hop.or(envelope.dst) else {
+            return self.broadcast(envelope, &health).await;
+        };
+
+        let mut last_error = TransportError::Down;
+        for index in self.paths.candidates(dst, &health, Instant::now().as_millis()) {
+            let kind = self.transports.kind(index);
+            match self.transports.send(index, envelope).await {
+                Ok(()) => return Ok(kind),
+                Err(e) => {
+                    debug!("Envelope seq {} to {} not sent over {:?}: {:?}", envelope.seq, dst, kind, e);
+                    last_error = e;
+                }
+            }
+        }
+        warn!("Envelope seq {} to {} not sent over any transport", envelope.seq, dst);
+        Err(last_error)
+    }
+
+    async fn broadcast(&mut self, envelope: &LoraEnvelope, health: &[PathHealth]) -> Result<TransportKind, TransportError> {
+        let any_up = health.iter().any(|path| path.up);
+        let mut result = Err(TransportError::Down);
+        for (index, path) in health.iter().enumerate() {
+            if any_up && !path.up {
+                continue;
+            }
+            let kind = self.transports.kind(index);
+            match self.transports.send(index, envelope).await {
+                Ok(()) => {
+                    if result.is_err() {
+                        result = Ok(kind);
+                    }
+                }
+                Err(e) => {
+                    debug!("Broadcast seq {} not sent over {:?}: {:?}", envelope.seq, kind, e);
+                    if result.is_err() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// Next envelope from any transport. The transport it arrived on
+    /// becomes the preferred path back to the node that sent it (the last
+    /// relay, or the origin).
+    pub async fn receive(&mut self) -> Result<Received, TransportError> {
+        let (index, received) = self.transports.receive().await;
+        if let Ok(received) = &received {
+            if let Some(node) = received.envelope.via.or(received.envelope.src) {
+                self.paths.heard(node, index, Instant::now().as_millis());
+            }
+        }
+        received
+    }
+
+    fn path_health(&self) -> heapless::Vec<PathHealth, MAX_TRANSPORTS> {
+        (0..T::LEN.min(MAX_TRANSPORTS))
+            .map(|index| PathHealth {
+                up: self.transports.health(index).is_up(),
+            })
+            .collect()
+    }
+
+    fn index_of(&self, kind: TransportKind) -> Option<usize> {
+        (0..T::LEN).find(|&index| self.transports.kind(index) == kind)
+    }
+}
@@ -0,0 +1,57 @@
+//! Whatever moves the barrier. The `GateController` drives it with `drive`
+//! and then calls `update` every few milliseconds until the move is over,
+//! so actuators that need timing (pulses, motion profiles) never block.
+
+use core::fmt::Debug;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateTarget {
+    Open,
+    Closed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    Moving,
+    /// The target of the last `drive` was reached (or, without feedback,
+    /// the travel time is over).
+    Arrived,
+}
+
+pub trait GateActuator {
+    type Error: Debug;
+
+    /// Starts moving towards `target`, replacing any move in progress.
+    fn drive(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error>;
+
+    /// `drive` for a safety reversal, e.g. a vehicle under a closing arm:
+    /// starts at once, skipping any pacing between commands.
+    fn reverse(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        self.drive(target, now_ms)
+    }
+
+    /// Advances the move started by `drive`.
+    fn update(&mut self, now_ms: u64) -> Result<Motion, Self::Error>;
+
+    /// Stops where it is, as far as the actuator can.
+    fn stop(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Actuator a node drives, from the `gate.actuator` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ActuatorKind {
+    #[default]
+    Servo,
+    /// Dry-contact pulses to a barrier controller.
+    Relay,
+}
+
+impl ActuatorKind {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim() {
+            "servo" => Some(ActuatorKind::Servo),
+            "relay" => Some(ActuatorKind::Relay),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,481 @@
+use alloc::{string::String, vec};
+
+use anyhow::Result;
+use embedded_storage::{ReadStorage, Storage};
+use esp_storage::FlashStorage;
+use log::{info, warn};
+
+use crate::{
+    controller::{espnow::EspNowSecurity, gate::GateTiming},
+    hal::{
+        actuator::ActuatorKind,
+        presence::{PresenceKind, PresenceSettings},
+        relay::RelaySettings,
+        servo_motor::ServoSettings,
+        wifi::{Ipv4Settings, NetworkSettings, StaticIpv4, WifiCredentials, WifiNetworks},
+    },
+    protocol::{
+        config_record::{self, ConfigEntries, RecordError},
+        lora::NodeAddress,
+        net_config::{self, NetConfigError},
+        node_config, provisioning,
+        servo::{self, MotionProfile, ServoCalibration},
+    },
+};
+
+/// Setting names understood by the firmware.
+pub mod keys {
+    pub const WIFI_SSID: &str = "wifi.ssid";
+    pub const WIFI_PASSWORD: &str = "wifi.password";
+    /// Fallback networks, in priority order after `wifi.ssid`.
+    pub const WIFI_FALLBACKS: [(&str, &str); 3] = [
+        ("wifi.2.ssid", "wifi.2.password"),
+        ("wifi.3.ssid", "wifi.3.password"),
+        ("wifi.4.ssid", "wifi.4.password"),
+    ];
+    pub const MQTT_HOST: &str = "mqtt.host";
+    /// Defaults to 1883, or 8883 with TLS.
+    pub const MQTT_PORT: &str = "mqtt.port";
+    pub const MQTT_USERNAME: &str = "mqtt.username";
+    pub const MQTT_PASSWORD: &str = "mqtt.password";
+    pub const HTTP_TOKEN: &str = "http.token";
+    /// `dhcp` (default) or a static `a.b.c.d/len`.
+    pub const NET_IPV4: &str = "net.ipv4";
+    /// Static mode only.
+    pub const NET_GATEWAY: &str = "net.gateway";
+    /// Comma-separated, static mode only.
+    pub const NET_DNS: &str = "net.dns";
+    /// DHCP mode only; defaults to one derived from the MAC address.
+    pub const NET_HOSTNAME: &str = "net.hostname";
+    /// `on` (default) or `off`.
+    pub const NET_IPV6: &str = "net.ipv6";
+    /// `id:name:ENTRY|EXIT:node;...`, see `protocol::provisioning::parse_gate_list`.
+    pub const SITE_GATES: &str = "site.gates";
+    /// LoRa address of a gate node, decimal or `0x` hex.
+    pub const NODE_ID: &str = "node.id";
+    /// 32 hex digits each; both must be set to encrypt ESP-NOW unicast.
+    pub const ESPNOW_PMK: &str = "espnow.pmk";
+    pub const ESPNOW_LMK: &str = "espnow.lmk";
+    /// Channel of the gateway's access point, for nodes that join no network.
+    pub const ESPNOW_CHANNEL: &str = "espnow.channel";
+    /// Milliseconds a gate stays open before closing by itself; `0` never.
+    pub const GATE_HOLD_MS: &str = "gate.hold_ms";
+    pub const GATE_MOVEMENT_TIMEOUT_MS: &str = "gate.movement_timeout_ms";
+    /// Milliseconds after a vehicle has passed before closing; missing or
+    /// `0` leaves it to the hold time.
+    pub const GATE_CLEAR_CLOSE_MS: &str = "gate.clear_close_ms";
+    /// `servo` (default) or `relay`.
+    pub const GATE_ACTUATOR: &str = "gate.actuator";
+    pub const RELAY_PULSE_MS: &str = "relay.pulse_ms";
+    /// `high` (default) or `low`: level that closes the contact.
+    pub const RELAY_ACTIVE_LEVEL: &str = "relay.active_level";
+    pub const RELAY_LOCKOUT_MS: &str = "relay.lockout_ms";
+    pub const RELAY_TRAVEL_MS: &str = "relay.travel_ms";
+    /// Pulse widths in microseconds at 0° and at 180°.
+    pub const SERVO_MIN_PULSE_US: &str = "servo.min_pulse_us";
+    pub const SERVO_MAX_PULSE_US: &str = "servo.max_pulse_us";
+    pub const SERVO_OPEN_ANGLE: &str = "servo.open_angle";
+    pub const SERVO_CLOSE_ANGLE: &str = "servo.close_angle";
+    /// `trapezoid` (default), `eased` or `jump`.
+    pub const SERVO_PROFILE: &str = "servo.profile";
+    pub const SERVO_SPEED_DEG_S: &str = "servo.speed_deg_s";
+    pub const SERVO_ACCEL_DEG_S2: &str = "servo.accel_deg_s2";
+    /// Length of an `eased` move.
+    pub const SERVO_MOVE_MS: &str = "servo.move_ms";
+    /// Milliseconds the angle is held after a move; `0` holds it for good.
+    pub const SERVO_RELEASE_MS: &str = "servo.release_ms";
+    /// `none` (default), `contact` or `ultrasonic`.
+    pub const PRESENCE_SENSOR: &str = "presence.sensor";
+    /// `low` (default) or `high`: contact level that means a vehicle.
+    pub const PRESENCE_ACTIVE_LEVEL: &str = "presence.active_level";
+    pub const PRESENCE_RANGE_CM: &str = "presence.range_cm";
+    pub const PRESENCE_ASSERT_MS: &str = "presence.assert_ms";
+    pub const PRESENCE_CLEAR_MS: &str = "presence.clear_ms";
+}
+
+pub const CONFIG_SECTOR_SIZE: usize = 4096;
+/// Two sectors of the `nvs` partition of the default partition table, which
+/// nothing else uses on bare metal. Commits alternate between them, so a
+/// power loss mid-write leaves the previous record intact.
+const CONFIG_SECTORS: [u32; 2] = [0x9000, 0xA000];
+
+/// Versioned key/value settings persisted in flash. Values are never logged;
+/// the store's `Debug` output only lists keys.
+pub struct ConfigStore {
+    flash: FlashStorage,
+    entries: ConfigEntries,
+    generation: u32,
+    /// Sector holding the record that was loaded or last committed.
+    current: Option<usize>,
+}
+
+impl ConfigStore {
+    /// Loads the newest valid record. Blank or corrupted flash gives an empty
+    /// store rather than an error, so a fresh board still boots.
+    pub fn load() -> Result<Self> {
+        let mut flash = FlashStorage::new();
+        let mut buffer = vec![0u8; CONFIG_SECTOR_SIZE];
+        let mut newest: Option<(usize, u32, ConfigEntries)> = None;
+
+        for (index, &offset) in CONFIG_SECTORS.iter().enumerate() {
+            flash
+                .read(offset, &mut buffer)
+                .map_err(|e| anyhow::anyhow!("Failed to read config sector {:#x}: {:?}", offset, e))?;
+
+            match config_record::decode_record(&buffer) {
+                Ok((generation, entries)) => {
+                    if newest.as_ref().is_none_or(|(_, newest_generation, _)| generation > *newest_generation) {
+                        newest = Some((index, generation, entries));
+                    }
+                }
+                Err(RecordError::NoRecord) => {}
+                Err(e) => warn!("Config sector {:#x} ignored: {:?}", offset, e),
+            }
+        }
+
+        let store = match newest {
+            Some((index, generation, entries)) => Self {
+                flash,
+                entries,
+                generation,
+                current: Some(index),
+            },
+            None => Self {
+                flash,
+                entries: ConfigEntries::new(),
+                generation: 0,
+                current: None,
+            },
+        };
+        info!("Config loaded: generation {}, keys {:?}", store.generation, store.entries);
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key)
+    }
+
+    /// Changes are kept in RAM until `commit`.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.entries
+            .set(key, value)
+            .map_err(|e| anyhow::anyhow!("Failed to set config key '{}': {:?}", key, e))
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        self.entries.remove(key)
+    }
+
+    pub fn entries(&self) -> &ConfigEntries {
+        &self.entries
+    }
+
+    /// Writes the settings to the sector not holding the current record.
+    pub fn commit(&mut self) -> Result<()> {
+        let target = self.current.map_or(0, |index| (index + 1) % CONFIG_SECTORS.len());
+        let generation = self.generation.wrapping_add(1);
+
+        let mut buffer = vec![0xFFu8; CONFIG_SECTOR_SIZE];
+        config_record::encode_record(&self.entries, generation, &mut buffer)
+            .map_err(|e| anyhow::anyhow!("Failed to encode config: {:?}", e))?;
+        self.flash
+            .write(CONFIG_SECTORS[target], &buffer)
+            .map_err(|e| anyhow::anyhow!("Failed to write config sector: {:?}", e))?;
+
+        self.generation = generation;
+        self.current = Some(target);
+        info!("Config committed: generation {}", generation);
+        Ok(())
+    }
+
+    /// Copies `value` under `key` only when the store has none, e.g. to carry
+    /// build-time settings over on the first boot. Returns whether it did.
+    pub fn seed(&mut self, key: &str, value: Option<&str>) -> Result<bool> {
+        match value {
+            Some(value) if self.get(key).is_none() => self.set(key, value).map(|()| true),
+            _ => Ok(false),
+        }
+    }
+
+    pub fn wifi_credentials(&self) -> Option<WifiCredentials> {
+        WifiCredentials::new(self.get(keys::WIFI_SSID)?, self.get(keys::WIFI_PASSWORD).unwrap_or(""))
+    }
+
+    /// Primary network followed by the fallbacks that are set; empty when
+    /// the primary is missing.
+    pub fn wifi_networks(&self) -> WifiNetworks {
+        let mut networks = WifiNetworks::new();
+        let Some(primary) = self.wifi_credentials() else {
+            return networks;
+        };
+        let _ = networks.push(primary);
+        for (ssid_key, password_key) in keys::WIFI_FALLBACKS {
+            let fallback = self
+                .get(ssid_key)
+                .and_then(|ssid| WifiCredentials::new(ssid, self.get(password_key).unwrap_or("")));
+            if let Some(fallback) = fallback {
+                let _ = networks.push(fallback);
+            }
+        }
+        networks
+    }
+
+    /// Addressing for the station. A malformed setting is logged and the
+    /// default (DHCP) is used instead, so a typo never leaves the gateway
+    /// unreachable.
+    pub fn network_settings(&self) -> NetworkSettings {
+        let ipv6 = self.get(keys::NET_IPV6).and_then(net_config::parse_switch).unwrap_or(true);
+        let ipv4 = match self.get(keys::NET_IPV4) {
+            None | Some("dhcp") => self.dhcp_settings(),
+            Some(cidr) => match self.static_settings(cidr) {
+                Ok(fixed) => Ipv4Settings::Static(fixed),
+                Err(e) => {
+                    warn!("Invalid static IPv4 settings, using DHCP: {:?}", e);
+                    self.dhcp_settings()
+                }
+            },
+        };
+        NetworkSettings { ipv4, ipv6 }
+    }
+
+    fn dhcp_settings(&self) -> Ipv4Settings {
+        let hostname = self.get(keys::NET_HOSTNAME).and_then(|name| match net_config::parse_hostname(name) {
+            Ok(hostname) => Some(hostname),
+            Err(e) => {
+                warn!("Invalid hostname '{}' ignored: {:?}", name, e);
+                None
+            }
+        });
+        Ipv4Settings::Dhcp { hostname }
+    }
+
+    fn static_settings(&self, cidr: &str) -> Result<StaticIpv4, NetConfigError> {
+        let (address, prefix) = net_config::parse_ipv4_cidr(cidr)?;
+        let gateway = self.get(keys::NET_GATEWAY).map(net_config::parse_ipv4).transpose()?;
+        let dns_servers = self.get(keys::NET_DNS).map(net_config::parse_dns_servers).transpose()?.unwrap_or_default();
+        Ok(StaticIpv4 {
+            address: embassy_net::Ipv4Cidr::new(address, prefix),
+            gateway,
+            dns_servers,
+        })
+    }
+
+    pub fn mqtt_port(&self) -> Option<u16> {
+        let text = self.get(keys::MQTT_PORT)?;
+        let port = provisioning::parse_port(text);
+        if port.is_none() {
+            warn!("Invalid MQTT port '{}' ignored", text);
+        }
+        port
+    }
+
+    pub fn node_address(&self) -> Option<NodeAddress> {
+        let text = self.get(keys::NODE_ID)?;
+        let address = node_config::parse_node_address(text);
+        if address.is_none() {
+            warn!("Invalid node address '{}' ignored", text);
+        }
+        address
+    }
+
+    /// `None` (clear frames) unless both keys are set and valid.
+    pub fn esp_now_security(&self) -> Option<EspNowSecurity> {
+        let pmk = self.get(keys::ESPNOW_PMK);
+        let lmk = self.get(keys::ESPNOW_LMK);
+        match (pmk.and_then(node_config::parse_key), lmk.and_then(node_config::parse_key)) {
+            (Some(pmk), Some(lmk)) => Some(EspNowSecurity { pmk, lmk }),
+            _ => {
+                if pmk.is_some() || lmk.is_some() {
+                    warn!("Invalid or incomplete ESP-NOW keys, frames go unencrypted");
+                }
+                None
+            }
+        }
+    }
+
+    pub fn esp_now_channel(&self) -> Option<u8> {
+        self.get(keys::ESPNOW_CHANNEL).and_then(node_config::parse_channel)
+    }
+
+    /// Defaults for whatever is missing or not a number.
+    pub fn gate_timing(&self) -> GateTiming {
+        let defaults = GateTiming::default();
+        GateTiming {
+            hold_ms: match self.number(keys::GATE_HOLD_MS) {
+                Some(0) => None,
+                Some(hold_ms) => Some(hold_ms),
+                None => defaults.hold_ms,
+            },
+            movement_timeout_ms: self.number(keys::GATE_MOVEMENT_TIMEOUT_MS).unwrap_or(defaults.movement_timeout_ms),
+            close_after_clear_ms: match self.number(keys::GATE_CLEAR_CLOSE_MS) {
+                Some(0) => None,
+                Some(close_ms) => Some(close_ms),
+                None => defaults.close_after_clear_ms,
+            },
+        }
+    }
+
+    pub fn actuator_kind(&self) -> ActuatorKind {
+        match self.get(keys::GATE_ACTUATOR) {
+            None => ActuatorKind::default(),
+            Some(text) => ActuatorKind::parse(text).unwrap_or_else(|| {
+                warn!("Unknown actuator '{}', using the servo", text);
+                ActuatorKind::default()
+            }),
+        }
+    }
+
+    pub fn presence_kind(&self) -> PresenceKind {
+        match self.get(keys::PRESENCE_SENSOR) {
+            None => PresenceKind::default(),
+            Some(text) => PresenceKind::parse(text).unwrap_or_else(|| {
+                warn!("Unknown presence sensor '{}', running without one", text);
+                PresenceKind::default()
+            }),
+        }
+    }
+
+    /// Defaults for whatever is missing or invalid.
+    pub fn presence_settings(&self) -> PresenceSettings {
+        let defaults = PresenceSettings::default();
+        PresenceSettings {
+            assert_ms: self.number(keys::PRESENCE_ASSERT_MS).unwrap_or(defaults.assert_ms),
+            clear_ms: self.number(keys::PRESENCE_CLEAR_MS).unwrap_or(defaults.clear_ms),
+            active_high: match self.get(keys::PRESENCE_ACTIVE_LEVEL).map(str::trim) {
+                Some("low") => false,
+                Some("high") => true,
+                _ => defaults.active_high,
+            },
+            range_cm: self.number(keys::PRESENCE_RANGE_CM).map_or(defaults.range_cm, |cm| cm.min(u32::MAX as u64) as u32),
+        }
+    }
+
+    /// Defaults for whatever is missing or invalid.
+    pub fn relay_settings(&self) -> RelaySettings {
+        let defaults = RelaySettings::default();
+        RelaySettings {
+            pulse_ms: self.number(keys::RELAY_PULSE_MS).unwrap_or(defaults.pulse_ms),
+            active_high: match self.get(keys::RELAY_ACTIVE_LEVEL).map(str::trim) {
+                Some("low") => false,
+                Some("high") => true,
+                _ => defaults.active_high,
+            },
+            lockout_ms: self.number(keys::RELAY_LOCKOUT_MS).unwrap_or(defaults.lockout_ms),
+            travel_ms: self.number(keys::RELAY_TRAVEL_MS).unwrap_or(defaults.travel_ms),
+        }
+    }
+
+    /// Defaults for whatever is missing; a calibration that does not fit a
+    /// 50 Hz period is dropped as a whole.
+    pub fn servo_settings(&self) -> ServoSettings {
+        let defaults = ServoSettings::default();
+        let default_calibration = defaults.calibration;
+        let angle = |key, default: u16| self.number(key).map_or(default, |deg| deg.min(u16::MAX as u64) as u16);
+        let mut calibration = ServoCalibration {
+            min_pulse_us: self.number(keys::SERVO_MIN_PULSE_US).map_or(default_calibration.min_pulse_us, |us| us as u32),
+            max_pulse_us: self.number(keys::SERVO_MAX_PULSE_US).map_or(default_calibration.max_pulse_us, |us| us as u32),
+            open_angle_deg: angle(keys::SERVO_OPEN_ANGLE, default_calibration.open_angle_deg),
+            close_angle_deg: angle(keys::SERVO_CLOSE_ANGLE, default_calibration.close_angle_deg),
+        };
+        if !calibration.is_valid() {
+            warn!("Invalid servo calibration {:?}, using the defaults", calibration);
+            calibration = default_calibration;
+        }
+
+        let trapezoid = || MotionProfile::Trapezoid {
+            speed_deg_s: self.number(keys::SERVO_SPEED_DEG_S).map_or(servo::DEFAULT_SPEED_DEG_S, |speed| speed.max(1) as u32),
+            accel_deg_s2: self.number(keys::SERVO_ACCEL_DEG_S2).map_or(servo::DEFAULT_ACCEL_DEG_S2, |accel| accel.max(1) as u32),
+        };
+        let profile = match self.get(keys::SERVO_PROFILE).map(str::trim) {
+            None | Some("trapezoid") => trapezoid(),
+            Some("eased") => MotionProfile::Eased {
+                duration_ms: self.number(keys::SERVO_MOVE_MS).map_or(servo::DEFAULT_EASED_MS, |ms| ms as u32),
+            },
+            Some("jump") => MotionProfile::Jump,
+            Some(text) => {
+                warn!("Unknown servo profile '{}', using a trapezoid", text);
+                trapezoid()
+            }
+        };
+
+        ServoSettings {
+            calibration,
+            profile,
+            release_after_ms: match self.number(keys::SERVO_RELEASE_MS) {
+                Some(0) => None,
+                Some(release_ms) => Some(release_ms),
+                None => defaults.release_after_ms,
+            },
+        }
+    }
+
+    fn number(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(|text| text.trim().parse().ok())
+    }
+
+    /// Setting that must outlive the store, e.g. a broker hostname handed to
+    /// a `'static` config. Leaked once at startup.
+    pub fn get_static(&self, key: &str) -> Option<&'static str> {
+        self.get(key).map(|value| String::leak(String::from(value)) as &'static str)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn keys_fit_in_a_record() {
+        let fallbacks = keys::WIFI_FALLBACKS.into_iter().flat_map(|(ssid, password)| [ssid, password]);
+        let all = [
+            keys::WIFI_SSID,
+            keys::WIFI_PASSWORD,
+            keys::MQTT_HOST,
+            keys::MQTT_PORT,
+            keys::MQTT_USERNAME,
+            keys::MQTT_PASSWORD,
+            keys::HTTP_TOKEN,
+            keys::NET_IPV4,
+            keys::NET_GATEWAY,
+            keys::NET_DNS,
+            keys::NET_HOSTNAME,
+            keys::NET_IPV6,
+            keys::SITE_GATES,
+            keys::NODE_ID,
+            keys::ESPNOW_PMK,
+            keys::ESPNOW_LMK,
+            keys::ESPNOW_CHANNEL,
+            keys::GATE_HOLD_MS,
+            keys::GATE_MOVEMENT_TIMEOUT_MS,
+            keys::GATE_CLEAR_CLOSE_MS,
+            keys::GATE_ACTUATOR,
+            keys::RELAY_PULSE_MS,
+            keys::RELAY_ACTIVE_LEVEL,
+            keys::RELAY_LOCKOUT_MS,
+            keys::RELAY_TRAVEL_MS,
+            keys::SERVO_MIN_PULSE_US,
+            keys::SERVO_MAX_PULSE_US,
+            keys::SERVO_OPEN_ANGLE,
+            keys::SERVO_CLOSE_ANGLE,
+            keys::SERVO_PROFILE,
+            keys::SERVO_SPEED_DEG_S,
+            keys::SERVO_ACCEL_DEG_S2,
+            keys::SERVO_MOVE_MS,
+            keys::SERVO_RELEASE_MS,
+            keys::PRESENCE_SENSOR,
+            keys::PRESENCE_ACTIVE_LEVEL,
+            keys::PRESENCE_RANGE_CM,
+            keys::PRESENCE_ASSERT_MS,
+            keys::PRESENCE_CLEAR_MS,
+        ];
+        for key in all.into_iter().chain(fallbacks) {
+            assert!(key.len() <= config_record::MAX_KEY_LENGTH, "key '{}' is too long", key);
+        }
+    }
+
+    #[test_case]
+    fn full_record_fits_a_sector() {
+        let entry = 2 + config_record::MAX_KEY_LENGTH + config_record::MAX_VALUE_LENGTH;
+        assert!(config_record::CONFIG_HEADER_LENGTH + config_record::MAX_ENTRIES * entry <= CONFIG_SECTOR_SIZE);
+    }
+}
@@ -17,10 +17,19 @@ use esp_hal::peripherals::{GPIO14, GPIO26, GPIO18};
 use core::default::Default;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDevice;
 use embassy_sync::mutex::Mutex as AsyncMutex;
-use crate::protocol::lora as lora_protocol;
+use crate::protocol::{discovery::RadioParameters, lora as lora_protocol};
 
 
 const LORA_FREQUENCY_IN_HZ: u32 = 903_900_000;
+const LORA_TX_POWER_DBM: i32 = 20;
+/// Must match the modulation set up in `Lora::new`; reported in discovery.
+pub const RADIO_PARAMETERS: RadioParameters = RadioParameters {
+    frequency_hz: LORA_FREQUENCY_IN_HZ,
+    spreading_factor: 10,
+    bandwidth_khz: 250,
+    coding_rate: "4/8",
+    tx_power_dbm: LORA_TX_POWER_DBM,
+};
 pub const PAYLOAD_LENGTH: usize = 255;
 pub type OutgoingMessage = lora_protocol::OutgoingFrame<PAYLOAD_LENGTH>;
 pub type DecodedProtocolMessage<'a> = lora_protocol::LoraEnvelope;
@@ -148,7 +157,7 @@ impl<'d> Lora<'d>
     }
 
     pub async fn send(&mut self, payload: &[u8]) -> Result<(), RadioError> {
-        match self.driver.prepare_for_tx(&self.modulation, &mut self.tx_packet_params, 20, payload).await {
+        match self.driver.prepare_for_tx(&self.modulation, &mut self.tx_packet_params, LORA_TX_POWER_DBM, payload).await {
             Ok(()) => {},
             Err(e) => {
                 error!("Failed to prepare for TX: {:?}", e);
@@ -2,4 +2,8 @@ pub mod display;
 pub mod lora;
 pub mod peripheral_manager;
 pub mod wifi;
-pub mod servo_motor;
\ No newline at end of file
+pub mod servo_motor;pub mod config_store;
+
+pub mod actuator;
+pub mod relay;
+pub mod presence;
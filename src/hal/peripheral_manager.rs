@@ -1,6 +1,6 @@
 use embassy_sync::blocking_mutex::Mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use esp_hal::peripherals::{Peripherals, I2C0, SPI2, GPIO4, GPIO5, GPIO19, GPIO27, GPIO26, GPIO18, GPIO14, GPIO15, GPIO16, GPIO13, TIMG0, TIMG1, RNG, WIFI, LEDC};
+use esp_hal::peripherals::{Peripherals, I2C0, SPI2, GPIO4, GPIO5, GPIO19, GPIO27, GPIO26, GPIO18, GPIO14, GPIO15, GPIO16, GPIO13, GPIO22, GPIO23, GPIO32, GPIO34, TIMG0, TIMG1, RNG, WIFI, LEDC};
 use esp_hal::timer::timg::TimerGroup;
 use core::cell::RefCell;
 use core::option::Option;
@@ -39,6 +39,19 @@ pub struct ServoPeripherals {
     pub ledc: LEDC<'static>,
 }
 
+/// Dry-contact relays of a barrier controller's open and close inputs.
+pub struct RelayPeripherals {
+    pub open: GPIO22<'static>,
+    pub close: GPIO23<'static>,
+}
+
+/// Vehicle presence sensor: `input` is the contact of a loop detector or
+/// IR beam, or the echo of an ultrasonic sensor fired through `trigger`.
+pub struct PresencePeripherals {
+    pub input: GPIO34<'static>,
+    pub trigger: GPIO32<'static>,
+}
+
 /// Centralized peripheral manager
 pub struct PeripheralManager {
     display_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<DisplayPeripherals>>>,
@@ -46,6 +59,8 @@ pub struct PeripheralManager {
     wifi_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<WifiPeripherals>>>,
     time_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<TIMG1<'static>>>>,
     servo_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<ServoPeripherals>>>,
+    relay_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<RelayPeripherals>>>,
+    presence_peripherals: Mutex<CriticalSectionRawMutex, RefCell<Option<PresencePeripherals>>>,
 }
 
 impl PeripheralManager {
@@ -77,6 +92,16 @@ impl PeripheralManager {
             pin: peripherals.GPIO13,
             ledc: peripherals.LEDC,
         };
+
+        let relay_peripherals = RelayPeripherals {
+            open: peripherals.GPIO22,
+            close: peripherals.GPIO23,
+        };
+
+        let presence_peripherals = PresencePeripherals {
+            input: peripherals.GPIO34,
+            trigger: peripherals.GPIO32,
+        };
         
         Self {
             display_peripherals: Mutex::new(RefCell::new(Some(display_peripherals))),
@@ -84,6 +109,8 @@ impl PeripheralManager {
             wifi_peripherals: Mutex::new(RefCell::new(Some(wifi_peripherals))),
             time_peripherals: Mutex::new(RefCell::new(Some(peripherals.TIMG1))),
             servo_peripherals: Mutex::new(RefCell::new(Some(servo_peripherals))),
+            relay_peripherals: Mutex::new(RefCell::new(Some(relay_peripherals))),
+            presence_peripherals: Mutex::new(RefCell::new(Some(presence_peripherals))),
         }
     }
 
@@ -111,6 +138,18 @@ impl PeripheralManager {
         })
     }
 
+    pub fn take_relay_peripherals(&self) -> Option<RelayPeripherals> {
+        self.relay_peripherals.lock(|cell| {
+            cell.borrow_mut().take()
+        })
+    }
+
+    pub fn take_presence_peripherals(&self) -> Option<PresencePeripherals> {
+        self.presence_peripherals.lock(|cell| {
+            cell.borrow_mut().take()
+        })
+    }
+
     pub fn take_time_peripherals(&self) -> Option<TIMG1> {
         self.time_peripherals.lock(|cell| {
             cell.borrow_mut().take()
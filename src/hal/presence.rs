@@ -0,0 +1,359 @@
+//! Vehicle presence under the barrier. A `PresenceSensor` gives raw
+//! readings; `Debounced` turns them into a settled state for the
+//! `GateController`. A reading that fails counts as a vehicle, so a broken
+//! sensor keeps the gate from closing instead of letting it close blind.
+
+use core::{convert::Infallible, fmt::Debug};
+
+use embassy_futures::select::{select, Either};
+use embedded_hal::digital::{InputPin, OutputPin};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+pub trait PresenceSensor {
+    type Error: Debug;
+
+    /// Raw reading: is something there right now?
+    fn is_present(&mut self, now_ms: u64) -> Result<bool, Self::Error>;
+}
+
+/// Gate without a sensor: never sees anything.
+pub struct NoPresence;
+
+impl PresenceSensor for NoPresence {
+    type Error = Infallible;
+
+    fn is_present(&mut self, _now_ms: u64) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+}
+
+/// Sensor a node reads, from the `presence.sensor` setting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PresenceKind {
+    #[default]
+    None,
+    /// Loop detector relay or IR beam receiver output.
+    Contact,
+    /// HC-SR04 style trigger/echo pair.
+    Ultrasonic,
+}
+
+impl PresenceKind {
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.trim() {
+            "none" => Some(PresenceKind::None),
+            "contact" => Some(PresenceKind::Contact),
+            "ultrasonic" => Some(PresenceKind::Ultrasonic),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PresenceSettings {
+    /// Readings must agree this long before a vehicle counts as present.
+    /// Short: a vehicle must stop the arm quickly.
+    pub assert_ms: u64,
+    /// And this long before it counts as gone.
+    pub clear_ms: u64,
+    /// Contact level that means a vehicle.
+    pub active_high: bool,
+    /// Anything nearer than this is a vehicle (ultrasonic only).
+    pub range_cm: u32,
+}
+
+impl Default for PresenceSettings {
+    fn default() -> Self {
+        Self {
+            assert_ms: 50,
+            clear_ms: 500,
+            active_high: false,
+            range_cm: 250,
+        }
+    }
+}
+
+/// Dry contact or open-collector output, e.g. a loop detector relay or
+/// an IR beam receiver.
+pub struct ContactSensor<P: InputPin> {
+    pin: P,
+    active_high: bool,
+}
+
+impl<P: InputPin> ContactSensor<P> {
+    pub fn new(pin: P, active_high: bool) -> Self {
+        Self { pin, active_high }
+    }
+}
+
+impl<P: InputPin> PresenceSensor for ContactSensor<P> {
+    type Error = P::Error;
+
+    fn is_present(&mut self, _now_ms: u64) -> Result<bool, Self::Error> {
+        Ok(self.pin.is_high()? == self.active_high)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UltrasonicError<T, E> {
+    Trigger(T),
+    Echo(E),
+    /// The echo never started: sensor missing or not powered.
+    NoEcho,
+}
+
+/// Round trip of sound over one centimetre.
+const ECHO_US_PER_CM: u32 = 58;
+const TRIGGER_US: u32 = 10;
+const ECHO_START_TIMEOUT_US: u32 = 1_000;
+
+/// Trigger/echo ultrasonic ranger. Timing an echo takes up to
+/// `range_cm * 58` µs, too long to spend blocked in the gate loop, so the
+/// reading is taken by the async `measure` and `is_present` returns the
+/// last one. A failed measurement counts as a vehicle.
+pub struct UltrasonicSensor<T: OutputPin, E: InputPin + Wait, D: DelayNs> {
+    trigger: T,
+    echo: E,
+    delay: D,
+    range_cm: u32,
+    present: bool,
+}
+
+impl<T: OutputPin, E: InputPin + Wait, D: DelayNs> UltrasonicSensor<T, E, D> {
+    /// `trigger` must already be low.
+    pub fn new(trigger: T, echo: E, delay: D, range_cm: u32) -> Self {
+        Self {
+            trigger,
+            echo,
+            delay,
+            range_cm,
+            present: true,
+        }
+    }
+
+    /// Fires one ping and waits for its echo, at most for the echo of
+    /// `range_cm`, since anything farther is not a vehicle.
+    pub async fn measure(&mut self) -> Result<bool, UltrasonicError<T::Error, E::Error>> {
+        let reading = self.ping().await;
+        self.present = !matches!(reading, Ok(false));
+        reading
+    }
+
+    async fn ping(&mut self) -> Result<bool, UltrasonicError<T::Error, E::Error>> {
+        self.trigger.set_high().map_err(UltrasonicError::Trigger)?;
+        self.delay.delay_us(TRIGGER_US).await;
+        self.trigger.set_low().map_err(UltrasonicError::Trigger)?;
+
+        match select(self.echo.wait_for_high(), self.delay.delay_us(ECHO_START_TIMEOUT_US)).await {
+            Either::First(started) => started.map_err(UltrasonicError::Echo)?,
+            Either::Second(()) => return Err(UltrasonicError::NoEcho),
+        }
+        // Echo over before the range limit: something is within range
+        let range_us = self.range_cm.saturating_mul(ECHO_US_PER_CM);
+        match select(self.echo.wait_for_low(), self.delay.delay_us(range_us)).await {
+            Either::First(ended) => ended.map(|()| true).map_err(UltrasonicError::Echo),
+            Either::Second(()) => Ok(false),
+        }
+    }
+}
+
+impl<T: OutputPin, E: InputPin + Wait, D: DelayNs> PresenceSensor for UltrasonicSensor<T, E, D> {
+    type Error = Infallible;
+
+    fn is_present(&mut self, _now_ms: u64) -> Result<bool, Self::Error> {
+        Ok(self.present)
+    }
+}
+
+/// Settled state of a sensor: a change only counts once every reading has
+/// agreed for `assert_ms` (to present) or `clear_ms` (to clear). Starts as
+/// present, so nothing closes until the sensor has read clear once.
+pub struct Debounced<S: PresenceSensor> {
+    sensor: S,
+    settings: PresenceSettings,
+    present: bool,
+    /// Reading that differs from `present`, and since when.
+    changing_since_ms: Option<u64>,
+}
+
+impl<S: PresenceSensor> Debounced<S> {
+    pub fn new(sensor: S, settings: PresenceSettings) -> Self {
+        Self {
+            sensor,
+            settings,
+            present: true,
+            changing_since_ms: None,
+        }
+    }
+
+    pub fn is_present(&self) -> bool {
+        self.present
+    }
+
+    pub fn sensor(&mut self) -> &mut S {
+        &mut self.sensor
+    }
+
+    /// Takes a reading; returns the new state when it just settled.
+    pub fn update(&mut self, now_ms: u64) -> Option<bool> {
+        let reading = self.sensor.is_present(now_ms).unwrap_or(true);
+        if reading == self.present {
+            self.changing_since_ms = None;
+            return None;
+        }
+
+        let since_ms = *self.changing_since_ms.get_or_insert(now_ms);
+        let settle_ms = if reading { self.settings.assert_ms } else { self.settings.clear_ms };
+        if now_ms.saturating_sub(since_ms) < settle_ms {
+            return None;
+        }
+        self.present = reading;
+        self.changing_since_ms = None;
+        Some(reading)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::pending;
+
+    use embassy_futures::block_on;
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    /// Trigger pin that only remembers its level.
+    struct FakeTrigger {
+        high: bool,
+    }
+
+    impl ErrorType for FakeTrigger {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakeTrigger {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            self.high = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.high = true;
+            Ok(())
+        }
+    }
+
+    /// Echo pin whose edges either come at once or never.
+    struct FakeEcho {
+        starts: bool,
+        ends: bool,
+    }
+
+    impl ErrorType for FakeEcho {
+        type Error = Infallible;
+    }
+
+    impl InputPin for FakeEcho {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(false)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    impl Wait for FakeEcho {
+        async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+            if !self.starts {
+                pending::<()>().await;
+            }
+            Ok(())
+        }
+
+        async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+            if !self.ends {
+                pending::<()>().await;
+            }
+            Ok(())
+        }
+
+        async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+            self.wait_for_high().await
+        }
+
+        async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+            self.wait_for_low().await
+        }
+
+        async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+            pending().await
+        }
+    }
+
+    /// Every delay is already over.
+    struct FakeDelay;
+
+    impl DelayNs for FakeDelay {
+        async fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    fn ultrasonic(starts: bool, ends: bool) -> UltrasonicSensor<FakeTrigger, FakeEcho, FakeDelay> {
+        UltrasonicSensor::new(FakeTrigger { high: false }, FakeEcho { starts, ends }, FakeDelay, 250)
+    }
+
+    /// Reads `present` from the first millisecond on.
+    struct FakeSensor {
+        present: bool,
+    }
+
+    impl PresenceSensor for FakeSensor {
+        type Error = ();
+
+        fn is_present(&mut self, _now_ms: u64) -> Result<bool, Self::Error> {
+            Ok(self.present)
+        }
+    }
+
+    #[test_case]
+    fn ultrasonic_reports_the_last_measurement() {
+        let mut sensor = ultrasonic(true, true);
+        // Nothing measured yet: a vehicle, like a failed reading
+        assert_eq!(sensor.is_present(0), Ok(true));
+
+        assert_eq!(block_on(sensor.measure()), Ok(true));
+        assert!(!sensor.trigger.high);
+        assert_eq!(sensor.is_present(0), Ok(true));
+
+        // Echo still going when the range is over
+        sensor.echo.ends = false;
+        assert_eq!(block_on(sensor.measure()), Ok(false));
+        assert_eq!(sensor.is_present(0), Ok(false));
+
+        sensor.echo.starts = false;
+        assert_eq!(block_on(sensor.measure()), Err(UltrasonicError::NoEcho));
+        assert_eq!(sensor.is_present(0), Ok(true));
+    }
+
+    #[test_case]
+    fn debounce_waits_for_agreeing_readings() {
+        let settings = PresenceSettings::default();
+        let mut presence = Debounced::new(FakeSensor { present: false }, settings);
+        assert!(presence.is_present());
+
+        assert_eq!(presence.update(0), None);
+        assert_eq!(presence.update(settings.clear_ms - 1), None);
+        assert_eq!(presence.update(settings.clear_ms), Some(false));
+        assert!(!presence.is_present());
+
+        // A blip shorter than `assert_ms` does not count
+        presence.sensor().present = true;
+        assert_eq!(presence.update(1_000), None);
+        presence.sensor().present = false;
+        assert_eq!(presence.update(1_020), None);
+        presence.sensor().present = true;
+        assert_eq!(presence.update(1_040), None);
+        assert_eq!(presence.update(1_040 + settings.assert_ms), Some(true));
+        assert!(presence.is_present());
+    }
+}
@@ -0,0 +1,284 @@
+use embedded_hal::digital::OutputPin;
+use esp_hal::gpio::{Level, Output, OutputConfig};
+
+use crate::hal::{
+    actuator::{GateActuator, GateTarget, Motion},
+    peripheral_manager::RelayPeripherals,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelaySettings {
+    /// How long a contact stays closed for one command.
+    pub pulse_ms: u64,
+    /// Relay boards differ: most optocoupled ones switch on a low input.
+    pub active_high: bool,
+    /// Quiet time after a pulse before the next one, so the barrier
+    /// controller sees two separate commands.
+    pub lockout_ms: u64,
+    /// Time the barrier takes to move; the contacts give no feedback.
+    pub travel_ms: u64,
+}
+
+impl Default for RelaySettings {
+    fn default() -> Self {
+        Self {
+            pulse_ms: 500,
+            active_high: true,
+            lockout_ms: 1_000,
+            travel_ms: 4_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pulse {
+    target: GateTarget,
+    ends_ms: u64,
+}
+
+/// Barrier controller driven by dry-contact pulses: one relay on its open
+/// input, one on its close input. A command during the lockout of the
+/// previous pulse waits for it to end; only the latest one is kept.
+pub struct RelayActuator<P: OutputPin> {
+    open: P,
+    close: P,
+    settings: RelaySettings,
+    pulse: Option<Pulse>,
+    pending: Option<GateTarget>,
+    lockout_until_ms: u64,
+    arrive_at_ms: Option<u64>,
+}
+
+impl RelayActuator<Output<'static>> {
+    pub fn from_peripherals(peripherals: RelayPeripherals, settings: RelaySettings) -> Self {
+        let idle = if settings.active_high { Level::Low } else { Level::High };
+        let open = Output::new(peripherals.open, idle, OutputConfig::default());
+        let close = Output::new(peripherals.close, idle, OutputConfig::default());
+        Self::new(open, close, settings)
+    }
+}
+
+impl<P: OutputPin> RelayActuator<P> {
+    /// Pins must already be at the inactive level.
+    pub fn new(open: P, close: P, settings: RelaySettings) -> Self {
+        Self {
+            open,
+            close,
+            settings,
+            pulse: None,
+            pending: None,
+            lockout_until_ms: 0,
+            arrive_at_ms: None,
+        }
+    }
+
+    pub fn settings(&self) -> RelaySettings {
+        self.settings
+    }
+
+    fn set(&mut self, target: GateTarget, active: bool) -> Result<(), P::Error> {
+        let pin = match target {
+            GateTarget::Open => &mut self.open,
+            GateTarget::Closed => &mut self.close,
+        };
+        if active == self.settings.active_high {
+            pin.set_high()
+        } else {
+            pin.set_low()
+        }
+    }
+
+    fn start_pulse(&mut self, target: GateTarget, now_ms: u64) -> Result<(), P::Error> {
+        self.set(target, true)?;
+        let ends_ms = now_ms + self.settings.pulse_ms;
+        self.pulse = Some(Pulse { target, ends_ms });
+        self.lockout_until_ms = ends_ms + self.settings.lockout_ms;
+        self.arrive_at_ms = Some(now_ms + self.settings.travel_ms);
+        Ok(())
+    }
+
+    fn end_pulse(&mut self) -> Result<(), P::Error> {
+        match self.pulse.take() {
+            Some(pulse) => self.set(pulse.target, false),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<P: OutputPin> GateActuator for RelayActuator<P> {
+    type Error = P::Error;
+
+    fn drive(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        if self.pulse.is_some() || now_ms < self.lockout_until_ms {
+            self.pending = Some(target);
+            return Ok(());
+        }
+        self.pending = None;
+        self.start_pulse(target, now_ms)
+    }
+
+    /// Cuts the pulse in progress and ignores the lockout, so the other
+    /// contact closes now instead of up to `pulse_ms + lockout_ms` later.
+    fn reverse(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        self.pending = None;
+        self.end_pulse()?;
+        self.start_pulse(target, now_ms)
+    }
+
+    fn update(&mut self, now_ms: u64) -> Result<Motion, Self::Error> {
+        if self.pulse.is_some_and(|pulse| now_ms >= pulse.ends_ms) {
+            self.end_pulse()?;
+        }
+        if self.pulse.is_none() && now_ms >= self.lockout_until_ms {
+            if let Some(target) = self.pending.take() {
+                self.start_pulse(target, now_ms)?;
+            }
+        }
+
+        let arrived = self.pulse.is_none()
+            && self.pending.is_none()
+            && self.arrive_at_ms.is_none_or(|arrive_at_ms| now_ms >= arrive_at_ms);
+        Ok(if arrived { Motion::Arrived } else { Motion::Moving })
+    }
+
+    /// Releases the contacts; the barrier controller finishes its own move.
+    fn stop(&mut self) -> Result<(), Self::Error> {
+        self.pending = None;
+        self.arrive_at_ms = None;
+        self.end_pulse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_hal::digital::ErrorType;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakePin {
+        high: bool,
+        /// Rising edges, i.e. pulses seen by the barrier controller.
+        pulses: u32,
+    }
+
+    impl ErrorType for FakePin {
+        type Error = Infallible;
+    }
+
+    impl OutputPin for FakePin {
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = false;
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            if !self.high {
+                self.pulses += 1;
+            }
+            self.high = true;
+            Ok(())
+        }
+    }
+
+    fn relay() -> RelayActuator<FakePin> {
+        RelayActuator::new(FakePin::default(), FakePin::default(), RelaySettings::default())
+    }
+
+    #[test_case]
+    fn pulse_lasts_pulse_ms() {
+        let mut relay = relay();
+        relay.drive(GateTarget::Open, 0).unwrap();
+        assert!(relay.open.high);
+        assert!(!relay.close.high);
+
+        assert_eq!(relay.update(499).unwrap(), Motion::Moving);
+        assert!(relay.open.high);
+        assert_eq!(relay.update(500).unwrap(), Motion::Moving);
+        assert!(!relay.open.high);
+
+        // No feedback: it counts as arrived once the travel time is over
+        assert_eq!(relay.update(3_999).unwrap(), Motion::Moving);
+        assert_eq!(relay.update(4_000).unwrap(), Motion::Arrived);
+        assert_eq!(relay.open.pulses, 1);
+    }
+
+    #[test_case]
+    fn active_low_board_pulses_low() {
+        let settings = RelaySettings { active_high: false, ..RelaySettings::default() };
+        let mut relay = RelayActuator::new(FakePin { high: true, pulses: 0 }, FakePin { high: true, pulses: 0 }, settings);
+
+        relay.drive(GateTarget::Closed, 0).unwrap();
+        assert!(!relay.close.high);
+        assert!(relay.open.high);
+        relay.update(500).unwrap();
+        assert!(relay.close.high);
+    }
+
+    #[test_case]
+    fn command_waits_for_the_lockout() {
+        let mut relay = relay();
+        relay.drive(GateTarget::Open, 0).unwrap();
+        relay.update(500).unwrap();
+
+        relay.drive(GateTarget::Closed, 600).unwrap();
+        assert!(!relay.close.high);
+        assert_eq!(relay.update(1_499).unwrap(), Motion::Moving);
+        assert!(!relay.close.high);
+
+        relay.update(1_500).unwrap();
+        assert!(relay.close.high);
+        relay.update(2_000).unwrap();
+        assert!(!relay.close.high);
+        assert_eq!(relay.close.pulses, 1);
+    }
+
+    #[test_case]
+    fn only_the_latest_pending_target_is_kept() {
+        let mut relay = relay();
+        relay.drive(GateTarget::Open, 0).unwrap();
+        relay.drive(GateTarget::Closed, 100).unwrap();
+        relay.drive(GateTarget::Open, 200).unwrap();
+
+        relay.update(500).unwrap();
+        // Still pending after the travel time of the first pulse
+        assert_eq!(relay.update(1_499).unwrap(), Motion::Moving);
+        relay.update(1_500).unwrap();
+        assert!(relay.open.high);
+        assert_eq!(relay.open.pulses, 2);
+        assert_eq!(relay.close.pulses, 0);
+    }
+
+    #[test_case]
+    fn reverse_skips_the_lockout() {
+        let mut relay = relay();
+        relay.drive(GateTarget::Closed, 0).unwrap();
+        relay.drive(GateTarget::Closed, 100).unwrap();
+
+        relay.reverse(GateTarget::Open, 200).unwrap();
+        assert!(!relay.close.high);
+        assert!(relay.open.high);
+
+        // The pending close was dropped with the cut pulse
+        relay.update(700).unwrap();
+        relay.update(2_000).unwrap();
+        assert!(!relay.open.high);
+        assert_eq!(relay.close.pulses, 1);
+        assert_eq!(relay.open.pulses, 1);
+        assert_eq!(relay.update(4_200).unwrap(), Motion::Arrived);
+    }
+
+    #[test_case]
+    fn stop_releases_the_contacts() {
+        let mut relay = relay();
+        relay.drive(GateTarget::Open, 0).unwrap();
+        relay.drive(GateTarget::Closed, 100).unwrap();
+
+        relay.stop().unwrap();
+        assert!(!relay.open.high);
+        assert_eq!(relay.update(2_000).unwrap(), Motion::Arrived);
+        assert_eq!(relay.close.pulses, 0);
+    }
+}
@@ -1,26 +1,52 @@
-use crate::hal::peripheral_manager::ServoPeripherals;
-use esp_hal::ledc::channel::ChannelHW;
+use crate::hal::{actuator::{GateActuator, GateTarget, Motion}, peripheral_manager::ServoPeripherals};
+use crate::protocol::servo::{self, MotionProfile, ServoCalibration, ServoMove};
 //use esp_hal::peripherals::{GPIO12, LEDC};
-use esp_hal::gpio::DriveMode;
 use esp_hal::ledc::channel::ChannelIFace;
 use esp_hal::ledc::timer::TimerIFace;
 use esp_hal::ledc::{HighSpeed, Ledc, channel, timer};
 use esp_hal::time::Rate;
 use static_cell::StaticCell;
-use embedded_hal::pwm::SetDutyCycle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoSettings {
+    pub calibration: ServoCalibration,
+    pub profile: MotionProfile,
+    /// Time the last angle is held after a move before the pulses stop;
+    /// `None` holds it for good (e.g. an arm pulled down by its weight).
+    pub release_after_ms: Option<u64>,
+}
+
+impl Default for ServoSettings {
+    fn default() -> Self {
+        Self {
+            calibration: ServoCalibration::default(),
+            profile: MotionProfile::default(),
+            release_after_ms: Some(300),
+        }
+    }
+}
 
 pub struct ServoMotor {
     // pin: GPIO12<'static>,
     // ledc_pin: LEDC<'static>,
     ledc: Ledc<'static>,
     pub channel: esp_hal::ledc::channel::Channel<'static, esp_hal::ledc::HighSpeed>,
-    max_duty_cycle: u32,
+    settings: ServoSettings,
+    /// Last angle written; unknown until the first one. The servo gives no
+    /// position feedback.
+    position_mdeg: Option<i32>,
+    motion: Option<ServoMove>,
+    release_at_ms: Option<u64>,
 }
 
 static HSTIMER0: StaticCell<esp_hal::ledc::timer::Timer<'static, esp_hal::ledc::HighSpeed>> = StaticCell::new();
 
 impl ServoMotor {
     pub fn new(peripherals: ServoPeripherals) -> Self {
+        Self::with_settings(peripherals, ServoSettings::default())
+    }
+
+    pub fn with_settings(peripherals: ServoPeripherals, settings: ServoSettings) -> Self {
         let pin = peripherals.pin;
         let ledc_pin = peripherals.ledc;
 
@@ -37,42 +63,108 @@ impl ServoMotor {
 
         let hstimer0 = HSTIMER0.init(hstimer0);
 
+        // No pulses until the first angle, so the arm does not jump at boot
         let mut channel = ledc.channel(channel::Number::Channel0, pin);
         channel
             .configure(channel::config::Config {
                 timer: hstimer0,
-                duty_pct: 10,
+                duty_pct: 0,
                 pin_config: channel::config::PinConfig::PushPull,
             })
-            .unwrap();     
-        
-        let max_duty_cycle = channel.max_duty_cycle() as u32;
-
-        Self { 
-            // pin, 
-            // ledc_pin, 
-            ledc, 
+            .unwrap();
+
+        Self {
+            // pin,
+            // ledc_pin,
+            ledc,
             channel,
-            max_duty_cycle
+            settings,
+            position_mdeg: None,
+            motion: None,
+            release_at_ms: None,
         }
     }
-    
-    pub fn set_angle(&mut self, deg: u32) -> Result<(), esp_hal::ledc::channel::Error> {
-        let min_duty = (25 * self.max_duty_cycle) / 1000;
-        let max_duty = (125 * self.max_duty_cycle) / 1000;
-        let duty_gap = max_duty - min_duty;
 
-        let duty = (min_duty + ((deg * duty_gap) / 180) ) as u16;
+    pub fn settings(&self) -> ServoSettings {
+        self.settings
+    }
 
-        self.channel.set_duty_cycle(duty)
+    /// Jumps straight to `deg`, without a motion profile.
+    pub fn set_angle(&mut self, deg: u32) -> Result<(), esp_hal::ledc::channel::Error> {
+        self.motion = None;
+        self.write(deg.min(180) as i32 * 1_000)
     }
 
     pub fn open(&mut self) -> Result<(), esp_hal::ledc::channel::Error> {
-        self.set_angle(135)
+        self.set_angle(self.settings.calibration.open_angle_deg as u32)
     }
 
     pub fn close(&mut self) -> Result<(), esp_hal::ledc::channel::Error> {
-        self.set_angle(180)
+        self.set_angle(self.settings.calibration.close_angle_deg as u32)
+    }
+
+    fn write(&mut self, angle_mdeg: i32) -> Result<(), esp_hal::ledc::channel::Error> {
+        servo::write_angle(&mut self.channel, &self.settings.calibration, angle_mdeg)?;
+        self.position_mdeg = Some(angle_mdeg);
+        Ok(())
+    }
+
+    fn target_mdeg(&self, target: GateTarget) -> i32 {
+        let calibration = self.settings.calibration;
+        let deg = match target {
+            GateTarget::Open => calibration.open_angle_deg,
+            GateTarget::Closed => calibration.close_angle_deg,
+        };
+        deg as i32 * 1_000
     }
 }
 
+impl GateActuator for ServoMotor {
+    type Error = esp_hal::ledc::channel::Error;
+
+    /// Starts from wherever the arm is, so a reversal mid-move is smooth. With
+    /// the position unknown the first move is a jump.
+    fn drive(&mut self, target: GateTarget, now_ms: u64) -> Result<(), Self::Error> {
+        let to_mdeg = self.target_mdeg(target);
+        let from_mdeg = self.position_mdeg.unwrap_or(to_mdeg);
+        let motion = ServoMove {
+            from_mdeg,
+            to_mdeg,
+            started_ms: now_ms,
+            profile: self.settings.profile,
+        };
+        self.release_at_ms = None;
+        self.write(motion.position(now_ms))?;
+        self.motion = Some(motion);
+        Ok(())
+    }
+
+    /// Steps along the profile. A move counts as arrived once the arm has
+    /// settled and, if configured, the holding torque is released.
+    fn update(&mut self, now_ms: u64) -> Result<Motion, Self::Error> {
+        if let Some(motion) = self.motion {
+            self.write(motion.position(now_ms))?;
+            if !motion.is_done(now_ms) {
+                return Ok(Motion::Moving);
+            }
+            self.motion = None;
+            self.release_at_ms = self.settings.release_after_ms.map(|after_ms| now_ms + after_ms);
+        }
+        match self.release_at_ms {
+            Some(release_at_ms) if now_ms < release_at_ms => Ok(Motion::Moving),
+            Some(_) => {
+                self.release_at_ms = None;
+                servo::release(&mut self.channel)?;
+                Ok(Motion::Arrived)
+            }
+            None => Ok(Motion::Arrived),
+        }
+    }
+
+    /// Holds the angle reached so far.
+    fn stop(&mut self) -> Result<(), Self::Error> {
+        self.motion = None;
+        self.release_at_ms = None;
+        Ok(())
+    }
+}
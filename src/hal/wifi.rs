@@ -1,28 +1,179 @@
+use core::net::Ipv6Addr;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel, watch::Watch};
+use embassy_time::{Duration, Instant, Timer};
 use embassy_net::{
-    Config as EmbassyNetConfig, Runner, Stack, StackResources, dns::DnsQueryType, tcp::TcpSocket
+    Config as EmbassyNetConfig, ConfigV6, DhcpConfig, Ipv4Address, Ipv4Cidr, Ipv6Cidr, Runner, Stack, StackResources, StaticConfigV4, StaticConfigV6, dns::DnsQueryType, tcp::TcpSocket,
+    raw::{IpProtocol, IpVersion, PacketMetadata, RawSocket},
 };
 
 use esp_hal::{rng::Rng, timer::timg::TimerGroup};
 use esp_wifi::{
-    wifi::{ClientConfiguration, Configuration, WifiController, WifiDevice, WifiEvent, WifiState},
+    esp_now::EspNow,
+    wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, ScanConfig, WifiController, WifiDevice, WifiEvent as DriverEvent, WifiState},
     EspWifiController,
 };
-use log::{error, info};
+use log::{error, info, warn};
 use static_cell::StaticCell;
 use esp_hal::peripherals::{TIMG0};
-use crate::hal::peripheral_manager::WifiPeripherals;
+use crate::{
+    controller::backoff::Backoff,
+    hal::peripheral_manager::WifiPeripherals,
+    protocol::{
+        net_config::{self, Hostname, MAX_DNS_SERVERS},
+        slaac::{self, RouterAdvert, SlaacPrefix},
+        roaming::{NetworkSelector, ScanEntry, FAILOVER_AFTER, MAX_NETWORKS},
+    },
+};
+
+/// Station credentials. `Debug` never shows the password.
+#[derive(Clone)]
+pub struct WifiCredentials {
+    pub ssid: heapless::String<32>,
+    pub password: heapless::String<64>,
+}
+
+impl WifiCredentials {
+    pub fn new(ssid: &str, password: &str) -> Option<Self> {
+        Some(Self {
+            ssid: heapless::String::try_from(ssid).ok()?,
+            password: heapless::String::try_from(password).ok()?,
+        })
+    }
+}
+
+impl core::fmt::Debug for WifiCredentials {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("WifiCredentials")
+            .field("ssid", &self.ssid)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Networks to try, highest priority first.
+pub type WifiNetworks = heapless::Vec<WifiCredentials, MAX_NETWORKS>;
+
+/// Network the station is currently joined to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveNetwork {
+    pub ssid: heapless::String<32>,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    /// Signal seen in the scan that picked this network.
+    pub rssi: i8,
+}
+
+/// Latest `ActiveNetwork`, `None` while disconnected.
+pub type ActiveNetworkWatch = Watch<CriticalSectionRawMutex, Option<ActiveNetwork>, 4>;
+
+/// Why the station is not (or no longer) connected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Dropped by the access point or out of range after being connected.
+    LinkLost,
+    StartFailed,
+    ScanFailed,
+    NoNetworkInRange,
+    ConfigurationRejected,
+    /// Authentication or association failed.
+    ConnectFailed,
+}
+
+/// Connection events published by the `WifiSupervisor`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WifiEvent {
+    Connected(ActiveNetwork),
+    GotIp(Ipv4Cidr),
+    LostIp,
+    Disconnected(DisconnectReason),
+}
+
+/// Events for up to 4 subscribers; a slow subscriber sees the oldest events
+/// dropped rather than blocking the supervisor.
+pub type WifiEventChannel = PubSubChannel<CriticalSectionRawMutex, WifiEvent, 8, 4, 1>;
+
+/// Address of the gateway on its own setup network.
+pub const ACCESS_POINT_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WifiMode {
+    Station,
+    /// Open access point used for first-time setup.
+    AccessPoint,
+}
+
+/// Fixed IPv4 configuration, e.g. for sites whose firewall rules need it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticIpv4 {
+    pub address: Ipv4Cidr,
+    pub gateway: Option<Ipv4Address>,
+    pub dns_servers: heapless::Vec<Ipv4Address, MAX_DNS_SERVERS>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv4Settings {
+    /// DHCP, announcing `hostname`, or one derived from the MAC address.
+    Dhcp { hostname: Option<Hostname> },
+    Static(StaticIpv4),
+}
+
+/// Station addressing; the setup access point always uses
+/// `ACCESS_POINT_ADDRESS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkSettings {
+    pub ipv4: Ipv4Settings,
+    /// Starts on the EUI-64 link-local address; `SlaacClient` then moves
+    /// to a global one from the router advertisements.
+    pub ipv6: bool,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            ipv4: Ipv4Settings::Dhcp { hostname: None },
+            ipv6: true,
+        }
+    }
+}
+
+/// Addresses the stack is using right now.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkAddresses {
+    pub ipv4: Option<Ipv4Cidr>,
+    pub gateway: Option<Ipv4Address>,
+    pub ipv6: Option<Ipv6Cidr>,
+}
+
+impl NetworkAddresses {
+    pub fn of(stack: &Stack<'_>) -> Self {
+        let v4 = stack.config_v4();
+        Self {
+            ipv4: v4.as_ref().map(|config| config.address),
+            gateway: v4.and_then(|config| config.gateway),
+            ipv6: stack.config_v6().map(|config| config.address),
+        }
+    }
+}
 
 pub struct Wifi {
-    pub ssid: &'static str,
-    pub password: &'static str,
+    mode: WifiMode,
+    networks: WifiNetworks,
+    station_mac: [u8; 6],
     wifi_controller: WifiController<'static>,
+    rng: Rng,
     stack: Stack<'static>,
     runner: Runner<'static, WifiDevice<'static>>,
+    esp_now: Option<EspNow<'static>>,
 }
 
 static WIFI_CONTROLLER: StaticCell<EspWifiController<'static>> = StaticCell::new();
 //static TIMER_GROUP_CELL: StaticCell<TimerGroup<TIMG0>> = StaticCell::new();
-static STACK_RESOURCE_CELL: StaticCell<StackResources<3>> = StaticCell::new();
+/// DHCP, DNS, the SLAAC raw socket, the MQTT connection and room for the
+/// local broker and HTTP API sockets.
+const MAX_SOCKETS: usize = 12;
+static STACK_RESOURCE_CELL: StaticCell<StackResources<MAX_SOCKETS>> = StaticCell::new();
 
 fn log_heap_info(context: &str) {
     let free = esp_alloc::HEAP.free();
@@ -32,14 +183,65 @@ fn log_heap_info(context: &str) {
           context, free, total, (used as f32 / total as f32) * 100.0);
 }
 
+fn station_config(settings: &NetworkSettings, mac: [u8; 6]) -> EmbassyNetConfig {
+    let mut config = match &settings.ipv4 {
+        Ipv4Settings::Dhcp { hostname } => {
+            let hostname = hostname.clone().unwrap_or_else(|| net_config::hostname_from_mac(mac));
+            info!("IPv4: DHCP as '{}'", hostname);
+            let mut dhcp = DhcpConfig::default();
+            dhcp.hostname = heapless08::String::try_from(hostname.as_str()).ok();
+            EmbassyNetConfig::dhcpv4(dhcp)
+        }
+        Ipv4Settings::Static(fixed) => {
+            info!("IPv4: static {} via {:?}", fixed.address, fixed.gateway);
+            EmbassyNetConfig::ipv4_static(StaticConfigV4 {
+                address: fixed.address,
+                gateway: fixed.gateway,
+                dns_servers: fixed.dns_servers.iter().copied().collect(),
+            })
+        }
+    };
+
+    if settings.ipv6 {
+        let address = net_config::link_local_from_mac(mac);
+        info!("IPv6: link-local {}", address);
+        config.ipv6 = ConfigV6::Static(StaticConfigV6 {
+            address: Ipv6Cidr::new(address, 64),
+            gateway: None,
+            dns_servers: Default::default(),
+        });
+    }
+    config
+}
+
 impl Wifi {
-    pub fn new(wifi_peripherals: WifiPeripherals) -> Wifi {
-        
+    /// `networks` and `settings` normally come from the `ConfigStore`. With an
+    /// empty list the station never joins anything; use `new_access_point`
+    /// and the setup portal instead.
+    pub fn new(wifi_peripherals: WifiPeripherals, networks: WifiNetworks, settings: NetworkSettings) -> Wifi {
+        Self::init(wifi_peripherals, networks, WifiMode::Station, settings)
+    }
+
+    /// Station that joins no network, only to carry ESP-NOW (gate nodes).
+    /// Start it with `start_radio`.
+    pub fn new_radio(wifi_peripherals: WifiPeripherals) -> Wifi {
+        Self::init(wifi_peripherals, WifiNetworks::new(), WifiMode::Station, NetworkSettings::default())
+    }
+
+    /// Open access point named `ssid` at `ACCESS_POINT_ADDRESS`, for when no
+    /// station credentials are stored. Clients need the setup portal's DHCP.
+    pub fn new_access_point(wifi_peripherals: WifiPeripherals, ssid: &str) -> Wifi {
+        let mut networks = WifiNetworks::new();
+        let _ = networks.push(WifiCredentials::new(ssid, "").expect("Access point SSID too long"));
+        Self::init(wifi_peripherals, networks, WifiMode::AccessPoint, NetworkSettings::default())
+    }
+
+    fn init(wifi_peripherals: WifiPeripherals, networks: WifiNetworks, mode: WifiMode, settings: NetworkSettings) -> Wifi {
         log_heap_info("Before initializing WiFi controller");
 
-        let ssid: &str = env!("SSID");
-        let password: &str = env!("PASSWORD");
-        info!("SSID: {} | PASSWORD {}", ssid, password);
+        for network in networks.iter() {
+            info!("SSID: {} ({:?})", network.ssid, mode);
+        }
 
         let timer_group = TimerGroup::new(wifi_peripherals.timg0);
         let mut rng = Rng::new(wifi_peripherals.rng);
@@ -65,14 +267,29 @@ impl Wifi {
             };
 
         let seed = (rng.random() as u64) << 32 | rng.random() as u64;
-        let embassy_net_config = EmbassyNetConfig::dhcpv4(Default::default());
+        let station_mac = interface.sta.mac_address();
+        let (device, embassy_net_config) = match mode {
+            WifiMode::Station => {
+                let config = station_config(&settings, station_mac);
+                (interface.sta, config)
+            }
+            WifiMode::AccessPoint => {
+                let [a, b, c, d] = ACCESS_POINT_ADDRESS;
+                let config = EmbassyNetConfig::ipv4_static(StaticConfigV4 {
+                    address: Ipv4Cidr::new(Ipv4Address::new(a, b, c, d), 24),
+                    gateway: None,
+                    dns_servers: Default::default(),
+                });
+                (interface.ap, config)
+            }
+        };
         
         info!("Creating network stack...");
-        let stack_resources  = StackResources::<3>::new();
+        let stack_resources  = StackResources::<MAX_SOCKETS>::new();
         let stack_resources = STACK_RESOURCE_CELL.init(stack_resources);
 
         let (stack, runner) = embassy_net::new(
-            interface.sta,
+            device,
             embassy_net_config,
             stack_resources,
             seed
@@ -80,19 +297,25 @@ impl Wifi {
 
 
         Wifi {
-            ssid,
-            password,
+            mode,
+            networks,
+            station_mac,
             wifi_controller,
+            rng,
             stack,
             runner,
+            esp_now: Some(interface.esp_now),
         }
     }
 
 
+    /// One-shot join of the highest-priority network. Use a `WifiSupervisor`
+    /// to stay connected.
     pub async fn connect(&mut self) -> Result<(), &'static str> {
+        let credentials = &self.networks[0];
         let client_config = ClientConfiguration {
-            ssid: self.ssid.into(),
-            password: self.password.into(),
+            ssid: credentials.ssid.as_str().into(),
+            password: credentials.password.as_str().into(),
             ..Default::default()
         };
 
@@ -100,40 +323,416 @@ impl Wifi {
 
         self.wifi_controller.set_configuration(&config).map_err(|_| "Failed to set WiFi configuration")?;
 
-        self.wifi_controller.start().map_err(|_| "Failed to start WiFi controller")?;
+        if !matches!(self.wifi_controller.is_started(), Ok(true)) {
+            self.wifi_controller.start_async().await.map_err(|_| "Failed to start WiFi controller")?;
+        }
 
-        //loop {
-            match esp_wifi::wifi::wifi_state() {
-                WifiState::StaStarted => {
-                    self.wifi_controller.connect().map_err(|_| "Failed to connect to WiFi")?;
-                }
-                WifiState::StaConnected => {
-                    info!("WiFi connected, obtaining IP address...");
-                }
-                WifiState::StaDisconnected => {
-                    error!("WiFi disconnected, retrying...");
-                    self.wifi_controller.connect().map_err(|_| "Failed to reconnect to WiFi")?;
-                }
-                _ => {}
-            }
-        //}
+        if !matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected) {
+            self.wifi_controller.connect_async().await.map_err(|_| "Failed to connect to WiFi")?;
+        }
+        info!("WiFi connected, obtaining IP address...");
+        Ok(())
+    }
 
+    /// Starts the open setup access point; no-op in station mode.
+    pub async fn start_access_point(&mut self) -> Result<(), &'static str> {
+        if self.mode != WifiMode::AccessPoint {
+            return Ok(());
+        }
+        let ap_config = AccessPointConfiguration {
+            ssid: self.networks[0].ssid.as_str().into(),
+            auth_method: AuthMethod::None,
+            max_connections: 4,
+            ..Default::default()
+        };
+
+        self.wifi_controller
+            .set_configuration(&Configuration::AccessPoint(ap_config))
+            .map_err(|_| "Failed to set access point configuration")?;
+        self.wifi_controller.start_async().await.map_err(|_| "Failed to start access point")?;
+        info!("Access point '{}' started", self.networks[0].ssid);
+        Ok(())
+    }
+
+    /// Starts the station without joining anything; ESP-NOW then works on
+    /// the channel given to `EspNow::set_channel`.
+    pub async fn start_radio(&mut self) -> Result<(), &'static str> {
+        self.wifi_controller
+            .set_configuration(&Configuration::Client(ClientConfiguration::default()))
+            .map_err(|_| "Failed to set WiFi configuration")?;
+        self.wifi_controller.start_async().await.map_err(|_| "Failed to start WiFi controller")?;
+        info!("WiFi radio started for ESP-NOW");
         Ok(())
     }
 
+    pub fn mode(&self) -> WifiMode {
+        self.mode
+    }
+
+    pub fn networks(&self) -> &WifiNetworks {
+        &self.networks
+    }
+
+    /// MAC the station's IPv6 interface identifier is derived from.
+    pub fn station_mac(&self) -> [u8; 6] {
+        self.station_mac
+    }
+
     pub fn get_controller(&self) -> &WifiController<'static> {
         &self.wifi_controller
     }
 
+    /// The RNG is `Copy`; TLS needs it after the WiFi driver took the peripheral.
+    pub fn rng(&self) -> Rng {
+        self.rng
+    }
+
+    /// Logs the addresses in use before handing out the stack.
     pub fn get_stack(&mut self) -> &mut Stack<'static> {
+        info!("Network addresses: {:?}", self.addresses());
         &mut self.stack
     }
 
+    pub fn addresses(&self) -> NetworkAddresses {
+        NetworkAddresses::of(&self.stack)
+    }
+
     pub fn get_runner(&mut self) -> &mut Runner<'static, WifiDevice<'static>> {
         &mut self.runner
     }
 
+    /// ESP-NOW runs alongside the station once the controller is started;
+    /// take it before `take_components`.
+    pub fn take_esp_now(&mut self) -> Option<EspNow<'static>> {
+        self.esp_now.take()
+    }
+
     pub fn take_components(self) -> (WifiController<'static>, Runner<'static, WifiDevice<'static>>, Stack<'static>) {
         (self.wifi_controller, self.runner, self.stack)
     }
-}
\ No newline at end of file
+}
+
+/// Picks one of several networks on every (re)connection: scans, prefers
+/// networks by priority and signal, and fails over to the next one after
+/// `FAILOVER_AFTER` failed attempts.
+pub struct WifiRoamer {
+    networks: WifiNetworks,
+    selector: NetworkSelector,
+    active: &'static ActiveNetworkWatch,
+}
+
+impl WifiRoamer {
+    pub fn new(networks: WifiNetworks, active: &'static ActiveNetworkWatch) -> Self {
+        active.sender().send(None);
+        Self {
+            networks,
+            selector: NetworkSelector::new(),
+            active,
+        }
+    }
+
+    /// Starts the station if needed, then scans and joins the best network.
+    pub async fn connect(&mut self, controller: &mut WifiController<'static>) -> Result<ActiveNetwork, DisconnectReason> {
+        if !matches!(controller.is_started(), Ok(true)) {
+            controller
+                .set_configuration(&Configuration::Client(ClientConfiguration::default()))
+                .map_err(|_| DisconnectReason::ConfigurationRejected)?;
+            controller.start_async().await.map_err(|_| DisconnectReason::StartFailed)?;
+            info!("WiFi started");
+        }
+
+        let access_points = controller
+            .scan_with_config_async(ScanConfig::default())
+            .await
+            .map_err(|_| DisconnectReason::ScanFailed)?;
+        let scan: heapless::Vec<ScanEntry, 32> = access_points
+            .iter()
+            .map(|ap| ScanEntry {
+                ssid: ap.ssid.as_str(),
+                bssid: ap.bssid,
+                channel: ap.channel,
+                rssi: ap.signal_strength,
+            })
+            .take(32)
+            .collect();
+        let ssids: heapless::Vec<&str, MAX_NETWORKS> = self.networks.iter().map(|network| network.ssid.as_str()).collect();
+
+        let choice = self.selector.choose(&ssids, &scan).ok_or(DisconnectReason::NoNetworkInRange)?;
+        let network = &self.networks[choice.index];
+        info!("Joining '{}' on channel {} ({} dBm)", network.ssid, choice.channel, choice.rssi);
+
+        let client_config = ClientConfiguration {
+            ssid: network.ssid.as_str().into(),
+            password: network.password.as_str().into(),
+            bssid: Some(choice.bssid),
+            channel: Some(choice.channel),
+            ..Default::default()
+        };
+        controller
+            .set_configuration(&Configuration::Client(client_config))
+            .map_err(|_| DisconnectReason::ConfigurationRejected)?;
+
+        match controller.connect_async().await {
+            Ok(()) => {
+                self.selector.record_success(choice.index);
+                let active = ActiveNetwork {
+                    ssid: network.ssid.clone(),
+                    bssid: choice.bssid,
+                    channel: choice.channel,
+                    rssi: choice.rssi,
+                };
+                self.active.sender().send(Some(active.clone()));
+                Ok(active)
+            }
+            Err(e) => {
+                self.selector.record_failure(choice.index);
+                warn!(
+                    "Failed to join '{}': {:?} ({}/{})",
+                    network.ssid,
+                    e,
+                    self.selector.failures(choice.index),
+                    FAILOVER_AFTER
+                );
+                Err(DisconnectReason::ConnectFailed)
+            }
+        }
+    }
+
+    /// Call when the station drops off its network.
+    pub fn disconnected(&mut self) {
+        self.active.sender().send(None);
+    }
+}
+
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+/// How often the DHCP address is checked while associated.
+const ADDRESS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Keeps the station connected: starts the driver, joins through the
+/// `WifiRoamer`, waits out disconnects with backoff and reports every
+/// transition on a `WifiEventChannel`. DHCP leases are renewed by the
+/// network stack; the supervisor reports when the address appears, changes
+/// or goes away.
+pub struct WifiSupervisor {
+    controller: WifiController<'static>,
+    stack: Stack<'static>,
+    roamer: WifiRoamer,
+    events: &'static WifiEventChannel,
+    backoff: Backoff,
+}
+
+impl WifiSupervisor {
+    pub fn new(
+        controller: WifiController<'static>,
+        stack: Stack<'static>,
+        roamer: WifiRoamer,
+        events: &'static WifiEventChannel,
+        seed: u32,
+    ) -> Self {
+        Self {
+            controller,
+            stack,
+            roamer,
+            events,
+            backoff: Backoff::new(RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY, seed),
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        loop {
+            match self.roamer.connect(&mut self.controller).await {
+                Ok(network) => {
+                    info!("WiFi connected to '{}' ({} dBm)", network.ssid, network.rssi);
+                    self.backoff.reset();
+                    self.publish(WifiEvent::Connected(network));
+
+                    self.while_connected().await;
+                    self.roamer.disconnected();
+                    warn!("WiFi disconnected");
+                    self.publish(WifiEvent::Disconnected(DisconnectReason::LinkLost));
+                }
+                Err(reason) => {
+                    error!("WiFi connection failed: {:?}", reason);
+                    self.publish(WifiEvent::Disconnected(reason));
+                }
+            }
+
+            let delay = self.backoff.next_delay();
+            info!("WiFi retry in {} ms", delay.as_millis());
+            Timer::after(delay).await;
+        }
+    }
+
+    /// Reports address changes until the station leaves its network.
+    async fn while_connected(&mut self) {
+        let mut address: Option<Ipv4Cidr> = None;
+        loop {
+            let disconnected = match select(
+                self.controller.wait_for_event(DriverEvent::StaDisconnected),
+                Timer::after(ADDRESS_POLL_INTERVAL),
+            )
+            .await
+            {
+                Either::First(()) => true,
+                // The event may have fired between two waits
+                Either::Second(()) => !matches!(esp_wifi::wifi::wifi_state(), WifiState::StaConnected),
+            };
+
+            let current = if disconnected {
+                None
+            } else {
+                self.stack.config_v4().map(|config| config.address)
+            };
+            if current != address {
+                if address.is_some() {
+                    self.publish(WifiEvent::LostIp);
+                }
+                if let Some(cidr) = current {
+                    info!("WiFi got IP {}", cidr);
+                    self.publish(WifiEvent::GotIp(cidr));
+                }
+                address = current;
+            }
+
+            if disconnected {
+                return;
+            }
+        }
+    }
+
+    fn publish(&self, event: WifiEvent) {
+        self.events.immediate_publisher().publish_immediate(event);
+    }
+}
+
+/// How often the link is checked while waiting for advertisements.
+const SLAAC_LINK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// IPv6 SLAAC for the station. embassy-net keeps a single IPv6 address, so
+/// while a router advertises an autonomous /64 prefix the link-local
+/// address gives way to the prefix plus the EUI-64 interface identifier,
+/// with the router as default gateway and its RDNSS servers. When the
+/// prefix expires or the link drops it falls back to link-local and
+/// solicits again.
+pub struct SlaacClient {
+    stack: Stack<'static>,
+    mac: [u8; 6],
+    link_local: Ipv6Addr,
+}
+
+impl SlaacClient {
+    pub fn new(stack: Stack<'static>, mac: [u8; 6]) -> Self {
+        Self {
+            stack,
+            mac,
+            link_local: net_config::link_local_from_mac(mac),
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        let mut rx_meta = [PacketMetadata::EMPTY; 2];
+        let mut tx_meta = [PacketMetadata::EMPTY; 1];
+        let mut rx_buffer = [0u8; 512];
+        let mut tx_buffer = [0u8; slaac::SOLICITATION_LENGTH];
+        let socket = RawSocket::new::<WifiDevice<'static>>(
+            self.stack,
+            IpVersion::Ipv6,
+            IpProtocol::Icmpv6,
+            &mut rx_meta,
+            &mut rx_buffer,
+            &mut tx_meta,
+            &mut tx_buffer,
+        );
+        let mut packet = [0u8; 512];
+
+        loop {
+            self.stack.wait_link_up().await;
+            self.while_linked(&socket, &mut packet).await;
+            self.use_link_local();
+        }
+    }
+
+    /// Solicits and follows advertisements until the link goes down.
+    async fn while_linked(&self, socket: &RawSocket<'_>, packet: &mut [u8]) {
+        let mut solicitations = 0;
+        let mut next_solicitation = Some(Instant::now());
+        let mut expires: Option<Instant> = None;
+
+        while self.stack.is_link_up() {
+            let now = Instant::now();
+            if expires.is_some_and(|at| now >= at) {
+                warn!("IPv6 prefix expired");
+                self.use_link_local();
+                expires = None;
+                solicitations = 0;
+                next_solicitation = Some(now);
+            }
+            if next_solicitation.is_some_and(|at| now >= at) {
+                let mut solicitation = [0u8; slaac::SOLICITATION_LENGTH];
+                if let Some(len) = slaac::router_solicitation(self.link_local, self.mac, &mut solicitation) {
+                    socket.send(&solicitation[..len]).await;
+                }
+                solicitations += 1;
+                // Routers also advertise on their own, so waiting goes on
+                next_solicitation = (solicitations < slaac::MAX_SOLICITATIONS)
+                    .then(|| now + Duration::from_millis(slaac::SOLICITATION_INTERVAL_MS));
+            }
+
+            let mut wake = now + SLAAC_LINK_POLL_INTERVAL;
+            for at in [next_solicitation, expires].into_iter().flatten() {
+                wake = wake.min(at);
+            }
+            let Either::First(Ok(len)) = select(socket.recv(packet), Timer::at(wake)).await else {
+                continue;
+            };
+            let Some(advert) = slaac::parse_router_advert(&packet[..len]) else {
+                continue;
+            };
+            match advert.prefix {
+                Some(prefix) if prefix.valid_secs > 0 => {
+                    self.use_prefix(&advert, prefix);
+                    next_solicitation = None;
+                    expires = (prefix.valid_secs != slaac::INFINITE_LIFETIME)
+                        .then(|| Instant::now() + Duration::from_secs(prefix.valid_secs as u64));
+                }
+                // The router withdrew the prefix in use
+                Some(prefix) if self.in_use(prefix) => {
+                    warn!("IPv6 prefix {} withdrawn", prefix.prefix);
+                    self.use_link_local();
+                    expires = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn in_use(&self, prefix: SlaacPrefix) -> bool {
+        self.stack.config_v6().is_some_and(|config| config.address.address() == prefix.address(self.mac))
+    }
+
+    fn use_prefix(&self, advert: &RouterAdvert, prefix: SlaacPrefix) {
+        let config = StaticConfigV6 {
+            address: Ipv6Cidr::new(prefix.address(self.mac), 64),
+            gateway: (advert.router_lifetime_secs > 0).then_some(advert.router),
+            dns_servers: advert.dns_servers.iter().copied().collect(),
+        };
+        if self.stack.config_v6().as_ref() != Some(&config) {
+            info!("IPv6: SLAAC {} via {:?}", config.address, config.gateway);
+            self.stack.set_config_v6(ConfigV6::Static(config));
+        }
+    }
+
+    fn use_link_local(&self) {
+        let address = Ipv6Cidr::new(self.link_local, 64);
+        if self.stack.config_v6().is_some_and(|config| config.address == address) {
+            return;
+        }
+        info!("IPv6: link-local {}", self.link_local);
+        self.stack.set_config_v6(ConfigV6::Static(StaticConfigV6 {
+            address,
+            gateway: None,
+            dns_servers: Default::default(),
+        }));
+    }
+}
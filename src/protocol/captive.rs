@@ -0,0 +1,390 @@
+//! Replies used by the setup access point: a DNS server that answers every
+//! `A` query with the gateway's own address, so any page a phone opens lands
+//! on the setup form, and just enough DHCP to hand out addresses.
+
+pub const DNS_PORT: u16 = 53;
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const DNS_HEADER_LENGTH: usize = 12;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_ANY: u16 = 255;
+const DNS_CLASS_IN: u16 = 1;
+const CATCH_ALL_TTL: u32 = 60;
+
+/// Builds the answer to a standard query in `query`, pointing the first
+/// question at `address`. Other record types get an empty answer; responses
+/// and malformed packets give `None`.
+pub fn dns_catch_all(query: &[u8], address: [u8; 4], out: &mut [u8]) -> Option<usize> {
+    let header = query.get(..DNS_HEADER_LENGTH)?;
+    let is_query = header[2] & 0x80 == 0;
+    let opcode = (header[2] >> 3) & 0x0F;
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    if !is_query || opcode != 0 || questions == 0 {
+        return None;
+    }
+
+    // Walk the first question's name; queries are never compressed
+    let mut pos = DNS_HEADER_LENGTH;
+    loop {
+        let length = *query.get(pos)? as usize;
+        if length & 0xC0 != 0 {
+            return None;
+        }
+        pos += 1 + length;
+        if length == 0 {
+            break;
+        }
+    }
+    let question_end = pos + 4;
+    let question = query.get(DNS_HEADER_LENGTH..question_end)?;
+    let record_type = u16::from_be_bytes([query[pos], query[pos + 1]]);
+    let class = u16::from_be_bytes([query[pos + 2], query[pos + 3]]) & 0x7FFF;
+    let answer = class == DNS_CLASS_IN && (record_type == DNS_TYPE_A || record_type == DNS_TYPE_ANY);
+
+    let answer_length = if answer { 16 } else { 0 };
+    let total = DNS_HEADER_LENGTH + question.len() + answer_length;
+    let out = out.get_mut(..total)?;
+
+    out[0..2].copy_from_slice(&header[0..2]);
+    // QR + AA, keep RD; RA and RCODE cleared
+    out[2] = 0x84 | (header[2] & 0x01);
+    out[3] = 0x00;
+    out[4..6].copy_from_slice(&1u16.to_be_bytes());
+    out[6..8].copy_from_slice(&(answer as u16).to_be_bytes());
+    out[8..12].fill(0);
+    out[DNS_HEADER_LENGTH..DNS_HEADER_LENGTH + question.len()].copy_from_slice(question);
+
+    if answer {
+        let record = &mut out[DNS_HEADER_LENGTH + question.len()..];
+        // Name pointer to the question
+        record[0..2].copy_from_slice(&[0xC0, DNS_HEADER_LENGTH as u8]);
+        record[2..4].copy_from_slice(&DNS_TYPE_A.to_be_bytes());
+        record[4..6].copy_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        record[6..10].copy_from_slice(&CATCH_ALL_TTL.to_be_bytes());
+        record[10..12].copy_from_slice(&4u16.to_be_bytes());
+        record[12..16].copy_from_slice(&address);
+    }
+    Some(total)
+}
+
+const DHCP_FIXED_LENGTH: usize = 236;
+const DHCP_MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const DHCP_OPTION_SUBNET_MASK: u8 = 1;
+const DHCP_OPTION_ROUTER: u8 = 3;
+const DHCP_OPTION_DNS: u8 = 6;
+const DHCP_OPTION_REQUESTED_IP: u8 = 50;
+const DHCP_OPTION_LEASE_TIME: u8 = 51;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCP_OPTION_SERVER_ID: u8 = 54;
+const DHCP_OPTION_END: u8 = 255;
+const DHCP_OPTION_PAD: u8 = 0;
+pub const DHCP_LEASE_SECS: u32 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DhcpMessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl DhcpMessageType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// The parts of a client message the server needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DhcpRequest {
+    pub message_type: DhcpMessageType,
+    pub xid: [u8; 4],
+    pub flags: [u8; 2],
+    pub mac: [u8; 6],
+    pub requested_ip: Option<[u8; 4]>,
+    pub server_id: Option<[u8; 4]>,
+}
+
+pub fn parse_dhcp_request(packet: &[u8]) -> Option<DhcpRequest> {
+    let fixed = packet.get(..DHCP_FIXED_LENGTH + 4)?;
+    // BOOTREQUEST over Ethernet
+    if fixed[0] != 1 || fixed[1] != 1 || fixed[2] != 6 || fixed[DHCP_FIXED_LENGTH..] != DHCP_MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested_ip = None;
+    let mut server_id = None;
+    let mut pos = DHCP_FIXED_LENGTH + 4;
+    while let Some(&code) = packet.get(pos) {
+        match code {
+            DHCP_OPTION_PAD => {
+                pos += 1;
+                continue;
+            }
+            DHCP_OPTION_END => break,
+            _ => {}
+        }
+        let length = *packet.get(pos + 1)? as usize;
+        let value = packet.get(pos + 2..pos + 2 + length)?;
+        match (code, value) {
+            (DHCP_OPTION_MESSAGE_TYPE, [kind]) => message_type = DhcpMessageType::from_byte(*kind),
+            (DHCP_OPTION_REQUESTED_IP, &[a, b, c, d]) => requested_ip = Some([a, b, c, d]),
+            (DHCP_OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some([a, b, c, d]),
+            _ => {}
+        }
+        pos += 2 + length;
+    }
+
+    let mut mac = [0u8; 6];
+    mac.copy_from_slice(&fixed[28..34]);
+    Some(DhcpRequest {
+        message_type: message_type?,
+        xid: [fixed[4], fixed[5], fixed[6], fixed[7]],
+        flags: [fixed[10], fixed[11]],
+        mac,
+        requested_ip,
+        server_id,
+    })
+}
+
+/// Builds an OFFER, ACK or NAK for `request`. The server is also the
+/// client's router and DNS server, on a /24.
+pub fn encode_dhcp_reply(
+    request: &DhcpRequest,
+    message_type: DhcpMessageType,
+    client_ip: [u8; 4],
+    server_ip: [u8; 4],
+    out: &mut [u8],
+) -> Option<usize> {
+    let mut options: heapless::Vec<u8, 40> = heapless::Vec::new();
+    let mut option = |code: u8, value: &[u8]| {
+        options.push(code).ok()?;
+        options.push(value.len() as u8).ok()?;
+        options.extend_from_slice(value).ok()
+    };
+    option(DHCP_OPTION_MESSAGE_TYPE, &[message_type as u8])?;
+    option(DHCP_OPTION_SERVER_ID, &server_ip)?;
+    if message_type != DhcpMessageType::Nak {
+        option(DHCP_OPTION_LEASE_TIME, &DHCP_LEASE_SECS.to_be_bytes())?;
+        option(DHCP_OPTION_SUBNET_MASK, &[255, 255, 255, 0])?;
+        option(DHCP_OPTION_ROUTER, &server_ip)?;
+        option(DHCP_OPTION_DNS, &server_ip)?;
+    }
+    options.push(DHCP_OPTION_END).ok()?;
+
+    let total = DHCP_FIXED_LENGTH + DHCP_MAGIC_COOKIE.len() + options.len();
+    let out = out.get_mut(..total)?;
+    out.fill(0);
+    out[0] = 2; // BOOTREPLY
+    out[1] = 1;
+    out[2] = 6;
+    out[4..8].copy_from_slice(&request.xid);
+    out[10..12].copy_from_slice(&request.flags);
+    if message_type != DhcpMessageType::Nak {
+        out[16..20].copy_from_slice(&client_ip);
+        out[20..24].copy_from_slice(&server_ip);
+    }
+    out[28..34].copy_from_slice(&request.mac);
+    out[DHCP_FIXED_LENGTH..DHCP_FIXED_LENGTH + 4].copy_from_slice(&DHCP_MAGIC_COOKIE);
+    out[DHCP_FIXED_LENGTH + 4..].copy_from_slice(&options);
+    Some(total)
+}
+
+pub const MAX_LEASES: usize = 8;
+
+/// Addresses handed out on the setup network, one per client MAC, taken
+/// from `first..first + MAX_LEASES` in the server's /24.
+#[derive(Debug, Clone)]
+pub struct LeasePool {
+    prefix: [u8; 3],
+    first: u8,
+    leases: [Option<[u8; 6]>; MAX_LEASES],
+}
+
+impl LeasePool {
+    pub const fn new(server_ip: [u8; 4], first: u8) -> Self {
+        Self {
+            prefix: [server_ip[0], server_ip[1], server_ip[2]],
+            first,
+            leases: [None; MAX_LEASES],
+        }
+    }
+
+    /// Address already leased to `mac`, or a free one. When the pool is
+    /// full the first slot is taken over; setup only ever sees a few phones.
+    pub fn lease(&mut self, mac: [u8; 6]) -> [u8; 4] {
+        let slot = self
+            .leases
+            .iter()
+            .position(|lease| *lease == Some(mac))
+            .or_else(|| self.leases.iter().position(Option::is_none))
+            .unwrap_or(0);
+        self.leases[slot] = Some(mac);
+        self.address(slot)
+    }
+
+    /// Whether `address` is the one leased to `mac`.
+    pub fn owns(&self, mac: [u8; 6], address: [u8; 4]) -> bool {
+        self.leases
+            .iter()
+            .position(|lease| *lease == Some(mac))
+            .is_some_and(|slot| self.address(slot) == address)
+    }
+
+    pub fn release(&mut self, mac: [u8; 6]) {
+        for lease in self.leases.iter_mut().filter(|lease| **lease == Some(mac)) {
+            *lease = None;
+        }
+    }
+
+    fn address(&self, slot: usize) -> [u8; 4] {
+        [self.prefix[0], self.prefix[1], self.prefix[2], self.first + slot as u8]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GATEWAY: [u8; 4] = [192, 168, 4, 1];
+    const MAC: [u8; 6] = [0x02, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    /// Query for `example.com` of `record_type`, with RD set.
+    fn dns_query(record_type: u16, out: &mut [u8]) -> usize {
+        let header = [0xAB, 0xCD, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let name = b"\x07example\x03com\x00";
+        out[..12].copy_from_slice(&header);
+        out[12..12 + name.len()].copy_from_slice(name);
+        let pos = 12 + name.len();
+        out[pos..pos + 2].copy_from_slice(&record_type.to_be_bytes());
+        out[pos + 2..pos + 4].copy_from_slice(&DNS_CLASS_IN.to_be_bytes());
+        pos + 4
+    }
+
+    #[test_case]
+    fn dns_answers_a_queries_with_the_gateway() {
+        let mut query = [0u8; 64];
+        let len = dns_query(DNS_TYPE_A, &mut query);
+        let mut reply = [0u8; 128];
+        let total = dns_catch_all(&query[..len], GATEWAY, &mut reply).unwrap();
+
+        assert_eq!(total, len + 16);
+        assert_eq!(&reply[..4], &[0xAB, 0xCD, 0x85, 0x00]);
+        assert_eq!(&reply[4..8], &[0, 1, 0, 1]);
+        assert_eq!(&reply[12..len], &query[12..len]);
+        assert_eq!(&reply[len..len + 2], &[0xC0, 12]);
+        assert_eq!(&reply[total - 4..total], &GATEWAY);
+    }
+
+    #[test_case]
+    fn dns_gives_other_types_an_empty_answer() {
+        let mut query = [0u8; 64];
+        let len = dns_query(28, &mut query); // AAAA
+        let mut reply = [0u8; 128];
+        assert_eq!(dns_catch_all(&query[..len], GATEWAY, &mut reply), Some(len));
+        assert_eq!(&reply[6..8], &[0, 0]);
+    }
+
+    #[test_case]
+    fn dns_ignores_responses_and_truncated_queries() {
+        let mut query = [0u8; 64];
+        let len = dns_query(DNS_TYPE_A, &mut query);
+        let mut reply = [0u8; 128];
+        assert_eq!(dns_catch_all(&query[..len - 1], GATEWAY, &mut reply), None);
+        assert_eq!(dns_catch_all(&query[..11], GATEWAY, &mut reply), None);
+        assert_eq!(dns_catch_all(&query[..len], GATEWAY, &mut reply[..len]), None);
+        query[2] |= 0x80;
+        assert_eq!(dns_catch_all(&query[..len], GATEWAY, &mut reply), None);
+    }
+
+    fn dhcp_packet(options: &[u8], out: &mut [u8]) -> usize {
+        out.fill(0);
+        out[..4].copy_from_slice(&[1, 1, 6, 0]);
+        out[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        out[10] = 0x80;
+        out[28..34].copy_from_slice(&MAC);
+        out[DHCP_FIXED_LENGTH..DHCP_FIXED_LENGTH + 4].copy_from_slice(&DHCP_MAGIC_COOKIE);
+        out[DHCP_FIXED_LENGTH + 4..DHCP_FIXED_LENGTH + 4 + options.len()].copy_from_slice(options);
+        DHCP_FIXED_LENGTH + 4 + options.len()
+    }
+
+    #[test_case]
+    fn dhcp_parses_a_request() {
+        let mut packet = [0u8; 300];
+        let len = dhcp_packet(&[53, 1, 3, 0, 50, 4, 192, 168, 4, 10, 54, 4, 192, 168, 4, 1, 255], &mut packet);
+        let request = parse_dhcp_request(&packet[..len]).unwrap();
+        assert_eq!(request.message_type, DhcpMessageType::Request);
+        assert_eq!(request.xid, [1, 2, 3, 4]);
+        assert_eq!(request.flags, [0x80, 0]);
+        assert_eq!(request.mac, MAC);
+        assert_eq!(request.requested_ip, Some([192, 168, 4, 10]));
+        assert_eq!(request.server_id, Some(GATEWAY));
+    }
+
+    #[test_case]
+    fn dhcp_rejects_bad_packets() {
+        let mut packet = [0u8; 300];
+        let len = dhcp_packet(&[50, 4, 192, 168, 4, 10, 255], &mut packet);
+        assert_eq!(parse_dhcp_request(&packet[..len]), None); // no message type
+
+        let len = dhcp_packet(&[53, 1, 1, 50, 4, 192], &mut packet);
+        assert_eq!(parse_dhcp_request(&packet[..len]), None); // truncated option
+
+        let len = dhcp_packet(&[53, 1, 1, 255], &mut packet);
+        packet[0] = 2;
+        assert_eq!(parse_dhcp_request(&packet[..len]), None); // a reply
+    }
+
+    #[test_case]
+    fn dhcp_reply_round_trips_the_client_fields() {
+        let mut packet = [0u8; 300];
+        let len = dhcp_packet(&[53, 1, 1, 255], &mut packet);
+        let request = parse_dhcp_request(&packet[..len]).unwrap();
+
+        let mut reply = [0u8; 300];
+        let total = encode_dhcp_reply(&request, DhcpMessageType::Offer, [192, 168, 4, 10], GATEWAY, &mut reply).unwrap();
+        assert_eq!(reply[0], 2);
+        assert_eq!(&reply[4..8], &request.xid);
+        assert_eq!(&reply[16..20], &[192, 168, 4, 10]);
+        assert_eq!(&reply[28..34], &MAC);
+        let options = &reply[DHCP_FIXED_LENGTH + 4..total];
+        assert_eq!(&options[..3], &[53, 1, DhcpMessageType::Offer as u8]);
+        assert_eq!(options.last(), Some(&DHCP_OPTION_END));
+
+        let nak = encode_dhcp_reply(&request, DhcpMessageType::Nak, [192, 168, 4, 10], GATEWAY, &mut reply).unwrap();
+        assert_eq!(&reply[16..20], &[0; 4]);
+        assert!(nak < total);
+        assert_eq!(encode_dhcp_reply(&request, DhcpMessageType::Ack, [192, 168, 4, 10], GATEWAY, &mut reply[..100]), None);
+    }
+
+    #[test_case]
+    fn lease_pool_keeps_one_address_per_client() {
+        let mut pool = LeasePool::new(GATEWAY, 10);
+        let other = [0x02, 0, 0, 0, 0, 1];
+        assert_eq!(pool.lease(MAC), [192, 168, 4, 10]);
+        assert_eq!(pool.lease(other), [192, 168, 4, 11]);
+        assert_eq!(pool.lease(MAC), [192, 168, 4, 10]);
+        assert!(pool.owns(MAC, [192, 168, 4, 10]));
+        assert!(!pool.owns(MAC, [192, 168, 4, 11]));
+
+        pool.release(MAC);
+        assert!(!pool.owns(MAC, [192, 168, 4, 10]));
+        assert_eq!(pool.lease([0x02, 0, 0, 0, 0, 2]), [192, 168, 4, 10]);
+    }
+}
@@ -0,0 +1,99 @@
+use serde::{Deserialize, Serialize};
+
+pub const MAX_REQUEST_ID_LENGTH: usize = 36;
+
+/// JSON form of a gate command. rust-mqtt 0.3 does not expose the MQTT v5
+/// Response Topic / Correlation Data properties of received publishes, so
+/// they travel in the payload instead:
+/// `{"requestId":"42","replyTo":"app/replies","gate":"G1"}`.
+/// Every field is optional; a payload that is not JSON is forwarded as is.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandRequest<'a> {
+    #[serde(default)]
+    pub request_id: Option<&'a str>,
+    #[serde(default)]
+    pub reply_to: Option<&'a str>,
+    #[serde(default)]
+    pub gate: Option<&'a str>,
+}
+
+impl<'a> CommandRequest<'a> {
+    pub fn parse(payload: &'a [u8]) -> Option<Self> {
+        serde_json_core::from_slice(payload).ok().map(|(request, _)| request)
+    }
+}
+
+/// Whether results may be published on `topic`: a plain topic under
+/// `{main_topic}/`. Anything else would let any publisher make the gateway
+/// write elsewhere, e.g. into the retained Home Assistant discovery tree.
+pub fn is_valid_reply_topic(main_topic: &str, topic: &str) -> bool {
+    let Some(rest) = topic.strip_prefix(main_topic).and_then(|rest| rest.strip_prefix('/')) else {
+        return false;
+    };
+    !rest.is_empty() && !topic.contains(['+', '#', '\0'])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOutcome {
+    /// Transmitted over LoRa; the final outcome follows.
+    Delivered,
+    Acknowledged,
+    TimedOut,
+    Failed,
+}
+
+impl CommandOutcome {
+    pub fn is_final(self) -> bool {
+        !matches!(self, CommandOutcome::Delivered)
+    }
+}
+
+/// Sent back to the requester for each step of a forwarded command.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandResult<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<&'a str>,
+    /// Absent for a command rejected before it was given one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u16>,
+    pub outcome: CommandOutcome,
+    pub latency_ms: u64,
+}
+
+impl CommandResult<'_> {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, serde_json_core::ser::Error> {
+        serde_json_core::to_slice(self, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn reply_topics_must_stay_under_the_main_topic() {
+        assert!(is_valid_reply_topic("site", "site/app/replies"));
+        assert!(!is_valid_reply_topic("site", "site"));
+        assert!(!is_valid_reply_topic("site", "site/"));
+        assert!(!is_valid_reply_topic("site", "sites/app"));
+        assert!(!is_valid_reply_topic("site", "homeassistant/button/x/config"));
+        assert!(!is_valid_reply_topic("site", "site/+/replies"));
+        assert!(!is_valid_reply_topic("site", "site/#"));
+    }
+
+    #[test_case]
+    fn results_omit_missing_fields() {
+        let mut buffer = [0u8; 128];
+
+        let forwarded = CommandResult { request_id: Some("42"), seq: Some(7), outcome: CommandOutcome::TimedOut, latency_ms: 1500 };
+        let len = forwarded.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], br#"{"requestId":"42","seq":7,"outcome":"timed_out","latencyMs":1500}"#);
+
+        let rejected = CommandResult { request_id: None, seq: None, outcome: CommandOutcome::Failed, latency_ms: 0 };
+        let len = rejected.encode(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], br#"{"outcome":"failed","latencyMs":0}"#);
+    }
+}
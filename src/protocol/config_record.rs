@@ -0,0 +1,162 @@
+//! On-flash layout of the site configuration: a header followed by
+//! length-prefixed key/value pairs, all little endian.
+//!
+//! | offset | size | field                         |
+//! |--------|------|-------------------------------|
+//! | 0      | 4    | magic `HVCF`                  |
+//! | 4      | 2    | schema version                |
+//! | 6      | 4    | generation (newest wins)      |
+//! | 10     | 2    | entries length                |
+//! | 12     | 4    | CRC-32 of the entries         |
+//! | 16     | ...  | `key_len u8, key, value_len u8, value` |
+
+pub const CONFIG_MAGIC: [u8; 4] = *b"HVCF";
+pub const CONFIG_SCHEMA_VERSION: u16 = 1;
+pub const CONFIG_HEADER_LENGTH: usize = 16;
+
+pub const MAX_KEY_LENGTH: usize = 24;
+pub const MAX_VALUE_LENGTH: usize = 96;
+/// Enough for every node setting; a record this full still fits one 4 KiB
+/// flash sector.
+pub const MAX_ENTRIES: usize = 32;
+
+pub type ConfigKey = heapless::String<MAX_KEY_LENGTH>;
+pub type ConfigValue = heapless::String<MAX_VALUE_LENGTH>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError {
+    /// Erased or foreign data; not an error on first boot.
+    NoRecord,
+    Corrupted,
+    UnsupportedVersion(u16),
+    TooLarge,
+}
+
+/// Settings held by the store, in insertion order.
+#[derive(Clone, Default)]
+pub struct ConfigEntries {
+    entries: heapless::Vec<(ConfigKey, ConfigValue), MAX_ENTRIES>,
+}
+
+impl ConfigEntries {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), RecordError> {
+        let value = ConfigValue::try_from(value).map_err(|_| RecordError::TooLarge)?;
+        if let Some((_, existing)) = self.entries.iter_mut().find(|(k, _)| k == key) {
+            *existing = value;
+            return Ok(());
+        }
+        let key = ConfigKey::try_from(key).map_err(|_| RecordError::TooLarge)?;
+        self.entries.push((key, value)).map_err(|_| RecordError::TooLarge)
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(k, _)| k != key);
+        self.entries.len() != before
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|(k, _)| k.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Only keys are shown, values may be secrets.
+impl core::fmt::Debug for ConfigEntries {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.keys()).finish()
+    }
+}
+
+/// Serializes `entries` with `generation` into `buffer`, returning the length.
+pub fn encode_record(entries: &ConfigEntries, generation: u32, buffer: &mut [u8]) -> Result<usize, RecordError> {
+    let mut pos = CONFIG_HEADER_LENGTH;
+    for (key, value) in entries.entries.iter() {
+        for field in [key.as_bytes(), value.as_bytes()] {
+            let end = pos + 1 + field.len();
+            let slot = buffer.get_mut(pos..end).ok_or(RecordError::TooLarge)?;
+            slot[0] = field.len() as u8;
+            slot[1..].copy_from_slice(field);
+            pos = end;
+        }
+    }
+
+    let body_length = pos - CONFIG_HEADER_LENGTH;
+    let header = buffer.get_mut(..CONFIG_HEADER_LENGTH).ok_or(RecordError::TooLarge)?;
+    header[0..4].copy_from_slice(&CONFIG_MAGIC);
+    header[4..6].copy_from_slice(&CONFIG_SCHEMA_VERSION.to_le_bytes());
+    header[6..10].copy_from_slice(&generation.to_le_bytes());
+    header[10..12].copy_from_slice(&u16::try_from(body_length).map_err(|_| RecordError::TooLarge)?.to_le_bytes());
+    let crc = crc32(&buffer[CONFIG_HEADER_LENGTH..pos]);
+    buffer[12..16].copy_from_slice(&crc.to_le_bytes());
+
+    Ok(pos)
+}
+
+/// Reads a record, returning its generation and entries.
+pub fn decode_record(buffer: &[u8]) -> Result<(u32, ConfigEntries), RecordError> {
+    let header = buffer.get(..CONFIG_HEADER_LENGTH).ok_or(RecordError::NoRecord)?;
+    if header[0..4] != CONFIG_MAGIC {
+        return Err(RecordError::NoRecord);
+    }
+
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    // Newer firmware may add keys but must keep this layout; older layouts
+    // would be migrated here
+    if version != CONFIG_SCHEMA_VERSION {
+        return Err(RecordError::UnsupportedVersion(version));
+    }
+    let generation = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+    let body_length = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let crc = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+
+    let body = buffer
+        .get(CONFIG_HEADER_LENGTH..CONFIG_HEADER_LENGTH + body_length)
+        .ok_or(RecordError::Corrupted)?;
+    if crc32(body) != crc {
+        return Err(RecordError::Corrupted);
+    }
+
+    let mut entries = ConfigEntries::new();
+    let mut pos = 0;
+    while pos < body.len() {
+        let key = read_field(body, &mut pos)?;
+        let value = read_field(body, &mut pos)?;
+        entries.set(key, value).map_err(|_| RecordError::Corrupted)?;
+    }
+
+    Ok((generation, entries))
+}
+
+fn read_field<'a>(body: &'a [u8], pos: &mut usize) -> Result<&'a str, RecordError> {
+    let length = *body.get(*pos).ok_or(RecordError::Corrupted)? as usize;
+    let field = body.get(*pos + 1..*pos + 1 + length).ok_or(RecordError::Corrupted)?;
+    *pos += 1 + length;
+    core::str::from_utf8(field).map_err(|_| RecordError::Corrupted)
+}
+
+/// CRC-32 (IEEE), bitwise; records are small and written rarely.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,72 @@
+use serde::Serialize;
+
+use crate::protocol::{gate::GateState, lora::NodeAddress};
+
+pub const MAX_GATE_ID_LENGTH: usize = 16;
+pub const MAX_GATE_NAME_LENGTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GateDirection {
+    Entry,
+    Exit,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GateInfo {
+    pub external_id: heapless::String<MAX_GATE_ID_LENGTH>,
+    pub name: heapless::String<MAX_GATE_NAME_LENGTH>,
+    pub direction: GateDirection,
+    /// LoRa node driving this gate; commands can't be routed without it.
+    #[serde(skip)]
+    pub node: Option<NodeAddress>,
+    #[serde(skip)]
+    pub state: GateState,
+}
+
+impl GateInfo {
+    pub fn new(external_id: &str, name: &str, direction: GateDirection) -> Option<Self> {
+        Some(Self {
+            external_id: heapless::String::try_from(external_id).ok()?,
+            name: heapless::String::try_from(name).ok()?,
+            direction,
+            node: None,
+            state: GateState::Unknown,
+        })
+    }
+
+    pub fn with_node(mut self, node: NodeAddress) -> Self {
+        self.node = Some(node);
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RadioParameters {
+    pub frequency_hz: u32,
+    pub spreading_factor: u8,
+    pub bandwidth_khz: u16,
+    pub coding_rate: &'static str,
+    pub tx_power_dbm: i32,
+}
+
+/// Documento de discovery descrito no artigo, acrescido da versão do firmware
+/// e dos parâmetros de rádio do gateway.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiscoveryPayload<'a> {
+    pub operation_center_name: &'a str,
+    pub network_name: &'a str,
+    pub firmware_version: &'a str,
+    pub radio: RadioParameters,
+    pub gates: &'a [GateInfo],
+}
+
+impl DiscoveryPayload<'_> {
+    /// Serializes the document as JSON into `buffer`, returning the used length.
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, serde_json_core::ser::Error> {
+        serde_json_core::to_slice(self, buffer)
+    }
+}
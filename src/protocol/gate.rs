@@ -0,0 +1,131 @@
+use core::fmt::Write;
+
+use serde::Serialize;
+
+use crate::protocol::lora::NodeAddress;
+
+pub const MAX_GATE_TOPIC_LENGTH: usize = 96;
+
+pub type GateTopic = heapless::String<MAX_GATE_TOPIC_LENGTH>;
+
+/// Barrier position as reported by the node driving it. Travels over LoRa as
+/// the single payload byte of a `GateState` frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GateState {
+    Unknown = 0,
+    Closed = 1,
+    Opening = 2,
+    Open = 3,
+    Closing = 4,
+    /// The barrier did not finish a move; needs attention on site.
+    Fault = 5,
+}
+
+impl GateState {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => GateState::Closed,
+            2 => GateState::Opening,
+            3 => GateState::Open,
+            4 => GateState::Closing,
+            5 => GateState::Fault,
+            _ => GateState::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GateState::Unknown => "UNKNOWN",
+            GateState::Closed => "CLOSED",
+            GateState::Opening => "OPENING",
+            GateState::Open => "OPEN",
+            GateState::Closing => "CLOSING",
+            GateState::Fault => "FAULT",
+        }
+    }
+}
+
+/// Leaf of the per-gate hierarchy `{main_topic}/{network}/{gate}/{leaf}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GateTopicKind {
+    /// Commands for the gate, from the backend.
+    Cmd,
+    /// Last known `GateState`, retained.
+    State,
+    /// Node reports and command outcomes, not retained.
+    Event,
+}
+
+impl GateTopicKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GateTopicKind::Cmd => "cmd",
+            GateTopicKind::State => "state",
+            GateTopicKind::Event => "event",
+        }
+    }
+}
+
+/// `{network}/{gate}/{leaf}`, relative to `main_topic`.
+pub fn gate_subtopic(network: &str, gate: &str, kind: GateTopicKind) -> Option<GateTopic> {
+    let mut topic = GateTopic::new();
+    write!(topic, "{}/{}/{}", network, gate, kind.as_str()).ok()?;
+    Some(topic)
+}
+
+/// `{main_topic}/{network}/+/cmd`; the gate id is level `gate_level(main_topic)`.
+pub fn gate_command_filter(main_topic: &str, network: &str) -> Option<GateTopic> {
+    let mut filter = GateTopic::new();
+    write!(filter, "{}/{}/+/{}", main_topic, network, GateTopicKind::Cmd.as_str()).ok()?;
+    Some(filter)
+}
+
+pub fn gate_level(main_topic: &str, network: &str) -> usize {
+    main_topic.split('/').count() + network.split('/').count()
+}
+
+/// Payload of the `event` topic for a node report.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GateStateEvent {
+    pub node: NodeAddress,
+    pub state: GateState,
+    pub previous: GateState,
+}
+
+impl GateStateEvent {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, serde_json_core::ser::Error> {
+        serde_json_core::to_slice(self, buffer)
+    }
+}
+
+/// Payload of a `Heartbeat` frame: gate state, uptime (u32 LE) and battery
+/// (`0xFF` when the node cannot measure it).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub state: GateState,
+    pub uptime_secs: u32,
+    pub battery_percent: Option<u8>,
+}
+
+impl Heartbeat {
+    pub const LENGTH: usize = 6;
+
+    pub fn encode(&self) -> [u8; Self::LENGTH] {
+        let mut payload = [0u8; Self::LENGTH];
+        payload[0] = self.state as u8;
+        payload[1..5].copy_from_slice(&self.uptime_secs.to_le_bytes());
+        payload[5] = self.battery_percent.map_or(0xFF, |percent| percent.min(100));
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Self> {
+        let payload: &[u8; Self::LENGTH] = payload.get(..Self::LENGTH)?.try_into().ok()?;
+        Some(Self {
+            state: GateState::from_byte(payload[0]),
+            uptime_secs: u32::from_le_bytes([payload[1], payload[2], payload[3], payload[4]]),
+            battery_percent: (payload[5] != 0xFF).then_some(payload[5]),
+        })
+    }
+}
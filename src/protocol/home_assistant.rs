@@ -0,0 +1,129 @@
+use core::fmt::Write;
+
+use serde::Serialize;
+
+pub const DISCOVERY_PREFIX: &str = "homeassistant";
+pub const MAX_HA_TOPIC_LENGTH: usize = 128;
+pub const MAX_HA_ID_LENGTH: usize = 64;
+
+pub type HaTopic = heapless::String<MAX_HA_TOPIC_LENGTH>;
+pub type HaId = heapless::String<MAX_HA_ID_LENGTH>;
+
+/// `homeassistant/{component}/{node_id}/{object_id}/config`
+pub fn config_topic(component: &str, node_id: &str, object_id: &str) -> Option<HaTopic> {
+    let mut topic = HaTopic::new();
+    write!(topic, "{}/{}/{}/{}/config", DISCOVERY_PREFIX, component, node_id, object_id).ok()?;
+    Some(topic)
+}
+
+#[derive(Debug, Serialize)]
+pub struct HaDevice<'a> {
+    pub identifiers: [&'a str; 1],
+    pub name: &'a str,
+    pub manufacturer: &'a str,
+    pub model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via_device: Option<&'a str>,
+}
+
+/// Gate entity: pressing it publishes `payload_press` on `command_topic`.
+#[derive(Debug, Serialize)]
+pub struct HaButtonConfig<'a> {
+    pub name: &'a str,
+    pub unique_id: &'a str,
+    pub command_topic: &'a str,
+    pub payload_press: &'a str,
+    pub availability_topic: &'a str,
+    pub icon: &'a str,
+    pub device: HaDevice<'a>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HaSensorConfig<'a> {
+    pub name: &'a str,
+    pub unique_id: &'a str,
+    pub state_topic: &'a str,
+    pub value_template: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit_of_measurement: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_class: Option<&'a str>,
+    pub state_class: &'a str,
+    pub entity_category: &'a str,
+    pub availability_topic: &'a str,
+    pub device: HaDevice<'a>,
+}
+
+/// Health values each node exposes; all are read from one JSON state topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeSensor {
+    Rssi,
+    Snr,
+    Battery,
+    LastSeen,
+}
+
+impl NodeSensor {
+    pub const ALL: [NodeSensor; 4] = [NodeSensor::Rssi, NodeSensor::Snr, NodeSensor::Battery, NodeSensor::LastSeen];
+
+    pub fn object_id(self) -> &'static str {
+        match self {
+            NodeSensor::Rssi => "rssi",
+            NodeSensor::Snr => "snr",
+            NodeSensor::Battery => "battery",
+            NodeSensor::LastSeen => "last_seen",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NodeSensor::Rssi => "RSSI",
+            NodeSensor::Snr => "SNR",
+            NodeSensor::Battery => "Battery",
+            NodeSensor::LastSeen => "Last seen",
+        }
+    }
+
+    pub fn value_template(self) -> &'static str {
+        match self {
+            NodeSensor::Rssi => "{{ value_json.rssi }}",
+            NodeSensor::Snr => "{{ value_json.snr }}",
+            NodeSensor::Battery => "{{ value_json.battery }}",
+            NodeSensor::LastSeen => "{{ value_json.last_seen }}",
+        }
+    }
+
+    pub fn unit(self) -> Option<&'static str> {
+        match self {
+            NodeSensor::Rssi => Some("dBm"),
+            NodeSensor::Snr => Some("dB"),
+            NodeSensor::Battery => Some("%"),
+            NodeSensor::LastSeen => Some("s"),
+        }
+    }
+
+    pub fn device_class(self) -> Option<&'static str> {
+        match self {
+            NodeSensor::Rssi => Some("signal_strength"),
+            NodeSensor::Snr => None,
+            NodeSensor::Battery => Some("battery"),
+            NodeSensor::LastSeen => Some("duration"),
+        }
+    }
+}
+
+/// Payload of a node state topic. `last_seen` is the age in seconds, since
+/// the gateway has no wall clock.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct HaNodeState {
+    pub rssi: i16,
+    pub snr: i16,
+    pub battery: Option<u8>,
+    pub last_seen: u64,
+}
+
+pub fn encode<T: Serialize>(value: &T, buffer: &mut [u8]) -> Option<usize> {
+    serde_json_core::to_slice(value, buffer).ok()
+}
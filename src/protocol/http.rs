@@ -0,0 +1,232 @@
+//! Minimal HTTP/1.1 request parsing and routing for the gateway's local API.
+//! Only what the API needs is understood: the request line, `Authorization`
+//! and `Content-Length`; every response closes the connection.
+
+use core::fmt::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// The header block has not fully arrived yet.
+    Incomplete,
+    Malformed,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    pub method: Method,
+    pub path: &'a str,
+    pub authorization: Option<&'a str>,
+    pub content_length: usize,
+    /// Bytes used by the request line and headers, including the blank line.
+    pub header_length: usize,
+}
+
+/// Parses the request line and headers at the start of `buffer`.
+pub fn parse_request(buffer: &[u8]) -> Result<Request<'_>, ParseError> {
+    let header_end = buffer.windows(4).position(|w| w == b"\r\n\r\n").ok_or(ParseError::Incomplete)?;
+    let head = core::str::from_utf8(&buffer[..header_end]).map_err(|_| ParseError::Malformed)?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next().ok_or(ParseError::Malformed)?.split(' ');
+    let method = match request_line.next() {
+        Some("GET") => Method::Get,
+        Some("POST") => Method::Post,
+        Some(_) => Method::Other,
+        None => return Err(ParseError::Malformed),
+    };
+    let target = request_line.next().ok_or(ParseError::Malformed)?;
+    if !request_line.next().is_some_and(|version| version.starts_with("HTTP/1.")) {
+        return Err(ParseError::Malformed);
+    }
+    // The API has no query parameters
+    let path = target.split('?').next().unwrap_or(target);
+
+    let mut authorization = None;
+    let mut content_length = 0;
+    for line in lines {
+        let (name, value) = line.split_once(':').ok_or(ParseError::Malformed)?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            authorization = Some(value);
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = value.parse().map_err(|_| ParseError::Malformed)?;
+        }
+    }
+
+    Ok(Request {
+        method,
+        path,
+        authorization,
+        content_length,
+        header_length: header_end + 4,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route<'a> {
+    ListGates,
+    OpenGate(&'a str),
+    Status,
+    Metrics,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteError {
+    NotFound,
+    MethodNotAllowed,
+}
+
+/// Maps `GET /gates`, `POST /gates/{id}/open`, `GET /status` and `GET /metrics`.
+pub fn route(method: Method, path: &str) -> Result<Route<'_>, RouteError> {
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let mut segments = path.strip_prefix('/').ok_or(RouteError::NotFound)?.split('/');
+
+    let (route, allowed) = match (segments.next(), segments.next(), segments.next(), segments.next()) {
+        (Some("gates"), None, _, _) => (Route::ListGates, Method::Get),
+        (Some("gates"), Some(id), Some("open"), None) if !id.is_empty() => (Route::OpenGate(id), Method::Post),
+        (Some("status"), None, _, _) => (Route::Status, Method::Get),
+        (Some("metrics"), None, _, _) => (Route::Metrics, Method::Get),
+        _ => return Err(RouteError::NotFound),
+    };
+
+    if method == allowed {
+        Ok(route)
+    } else {
+        Err(RouteError::MethodNotAllowed)
+    }
+}
+
+/// Checks `Authorization: Bearer <token>` in constant time. An empty
+/// `token` authorizes nobody.
+pub fn is_authorized(authorization: Option<&str>, token: &str) -> bool {
+    if token.is_empty() {
+        return false;
+    }
+    let Some(presented) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    let presented = presented.trim().as_bytes();
+    let expected = token.as_bytes();
+
+    let mut difference = presented.len() ^ expected.len();
+    for (i, byte) in expected.iter().enumerate() {
+        difference |= (byte ^ presented.get(i).copied().unwrap_or(0)) as usize;
+    }
+    difference == 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusCode(pub u16, pub &'static str);
+
+impl StatusCode {
+    pub const OK: Self = Self(200, "OK");
+    pub const ACCEPTED: Self = Self(202, "Accepted");
+    pub const BAD_REQUEST: Self = Self(400, "Bad Request");
+    pub const UNAUTHORIZED: Self = Self(401, "Unauthorized");
+    pub const NOT_FOUND: Self = Self(404, "Not Found");
+    pub const METHOD_NOT_ALLOWED: Self = Self(405, "Method Not Allowed");
+    pub const CONFLICT: Self = Self(409, "Conflict");
+    pub const PAYLOAD_TOO_LARGE: Self = Self(413, "Payload Too Large");
+    pub const SERVICE_UNAVAILABLE: Self = Self(503, "Service Unavailable");
+}
+
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+/// Writes the status line and headers for a body of `body_length` bytes.
+pub fn write_response_head<const N: usize>(
+    out: &mut heapless::String<N>,
+    status: StatusCode,
+    content_type: &str,
+    body_length: usize,
+) -> core::fmt::Result {
+    write!(out, "HTTP/1.1 {} {}\r\n", status.0, status.1)?;
+    write!(out, "Content-Type: {}\r\nContent-Length: {}\r\n", content_type, body_length)?;
+    if status == StatusCode::UNAUTHORIZED {
+        out.write_str("WWW-Authenticate: Bearer\r\n")?;
+    }
+    out.write_str("Connection: close\r\n\r\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn parses_request_line_and_headers() {
+        let raw = b"POST /gates/north/open?x=1 HTTP/1.1\r\nHost: gw\r\nauthorization:  Bearer abc \r\nContent-Length: 2\r\n\r\n{}";
+        let request = parse_request(raw).unwrap();
+        assert_eq!(request.method, Method::Post);
+        assert_eq!(request.path, "/gates/north/open");
+        assert_eq!(request.authorization, Some("Bearer abc"));
+        assert_eq!(request.content_length, 2);
+        assert_eq!(request.header_length, raw.len() - 2);
+    }
+
+    #[test_case]
+    fn incomplete_and_malformed_requests() {
+        assert_eq!(parse_request(b"GET /status HTTP/1.1\r\nHost: gw\r\n").err(), Some(ParseError::Incomplete));
+        assert_eq!(parse_request(b"GET /status\r\n\r\n").err(), Some(ParseError::Malformed));
+        assert_eq!(parse_request(b"GET /status HTTP/2\r\n\r\n").err(), Some(ParseError::Malformed));
+        assert_eq!(parse_request(b"GET /status HTTP/1.1\r\nno colon\r\n\r\n").err(), Some(ParseError::Malformed));
+        assert_eq!(
+            parse_request(b"GET /status HTTP/1.1\r\nContent-Length: lots\r\n\r\n").err(),
+            Some(ParseError::Malformed)
+        );
+        assert_eq!(parse_request(b"PUT /status HTTP/1.0\r\n\r\n").unwrap().method, Method::Other);
+    }
+
+    #[test_case]
+    fn routes_known_paths() {
+        assert_eq!(route(Method::Get, "/gates"), Ok(Route::ListGates));
+        assert_eq!(route(Method::Get, "/gates/"), Ok(Route::ListGates));
+        assert_eq!(route(Method::Post, "/gates/north/open"), Ok(Route::OpenGate("north")));
+        assert_eq!(route(Method::Get, "/status"), Ok(Route::Status));
+        assert_eq!(route(Method::Get, "/metrics"), Ok(Route::Metrics));
+    }
+
+    #[test_case]
+    fn rejects_unknown_paths_and_methods() {
+        assert_eq!(route(Method::Get, "/"), Err(RouteError::NotFound));
+        assert_eq!(route(Method::Get, "status"), Err(RouteError::NotFound));
+        assert_eq!(route(Method::Post, "/gates//open"), Err(RouteError::NotFound));
+        assert_eq!(route(Method::Post, "/gates/north/open/now"), Err(RouteError::NotFound));
+        assert_eq!(route(Method::Post, "/gates"), Err(RouteError::MethodNotAllowed));
+        assert_eq!(route(Method::Get, "/gates/north/open"), Err(RouteError::MethodNotAllowed));
+        assert_eq!(route(Method::Other, "/status"), Err(RouteError::MethodNotAllowed));
+    }
+
+    #[test_case]
+    fn authorization_needs_the_exact_bearer_token() {
+        assert!(is_authorized(Some("Bearer s3cret"), "s3cret"));
+        assert!(!is_authorized(Some("Bearer s3cre"), "s3cret"));
+        assert!(!is_authorized(Some("Bearer s3cret!"), "s3cret"));
+        assert!(!is_authorized(Some("Basic s3cret"), "s3cret"));
+        assert!(!is_authorized(None, "s3cret"));
+    }
+
+    #[test_case]
+    fn an_empty_token_authorizes_nobody() {
+        assert!(!is_authorized(Some("Bearer "), ""));
+        assert!(!is_authorized(Some("Bearer"), ""));
+        assert!(!is_authorized(None, ""));
+    }
+
+    #[test_case]
+    fn response_head_closes_the_connection() {
+        let mut head = heapless::String::<256>::new();
+        write_response_head(&mut head, StatusCode::UNAUTHORIZED, CONTENT_TYPE_JSON, 7).unwrap();
+        assert_eq!(
+            head.as_str(),
+            "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\nContent-Length: 7\r\n\
+             WWW-Authenticate: Bearer\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
@@ -11,11 +11,16 @@ pub const PROTOCOL_VERSION: u8 = 1;
 ///
 /// Cálculo (pior caso):
 /// - 2 bytes reservados para o prefixo de tamanho do CBOR
-/// - ~24 bytes de overhead do envelope (mapa, chaves e campos escalares)
+/// - ~45 bytes de overhead do envelope (mapa, chaves e campos escalares,
+///   incluindo os endereços opcionais de origem e destino e os campos de
+///   roteamento: TTL, saltos, repetidor e próximo salto)
 /// - restante para os dados do payload
 ///
-/// 255 (PAYLOAD_LENGTH) - 2 (prefixo) - 24 (overhead) = 229 bytes úteis.
-pub const MAX_APP_PAYLOAD: usize = 229;
+/// 255 (PAYLOAD_LENGTH) - 2 (prefixo) - 45 (overhead) = 208 bytes úteis.
+pub const MAX_APP_PAYLOAD: usize = 208;
+
+/// Endereço LoRa de um nó (cancela) ou do gateway.
+pub type NodeAddress = u16;
 
 #[derive(Debug, Encode, Decode)]
 pub struct LoraEnvelope {
@@ -31,6 +36,24 @@ pub struct LoraEnvelope {
     pub elapsed_ms: u32,
     #[n(5)]
     pub payload: ByteVec,
+    /// Nó que originou o frame; ausente em firmwares antigos.
+    #[n(6)]
+    pub src: Option<NodeAddress>,
+    /// Nó de destino; ausente quando o frame vale para qualquer nó.
+    #[n(7)]
+    pub dst: Option<NodeAddress>,
+    /// Saltos restantes; ausente em frames de um salto só.
+    #[n(8)]
+    pub ttl: Option<u8>,
+    /// Saltos já percorridos desde `src`.
+    #[n(9)]
+    pub hops: Option<u8>,
+    /// Quem transmitiu este frame por último (a origem ou um repetidor).
+    #[n(10)]
+    pub via: Option<NodeAddress>,
+    /// Repetidor escolhido para o próximo salto; ausente = inundação.
+    #[n(11)]
+    pub next_hop: Option<NodeAddress>,
 }
 
 impl LoraEnvelope {
@@ -49,9 +72,25 @@ impl LoraEnvelope {
             elapsed_ms,
             seq,
             payload: payload.into(),
+            src: None,
+            dst: None,
+            ttl: None,
+            hops: None,
+            via: None,
+            next_hop: None,
         }
     }
 
+    pub fn with_source(mut self, src: NodeAddress) -> Self {
+        self.src = Some(src);
+        self
+    }
+
+    pub fn with_destination(mut self, dst: NodeAddress) -> Self {
+        self.dst = Some(dst);
+        self
+    }
+
     pub fn new_version(
         version: u8,
         msg_type: MessageType,
@@ -67,6 +106,12 @@ impl LoraEnvelope {
             elapsed_ms,
             seq,
             payload: payload.into(),
+            src: None,
+            dst: None,
+            ttl: None,
+            hops: None,
+            via: None,
+            next_hop: None,
         }
     }
 
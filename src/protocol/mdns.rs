@@ -0,0 +1,173 @@
+use embassy_net::Ipv4Address;
+
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_GROUP: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+pub const MQTT_SERVICE: &str = "_mqtt._tcp.local";
+
+const MAX_NAME_LENGTH: usize = 128;
+const MAX_POINTER_JUMPS: usize = 16;
+const HEADER_LENGTH: usize = 12;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+type Name = heapless::String<MAX_NAME_LENGTH>;
+
+/// Host and port advertised for a service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceAddress {
+    pub address: Ipv4Address,
+    pub port: u16,
+}
+
+/// Writes a one-shot PTR query for `service` into `buffer` and returns its
+/// length. Sent from a port other than 5353, responders answer by unicast
+/// (RFC 6762 §6.7), so no multicast group has to be joined.
+pub fn build_ptr_query(id: u16, service: &str, buffer: &mut [u8]) -> Option<usize> {
+    let mut writer = Writer { buffer, pos: 0 };
+
+    writer.u16(id)?;
+    writer.u16(0)?; // standard query
+    writer.u16(1)?; // one question
+    writer.u16(0)?;
+    writer.u16(0)?;
+    writer.u16(0)?;
+
+    for label in service.split('.').filter(|l| !l.is_empty()) {
+        if label.len() > 63 {
+            return None;
+        }
+        writer.u8(label.len() as u8)?;
+        writer.bytes(label.as_bytes())?;
+    }
+    writer.u8(0)?;
+    writer.u16(TYPE_PTR)?;
+    writer.u16(CLASS_IN)?;
+
+    Some(writer.pos)
+}
+
+/// Extracts the SRV port and the matching A record from a response to the
+/// query `id`. Answers and additional records are both searched, since
+/// responders usually put SRV and A in the latter.
+pub fn parse_service_response(id: u16, packet: &[u8]) -> Option<ServiceAddress> {
+    if packet.len() < HEADER_LENGTH || read_u16(packet, 0)? != id {
+        return None;
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 || flags & 0x000F != 0 {
+        return None; // not a response, or an error
+    }
+
+    let questions = read_u16(packet, 4)?;
+    let records = read_u16(packet, 6)? as usize + read_u16(packet, 8)? as usize + read_u16(packet, 10)? as usize;
+
+    let mut pos = HEADER_LENGTH;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+
+    let mut srv: Option<(u16, Name)> = None;
+    let mut first_a: Option<Ipv4Address> = None;
+    let mut addresses: heapless::Vec<(Name, Ipv4Address), 4> = heapless::Vec::new();
+
+    for _ in 0..records {
+        let mut name = Name::new();
+        pos = read_name(packet, pos, &mut name)?;
+        let record_type = read_u16(packet, pos)?;
+        let data_length = read_u16(packet, pos + 8)? as usize;
+        let data = pos + 10;
+        pos = data + data_length;
+        if pos > packet.len() {
+            return None;
+        }
+
+        match record_type {
+            TYPE_SRV if srv.is_none() && data_length >= 7 => {
+                let port = read_u16(packet, data + 4)?;
+                let mut target = Name::new();
+                read_name(packet, data + 6, &mut target)?;
+                srv = Some((port, target));
+            }
+            TYPE_A if data_length == 4 => {
+                let address = Ipv4Address::new(packet[data], packet[data + 1], packet[data + 2], packet[data + 3]);
+                first_a.get_or_insert(address);
+                let _ = addresses.push((name, address));
+            }
+            _ => {}
+        }
+    }
+
+    let (port, target) = srv?;
+    let address = addresses
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(&target))
+        .map(|(_, address)| *address)
+        .or(first_a)?;
+
+    Some(ServiceAddress { address, port })
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*packet.get(pos)?, *packet.get(pos + 1)?]))
+}
+
+fn skip_name(packet: &[u8], pos: usize) -> Option<usize> {
+    read_name(packet, pos, &mut Name::new())
+}
+
+/// Decodes a possibly compressed name into `out` (dotted, no trailing dot)
+/// and returns the position right after it in the record.
+fn read_name(packet: &[u8], mut pos: usize, out: &mut Name) -> Option<usize> {
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *packet.get(pos)? as usize;
+        match length & 0xC0 {
+            0x00 if length == 0 => {
+                return Some(end.unwrap_or(pos + 1));
+            }
+            0x00 => {
+                let label = packet.get(pos + 1..pos + 1 + length)?;
+                if !out.is_empty() {
+                    out.push('.').ok()?;
+                }
+                out.push_str(core::str::from_utf8(label).ok()?).ok()?;
+                pos += 1 + length;
+            }
+            0xC0 => {
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (read_u16(packet, pos)? & 0x3FFF) as usize;
+            }
+            _ => return None,
+        }
+    }
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Option<()> {
+        self.buffer.get_mut(self.pos..self.pos + bytes.len())?.copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Some(())
+    }
+
+    fn u8(&mut self, value: u8) -> Option<()> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Option<()> {
+        self.bytes(&value.to_be_bytes())
+    }
+}
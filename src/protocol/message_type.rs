@@ -19,4 +19,20 @@ pub enum MessageType {
 
     #[n(5)]
     Open = 5,
+
+    /// Nó reportando a posição da cancela; payload de 1 byte (`GateState`).
+    #[n(6)]
+    GateState = 6,
+
+    /// Anúncio de presença para descoberta de vizinhos (ESP-NOW); payload vazio.
+    #[n(7)]
+    Discovery = 7,
+
+    /// Sinal de vida periódico do nó; payload `gate::Heartbeat`.
+    #[n(8)]
+    Heartbeat = 8,
+
+    /// Nó recusando um `Open` que não conseguiu executar; payload de 1 byte (`GateState`).
+    #[n(9)]
+    Nack = 9,
 }
\ No newline at end of file
@@ -1,2 +1,20 @@
 pub mod lora;
 pub mod message_type;
+pub mod topic;
+pub mod discovery;
+pub mod home_assistant;
+pub mod command;
+pub mod gate;
+pub mod mdns;
+pub mod mqtt_codec;
+pub mod http;
+pub mod config_record;
+pub mod provisioning;
+pub mod captive;
+pub mod roaming;
+pub mod net_config;
+pub mod slaac;
+pub mod routing;
+pub mod paths;
+pub mod node_config;
+pub mod servo;
@@ -0,0 +1,532 @@
+//! Server side of the MQTT 3.1.1 and 5 wire format, just enough for the
+//! local broker: CONNECT, PUBLISH, PUBACK, (UN)SUBSCRIBE, PINGREQ and
+//! DISCONNECT in, their acknowledgements and PUBLISH out. MQTT 5 properties
+//! are parsed over and ignored; outgoing packets carry none.
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolLevel {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CodecError {
+    Malformed,
+    UnsupportedProtocol,
+    UnsupportedPacket(u8),
+    BufferTooSmall,
+}
+
+#[derive(Debug)]
+pub enum Packet<'a> {
+    Connect {
+        level: ProtocolLevel,
+        client_id: &'a str,
+        keep_alive: u16,
+        username: Option<&'a str>,
+        password: Option<&'a [u8]>,
+    },
+    Publish {
+        topic: &'a str,
+        payload: &'a [u8],
+        qos: u8,
+        retain: bool,
+        packet_id: Option<u16>,
+    },
+    PubAck {
+        packet_id: u16,
+    },
+    Subscribe {
+        packet_id: u16,
+        filters: FilterList<'a>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: FilterList<'a>,
+    },
+    PingReq,
+    Disconnect,
+}
+
+/// Topic filters of a (UN)SUBSCRIBE, decoded lazily as `(filter, qos)`.
+/// UNSUBSCRIBE filters have no options byte and report QoS 0.
+#[derive(Debug, Clone, Copy)]
+pub struct FilterList<'a> {
+    data: &'a [u8],
+    with_options: bool,
+}
+
+impl<'a> Iterator for FilterList<'a> {
+    type Item = Result<(&'a str, u8), CodecError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut reader = Reader::new(self.data);
+        let item = reader.str().and_then(|filter| {
+            let qos = if self.with_options { reader.u8()? & 0x03 } else { 0 };
+            Ok((filter, qos))
+        });
+        self.data = if item.is_ok() { reader.rest() } else { &[] };
+        Some(item)
+    }
+}
+
+/// Decodes one packet from the start of `buffer`. Returns `Ok(None)` until the
+/// whole packet has arrived, otherwise the packet and the bytes it used.
+/// Before CONNECT the level is unknown; any value works since CONNECT carries it.
+pub fn decode(buffer: &[u8], level: ProtocolLevel) -> Result<Option<(Packet<'_>, usize)>, CodecError> {
+    let Some(&header) = buffer.first() else {
+        return Ok(None);
+    };
+    let Some((remaining, length_bytes)) = decode_remaining_length(&buffer[1..])? else {
+        return Ok(None);
+    };
+    let total = 1 + length_bytes + remaining;
+    if buffer.len() < total {
+        return Ok(None);
+    }
+
+    let body = &buffer[1 + length_bytes..total];
+    let packet = match header >> 4 {
+        CONNECT => decode_connect(body)?,
+        PUBLISH => decode_publish(header, body, level)?,
+        PUBACK => Packet::PubAck {
+            packet_id: Reader::new(body).u16()?,
+        },
+        SUBSCRIBE | UNSUBSCRIBE => {
+            let mut reader = Reader::new(body);
+            let packet_id = reader.u16()?;
+            if level == ProtocolLevel::V5 {
+                reader.skip_properties()?;
+            }
+            let filters = FilterList {
+                data: reader.rest(),
+                with_options: header >> 4 == SUBSCRIBE,
+            };
+            if header >> 4 == SUBSCRIBE {
+                Packet::Subscribe { packet_id, filters }
+            } else {
+                Packet::Unsubscribe { packet_id, filters }
+            }
+        }
+        PINGREQ => Packet::PingReq,
+        DISCONNECT => Packet::Disconnect,
+        other => return Err(CodecError::UnsupportedPacket(other)),
+    };
+
+    Ok(Some((packet, total)))
+}
+
+fn decode_connect(body: &[u8]) -> Result<Packet<'_>, CodecError> {
+    let mut reader = Reader::new(body);
+    if reader.str()? != "MQTT" {
+        return Err(CodecError::UnsupportedProtocol);
+    }
+    let level = match reader.u8()? {
+        4 => ProtocolLevel::V311,
+        5 => ProtocolLevel::V5,
+        _ => return Err(CodecError::UnsupportedProtocol),
+    };
+    let flags = reader.u8()?;
+    let keep_alive = reader.u16()?;
+    if level == ProtocolLevel::V5 {
+        reader.skip_properties()?;
+    }
+
+    let client_id = reader.str()?;
+    // The Last Will is accepted but never published by the local broker
+    if flags & 0x04 != 0 {
+        if level == ProtocolLevel::V5 {
+            reader.skip_properties()?;
+        }
+        reader.str()?;
+        reader.binary()?;
+    }
+    let username = if flags & 0x80 != 0 { Some(reader.str()?) } else { None };
+    let password = if flags & 0x40 != 0 { Some(reader.binary()?) } else { None };
+
+    Ok(Packet::Connect {
+        level,
+        client_id,
+        keep_alive,
+        username,
+        password,
+    })
+}
+
+fn decode_publish(header: u8, body: &[u8], level: ProtocolLevel) -> Result<Packet<'_>, CodecError> {
+    let qos = (header >> 1) & 0x03;
+    if qos > 2 {
+        return Err(CodecError::Malformed);
+    }
+
+    let mut reader = Reader::new(body);
+    let topic = reader.str()?;
+    let packet_id = if qos > 0 { Some(reader.u16()?) } else { None };
+    if level == ProtocolLevel::V5 {
+        reader.skip_properties()?;
+    }
+    // Topic aliases are not supported, so an empty topic is never valid
+    if topic.is_empty() {
+        return Err(CodecError::Malformed);
+    }
+
+    Ok(Packet::Publish {
+        topic,
+        payload: reader.rest(),
+        qos,
+        retain: header & 0x01 != 0,
+        packet_id,
+    })
+}
+
+/// CONNACK with `code` as the 3.1.1 return code or the MQTT 5 reason code.
+pub fn encode_connack(level: ProtocolLevel, code: u8, buffer: &mut [u8]) -> Result<usize, CodecError> {
+    let mut writer = Writer::packet(buffer, CONNACK << 4)?;
+    writer.u8(0)?; // no session present
+    writer.u8(code)?;
+    writer.empty_properties(level)?;
+    writer.finish()
+}
+
+pub fn encode_publish(
+    level: ProtocolLevel,
+    topic: &str,
+    payload: &[u8],
+    packet_id: Option<u16>,
+    retain: bool,
+    buffer: &mut [u8],
+) -> Result<usize, CodecError> {
+    let qos = if packet_id.is_some() { 1 } else { 0 };
+    let mut writer = Writer::packet(buffer, PUBLISH << 4 | qos << 1 | retain as u8)?;
+    writer.str(topic)?;
+    if let Some(packet_id) = packet_id {
+        writer.u16(packet_id)?;
+    }
+    writer.empty_properties(level)?;
+    writer.bytes(payload)?;
+    writer.finish()
+}
+
+pub fn encode_puback(packet_id: u16, buffer: &mut [u8]) -> Result<usize, CodecError> {
+    // The MQTT 5 reason code may be omitted when it is Success
+    let mut writer = Writer::packet(buffer, PUBACK << 4)?;
+    writer.u16(packet_id)?;
+    writer.finish()
+}
+
+/// SUBACK granting `granted` (one QoS, or 0x80 failure, per filter).
+pub fn encode_suback(level: ProtocolLevel, packet_id: u16, granted: &[u8], buffer: &mut [u8]) -> Result<usize, CodecError> {
+    let mut writer = Writer::packet(buffer, SUBACK << 4)?;
+    writer.u16(packet_id)?;
+    writer.empty_properties(level)?;
+    writer.bytes(granted)?;
+    writer.finish()
+}
+
+pub fn encode_unsuback(level: ProtocolLevel, packet_id: u16, filter_count: usize, buffer: &mut [u8]) -> Result<usize, CodecError> {
+    let mut writer = Writer::packet(buffer, UNSUBACK << 4)?;
+    writer.u16(packet_id)?;
+    if level == ProtocolLevel::V5 {
+        writer.u8(0)?;
+        for _ in 0..filter_count {
+            writer.u8(0)?; // Success
+        }
+    }
+    writer.finish()
+}
+
+pub fn encode_pingresp(buffer: &mut [u8]) -> Result<usize, CodecError> {
+    Writer::packet(buffer, PINGRESP << 4)?.finish()
+}
+
+fn decode_remaining_length(bytes: &[u8]) -> Result<Option<(usize, usize)>, CodecError> {
+    let mut value = 0usize;
+    for (i, &byte) in bytes.iter().enumerate().take(4) {
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    if bytes.len() >= 4 {
+        Err(CodecError::Malformed)
+    } else {
+        Ok(None)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        if self.data.len() < len {
+            return Err(CodecError::Malformed);
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CodecError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8], CodecError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+
+    fn str(&mut self) -> Result<&'a str, CodecError> {
+        core::str::from_utf8(self.binary()?).map_err(|_| CodecError::Malformed)
+    }
+
+    fn skip_properties(&mut self) -> Result<(), CodecError> {
+        let (len, used) = decode_remaining_length(self.data)?.ok_or(CodecError::Malformed)?;
+        self.take(used + len).map(|_| ())
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        core::mem::take(&mut self.data)
+    }
+}
+
+/// Writes the body after room for the longest fixed header, then moves it
+/// down once the remaining length is known.
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    pos: usize,
+}
+
+const BODY_OFFSET: usize = 5;
+
+impl<'a> Writer<'a> {
+    fn packet(buffer: &'a mut [u8], header: u8) -> Result<Self, CodecError> {
+        *buffer.first_mut().ok_or(CodecError::BufferTooSmall)? = header;
+        Ok(Self { buffer, pos: BODY_OFFSET })
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), CodecError> {
+        self.buffer
+            .get_mut(self.pos..self.pos + bytes.len())
+            .ok_or(CodecError::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.pos += bytes.len();
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), CodecError> {
+        self.bytes(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), CodecError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    fn str(&mut self, value: &str) -> Result<(), CodecError> {
+        let len = u16::try_from(value.len()).map_err(|_| CodecError::Malformed)?;
+        self.u16(len)?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn empty_properties(&mut self, level: ProtocolLevel) -> Result<(), CodecError> {
+        match level {
+            ProtocolLevel::V5 => self.u8(0),
+            ProtocolLevel::V311 => Ok(()),
+        }
+    }
+
+    fn finish(self) -> Result<usize, CodecError> {
+        let body_len = self.pos - BODY_OFFSET;
+        let mut length = [0u8; 4];
+        let mut used = 0;
+        let mut value = body_len;
+        loop {
+            let mut byte = (value % 128) as u8;
+            value /= 128;
+            if value > 0 {
+                byte |= 0x80;
+            }
+            length[used] = byte;
+            used += 1;
+            if value == 0 {
+                break;
+            }
+            if used == length.len() {
+                return Err(CodecError::BufferTooSmall);
+            }
+        }
+
+        self.buffer[1..1 + used].copy_from_slice(&length[..used]);
+        self.buffer.copy_within(BODY_OFFSET..self.pos, 1 + used);
+        Ok(1 + used + body_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connect_v311(flags: u8, tail: &[u8], buffer: &mut [u8]) -> usize {
+        let mut writer = Writer::packet(buffer, CONNECT << 4).unwrap();
+        writer.str("MQTT").unwrap();
+        writer.u8(4).unwrap();
+        writer.u8(flags).unwrap();
+        writer.u16(60).unwrap();
+        writer.str("door").unwrap();
+        writer.bytes(tail).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test_case]
+    fn connect_decodes_credentials() {
+        let mut buffer = [0u8; 64];
+        let len = connect_v311(0xC2, b"\x00\x02me\x00\x03pwd", &mut buffer);
+        let Ok(Some((Packet::Connect { level, client_id, keep_alive, username, password }, used))) =
+            decode(&buffer[..len], ProtocolLevel::V311)
+        else {
+            panic!("not a CONNECT");
+        };
+        assert_eq!(used, len);
+        assert_eq!(level, ProtocolLevel::V311);
+        assert_eq!((client_id, keep_alive), ("door", 60));
+        assert_eq!(username, Some("me"));
+        assert_eq!(password, Some(&b"pwd"[..]));
+
+        let len = connect_v311(0x02, &[], &mut buffer);
+        let Ok(Some((Packet::Connect { username, password, .. }, _))) = decode(&buffer[..len], ProtocolLevel::V311) else {
+            panic!("not a CONNECT");
+        };
+        assert_eq!((username, password), (None, None));
+    }
+
+    #[test_case]
+    fn connect_rejects_unknown_protocols() {
+        let mut buffer = [0u8; 64];
+        let len = connect_v311(0x02, &[], &mut buffer);
+        buffer[8] = 3; // MQTT 3.1
+        assert_eq!(decode(&buffer[..len], ProtocolLevel::V311).err(), Some(CodecError::UnsupportedProtocol));
+    }
+
+    #[test_case]
+    fn publish_round_trips_on_both_levels() {
+        for level in [ProtocolLevel::V311, ProtocolLevel::V5] {
+            for (packet_id, retain) in [(None, false), (Some(7), true)] {
+                let mut buffer = [0u8; 64];
+                let len = encode_publish(level, "gate/1/state", b"open", packet_id, retain, &mut buffer).unwrap();
+                let Ok(Some((Packet::Publish { topic, payload, qos, retain: decoded_retain, packet_id: decoded_id }, used))) =
+                    decode(&buffer[..len], level)
+                else {
+                    panic!("not a PUBLISH");
+                };
+                assert_eq!(used, len);
+                assert_eq!((topic, payload), ("gate/1/state", &b"open"[..]));
+                assert_eq!(qos, packet_id.is_some() as u8);
+                assert_eq!(decoded_retain, retain);
+                assert_eq!(decoded_id, packet_id);
+            }
+        }
+    }
+
+    #[test_case]
+    fn long_payloads_use_multi_byte_lengths() {
+        let payload = [0xA5u8; 300];
+        let mut buffer = [0u8; 400];
+        let len = encode_publish(ProtocolLevel::V311, "t", &payload, None, false, &mut buffer).unwrap();
+        assert_eq!(&buffer[1..3], &[0xAF, 0x02]); // 2 + 1 + 300 = 303
+        assert_eq!(len, 3 + 303);
+
+        let Ok(Some((Packet::Publish { payload: decoded, .. }, _))) = decode(&buffer[..len], ProtocolLevel::V311) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(decoded, &payload[..]);
+    }
+
+    #[test_case]
+    fn partial_packets_wait_for_more() {
+        let mut buffer = [0u8; 64];
+        let len = encode_publish(ProtocolLevel::V311, "a/b", b"x", Some(1), false, &mut buffer).unwrap();
+        for end in 0..len {
+            assert!(matches!(decode(&buffer[..end], ProtocolLevel::V311), Ok(None)));
+        }
+    }
+
+    #[test_case]
+    fn puback_round_trips() {
+        let mut buffer = [0u8; 8];
+        let len = encode_puback(0x1234, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[PUBACK << 4, 2, 0x12, 0x34]);
+        assert!(matches!(decode(&buffer[..len], ProtocolLevel::V311), Ok(Some((Packet::PubAck { packet_id: 0x1234 }, 4)))));
+    }
+
+    #[test_case]
+    fn subscribe_lists_filters_with_qos() {
+        let mut buffer = [0u8; 64];
+        let mut writer = Writer::packet(&mut buffer, SUBSCRIBE << 4 | 0x02).unwrap();
+        writer.u16(9).unwrap();
+        writer.u8(0).unwrap(); // MQTT 5 properties
+        writer.str("a/+").unwrap();
+        writer.u8(1).unwrap();
+        writer.str("b/#").unwrap();
+        writer.u8(0x2C).unwrap(); // QoS 0 with MQTT 5 options
+        let len = writer.finish().unwrap();
+
+        let Ok(Some((Packet::Subscribe { packet_id, filters }, _))) = decode(&buffer[..len], ProtocolLevel::V5) else {
+            panic!("not a SUBSCRIBE");
+        };
+        assert_eq!(packet_id, 9);
+        let mut filters = filters.map(Result::unwrap);
+        assert_eq!(filters.next(), Some(("a/+", 1)));
+        assert_eq!(filters.next(), Some(("b/#", 0)));
+        assert_eq!(filters.next(), None);
+    }
+
+    #[test_case]
+    fn acknowledgements_match_the_level() {
+        let mut buffer = [0u8; 16];
+        let len = encode_connack(ProtocolLevel::V311, 0x04, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[CONNACK << 4, 2, 0, 0x04]);
+        let len = encode_connack(ProtocolLevel::V5, 0x86, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[CONNACK << 4, 3, 0, 0x86, 0]);
+
+        let len = encode_suback(ProtocolLevel::V311, 3, &[1, 0x80], &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[SUBACK << 4, 4, 0, 3, 1, 0x80]);
+        let len = encode_unsuback(ProtocolLevel::V5, 3, 2, &mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[UNSUBACK << 4, 5, 0, 3, 0, 0, 0]);
+        let len = encode_pingresp(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[PINGRESP << 4, 0]);
+    }
+
+    #[test_case]
+    fn encoding_reports_a_small_buffer() {
+        let mut buffer = [0u8; 8];
+        assert_eq!(
+            encode_publish(ProtocolLevel::V311, "gate/state", b"x", None, false, &mut buffer),
+            Err(CodecError::BufferTooSmall)
+        );
+    }
+}
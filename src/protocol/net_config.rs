@@ -0,0 +1,94 @@
+//! Text forms of the network settings kept in the config store, and the
+//! values derived from the MAC address: DHCP hostname and IPv6 link-local
+//! address.
+
+use core::fmt::Write;
+use core::net::{Ipv4Addr, Ipv6Addr};
+
+pub const MAX_HOSTNAME_LENGTH: usize = 32;
+pub const MAX_DNS_SERVERS: usize = 3;
+pub const HOSTNAME_PREFIX: &str = "haviliar";
+
+pub type Hostname = heapless::String<MAX_HOSTNAME_LENGTH>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetConfigError {
+    InvalidAddress,
+    InvalidPrefix,
+    InvalidHostname,
+    TooManyDnsServers,
+}
+
+/// Parses `a.b.c.d/len`; the prefix length must be 1 to 32.
+pub fn parse_ipv4_cidr(text: &str) -> Result<(Ipv4Addr, u8), NetConfigError> {
+    let (address, prefix) = text.trim().split_once('/').ok_or(NetConfigError::InvalidPrefix)?;
+    let address = address.parse().map_err(|_| NetConfigError::InvalidAddress)?;
+    let prefix: u8 = prefix.parse().map_err(|_| NetConfigError::InvalidPrefix)?;
+    if !(1..=32).contains(&prefix) {
+        return Err(NetConfigError::InvalidPrefix);
+    }
+    Ok((address, prefix))
+}
+
+pub fn parse_ipv4(text: &str) -> Result<Ipv4Addr, NetConfigError> {
+    text.trim().parse().map_err(|_| NetConfigError::InvalidAddress)
+}
+
+/// Parses a comma-separated list of up to `MAX_DNS_SERVERS` addresses.
+pub fn parse_dns_servers(text: &str) -> Result<heapless::Vec<Ipv4Addr, MAX_DNS_SERVERS>, NetConfigError> {
+    let mut servers = heapless::Vec::new();
+    for server in text.split(',').filter(|server| !server.trim().is_empty()) {
+        servers
+            .push(parse_ipv4(server)?)
+            .map_err(|_| NetConfigError::TooManyDnsServers)?;
+    }
+    Ok(servers)
+}
+
+/// RFC 1123 label: letters, digits and `-`, not at either end.
+pub fn parse_hostname(text: &str) -> Result<Hostname, NetConfigError> {
+    let text = text.trim();
+    let valid = !text.is_empty()
+        && !text.starts_with('-')
+        && !text.ends_with('-')
+        && text.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    if !valid {
+        return Err(NetConfigError::InvalidHostname);
+    }
+    Hostname::try_from(text).map_err(|_| NetConfigError::InvalidHostname)
+}
+
+/// `haviliar-` followed by the last three MAC bytes, e.g. `haviliar-a1b2c3`.
+pub fn hostname_from_mac(mac: [u8; 6]) -> Hostname {
+    let mut hostname = Hostname::new();
+    let _ = write!(hostname, "{}-{:02x}{:02x}{:02x}", HOSTNAME_PREFIX, mac[3], mac[4], mac[5]);
+    hostname
+}
+
+/// `fe80::/64` address with the modified EUI-64 interface identifier, as
+/// SLAAC derives it from the MAC address.
+pub fn link_local_from_mac(mac: [u8; 6]) -> Ipv6Addr {
+    address_from_mac([0xfe, 0x80, 0, 0, 0, 0, 0, 0], mac)
+}
+
+/// Address in the /64 `network` with the modified EUI-64 interface
+/// identifier of `mac`.
+pub fn address_from_mac(network: [u8; 8], mac: [u8; 6]) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets[0..8].copy_from_slice(&network);
+    // Universal/local bit flipped, FF:FE in the middle
+    octets[8] = mac[0] ^ 0x02;
+    octets[9..11].copy_from_slice(&mac[1..3]);
+    octets[11..13].copy_from_slice(&[0xff, 0xfe]);
+    octets[13..16].copy_from_slice(&mac[3..6]);
+    Ipv6Addr::from(octets)
+}
+
+/// Accepts `on`/`off` style switches.
+pub fn parse_switch(text: &str) -> Option<bool> {
+    match text.trim() {
+        "on" | "true" | "1" | "yes" => Some(true),
+        "off" | "false" | "0" | "no" => Some(false),
+        _ => None,
+    }
+}
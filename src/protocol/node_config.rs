@@ -0,0 +1,45 @@
+//! Text forms of a gate node's identity kept in the config store: its LoRa
+//! address and the ESP-NOW keys shared with the gateway.
+
+use crate::protocol::lora::NodeAddress;
+
+pub const KEY_LENGTH: usize = 16;
+/// Addresses from here up are kept for gateways (see `GatewayConfig`).
+pub const FIRST_GATEWAY_ADDRESS: NodeAddress = 0xFF00;
+
+/// Decimal or `0x`-prefixed hex; 0 (nodes without an address) and gateway
+/// addresses are refused.
+pub fn parse_node_address(text: &str) -> Option<NodeAddress> {
+    let text = text.trim();
+    let address = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => NodeAddress::from_str_radix(hex, 16).ok()?,
+        None => text.parse().ok()?,
+    };
+    is_node_address(address).then_some(address)
+}
+
+pub fn is_node_address(address: NodeAddress) -> bool {
+    address != 0 && address < FIRST_GATEWAY_ADDRESS
+}
+
+/// 32 hex digits.
+pub fn parse_key(text: &str) -> Option<[u8; KEY_LENGTH]> {
+    let text = text.trim().as_bytes();
+    if text.len() != KEY_LENGTH * 2 {
+        return None;
+    }
+    let mut key = [0u8; KEY_LENGTH];
+    for (byte, pair) in key.iter_mut().zip(text.chunks_exact(2)) {
+        *byte = (hex_digit(pair[0])? << 4) | hex_digit(pair[1])?;
+    }
+    Some(key)
+}
+
+/// WiFi channel, 1 to 13.
+pub fn parse_channel(text: &str) -> Option<u8> {
+    text.trim().parse().ok().filter(|channel| (1..=13).contains(channel))
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
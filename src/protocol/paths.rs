@@ -0,0 +1,93 @@
+//! Choice of transport per destination. Transports are identified by their
+//! index, which is also their priority (lower first). A destination is sent
+//! first over the transports it was recently heard on, then over the other
+//! healthy ones; a pinned transport always comes first.
+
+use crate::protocol::lora::NodeAddress;
+
+pub const MAX_TRANSPORTS: usize = 3;
+pub const MAX_DESTINATIONS: usize = 16;
+/// A destination not heard on a transport for this long is no longer
+/// assumed reachable through it.
+pub const PATH_TIMEOUT_MS: u64 = 5 * 60 * 1000;
+
+/// Health of one transport as seen by the path table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PathHealth {
+    pub up: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PathEntry {
+    node: NodeAddress,
+    heard_ms: [Option<u64>; MAX_TRANSPORTS],
+    pinned: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathTable {
+    entries: heapless::Vec<PathEntry, MAX_DESTINATIONS>,
+}
+
+impl PathTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    /// Records that `node` was heard on `transport`.
+    pub fn heard(&mut self, node: NodeAddress, transport: usize, now_ms: u64) {
+        if transport >= MAX_TRANSPORTS {
+            return;
+        }
+        if let Some(entry) = self.entry_mut(node) {
+            entry.heard_ms[transport] = Some(now_ms);
+        }
+    }
+
+    /// Forces `node` onto `transport` while it is up; `None` clears it.
+    pub fn pin(&mut self, node: NodeAddress, transport: Option<usize>) {
+        if let Some(entry) = self.entry_mut(node) {
+            entry.pinned = transport.filter(|&index| index < MAX_TRANSPORTS);
+        }
+    }
+
+    /// Transports to try for `node`, best first. Down transports are left
+    /// out, unless all are down, in which case all are tried by priority.
+    pub fn candidates(&self, node: NodeAddress, health: &[PathHealth], now_ms: u64) -> heapless::Vec<usize, MAX_TRANSPORTS> {
+        let count = health.len().min(MAX_TRANSPORTS);
+        let entry = self.entries.iter().find(|entry| entry.node == node);
+        let heard = |index: usize| {
+            entry
+                .and_then(|entry| entry.heard_ms[index])
+                .is_some_and(|heard_ms| now_ms.saturating_sub(heard_ms) <= PATH_TIMEOUT_MS)
+        };
+        let pinned = entry.and_then(|entry| entry.pinned);
+
+        let mut order: heapless::Vec<usize, MAX_TRANSPORTS> = (0..count).filter(|&index| health[index].up).collect();
+        if order.is_empty() {
+            return (0..count).collect();
+        }
+        // Pinned, then recently heard, then the rest; ties by priority
+        order.sort_unstable_by_key(|&index| (Some(index) != pinned, !heard(index), index));
+        order
+    }
+
+    fn entry_mut(&mut self, node: NodeAddress) -> Option<&mut PathEntry> {
+        if let Some(index) = self.entries.iter().position(|entry| entry.node == node) {
+            return self.entries.get_mut(index);
+        }
+        if self.entries.is_full() {
+            // Forget the destination heard least recently
+            let oldest = (0..self.entries.len()).min_by_key(|&i| self.entries[i].heard_ms.iter().flatten().max().copied())?;
+            self.entries.swap_remove(oldest);
+        }
+        let _ = self.entries.push(PathEntry {
+            node,
+            heard_ms: [None; MAX_TRANSPORTS],
+            pinned: None,
+        });
+        self.entries.last_mut()
+    }
+}
@@ -0,0 +1,382 @@
+//! First-time setup form: `application/x-www-form-urlencoded` decoding and
+//! validation of the settings submitted through the captive portal.
+
+use crate::protocol::{
+    config_record::{ConfigValue, MAX_VALUE_LENGTH},
+    discovery::{GateDirection, MAX_GATE_ID_LENGTH, MAX_GATE_NAME_LENGTH},
+    lora::NodeAddress,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormError {
+    Malformed,
+    MissingSsid,
+    InvalidSsid,
+    /// WPA2 needs 8 to 63 characters; empty means an open network.
+    InvalidPassword,
+    InvalidBrokerHost,
+    InvalidBrokerPort,
+    ValueTooLong,
+    InvalidGates,
+    /// A token shorter than this is too easy to guess.
+    TokenTooShort,
+}
+
+impl FormError {
+    /// Message shown above the form.
+    pub fn message(self) -> &'static str {
+        match self {
+            FormError::Malformed => "Invalid form data",
+            FormError::MissingSsid => "WiFi network name is required",
+            FormError::InvalidSsid => "WiFi network name must have at most 32 bytes",
+            FormError::InvalidPassword => "WiFi password must have 8 to 63 characters, or be empty",
+            FormError::InvalidBrokerHost => "Broker must be a hostname or IPv4 address",
+            FormError::InvalidBrokerPort => "Broker port must be a number from 1 to 65535",
+            FormError::ValueTooLong => "A field is too long",
+            FormError::InvalidGates => "Gates must look like G1:Main entrance:ENTRY:1;G2:Main exit:EXIT:2",
+            FormError::TokenTooShort => "API token must have at least 16 characters, or be empty",
+        }
+    }
+}
+
+pub const MIN_TOKEN_LENGTH: usize = 16;
+
+/// Validated settings, ready to be written to the config store. Empty
+/// optional fields are `None`.
+#[derive(Clone, Default)]
+pub struct ProvisioningForm {
+    pub ssid: ConfigValue,
+    pub password: ConfigValue,
+    pub mqtt_host: Option<ConfigValue>,
+    pub mqtt_port: Option<ConfigValue>,
+    pub mqtt_username: Option<ConfigValue>,
+    pub mqtt_password: Option<ConfigValue>,
+    pub http_token: Option<ConfigValue>,
+    pub gates: Option<ConfigValue>,
+}
+
+/// Passwords and tokens are left out.
+impl core::fmt::Debug for ProvisioningForm {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("ProvisioningForm")
+            .field("ssid", &self.ssid)
+            .field("mqtt_host", &self.mqtt_host)
+            .field("mqtt_port", &self.mqtt_port)
+            .field("mqtt_username", &self.mqtt_username)
+            .field("gates", &self.gates)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProvisioningForm {
+    /// Decodes and validates a submitted form body. Unknown fields are ignored.
+    pub fn parse(body: &[u8]) -> Result<Self, FormError> {
+        let body = core::str::from_utf8(body).map_err(|_| FormError::Malformed)?;
+        let mut form = ProvisioningForm::default();
+        let mut has_ssid = false;
+
+        for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, raw) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = url_decode(raw)?;
+            let optional = (!value.is_empty()).then(|| value.clone());
+
+            match name {
+                "ssid" => {
+                    has_ssid = !value.is_empty();
+                    form.ssid = value;
+                }
+                "password" => form.password = value,
+                "mqtt_host" => form.mqtt_host = optional,
+                "mqtt_port" => form.mqtt_port = optional,
+                "mqtt_username" => form.mqtt_username = optional,
+                "mqtt_password" => form.mqtt_password = optional,
+                "http_token" => form.http_token = optional,
+                "gates" => form.gates = optional,
+                _ => {}
+            }
+        }
+
+        if !has_ssid {
+            return Err(FormError::MissingSsid);
+        }
+        form.validate()?;
+        Ok(form)
+    }
+
+    fn validate(&self) -> Result<(), FormError> {
+        if self.ssid.len() > 32 {
+            return Err(FormError::InvalidSsid);
+        }
+        if !self.password.is_empty() && !(8..=63).contains(&self.password.len()) {
+            return Err(FormError::InvalidPassword);
+        }
+        if let Some(host) = &self.mqtt_host {
+            if !is_valid_host(host) {
+                return Err(FormError::InvalidBrokerHost);
+            }
+        }
+        if self.mqtt_port.as_deref().is_some_and(|port| parse_port(port).is_none()) {
+            return Err(FormError::InvalidBrokerPort);
+        }
+        if self.http_token.as_ref().is_some_and(|token| token.len() < MIN_TOKEN_LENGTH) {
+            return Err(FormError::TokenTooShort);
+        }
+        if let Some(gates) = &self.gates {
+            if parse_gate_list(gates).any(|gate| gate.is_err()) {
+                return Err(FormError::InvalidGates);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hostname labels (letters, digits, `-`) separated by dots; an IPv4 address
+/// passes as well.
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// TCP port, 1 to 65535.
+pub fn parse_port(text: &str) -> Option<u16> {
+    text.trim().parse().ok().filter(|&port| port != 0)
+}
+
+/// One entry of the `site.gates` setting: `id:name:ENTRY|EXIT:node`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GateSpec<'a> {
+    pub external_id: &'a str,
+    pub name: &'a str,
+    pub direction: GateDirection,
+    pub node: NodeAddress,
+}
+
+/// Splits a `;`-separated gate list into its entries.
+pub fn parse_gate_list(list: &str) -> impl Iterator<Item = Result<GateSpec<'_>, FormError>> {
+    list.split(';').filter(|entry| !entry.trim().is_empty()).map(parse_gate)
+}
+
+fn parse_gate(entry: &str) -> Result<GateSpec<'_>, FormError> {
+    let mut fields = entry.trim().split(':').map(str::trim);
+    let (Some(external_id), Some(name), Some(direction), Some(node), None) =
+        (fields.next(), fields.next(), fields.next(), fields.next(), fields.next())
+    else {
+        return Err(FormError::InvalidGates);
+    };
+
+    if external_id.is_empty() || external_id.len() > MAX_GATE_ID_LENGTH || external_id.contains(['/', '+', '#']) {
+        return Err(FormError::InvalidGates);
+    }
+    if name.is_empty() || name.len() > MAX_GATE_NAME_LENGTH {
+        return Err(FormError::InvalidGates);
+    }
+    let direction = match direction {
+        "ENTRY" => GateDirection::Entry,
+        "EXIT" => GateDirection::Exit,
+        _ => return Err(FormError::InvalidGates),
+    };
+    let node = node.parse().map_err(|_| FormError::InvalidGates)?;
+
+    Ok(GateSpec {
+        external_id,
+        name,
+        direction,
+        node,
+    })
+}
+
+/// Decodes `+` and `%XX` escapes.
+pub fn url_decode(raw: &str) -> Result<ConfigValue, FormError> {
+    let mut bytes: heapless::Vec<u8, MAX_VALUE_LENGTH> = heapless::Vec::new();
+    let mut input = raw.bytes();
+
+    while let Some(byte) = input.next() {
+        let decoded = match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = input.next().and_then(hex_value).ok_or(FormError::Malformed)?;
+                let low = input.next().and_then(hex_value).ok_or(FormError::Malformed)?;
+                high << 4 | low
+            }
+            other => other,
+        };
+        bytes.push(decoded).map_err(|_| FormError::ValueTooLong)?;
+    }
+
+    let text = core::str::from_utf8(&bytes).map_err(|_| FormError::Malformed)?;
+    ConfigValue::try_from(text).map_err(|_| FormError::ValueTooLong)
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+/// Writes the setup page, with `error` shown above the fields.
+pub fn render_form<const N: usize>(out: &mut heapless::String<N>, error: Option<FormError>) -> core::fmt::Result {
+    use core::fmt::Write;
+
+    out.write_str(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\"><title>Gateway setup</title></head>\
+         <body><h1>Gateway setup</h1>",
+    )?;
+    if let Some(error) = error {
+        write!(out, "<p style=\"color:red\">{}</p>", error.message())?;
+    }
+    out.write_str(
+        "<form method=\"post\" action=\"/save\">\
+         <p>WiFi network<br><input name=\"ssid\" maxlength=\"32\" required></p>\
+         <p>WiFi password<br><input name=\"password\" type=\"password\" maxlength=\"63\"></p>\
+         <p>MQTT broker<br><input name=\"mqtt_host\" maxlength=\"96\"></p>\
+         <p>MQTT port<br><input name=\"mqtt_port\" inputmode=\"numeric\" maxlength=\"5\"></p>\
+         <p>MQTT username<br><input name=\"mqtt_username\" maxlength=\"96\"></p>\
+         <p>MQTT password<br><input name=\"mqtt_password\" type=\"password\" maxlength=\"96\"></p>\
+         <p>HTTP API token<br><input name=\"http_token\" type=\"password\" maxlength=\"96\"></p>\
+         <p>Gates (id:name:ENTRY|EXIT:node;...)<br><input name=\"gates\" maxlength=\"96\"></p>\
+         <p><button>Save</button></p></form></body></html>",
+    )
+}
+
+/// Page returned once the settings are stored.
+pub const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Gateway setup</title></head>\
+     <body><h1>Saved</h1><p>The gateway is restarting and will join the configured network.</p></body></html>";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> Result<ProvisioningForm, FormError> {
+        ProvisioningForm::parse(body.as_bytes())
+    }
+
+    #[test_case]
+    fn url_decode_handles_plus_and_escapes() {
+        assert_eq!(url_decode("my+home%20net").unwrap().as_str(), "my home net");
+        assert_eq!(url_decode("a%2Fb%2fc").unwrap().as_str(), "a/b/c");
+        assert_eq!(url_decode("%2B1").unwrap().as_str(), "+1");
+        assert_eq!(url_decode("caf%C3%A9").unwrap().as_str(), "café");
+        assert_eq!(url_decode("").unwrap().as_str(), "");
+    }
+
+    #[test_case]
+    fn url_decode_rejects_truncated_and_bad_escapes() {
+        assert_eq!(url_decode("abc%"), Err(FormError::Malformed));
+        assert_eq!(url_decode("abc%4"), Err(FormError::Malformed));
+        assert_eq!(url_decode("%G1"), Err(FormError::Malformed));
+        assert_eq!(url_decode("%1G"), Err(FormError::Malformed));
+        // Not UTF-8 once decoded
+        assert_eq!(url_decode("%FF"), Err(FormError::Malformed));
+    }
+
+    #[test_case]
+    fn url_decode_limits_the_length() {
+        let mut raw = heapless::String::<{ 3 * (MAX_VALUE_LENGTH + 1) }>::new();
+        for _ in 0..MAX_VALUE_LENGTH {
+            raw.push('x').unwrap();
+        }
+        assert_eq!(url_decode(&raw).unwrap().len(), MAX_VALUE_LENGTH);
+        raw.push('x').unwrap();
+        assert_eq!(url_decode(&raw), Err(FormError::ValueTooLong));
+    }
+
+    #[test_case]
+    fn parses_a_complete_form() {
+        let form = parse(
+            "ssid=Site+WiFi&password=correct%20horse&mqtt_host=broker.local&mqtt_port=8883\
+             &mqtt_username=gw&mqtt_password=&http_token=0123456789abcdef&gates=G1%3AMain%3AENTRY%3A1&extra=1",
+        )
+        .unwrap();
+        assert_eq!(form.ssid.as_str(), "Site WiFi");
+        assert_eq!(form.password.as_str(), "correct horse");
+        assert_eq!(form.mqtt_host.as_deref(), Some("broker.local"));
+        assert_eq!(form.mqtt_port.as_deref(), Some("8883"));
+        assert_eq!(form.mqtt_username.as_deref(), Some("gw"));
+        assert_eq!(form.mqtt_password, None);
+        assert_eq!(form.http_token.as_deref(), Some("0123456789abcdef"));
+        assert_eq!(form.gates.as_deref(), Some("G1:Main:ENTRY:1"));
+    }
+
+    #[test_case]
+    fn open_networks_need_no_password() {
+        let form = parse("ssid=Guest&password=").unwrap();
+        assert!(form.password.is_empty());
+        assert_eq!(form.mqtt_host, None);
+        assert_eq!(form.mqtt_port, None);
+    }
+
+    #[test_case]
+    fn rejects_bad_ssids() {
+        assert_eq!(parse("password=12345678").err(), Some(FormError::MissingSsid));
+        assert_eq!(parse("ssid=&password=12345678").err(), Some(FormError::MissingSsid));
+        assert!(parse("ssid=abcdefghijklmnopqrstuvwxyz012345").is_ok());
+        assert_eq!(parse("ssid=abcdefghijklmnopqrstuvwxyz0123456").err(), Some(FormError::InvalidSsid));
+        // 17 two-byte characters are 34 bytes
+        let mut accented = heapless::String::<128>::new();
+        accented.push_str("ssid=").unwrap();
+        for _ in 0..17 {
+            accented.push_str("%C3%A9").unwrap();
+        }
+        assert_eq!(parse(&accented).err(), Some(FormError::InvalidSsid));
+        assert_eq!(parse("ssid=%ZZ").err(), Some(FormError::Malformed));
+        assert_eq!(ProvisioningForm::parse(b"ssid=\xFF").err(), Some(FormError::Malformed));
+    }
+
+    #[test_case]
+    fn rejects_bad_passwords() {
+        assert_eq!(parse("ssid=a&password=1234567").err(), Some(FormError::InvalidPassword));
+        assert!(parse("ssid=a&password=12345678").is_ok());
+        assert!(parse("ssid=a&password=123456789012345678901234567890123456789012345678901234567890123").is_ok());
+        assert_eq!(
+            parse("ssid=a&password=1234567890123456789012345678901234567890123456789012345678901234").err(),
+            Some(FormError::InvalidPassword)
+        );
+    }
+
+    #[test_case]
+    fn rejects_bad_ports() {
+        for port in ["0", "65536", "-1", "18x3", "%20"] {
+            let mut body = heapless::String::<64>::new();
+            body.push_str("ssid=a&mqtt_port=").unwrap();
+            body.push_str(port).unwrap();
+            assert_eq!(parse(&body).err(), Some(FormError::InvalidBrokerPort), "port {}", port);
+        }
+        assert!(parse("ssid=a&mqtt_port=1").is_ok());
+        assert!(parse("ssid=a&mqtt_port=65535").is_ok());
+        assert_eq!(parse_port(" 1883 "), Some(1883));
+    }
+
+    #[test_case]
+    fn rejects_bad_hosts_tokens_and_gates() {
+        assert_eq!(parse("ssid=a&mqtt_host=bad_host").err(), Some(FormError::InvalidBrokerHost));
+        assert_eq!(parse("ssid=a&mqtt_host=-a.local").err(), Some(FormError::InvalidBrokerHost));
+        assert!(parse("ssid=a&mqtt_host=192.168.1.10").is_ok());
+        assert_eq!(parse("ssid=a&http_token=short").err(), Some(FormError::TokenTooShort));
+        assert_eq!(parse("ssid=a&gates=G1%3AMain%3ASIDEWAYS%3A1").err(), Some(FormError::InvalidGates));
+    }
+
+    #[test_case]
+    fn parses_gate_lists() {
+        let mut gates = parse_gate_list(" G1:Main entrance:ENTRY:1 ; G2:Exit:EXIT:2;;");
+        assert_eq!(
+            gates.next(),
+            Some(Ok(GateSpec {
+                external_id: "G1",
+                name: "Main entrance",
+                direction: GateDirection::Entry,
+                node: 1,
+            }))
+        );
+        assert_eq!(gates.next().map(|gate| gate.map(|gate| gate.direction)), Some(Ok(GateDirection::Exit)));
+        assert_eq!(gates.next(), None);
+
+        for bad in ["G1:Main:ENTRY", "G1:Main:ENTRY:1:2", ":Main:ENTRY:1", "G/1:Main:ENTRY:1", "G1::ENTRY:1", "G1:Main:ENTRY:x"] {
+            assert_eq!(parse_gate_list(bad).next(), Some(Err(FormError::InvalidGates)), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,95 @@
+//! Choice between the configured WiFi networks from a scan: by priority
+//! (list order) among networks with a usable signal, by RSSI otherwise, and
+//! skipping networks that keep failing.
+
+pub const MAX_NETWORKS: usize = 4;
+/// Consecutive connection failures before a network is skipped.
+pub const FAILOVER_AFTER: u8 = 3;
+/// Below this a higher-priority network loses to any usable one.
+pub const MIN_USABLE_RSSI: i8 = -85;
+
+/// One access point seen in a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanEntry<'a> {
+    pub ssid: &'a str,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+/// Network to join and the access point to pin, the strongest with that SSID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Choice {
+    /// Index into the configured list.
+    pub index: usize,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub rssi: i8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetworkSelector {
+    failures: [u8; MAX_NETWORKS],
+}
+
+impl NetworkSelector {
+    pub const fn new() -> Self {
+        Self {
+            failures: [0; MAX_NETWORKS],
+        }
+    }
+
+    /// Picks among `ssids` (highest priority first) from `scan`. When every
+    /// visible network has failed over, the counters start again so the
+    /// gateway never gives up for good.
+    pub fn choose(&mut self, ssids: &[&str], scan: &[ScanEntry<'_>]) -> Option<Choice> {
+        let choice = self.best(ssids, scan, true);
+        if choice.is_some() {
+            return choice;
+        }
+        let fallback = self.best(ssids, scan, false);
+        if fallback.is_some() {
+            self.failures = [0; MAX_NETWORKS];
+        }
+        fallback
+    }
+
+    fn best(&self, ssids: &[&str], scan: &[ScanEntry<'_>], skip_failed: bool) -> Option<Choice> {
+        let visible = ssids.iter().enumerate().take(MAX_NETWORKS).filter_map(|(index, ssid)| {
+            if skip_failed && self.failures[index] >= FAILOVER_AFTER {
+                return None;
+            }
+            scan.iter().filter(|entry| entry.ssid == *ssid).max_by_key(|entry| entry.rssi).map(|entry| Choice {
+                index,
+                bssid: entry.bssid,
+                channel: entry.channel,
+                rssi: entry.rssi,
+            })
+        });
+
+        // Usable networks by priority, then the rest by signal
+        visible.min_by_key(|choice| {
+            if choice.rssi >= MIN_USABLE_RSSI {
+                (0, choice.index as i16)
+            } else {
+                (1, -(choice.rssi as i16))
+            }
+        })
+    }
+
+    pub fn record_failure(&mut self, index: usize) {
+        if let Some(failures) = self.failures.get_mut(index) {
+            *failures = failures.saturating_add(1);
+        }
+    }
+
+    pub fn record_success(&mut self, index: usize) {
+        if let Some(failures) = self.failures.get_mut(index) {
+            *failures = 0;
+        }
+    }
+
+    pub fn failures(&self, index: usize) -> u8 {
+        self.failures.get(index).copied().unwrap_or(0)
+    }
+}
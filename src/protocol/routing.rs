@@ -0,0 +1,409 @@
+//! Multi-hop relaying between LoRa nodes. Routes are learned distance-vector
+//! style from every frame heard (the sender is a neighbour, the origin is
+//! one hop further through it); frames to unknown destinations are flooded,
+//! and a duplicate cache keeps floods from looping. Unicast frames follow
+//! `next_hop` and are bounded by their TTL. Time is passed in, so a whole
+//! topology can be simulated on the host.
+
+use crate::protocol::lora::{LoraEnvelope, NodeAddress};
+
+/// Hops a frame may take, set by its origin.
+pub const DEFAULT_TTL: u8 = 4;
+pub const MAX_ROUTES: usize = 16;
+pub const DEDUPE_CAPACITY: usize = 32;
+/// Routes not refreshed for this long are dropped.
+pub const ROUTE_TIMEOUT_MS: u64 = 10 * 60 * 1000;
+/// Flooded frames seen longer ago than this are no longer duplicates. Kept
+/// below the gateway's retransmit interval, so a retransmission is relayed
+/// again instead of being taken for a copy of the first attempt.
+pub const DEDUPE_WINDOW_MS: u64 = 4 * 1000;
+/// A route with more hops only wins if its link is this much better (dB).
+const HOP_PENALTY_DB: i16 = 10;
+
+/// Quality of the last link of a received frame, from the radio's
+/// `PacketStatus`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LinkQuality {
+    pub rssi: i16,
+    pub snr: i16,
+}
+
+impl LinkQuality {
+    /// RSSI with SNR weighed in; higher is better.
+    pub fn score(&self) -> i16 {
+        self.rssi.saturating_add(self.snr.saturating_mul(2))
+    }
+}
+
+/// Routing fields of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RouteHeader {
+    pub src: NodeAddress,
+    pub dst: Option<NodeAddress>,
+    pub seq: u16,
+    pub kind: u8,
+    pub ttl: u8,
+    pub hops: u8,
+    pub via: NodeAddress,
+    pub next_hop: Option<NodeAddress>,
+}
+
+impl RouteHeader {
+    /// Frames from nodes without routing count as direct, single-hop ones.
+    pub fn of(envelope: &LoraEnvelope) -> Self {
+        let src = envelope.src.unwrap_or(0);
+        Self {
+            src,
+            dst: envelope.dst,
+            seq: envelope.seq,
+            kind: envelope.msg_type as u8,
+            ttl: envelope.ttl.unwrap_or(1),
+            hops: envelope.hops.unwrap_or(0),
+            via: envelope.via.unwrap_or(src),
+            next_hop: envelope.next_hop,
+        }
+    }
+
+    pub fn apply(&self, envelope: &mut LoraEnvelope) {
+        envelope.ttl = Some(self.ttl);
+        envelope.hops = Some(self.hops);
+        envelope.via = Some(self.via);
+        envelope.next_hop = self.next_hop;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub destination: NodeAddress,
+    pub next_hop: NodeAddress,
+    pub hops: u8,
+    pub quality: i16,
+    pub updated_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Our own frame, repeated back by a neighbour.
+    Echo,
+    Duplicate,
+    /// Unicast relayed through another node.
+    NotForUs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Hand the frame to the application; `relay` is set when it must also
+    /// be forwarded (broadcasts).
+    Deliver { relay: Option<RouteHeader> },
+    /// Retransmit with this header; not for us.
+    Forward(RouteHeader),
+    Drop(DropReason),
+}
+
+#[derive(Debug, Clone)]
+pub struct Router {
+    local: NodeAddress,
+    routes: heapless::Vec<Route, MAX_ROUTES>,
+    seen: heapless::Deque<(NodeAddress, u16, u8, u64), DEDUPE_CAPACITY>,
+}
+
+impl Router {
+    pub const fn new(local: NodeAddress) -> Self {
+        Self {
+            local,
+            routes: heapless::Vec::new(),
+            seen: heapless::Deque::new(),
+        }
+    }
+
+    pub fn local(&self) -> NodeAddress {
+        self.local
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    /// Fresh route to `destination`, if any.
+    pub fn route(&self, destination: NodeAddress, now_ms: u64) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|route| route.destination == destination && now_ms.saturating_sub(route.updated_ms) <= ROUTE_TIMEOUT_MS)
+    }
+
+    /// Header for a frame originated here. Known destinations go through
+    /// their next hop; unknown ones are flooded.
+    pub fn originate(&self, dst: Option<NodeAddress>, seq: u16, kind: u8, now_ms: u64) -> RouteHeader {
+        RouteHeader {
+            src: self.local,
+            dst,
+            seq,
+            kind,
+            ttl: DEFAULT_TTL,
+            hops: 0,
+            via: self.local,
+            next_hop: dst.and_then(|dst| self.route(dst, now_ms)).map(|route| route.next_hop),
+        }
+    }
+
+    /// Learns from a received frame and decides what to do with it.
+    pub fn on_receive(&mut self, header: &RouteHeader, link: LinkQuality, now_ms: u64) -> Decision {
+        if header.via == self.local || header.src == self.local {
+            return Decision::Drop(DropReason::Echo);
+        }
+
+        // The sender is a neighbour; the origin is reachable through it
+        let quality = link.score();
+        self.learn(header.via, header.via, 1, quality, now_ms);
+        if header.src != header.via {
+            self.learn(header.src, header.via, header.hops.saturating_add(1), quality, now_ms);
+        }
+
+        if header.next_hop.is_none() {
+            if self.is_duplicate(header, now_ms) {
+                return Decision::Drop(DropReason::Duplicate);
+            }
+            self.remember(header.src, header.seq, header.kind, now_ms);
+        }
+
+        let relay = self.relay_header(header, now_ms);
+        match header.dst {
+            Some(dst) if dst == self.local => Decision::Deliver { relay: None },
+            None => Decision::Deliver { relay },
+            Some(_) => match header.next_hop {
+                Some(next_hop) if next_hop != self.local => Decision::Drop(DropReason::NotForUs),
+                _ => match relay {
+                    Some(relay) => Decision::Forward(relay),
+                    None => Decision::Drop(DropReason::NotForUs),
+                },
+            },
+        }
+    }
+
+    /// Header to retransmit with, or `None` when the TTL is spent.
+    fn relay_header(&self, header: &RouteHeader, now_ms: u64) -> Option<RouteHeader> {
+        if header.ttl <= 1 {
+            return None;
+        }
+        Some(RouteHeader {
+            ttl: header.ttl - 1,
+            hops: header.hops.saturating_add(1),
+            via: self.local,
+            next_hop: header.dst.and_then(|dst| self.route(dst, now_ms)).map(|route| route.next_hop),
+            ..*header
+        })
+    }
+
+    fn learn(&mut self, destination: NodeAddress, next_hop: NodeAddress, hops: u8, quality: i16, now_ms: u64) {
+        let candidate = Route {
+            destination,
+            next_hop,
+            hops,
+            quality,
+            updated_ms: now_ms,
+        };
+
+        if let Some(route) = self.routes.iter_mut().find(|route| route.destination == destination) {
+            let stale = now_ms.saturating_sub(route.updated_ms) > ROUTE_TIMEOUT_MS;
+            if stale || route.next_hop == next_hop || is_better(&candidate, route) {
+                *route = candidate;
+            }
+            return;
+        }
+
+        if self.routes.is_full() {
+            // Make room by dropping the oldest route
+            if let Some(oldest) = (0..self.routes.len()).min_by_key(|&i| self.routes[i].updated_ms) {
+                self.routes.swap_remove(oldest);
+            }
+        }
+        let _ = self.routes.push(candidate);
+    }
+
+    fn is_duplicate(&self, header: &RouteHeader, now_ms: u64) -> bool {
+        self.seen.iter().any(|&(src, seq, kind, seen_ms)| {
+            src == header.src && seq == header.seq && kind == header.kind && now_ms.saturating_sub(seen_ms) <= DEDUPE_WINDOW_MS
+        })
+    }
+
+    fn remember(&mut self, src: NodeAddress, seq: u16, kind: u8, now_ms: u64) {
+        if self.seen.is_full() {
+            self.seen.pop_front();
+        }
+        let _ = self.seen.push_back((src, seq, kind, now_ms));
+    }
+}
+
+/// Fewer hops wins unless the longer path's link is clearly better.
+fn is_better(candidate: &Route, current: &Route) -> bool {
+    let hop_difference = candidate.hops as i16 - current.hops as i16;
+    candidate.quality - hop_difference * HOP_PENALTY_DB > current.quality
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIND: u8 = 1;
+    const SNR: i16 = 5;
+
+    /// Nodes and the radio links between them; every transmission reaches
+    /// all neighbours of the sender, and relays go back on the air.
+    struct Mesh<const N: usize> {
+        routers: [Router; N],
+        links: heapless::Vec<(NodeAddress, NodeAddress, i16), 16>,
+        delivered: heapless::Vec<(NodeAddress, NodeAddress, u16), 32>,
+        dropped: heapless::Vec<(NodeAddress, DropReason), 64>,
+    }
+
+    impl<const N: usize> Mesh<N> {
+        fn new(nodes: [NodeAddress; N], links: &[(NodeAddress, NodeAddress, i16)]) -> Self {
+            Self {
+                routers: nodes.map(Router::new),
+                links: links.iter().copied().collect(),
+                delivered: heapless::Vec::new(),
+                dropped: heapless::Vec::new(),
+            }
+        }
+
+        fn router(&mut self, node: NodeAddress) -> &mut Router {
+            self.routers.iter_mut().find(|router| router.local() == node).unwrap()
+        }
+
+        fn send(&mut self, from: NodeAddress, dst: Option<NodeAddress>, seq: u16, now_ms: u64) {
+            let header = self.router(from).originate(dst, seq, KIND, now_ms);
+            self.transmit(from, header, now_ms);
+        }
+
+        fn transmit(&mut self, from: NodeAddress, header: RouteHeader, now_ms: u64) {
+            let mut air: heapless::Deque<(NodeAddress, RouteHeader), 32> = heapless::Deque::new();
+            air.push_back((from, header)).unwrap();
+
+            while let Some((sender, header)) = air.pop_front() {
+                let links = self.links.clone();
+                for &(a, b, rssi) in links.iter() {
+                    let receiver = match (a == sender, b == sender) {
+                        (true, _) => b,
+                        (_, true) => a,
+                        _ => continue,
+                    };
+                    let link = LinkQuality { rssi, snr: SNR };
+                    match self.router(receiver).on_receive(&header, link, now_ms) {
+                        Decision::Deliver { relay } => {
+                            self.delivered.push((receiver, header.src, header.seq)).unwrap();
+                            if let Some(relay) = relay {
+                                air.push_back((receiver, relay)).unwrap();
+                            }
+                        }
+                        Decision::Forward(relay) => air.push_back((receiver, relay)).unwrap(),
+                        Decision::Drop(reason) => self.dropped.push((receiver, reason)).unwrap(),
+                    }
+                }
+            }
+        }
+
+        fn deliveries(&self, node: NodeAddress, seq: u16) -> usize {
+            self.delivered.iter().filter(|&&(at, _, s)| at == node && s == seq).count()
+        }
+
+        fn drops(&self, node: NodeAddress, reason: DropReason) -> usize {
+            self.dropped.iter().filter(|&&(at, r)| at == node && r == reason).count()
+        }
+
+        fn next_hop(&mut self, node: NodeAddress, destination: NodeAddress, now_ms: u64) -> Option<(NodeAddress, u8)> {
+            self.router(node).route(destination, now_ms).map(|route| (route.next_hop, route.hops))
+        }
+    }
+
+    fn line<const N: usize>(nodes: [NodeAddress; N]) -> Mesh<N> {
+        let links: heapless::Vec<(NodeAddress, NodeAddress, i16), 16> = nodes.windows(2).map(|pair| (pair[0], pair[1], -70)).collect();
+        Mesh::new(nodes, &links)
+    }
+
+    #[test_case]
+    fn flood_on_a_line_is_delivered_once_and_teaches_routes() {
+        let mut mesh = line([10, 20, 30, 40]);
+        mesh.send(10, None, 1, 0);
+
+        for node in [20, 30, 40] {
+            assert_eq!(mesh.deliveries(node, 1), 1);
+        }
+        // Each relay comes back to the node before it
+        assert_eq!(mesh.drops(10, DropReason::Echo), 1);
+        assert_eq!(mesh.drops(20, DropReason::Duplicate), 1);
+        assert_eq!(mesh.drops(30, DropReason::Duplicate), 1);
+
+        assert_eq!(mesh.next_hop(20, 10, 0), Some((10, 1)));
+        assert_eq!(mesh.next_hop(30, 10, 0), Some((20, 2)));
+        assert_eq!(mesh.next_hop(40, 10, 0), Some((30, 3)));
+        assert_eq!(mesh.next_hop(40, 30, 0), Some((30, 1)));
+    }
+
+    #[test_case]
+    fn unicast_follows_learned_routes_back() {
+        let mut mesh = line([10, 20, 30, 40]);
+        mesh.send(10, None, 1, 0);
+        mesh.delivered.clear();
+        mesh.dropped.clear();
+
+        mesh.send(40, Some(10), 7, 100);
+        assert_eq!(mesh.deliveries(10, 7), 1);
+        // Only the destination delivers; relays just forward
+        assert_eq!(mesh.delivered.len(), 1);
+        // 30 overhears 20 forwarding to 10
+        assert_eq!(mesh.drops(30, DropReason::NotForUs), 1);
+        assert_eq!(mesh.next_hop(10, 40, 100), Some((20, 3)));
+    }
+
+    #[test_case]
+    fn ttl_bounds_the_flood() {
+        let mut mesh = line([1, 2, 3, 4, 5, 6]);
+        mesh.send(1, None, 1, 0);
+
+        // DEFAULT_TTL hops: the node four hops away still hears it, but does
+        // not relay it
+        for node in [2, 3, 4, 5] {
+            assert_eq!(mesh.deliveries(node, 1), 1);
+        }
+        assert_eq!(mesh.deliveries(6, 1), 0);
+        assert_eq!(mesh.next_hop(6, 1, 0), None);
+    }
+
+    #[test_case]
+    fn diamond_dedupes_and_prefers_the_better_path() {
+        // 1 reaches 4 through 2 (strong) or 3 (weak); 3 is heard first
+        let links = [(1, 3, -70), (1, 2, -70), (3, 4, -100), (2, 4, -60)];
+        let mut mesh = Mesh::new([1, 2, 3, 4], &links);
+        mesh.send(1, None, 1, 0);
+
+        assert_eq!(mesh.deliveries(4, 1), 1);
+        assert_eq!(mesh.drops(4, DropReason::Duplicate), 1);
+        // Learned through 3 first, then replaced by the better link through 2
+        assert_eq!(mesh.next_hop(4, 1, 0), Some((2, 2)));
+
+        // A retransmission inside the window is a duplicate, after it is new
+        mesh.send(1, None, 1, DEDUPE_WINDOW_MS);
+        assert_eq!(mesh.deliveries(4, 1), 1);
+        mesh.send(1, None, 1, 2 * DEDUPE_WINDOW_MS + 1);
+        assert_eq!(mesh.deliveries(4, 1), 2);
+    }
+
+    #[test_case]
+    fn stale_routes_are_replaced_by_worse_ones() {
+        let links = [(1, 2, -70), (1, 3, -70), (2, 4, -60), (3, 4, -100)];
+        let mut mesh = Mesh::new([1, 2, 3, 4], &links);
+        mesh.send(1, None, 1, 0);
+        assert_eq!(mesh.next_hop(4, 1, 0), Some((2, 2)));
+
+        // The strong link goes away; the weak path only wins once the old
+        // route has timed out
+        mesh.links.retain(|&(a, b, _)| (a, b) != (2, 4));
+        mesh.send(1, None, 2, 1_000);
+        assert_eq!(mesh.next_hop(4, 1, 1_000), Some((2, 2)));
+
+        let later = 1_000 + ROUTE_TIMEOUT_MS + 1;
+        assert_eq!(mesh.next_hop(4, 1, later), None);
+        mesh.send(1, None, 3, later);
+        assert_eq!(mesh.next_hop(4, 1, later), Some((3, 2)));
+    }
+}
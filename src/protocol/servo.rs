@@ -0,0 +1,347 @@
+//! Servo calibration and motion profiles. Angles are in millidegrees and
+//! time in milliseconds, all integer: the duty written for an angle goes
+//! through `SetDutyCycle`, so a fake channel can check it on the host.
+
+use embedded_hal::pwm::SetDutyCycle;
+
+/// PWM period of a hobby servo (50 Hz).
+pub const SERVO_PERIOD_US: u32 = 20_000;
+pub const MAX_ANGLE_MDEG: i32 = 180_000;
+pub const DEFAULT_SPEED_DEG_S: u32 = 90;
+pub const DEFAULT_ACCEL_DEG_S2: u32 = 180;
+pub const DEFAULT_EASED_MS: u32 = 1_000;
+
+/// Pulse widths at 0° and 180°, and the two gate positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoCalibration {
+    pub min_pulse_us: u32,
+    pub max_pulse_us: u32,
+    pub open_angle_deg: u16,
+    pub close_angle_deg: u16,
+}
+
+impl Default for ServoCalibration {
+    /// 2.5%–12.5% duty at 50 Hz, open at 135°, closed at 180°.
+    fn default() -> Self {
+        Self {
+            min_pulse_us: 500,
+            max_pulse_us: 2_500,
+            open_angle_deg: 135,
+            close_angle_deg: 180,
+        }
+    }
+}
+
+impl ServoCalibration {
+    /// Both pulse widths inside the period, in order, and both angles
+    /// reachable.
+    pub fn is_valid(&self) -> bool {
+        self.min_pulse_us < self.max_pulse_us
+            && self.max_pulse_us < SERVO_PERIOD_US
+            && self.open_angle_deg <= 180
+            && self.close_angle_deg <= 180
+    }
+
+    pub fn pulse_us(&self, angle_mdeg: i32) -> u32 {
+        let angle_mdeg = angle_mdeg.clamp(0, MAX_ANGLE_MDEG) as u64;
+        let span = (self.max_pulse_us - self.min_pulse_us) as u64;
+        self.min_pulse_us + (angle_mdeg * span / MAX_ANGLE_MDEG as u64) as u32
+    }
+
+    /// Duty for `angle_mdeg` on a channel whose full scale is `max_duty`.
+    pub fn duty(&self, angle_mdeg: i32, max_duty: u16) -> u16 {
+        (self.pulse_us(angle_mdeg) as u64 * max_duty as u64 / SERVO_PERIOD_US as u64) as u16
+    }
+}
+
+/// Points `channel` at `angle_mdeg`.
+pub fn write_angle<C: SetDutyCycle>(channel: &mut C, calibration: &ServoCalibration, angle_mdeg: i32) -> Result<(), C::Error> {
+    let duty = calibration.duty(angle_mdeg, channel.max_duty_cycle());
+    channel.set_duty_cycle(duty)
+}
+
+/// Stops the pulses, so the servo no longer holds its position.
+pub fn release<C: SetDutyCycle>(channel: &mut C) -> Result<(), C::Error> {
+    channel.set_duty_cycle_fully_off()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MotionProfile {
+    /// Straight to the target.
+    Jump,
+    /// Constant acceleration up to `speed`, constant deceleration at the end.
+    Trapezoid { speed_deg_s: u32, accel_deg_s2: u32 },
+    /// Smoothstep over a fixed duration: slow at both ends.
+    Eased { duration_ms: u32 },
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        MotionProfile::Trapezoid {
+            speed_deg_s: DEFAULT_SPEED_DEG_S,
+            accel_deg_s2: DEFAULT_ACCEL_DEG_S2,
+        }
+    }
+}
+
+impl MotionProfile {
+    /// Length of a move over `distance_mdeg`.
+    pub fn duration_ms(&self, distance_mdeg: u32) -> u64 {
+        if distance_mdeg == 0 {
+            return 0;
+        }
+        match *self {
+            MotionProfile::Jump => 0,
+            MotionProfile::Eased { duration_ms } => duration_ms as u64,
+            MotionProfile::Trapezoid { .. } => {
+                let ramp = self.ramp(distance_mdeg);
+                2 * ramp.accel_ms + ramp.cruise_ms
+            }
+        }
+    }
+
+    /// Distance covered `elapsed_ms` into a move over `distance_mdeg`.
+    pub fn travelled(&self, distance_mdeg: u32, elapsed_ms: u64) -> u32 {
+        let total_ms = self.duration_ms(distance_mdeg);
+        if elapsed_ms >= total_ms {
+            return distance_mdeg;
+        }
+        let distance = distance_mdeg as u128;
+        let t = elapsed_ms as u128;
+        match *self {
+            MotionProfile::Jump => distance_mdeg,
+            MotionProfile::Eased { .. } => {
+                // d * (3t²T - 2t³) / T³
+                let total = total_ms as u128;
+                (distance * (3 * t * t * total - 2 * t * t * t) / (total * total * total)) as u32
+            }
+            MotionProfile::Trapezoid { .. } => {
+                let ramp = self.ramp(distance_mdeg);
+                let (accel, speed) = (ramp.accel as u128, ramp.speed as u128);
+                let accel_ms = ramp.accel_ms as u128;
+                let cruise_end_ms = accel_ms + ramp.cruise_ms as u128;
+                // Speeds in mdeg/s and accelerations in mdeg/s², times in ms
+                let ramp_distance = |t: u128| accel * t * t / 2_000_000;
+                let travelled = if t < accel_ms {
+                    ramp_distance(t)
+                } else if t < cruise_end_ms {
+                    ramp_distance(accel_ms) + speed * (t - accel_ms) / 1_000
+                } else {
+                    distance.saturating_sub(ramp_distance(total_ms as u128 - t))
+                };
+                travelled.min(distance) as u32
+            }
+        }
+    }
+
+    fn ramp(&self, distance_mdeg: u32) -> Ramp {
+        let MotionProfile::Trapezoid { speed_deg_s, accel_deg_s2 } = *self else {
+            return Ramp::default();
+        };
+        let accel = accel_deg_s2.max(1) as u64 * 1_000;
+        let distance = distance_mdeg as u64;
+        let mut speed = speed_deg_s.max(1) as u64 * 1_000;
+
+        // Too short to reach full speed: accelerate to the middle, then brake
+        if speed * speed / accel > distance {
+            speed = (distance * accel).isqrt().max(1);
+        }
+        let accel_ms = speed * 1_000 / accel;
+        let ramps_mdeg = speed * speed / accel;
+        let cruise_ms = distance.saturating_sub(ramps_mdeg) * 1_000 / speed;
+        Ramp {
+            speed,
+            accel,
+            accel_ms,
+            cruise_ms,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Ramp {
+    speed: u64,
+    accel: u64,
+    accel_ms: u64,
+    cruise_ms: u64,
+}
+
+/// One move from `from` to `to`, started at `started_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServoMove {
+    pub from_mdeg: i32,
+    pub to_mdeg: i32,
+    pub started_ms: u64,
+    pub profile: MotionProfile,
+}
+
+impl ServoMove {
+    fn distance(&self) -> u32 {
+        self.to_mdeg.abs_diff(self.from_mdeg)
+    }
+
+    pub fn ends_ms(&self) -> u64 {
+        self.started_ms + self.profile.duration_ms(self.distance())
+    }
+
+    pub fn position(&self, now_ms: u64) -> i32 {
+        let travelled = self.profile.travelled(self.distance(), now_ms.saturating_sub(self.started_ms)) as i32;
+        if self.to_mdeg >= self.from_mdeg {
+            self.from_mdeg + travelled
+        } else {
+            self.from_mdeg - travelled
+        }
+    }
+
+    pub fn is_done(&self, now_ms: u64) -> bool {
+        now_ms >= self.ends_ms()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::Infallible;
+
+    use embedded_hal::pwm::ErrorType;
+
+    use super::*;
+
+    /// Remembers the last duty written.
+    struct FakeChannel {
+        max_duty: u16,
+        duty: Option<u16>,
+    }
+
+    impl ErrorType for FakeChannel {
+        type Error = Infallible;
+    }
+
+    impl SetDutyCycle for FakeChannel {
+        fn max_duty_cycle(&self) -> u16 {
+            self.max_duty
+        }
+
+        fn set_duty_cycle(&mut self, duty: u16) -> Result<(), Self::Error> {
+            self.duty = Some(duty);
+            Ok(())
+        }
+    }
+
+    fn written(max_duty: u16, calibration: &ServoCalibration, angle_mdeg: i32) -> u16 {
+        let mut channel = FakeChannel { max_duty, duty: None };
+        write_angle(&mut channel, calibration, angle_mdeg).unwrap();
+        channel.duty.unwrap()
+    }
+
+    #[test_case]
+    fn duty_follows_calibration() {
+        let calibration = ServoCalibration::default();
+        // One duty step per microsecond
+        assert_eq!(written(20_000, &calibration, 0), 500);
+        assert_eq!(written(20_000, &calibration, MAX_ANGLE_MDEG), 2_500);
+        assert_eq!(written(20_000, &calibration, calibration.open_angle_deg as i32 * 1_000), 2_000);
+        assert_eq!(written(20_000, &calibration, calibration.close_angle_deg as i32 * 1_000), 2_500);
+        // Out of range angles stop at the ends
+        assert_eq!(written(20_000, &calibration, -5_000), 500);
+        assert_eq!(written(20_000, &calibration, 200_000), 2_500);
+
+        // 14-bit channel: 2.5% and 12.5%
+        assert_eq!(written(16_383, &calibration, 0), 409);
+        assert_eq!(written(16_383, &calibration, MAX_ANGLE_MDEG), 2_047);
+
+        let calibration = ServoCalibration {
+            min_pulse_us: 1_000,
+            max_pulse_us: 2_000,
+            open_angle_deg: 90,
+            close_angle_deg: 0,
+        };
+        assert!(calibration.is_valid());
+        assert_eq!(written(20_000, &calibration, 90_000), 1_500);
+        assert_eq!(written(20_000, &calibration, 0), 1_000);
+
+        let mut channel = FakeChannel { max_duty: 20_000, duty: None };
+        release(&mut channel).unwrap();
+        assert_eq!(channel.duty, Some(0));
+    }
+
+    #[test_case]
+    fn rejects_bad_calibration() {
+        let calibration = ServoCalibration::default();
+        assert!(!ServoCalibration { min_pulse_us: 2_500, ..calibration }.is_valid());
+        assert!(!ServoCalibration { max_pulse_us: SERVO_PERIOD_US, ..calibration }.is_valid());
+        assert!(!ServoCalibration { open_angle_deg: 181, ..calibration }.is_valid());
+    }
+
+    #[test_case]
+    fn trapezoid_starts_and_ends_on_target() {
+        let profile = MotionProfile::default();
+
+        // 45°: accelerates for 500 ms to 90°/s, then brakes at once
+        assert_eq!(profile.duration_ms(45_000), 1_000);
+        assert_eq!(profile.travelled(45_000, 0), 0);
+        assert_eq!(profile.travelled(45_000, 500), 22_500);
+        assert_eq!(profile.travelled(45_000, 1_000), 45_000);
+        assert_eq!(profile.travelled(45_000, 5_000), 45_000);
+
+        // 90°: 500 ms of cruise in the middle
+        assert_eq!(profile.duration_ms(90_000), 1_500);
+        assert_eq!(profile.travelled(90_000, 500), 22_500);
+        assert_eq!(profile.travelled(90_000, 1_000), 67_500);
+        assert_eq!(profile.travelled(90_000, 1_500), 90_000);
+
+        // 10°: never reaches full speed
+        let duration_ms = profile.duration_ms(10_000);
+        assert_eq!(duration_ms, 470);
+        assert_eq!(profile.travelled(10_000, 0), 0);
+        assert_eq!(profile.travelled(10_000, duration_ms), 10_000);
+        let mut last = 0;
+        for elapsed_ms in 0..=duration_ms {
+            let travelled = profile.travelled(10_000, elapsed_ms);
+            assert!(travelled >= last);
+            last = travelled;
+        }
+
+        assert_eq!(profile.duration_ms(0), 0);
+        assert_eq!(profile.travelled(0, 0), 0);
+    }
+
+    #[test_case]
+    fn eased_starts_and_ends_on_target() {
+        let profile = MotionProfile::Eased { duration_ms: DEFAULT_EASED_MS };
+        assert_eq!(profile.duration_ms(45_000), 1_000);
+        assert_eq!(profile.travelled(45_000, 0), 0);
+        assert_eq!(profile.travelled(45_000, 500), 22_500);
+        assert_eq!(profile.travelled(45_000, 1_000), 45_000);
+        // Slow at both ends
+        assert!(profile.travelled(45_000, 100) < 45_000 / 10);
+        assert!(profile.travelled(45_000, 900) > 45_000 * 9 / 10);
+
+        assert_eq!(MotionProfile::Jump.duration_ms(45_000), 0);
+        assert_eq!(MotionProfile::Jump.travelled(45_000, 0), 45_000);
+    }
+
+    #[test_case]
+    fn move_runs_in_either_direction() {
+        let closing = ServoMove {
+            from_mdeg: 135_000,
+            to_mdeg: 180_000,
+            started_ms: 2_000,
+            profile: MotionProfile::default(),
+        };
+        assert_eq!(closing.position(1_000), 135_000);
+        assert_eq!(closing.position(2_500), 157_500);
+        assert_eq!(closing.ends_ms(), 3_000);
+        assert!(!closing.is_done(2_999));
+        assert!(closing.is_done(3_000));
+        assert_eq!(closing.position(3_000), 180_000);
+
+        let opening = ServoMove {
+            from_mdeg: 180_000,
+            to_mdeg: 135_000,
+            ..closing
+        };
+        assert_eq!(opening.position(2_000), 180_000);
+        assert_eq!(opening.position(2_500), 157_500);
+        assert_eq!(opening.position(3_000), 135_000);
+    }
+}
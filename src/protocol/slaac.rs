@@ -0,0 +1,326 @@
+//! IPv6 stateless address autoconfiguration (RFC 4862) on top of raw
+//! ICMPv6: the Router Solicitation sent when the link comes up and the
+//! parts of a Router Advertisement that SLAAC needs. Packets include the
+//! IPv6 header, as raw sockets send and receive them.
+
+use core::net::Ipv6Addr;
+
+use crate::protocol::net_config::{self, MAX_DNS_SERVERS};
+
+pub const IPV6_HEADER_LENGTH: usize = 40;
+/// Router Solicitation with a source link-layer address option.
+pub const SOLICITATION_LENGTH: usize = IPV6_HEADER_LENGTH + 16;
+/// RFC 4861 limits: three solicitations, four seconds apart.
+pub const MAX_SOLICITATIONS: u8 = 3;
+pub const SOLICITATION_INTERVAL_MS: u64 = 4_000;
+/// Lifetime that never runs out.
+pub const INFINITE_LIFETIME: u32 = u32::MAX;
+
+const NEXT_HEADER_ICMPV6: u8 = 58;
+/// Neighbor Discovery packets must not have crossed a router.
+const ND_HOP_LIMIT: u8 = 255;
+const ROUTER_SOLICITATION: u8 = 133;
+const ROUTER_ADVERTISEMENT: u8 = 134;
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+const OPTION_SOURCE_LINK_LAYER: u8 = 1;
+const OPTION_PREFIX_INFORMATION: u8 = 3;
+const OPTION_RDNSS: u8 = 25;
+const PREFIX_AUTONOMOUS: u8 = 0x40;
+/// SLAAC only forms addresses from /64 prefixes with the EUI-64 identifier.
+const SLAAC_PREFIX_LENGTH: u8 = 64;
+
+/// Address prefix a router lets hosts configure themselves in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlaacPrefix {
+    pub prefix: Ipv6Addr,
+    pub valid_secs: u32,
+    pub preferred_secs: u32,
+}
+
+impl SlaacPrefix {
+    /// Address for the interface with `mac` in this prefix.
+    pub fn address(&self, mac: [u8; 6]) -> Ipv6Addr {
+        let mut network = [0u8; 8];
+        network.copy_from_slice(&self.prefix.octets()[..8]);
+        net_config::address_from_mac(network, mac)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterAdvert {
+    /// Link-local address of the router.
+    pub router: Ipv6Addr,
+    /// Zero: the router is not a default gateway.
+    pub router_lifetime_secs: u16,
+    /// First autonomous /64 prefix, if any.
+    pub prefix: Option<SlaacPrefix>,
+    /// RDNSS servers (RFC 8106) whose lifetime has not run out.
+    pub dns_servers: heapless::Vec<Ipv6Addr, MAX_DNS_SERVERS>,
+}
+
+/// Writes a Router Solicitation from `source` (link-local) to all routers.
+pub fn router_solicitation(source: Ipv6Addr, mac: [u8; 6], out: &mut [u8]) -> Option<usize> {
+    let out = out.get_mut(..SOLICITATION_LENGTH)?;
+    out.fill(0);
+    write_ipv6_header(out, source, ALL_ROUTERS, (SOLICITATION_LENGTH - IPV6_HEADER_LENGTH) as u16);
+
+    let icmp = &mut out[IPV6_HEADER_LENGTH..];
+    icmp[0] = ROUTER_SOLICITATION;
+    icmp[8] = OPTION_SOURCE_LINK_LAYER;
+    icmp[9] = 1; // in units of 8 bytes
+    icmp[10..16].copy_from_slice(&mac);
+
+    let checksum = icmpv6_checksum(source, ALL_ROUTERS, icmp);
+    icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+    Some(SOLICITATION_LENGTH)
+}
+
+/// Parses a Router Advertisement, `None` for anything else or for one that
+/// fails the RFC 4861 validity checks.
+pub fn parse_router_advert(packet: &[u8]) -> Option<RouterAdvert> {
+    let header = packet.get(..IPV6_HEADER_LENGTH)?;
+    if header[0] >> 4 != 6 || header[6] != NEXT_HEADER_ICMPV6 || header[7] != ND_HOP_LIMIT {
+        return None;
+    }
+    let payload_length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let icmp = packet.get(IPV6_HEADER_LENGTH..IPV6_HEADER_LENGTH + payload_length)?;
+    let source = ipv6_at(header, 8);
+    let destination = ipv6_at(header, 24);
+
+    if icmp.len() < 16 || icmp[0] != ROUTER_ADVERTISEMENT || icmp[1] != 0 {
+        return None;
+    }
+    if !is_link_local(source) || icmpv6_checksum(source, destination, icmp) != 0 {
+        return None;
+    }
+
+    let mut advert = RouterAdvert {
+        router: source,
+        router_lifetime_secs: u16::from_be_bytes([icmp[6], icmp[7]]),
+        prefix: None,
+        dns_servers: heapless::Vec::new(),
+    };
+
+    let mut options = &icmp[16..];
+    while !options.is_empty() {
+        let length = *options.get(1)? as usize * 8;
+        if length == 0 {
+            return None;
+        }
+        let option = options.get(..length)?;
+        match option[0] {
+            OPTION_PREFIX_INFORMATION if length == 32 && advert.prefix.is_none() => advert.prefix = slaac_prefix(option),
+            OPTION_RDNSS if length >= 24 => {
+                let lifetime = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+                if lifetime > 0 {
+                    for server in option[8..].chunks_exact(16) {
+                        let _ = advert.dns_servers.push(ipv6_at(server, 0));
+                    }
+                }
+            }
+            _ => {}
+        }
+        options = &options[length..];
+    }
+    Some(advert)
+}
+
+/// Prefix Information option usable for SLAAC (RFC 4862 section 5.5.3).
+fn slaac_prefix(option: &[u8]) -> Option<SlaacPrefix> {
+    let prefix_length = option[2];
+    let flags = option[3];
+    let valid_secs = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+    let preferred_secs = u32::from_be_bytes([option[8], option[9], option[10], option[11]]);
+    let prefix = ipv6_at(option, 16);
+
+    let usable = flags & PREFIX_AUTONOMOUS != 0
+        && prefix_length == SLAAC_PREFIX_LENGTH
+        && !is_link_local(prefix)
+        && preferred_secs <= valid_secs;
+    usable.then_some(SlaacPrefix {
+        prefix,
+        valid_secs,
+        preferred_secs,
+    })
+}
+
+fn write_ipv6_header(out: &mut [u8], source: Ipv6Addr, destination: Ipv6Addr, payload_length: u16) {
+    out[0] = 6 << 4;
+    out[4..6].copy_from_slice(&payload_length.to_be_bytes());
+    out[6] = NEXT_HEADER_ICMPV6;
+    out[7] = ND_HOP_LIMIT;
+    out[8..24].copy_from_slice(&source.octets());
+    out[24..40].copy_from_slice(&destination.octets());
+}
+
+/// One's complement sum over the pseudo-header and `icmp`; zero when a
+/// received packet's checksum is right.
+fn icmpv6_checksum(source: Ipv6Addr, destination: Ipv6Addr, icmp: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut add = |bytes: &[u8]| {
+        for pair in bytes.chunks(2) {
+            sum += u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32;
+        }
+    };
+    add(&source.octets());
+    add(&destination.octets());
+    add(&(icmp.len() as u32).to_be_bytes());
+    add(&[0, 0, 0, NEXT_HEADER_ICMPV6]);
+    add(icmp);
+
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn ipv6_at(bytes: &[u8], offset: usize) -> Ipv6Addr {
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(&bytes[offset..offset + 16]);
+    Ipv6Addr::from(octets)
+}
+
+fn is_link_local(address: Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x24, 0x6f, 0x28, 0xaa, 0xbb, 0xcc];
+    const ROUTER: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0x1, 0x2, 0x3, 0x4);
+    const ALL_NODES: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+    const PREFIX: Ipv6Addr = Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x2, 0, 0, 0, 0);
+
+    fn prefix_option(prefix_length: u8, flags: u8, valid: u32, preferred: u32, prefix: Ipv6Addr) -> [u8; 32] {
+        let mut option = [0u8; 32];
+        option[0] = OPTION_PREFIX_INFORMATION;
+        option[1] = 4;
+        option[2] = prefix_length;
+        option[3] = flags;
+        option[4..8].copy_from_slice(&valid.to_be_bytes());
+        option[8..12].copy_from_slice(&preferred.to_be_bytes());
+        option[16..32].copy_from_slice(&prefix.octets());
+        option
+    }
+
+    fn rdnss_option(lifetime: u32, server: Ipv6Addr) -> [u8; 24] {
+        let mut option = [0u8; 24];
+        option[0] = OPTION_RDNSS;
+        option[1] = 3;
+        option[4..8].copy_from_slice(&lifetime.to_be_bytes());
+        option[8..24].copy_from_slice(&server.octets());
+        option
+    }
+
+    /// Full Router Advertisement from `ROUTER` to all nodes.
+    fn advert(router_lifetime: u16, options: &[&[u8]], out: &mut [u8]) -> usize {
+        let icmp_length = 16 + options.iter().map(|option| option.len()).sum::<usize>();
+        let total = IPV6_HEADER_LENGTH + icmp_length;
+        out[..total].fill(0);
+        write_ipv6_header(out, ROUTER, ALL_NODES, icmp_length as u16);
+
+        let icmp = &mut out[IPV6_HEADER_LENGTH..total];
+        icmp[0] = ROUTER_ADVERTISEMENT;
+        icmp[4] = 64; // cur hop limit
+        icmp[6..8].copy_from_slice(&router_lifetime.to_be_bytes());
+        let mut pos = 16;
+        for option in options {
+            icmp[pos..pos + option.len()].copy_from_slice(option);
+            pos += option.len();
+        }
+        let checksum = icmpv6_checksum(ROUTER, ALL_NODES, icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+        total
+    }
+
+    #[test_case]
+    fn solicitation_is_a_valid_nd_packet() {
+        let source = net_config::link_local_from_mac(MAC);
+        let mut out = [0u8; 64];
+        let len = router_solicitation(source, MAC, &mut out).unwrap();
+        assert_eq!(len, SOLICITATION_LENGTH);
+        assert_eq!(out[0], 0x60);
+        assert_eq!(&out[4..8], &[0, 16, NEXT_HEADER_ICMPV6, 255]);
+        assert_eq!(ipv6_at(&out, 24), ALL_ROUTERS);
+
+        let icmp = &out[IPV6_HEADER_LENGTH..len];
+        assert_eq!(icmp[0], ROUTER_SOLICITATION);
+        assert_eq!(&icmp[8..16], &[1, 1, 0x24, 0x6f, 0x28, 0xaa, 0xbb, 0xcc]);
+        assert_eq!(icmpv6_checksum(source, ALL_ROUTERS, icmp), 0);
+        assert_eq!(router_solicitation(source, MAC, &mut out[..SOLICITATION_LENGTH - 1]), None);
+    }
+
+    #[test_case]
+    fn advert_gives_prefix_router_and_dns() {
+        let dns = Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x2, 0, 0, 0, 0x53);
+        let mut packet = [0u8; 160];
+        let len = advert(
+            1800,
+            &[&prefix_option(64, 0xC0, 86_400, 14_400, PREFIX), &rdnss_option(600, dns)],
+            &mut packet,
+        );
+        let advert = parse_router_advert(&packet[..len]).unwrap();
+
+        assert_eq!(advert.router, ROUTER);
+        assert_eq!(advert.router_lifetime_secs, 1800);
+        let prefix = advert.prefix.unwrap();
+        assert_eq!((prefix.valid_secs, prefix.preferred_secs), (86_400, 14_400));
+        assert_eq!(prefix.address(MAC), Ipv6Addr::new(0x2001, 0xdb8, 0x1, 0x2, 0x266f, 0x28ff, 0xfeaa, 0xbbcc));
+        assert_eq!(advert.dns_servers.as_slice(), &[dns]);
+    }
+
+    #[test_case]
+    fn unusable_prefixes_are_skipped() {
+        let link_local = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
+        let mut packet = [0u8; 256];
+        let len = advert(
+            0,
+            &[
+                &prefix_option(64, 0x80, 600, 600, PREFIX),    // on-link only
+                &prefix_option(48, 0xC0, 600, 600, PREFIX),    // not a /64
+                &prefix_option(64, 0xC0, 600, 900, PREFIX),    // preferred > valid
+                &prefix_option(64, 0xC0, 600, 600, link_local), // link-local
+                &rdnss_option(0, PREFIX),                      // expired
+            ],
+            &mut packet,
+        );
+        let advert = parse_router_advert(&packet[..len]).unwrap();
+        assert_eq!(advert.prefix, None);
+        assert!(advert.dns_servers.is_empty());
+        assert_eq!(advert.router_lifetime_secs, 0);
+    }
+
+    #[test_case]
+    fn invalid_adverts_are_rejected() {
+        let option = prefix_option(64, 0xC0, 600, 600, PREFIX);
+        let mut packet = [0u8; 128];
+        let len = advert(1800, &[&option], &mut packet);
+
+        let mut forwarded = packet;
+        forwarded[7] = 254;
+        assert_eq!(parse_router_advert(&forwarded[..len]), None);
+
+        let mut corrupted = packet;
+        corrupted[len - 1] ^= 1;
+        assert_eq!(parse_router_advert(&corrupted[..len]), None);
+
+        assert_eq!(parse_router_advert(&packet[..len - 1]), None);
+
+        let mut global_source = packet;
+        global_source[8..24].copy_from_slice(&PREFIX.octets());
+        let icmp = &mut global_source[IPV6_HEADER_LENGTH..len];
+        icmp[2..4].fill(0);
+        let checksum = icmpv6_checksum(PREFIX, ALL_NODES, icmp);
+        icmp[2..4].copy_from_slice(&checksum.to_be_bytes());
+        assert_eq!(parse_router_advert(&global_source[..len]), None);
+
+        let mut zero_length_option = [0u8; 128];
+        let mut option = option;
+        option[1] = 0;
+        let len = advert(1800, &[&option], &mut zero_length_option);
+        assert_eq!(parse_router_advert(&zero_length_option[..len]), None);
+    }
+}
@@ -0,0 +1,205 @@
+/// Longest filter the router stores.
+pub const MAX_FILTER_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopicError {
+    InvalidFilter,
+    FilterTooLong,
+    RouterFull,
+}
+
+/// Checks a subscription filter against the MQTT rules: `+` must fill a whole
+/// level and `#` must be the whole last level.
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        if level.contains('#') && (level != "#" || levels.peek().is_some()) {
+            return false;
+        }
+        if level.contains('+') && level != "+" {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Returns whether `topic` is matched by the subscription `filter`.
+///
+/// `sensors/#` also matches `sensors` itself, and topics starting with `$`
+/// are never matched by a leading wildcard, as in the specification.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(filter_level), Some(topic_level)) if filter_level == topic_level => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns the topic level at `index`, e.g. the gate id captured by a `+`.
+pub fn topic_level(topic: &str, index: usize) -> Option<&str> {
+    topic.split('/').nth(index)
+}
+
+/// Maps subscription filters to routes of any type `R`.
+pub struct TopicRouter<R, const N: usize> {
+    routes: heapless::Vec<(heapless::String<MAX_FILTER_LENGTH>, R), N>,
+}
+
+impl<R, const N: usize> TopicRouter<R, N> {
+    pub const fn new() -> Self {
+        Self {
+            routes: heapless::Vec::new(),
+        }
+    }
+
+    /// Registers `route` for `filter`, replacing the route of an identical filter.
+    pub fn add(&mut self, filter: &str, route: R) -> Result<(), TopicError> {
+        if !is_valid_filter(filter) {
+            return Err(TopicError::InvalidFilter);
+        }
+
+        if let Some((_, existing)) = self.routes.iter_mut().find(|(f, _)| f.as_str() == filter) {
+            *existing = route;
+            return Ok(());
+        }
+
+        let filter = heapless::String::try_from(filter).map_err(|_| TopicError::FilterTooLong)?;
+        self.routes
+            .push((filter, route))
+            .map_err(|_| TopicError::RouterFull)
+    }
+
+    pub fn remove(&mut self, filter: &str) -> Option<R> {
+        let index = self.routes.iter().position(|(f, _)| f.as_str() == filter)?;
+        Some(self.routes.swap_remove(index).1)
+    }
+
+    pub fn contains(&self, filter: &str) -> bool {
+        self.routes.iter().any(|(f, _)| f.as_str() == filter)
+    }
+
+    /// Every route whose filter matches `topic`, in registration order.
+    pub fn routes<'r>(&'r self, topic: &'r str) -> impl Iterator<Item = &'r R> + 'r {
+        self.routes
+            .iter()
+            .filter(move |(filter, _)| topic_matches(filter, topic))
+            .map(|(_, route)| route)
+    }
+
+    pub fn filters(&self) -> impl Iterator<Item = &str> {
+        self.routes.iter().map(|(filter, _)| filter.as_str())
+    }
+
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+}
+
+impl<R, const N: usize> Default for TopicRouter<R, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn single_level_wildcard_fills_exactly_one_level() {
+        assert!(topic_matches("gates/+/cmd", "gates/7/cmd"));
+        assert!(!topic_matches("gates/+/cmd", "gates/7/8/cmd"));
+        assert!(!topic_matches("gates/+/cmd", "gates/cmd"));
+        assert!(topic_matches("+/+", "a/b"));
+        assert!(!topic_matches("+", "a/b"));
+    }
+
+    #[test_case]
+    fn multi_level_wildcard_matches_the_rest_and_the_parent() {
+        assert!(topic_matches("sensors/#", "sensors/a/b/c"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("#", "anything/at/all"));
+        assert!(!topic_matches("sensors/#", "other/a"));
+    }
+
+    #[test_case]
+    fn dollar_topics_need_an_explicit_first_level() {
+        assert!(!topic_matches("#", "$SYS/broker/uptime"));
+        assert!(!topic_matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(topic_matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test_case]
+    fn empty_levels_are_levels() {
+        assert!(topic_matches("a/+/b", "a//b"));
+        assert!(topic_matches("+/a", "/a"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/b", "a/b/"));
+        assert!(topic_matches("a//b", "a//b"));
+    }
+
+    #[test_case]
+    fn invalid_filters_are_rejected() {
+        assert!(!is_valid_filter(""));
+        assert!(!is_valid_filter("a/#/b"));
+        assert!(!is_valid_filter("a/b#"));
+        assert!(!is_valid_filter("a/+b"));
+        assert!(!is_valid_filter("a+/b"));
+        assert!(is_valid_filter("#"));
+        assert!(is_valid_filter("+"));
+        assert!(is_valid_filter("a/+/#"));
+        assert!(is_valid_filter("a//b"));
+    }
+
+    #[test_case]
+    fn router_delivers_to_every_matching_filter() {
+        let mut router: TopicRouter<u8, 4> = TopicRouter::new();
+        router.add("gates/+/cmd", 1).unwrap();
+        router.add("gates/#", 2).unwrap();
+        router.add("other", 3).unwrap();
+
+        let routes: heapless::Vec<u8, 4> = router.routes("gates/7/cmd").copied().collect();
+        assert_eq!(routes.as_slice(), &[1, 2]);
+        assert_eq!(router.routes("nothing").count(), 0);
+    }
+
+    #[test_case]
+    fn router_replaces_identical_filters_and_rejects_bad_ones() {
+        let mut router: TopicRouter<u8, 2> = TopicRouter::new();
+        router.add("a/+", 1).unwrap();
+        router.add("a/+", 2).unwrap();
+        assert_eq!(router.len(), 1);
+        assert_eq!(router.routes("a/b").next(), Some(&2));
+
+        assert_eq!(router.add("a/#/b", 3), Err(TopicError::InvalidFilter));
+        let long = [b'a'; MAX_FILTER_LENGTH + 1];
+        assert_eq!(router.add(core::str::from_utf8(&long).unwrap(), 3), Err(TopicError::FilterTooLong));
+
+        router.add("b", 3).unwrap();
+        assert_eq!(router.add("c", 4), Err(TopicError::RouterFull));
+        assert_eq!(router.remove("a/+"), Some(2));
+        assert!(!router.contains("a/+"));
+        assert_eq!(router.remove("a/+"), None);
+    }
+}